@@ -1,8 +1,12 @@
 use crate::{
-    agent::{NonceFactory, NonceGenerator, Transport},
+    agent::{
+        AgentObserver, CborLimits, Clock, DefaultSleeper, NonceFactory, NonceGenerator, Sleeper,
+        Transport, WallClock, WireFormat,
+    },
+    export::Principal,
     identity::{anonymous::AnonymousIdentity, Identity},
 };
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 /// A configuration for an agent.
 pub struct AgentConfig {
@@ -10,14 +14,38 @@ pub struct AgentConfig {
     pub nonce_factory: Arc<dyn NonceGenerator>,
     /// See [`with_identity`](super::AgentBuilder::with_identity).
     pub identity: Arc<dyn Identity>,
+    /// See [`with_fallback_identity`](super::AgentBuilder::with_fallback_identity).
+    pub fallback_identity: Option<Arc<dyn Identity>>,
+    /// See [`with_default_to_anonymous`](super::AgentBuilder::with_default_to_anonymous).
+    pub default_to_anonymous: bool,
     /// See [`with_ingress_expiry`](super::AgentBuilder::with_ingress_expiry).
     pub ingress_expiry: Option<Duration>,
+    /// See [`with_max_cert_age`](super::AgentBuilder::with_max_cert_age).
+    pub max_cert_age: Duration,
     /// See [`with_transport`](super::AgentBuilder::with_transport).
     pub transport: Option<Arc<dyn Transport>>,
     /// See [`verify_query_signatures`](super::AgentBuilder::with_verify_query_signatures).
     pub verify_query_signatures: bool,
     /// See [`with_max_concurrent_requests`](super::AgentBuilder::with_max_concurrent_requests).
     pub max_concurrent_requests: usize,
+    /// See [`with_max_read_state_paths`](super::AgentBuilder::with_max_read_state_paths).
+    pub max_read_state_paths: usize,
+    /// See [`with_canister_aliases`](super::AgentBuilder::with_canister_aliases).
+    pub canister_aliases: HashMap<String, Principal>,
+    /// See [`with_canister_info_cache`](super::AgentBuilder::with_canister_info_cache).
+    pub canister_info_cache_ttl: Option<Duration>,
+    /// See [`with_cbor_limits`](super::AgentBuilder::with_cbor_limits).
+    pub cbor_limits: CborLimits,
+    /// See [`with_wire_format`](super::AgentBuilder::with_wire_format).
+    pub wire_format: Option<Arc<dyn WireFormat>>,
+    /// See [`with_observer`](super::AgentBuilder::with_observer).
+    pub observer: Option<Arc<dyn AgentObserver>>,
+    /// See [`with_clock`](super::AgentBuilder::with_clock).
+    pub clock: Arc<dyn Clock>,
+    /// See [`with_sleeper`](super::AgentBuilder::with_sleeper).
+    pub sleeper: Arc<dyn Sleeper>,
+    /// See [`with_verification_key_source`](super::AgentBuilder::with_verification_key_source).
+    pub verification_key_source: Option<Arc<dyn Transport>>,
 }
 
 impl Default for AgentConfig {
@@ -25,10 +53,22 @@ impl Default for AgentConfig {
         Self {
             nonce_factory: Arc::new(NonceFactory::random()),
             identity: Arc::new(AnonymousIdentity {}),
+            fallback_identity: None,
+            default_to_anonymous: true,
             ingress_expiry: None,
+            max_cert_age: Duration::from_secs(5 * 60),
             transport: None,
             verify_query_signatures: true,
             max_concurrent_requests: 50,
+            max_read_state_paths: 1000,
+            canister_aliases: HashMap::new(),
+            canister_info_cache_ttl: None,
+            cbor_limits: CborLimits::default(),
+            wire_format: None,
+            observer: None,
+            clock: Arc::new(WallClock),
+            sleeper: Arc::new(DefaultSleeper),
+            verification_key_source: None,
         }
     }
 }
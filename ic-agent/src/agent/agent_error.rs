@@ -1,6 +1,10 @@
 //! Errors that can occur when using the replica agent.
 
-use crate::{agent::status::Status, RequestIdError};
+use crate::{
+    agent::{http_transport::Endpoint, status::Status},
+    export::Principal,
+    RequestIdError,
+};
 use ic_certification::Label;
 use ic_transport_types::{InvalidRejectCodeError, RejectResponse};
 use leb128::read;
@@ -12,7 +16,10 @@ use std::{
 use thiserror::Error;
 
 /// An error that occurred when using the agent.
+///
+/// New variants may be added in minor releases, so matches on it must have a wildcard arm.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum AgentError {
     /// The replica URL was invalid.
     #[error(r#"Invalid Replica URL: "{0}""#)]
@@ -26,6 +33,15 @@ pub enum AgentError {
     #[error("Identity had a signing error: {0}")]
     SigningError(String),
 
+    /// The identity's signature was declined, e.g. by the user asked to confirm it.
+    #[error("Signing was declined: {0}")]
+    SigningDeclined(String),
+
+    /// An update call was to be made anonymously, by an agent built with
+    /// [`with_default_to_anonymous(false)`](crate::agent::AgentBuilder::with_default_to_anonymous).
+    #[error("An identity is required to make update calls, but none was set.")]
+    IdentityRequired,
+
     /// The data fetched was invalid CBOR.
     #[error("Invalid CBOR data, could not deserialize: {0}")]
     InvalidCborData(#[from] serde_cbor::Error),
@@ -66,6 +82,13 @@ pub enum AgentError {
     #[error("Status endpoint returned an invalid status.")]
     InvalidReplicaStatus,
 
+    /// The replica did not report itself healthy in time.
+    #[error("The replica did not become healthy in time; its last reported health status was {last_status:?}")]
+    ReplicaUnhealthy {
+        /// The health status the replica last reported, if it reported one.
+        last_status: Option<String>,
+    },
+
     /// The call was marked done, but no reply was provided.
     #[error("Call was marked as done but we never saw the reply. Request ID: {0}")]
     RequestStatusDoneNoReply(String),
@@ -114,10 +137,35 @@ pub enum AgentError {
     #[error("Certificate is stale (over {0:?}). Is the computer's clock synchronized?")]
     CertificateOutdated(Duration),
 
+    /// The certificate was older than the agent's maximum certificate age.
+    #[error("Certificate is {age:?} old, more than the maximum age allowed")]
+    CertificateTooOld {
+        /// How long ago the certificate was issued, by its `time` leaf.
+        age: Duration,
+    },
+
     /// The certificate contained more than one delegation.
     #[error("The certificate contained more than one delegation")]
     CertificateHasTooManyDelegations,
 
+    /// A delegation in a delegation chain had expired.
+    #[error("Delegation {index} of the chain expired at {expiration} ns since the Unix epoch")]
+    DelegationExpired {
+        /// The position of the delegation in the chain.
+        index: usize,
+        /// The delegation's expiration, in nanoseconds since the Unix epoch.
+        expiration: u64,
+    },
+
+    /// A delegation in a delegation chain was not validly signed by the key preceding it.
+    #[error("Delegation {index} of the chain has an invalid signature: {reason}")]
+    DelegationSignatureInvalid {
+        /// The position of the delegation in the chain.
+        index: usize,
+        /// Why the signature could not be verified.
+        reason: String,
+    },
+
     /// The query response did not contain any node signatures.
     #[error("Query response did not contain any node signatures")]
     MissingSignature,
@@ -179,14 +227,165 @@ pub enum AgentError {
     #[error("Missing replica transport in the Agent Builder.")]
     MissingReplicaTransport(),
 
+    /// A `BlockingAgent` method was called from within an async runtime.
+    #[error("BlockingAgent methods cannot be called from within an async runtime; use the Agent's async methods instead.")]
+    BlockingInAsyncContext,
+
+    /// The request size exceeded the transport's limit, so it was not sent.
+    #[error("Request of {size} bytes exceeds the transport's limit of {limit} bytes, so it was not sent.")]
+    RequestTooLarge {
+        /// The size of the request body.
+        size: usize,
+        /// The transport's limit.
+        limit: usize,
+    },
+
+    /// The transport has sent and received as many bytes as its budget allows, so the request was
+    /// not sent; see e.g. [`ReqwestTransport::with_byte_budget`](super::http_transport::ReqwestTransport::with_byte_budget).
+    #[error("The transport's budget of {budget} bytes sent and received is spent.")]
+    ByteBudgetExceeded {
+        /// The transport's budget.
+        budget: u64,
+    },
+
     /// The response size exceeded the provided limit.
     #[error("Response size exceeded limit.")]
     ResponseSizeExceededLimit(),
 
+    /// A [`CircuitBreakerTransport`](super::http_transport::CircuitBreakerTransport) is failing
+    /// requests fast because the replica has been failing.
+    #[error("The circuit breaker is open; the request was not sent.")]
+    CircuitOpen,
+
+    /// The root key cannot be fetched from the mainnet, whose root key is built into the agent.
+    #[error("Refusing to fetch the root key from the Internet Computer mainnet; the built-in root key must be used.")]
+    FetchRootKeyOnMainnet,
+
+    /// The method only exists on local and test replicas, and the agent points at the mainnet.
+    #[error("The method {0} is not available on the Internet Computer mainnet; the request was not sent.")]
+    UnavailableOnMainnet(String),
+
+    /// The transport was configured not to make requests to this kind of endpoint.
+    #[error(
+        "The transport does not allow requests to the {0:?} endpoint; the request was not sent."
+    )]
+    EndpointNotAllowed(Endpoint),
+
+    /// A successful response was not of the expected content type, with
+    /// [`ReqwestTransport::with_strict_content_type`](super::http_transport::ReqwestTransport::with_strict_content_type).
+    #[error("The response has an unexpected content type: {got:?}")]
+    UnexpectedContentType {
+        /// The `Content-Type` header of the response, if it had one.
+        got: Option<String>,
+    },
+
+    /// The request was cancelled through its [`AbortHandle`](super::AbortHandle).
+    #[error("The request was cancelled.")]
+    RequestCancelled,
+
+    /// Too few of the replicas behind a [`QuorumTransport`](super::http_transport::QuorumTransport)
+    /// returned the same query response.
+    #[error(
+        "Only {agreements} replicas agreed on the query response, short of the quorum of {quorum}."
+    )]
+    QuorumNotReached {
+        /// The number of replicas in the largest group that returned the same response.
+        agreements: usize,
+        /// The number of replicas required to agree.
+        quorum: usize,
+    },
+
+    /// An `IC-Certificate` header could not be parsed.
+    #[error("Invalid IC-Certificate header: {0}")]
+    InvalidCertificateHeader(String),
+
+    /// An asset fetched with [`Agent::fetch_asset`](super::Agent::fetch_asset) could not be
+    /// verified against its certification.
+    #[error("Asset verification failed: {0}")]
+    AssetVerificationFailed(String),
+
+    /// The certificate embedded in a reply checked with
+    /// [`Agent::verify_response_certificate`](super::Agent::verify_response_certificate) does not
+    /// certify its data.
+    #[error("Response verification failed: {0}")]
+    ResponseVerificationFailed(String),
+
+    /// An environment variable read by [`Agent::from_env`](super::Agent::from_env) is missing or
+    /// malformed.
+    #[error("Invalid environment variable {name}: {reason}")]
+    InvalidEnvironmentVariable {
+        /// The name of the variable.
+        name: &'static str,
+        /// What is wrong with its value.
+        reason: String,
+    },
+
+    /// No canister was given this alias; see [`AgentBuilder::with_canister_aliases`](crate::agent::AgentBuilder::with_canister_aliases).
+    #[error("No canister has the alias `{0}`.")]
+    UnknownCanisterAlias(String),
+
+    /// The canister does not exist on the subnet its ID is routed to, so it is not hosted by any
+    /// subnet; see [`Agent::get_subnet_id`](super::Agent::get_subnet_id).
+    #[error("The canister {0} is not assigned to a subnet.")]
+    CanisterNotAssigned(Principal),
+
+    /// The canister has no metadata section of this name.
+    #[error("The canister has no metadata section `{0}`.")]
+    MetadataNotFound(String),
+
+    /// The metadata section is private, and the sender is not a controller of the canister.
+    #[error("The metadata section `{0}` is private to the canister's controllers.")]
+    MetadataAccessDenied(String),
+
+    /// The certified module hash of a canister differs from the expected one.
+    #[error(
+        "The canister's module hash is {}, but {} was expected.",
+        hex::encode(actual),
+        hex::encode(expected)
+    )]
+    ModuleHashMismatch {
+        /// The expected module hash.
+        expected: [u8; 32],
+        /// The certified module hash.
+        actual: [u8; 32],
+    },
+
+    /// The canister has no module installed, so it has no module hash.
+    #[error("The canister has no module installed.")]
+    NoModuleInstalled,
+
+    /// An error from a query or call made with a correlation id (see
+    /// [`QueryBuilder::with_correlation_id`](super::QueryBuilder::with_correlation_id) and
+    /// [`UpdateBuilder::with_correlation_id`](super::UpdateBuilder::with_correlation_id)), which
+    /// it is shown with.
+    #[error("{error} (correlation id: {correlation_id})")]
+    Correlated {
+        /// The correlation id of the request.
+        correlation_id: String,
+        /// The error itself.
+        error: Box<AgentError>,
+    },
+
+    /// A request was addressed to a principal that cannot be a canister's, so it was not sent.
+    #[error("{0} is not a canister id, so it cannot be the effective canister id of a request.")]
+    InvalidCanisterId(Principal),
+
+    /// CBOR data in a response exceeded the configured [`CborLimits`](super::CborLimits).
+    #[error("CBOR data exceeded decoding limits: {0}")]
+    CborLimitExceeded(String),
+
     /// An unknown error occurred during communication with the replica.
     #[error("An error happened during communication with the replica: {0}")]
     TransportError(Box<dyn std::error::Error + Send + Sync>),
 
+    /// The request to the replica timed out.
+    #[error("The request to the replica timed out: {0}")]
+    TimeoutError(Box<dyn std::error::Error + Send + Sync>),
+
+    /// Connecting to the replica timed out.
+    #[error("Timed out connecting to the replica: {0}")]
+    ConnectTimeout(Box<dyn std::error::Error + Send + Sync>),
+
     /// There was a mismatch between the expected and actual CBOR data during inspection.
     #[error("There is a mismatch between the CBOR encoded call and the arguments: field {field}, value in argument is {value_arg}, value in CBOR is {value_cbor}")]
     CallDataMismatch {
@@ -229,6 +428,8 @@ impl From<candid::Error> for AgentError {
 pub struct HttpErrorPayload {
     /// The HTTP status code.
     pub status: u16,
+    /// The reason phrase accompanying the status code, if known.
+    pub reason: Option<String>,
     /// The MIME type of `content`.
     pub content_type: Option<String>,
     /// The body of the error.
@@ -236,22 +437,59 @@ pub struct HttpErrorPayload {
 }
 
 impl HttpErrorPayload {
+    /// The number of bytes of a binary body shown in its summary.
+    const SUMMARY_BYTES: usize = 32;
+
     fn fmt_human_readable(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        // No matter content_type is TEXT or not,
-        // always try to parse it as a String.
-        // When fail, print the raw byte array
+        let status = match (&self.reason, http::StatusCode::from_u16(self.status)) {
+            (Some(reason), _) => format!("{} {reason}", self.status),
+            (None, Ok(code)) => format!("{code}"),
+            (None, Err(_)) => format!("{}", self.status),
+        };
         f.write_fmt(format_args!(
             "Http Error: status {}, content type {:?}, content: {}",
-            http::StatusCode::from_u16(self.status)
-                .map_or_else(|_| format!("{}", self.status), |code| format!("{}", code)),
+            status,
             self.content_type.clone().unwrap_or_default(),
-            String::from_utf8(self.content.clone()).unwrap_or_else(|_| format!(
-                "(unable to decode content as UTF-8: {:?})",
-                self.content
-            ))
+            self.content_summary(),
         ))?;
         Ok(())
     }
+
+    /// Describes the content: CBOR content is decoded, as a reject response if it is one, and any
+    /// other content is shown as text if it is UTF-8, or else as a length and leading bytes in hex.
+    fn content_summary(&self) -> String {
+        let is_cbor = self.content_type.as_deref().map_or(false, |content_type| {
+            let mime_type = content_type.split(';').next().unwrap_or_default();
+            mime_type.trim().eq_ignore_ascii_case("application/cbor")
+        });
+        if is_cbor {
+            if let Ok(reject) = serde_cbor::from_slice::<RejectResponse>(&self.content) {
+                return format!(
+                    "reject code {:?}, reject message {}, error code {:?}",
+                    reject.reject_code, reject.reject_message, reject.error_code
+                );
+            }
+            if let Ok(value) = serde_cbor::from_slice::<serde_cbor::Value>(&self.content) {
+                return format!("{value:?}");
+            }
+        }
+        match std::str::from_utf8(&self.content) {
+            Ok(text) => text.to_string(),
+            Err(_) => {
+                let shown = self.content.len().min(Self::SUMMARY_BYTES);
+                format!(
+                    "({} bytes: {}{})",
+                    self.content.len(),
+                    hex::encode(&self.content[..shown]),
+                    if shown < self.content.len() {
+                        "..."
+                    } else {
+                        ""
+                    }
+                )
+            }
+        }
+    }
 }
 
 impl Debug for HttpErrorPayload {
@@ -275,6 +513,7 @@ mod tests {
     fn content_type_none_valid_utf8() {
         let payload = HttpErrorPayload {
             status: 420,
+            reason: None,
             content_type: None,
             content: vec![104, 101, 108, 108, 111],
         };
@@ -289,13 +528,29 @@ mod tests {
     fn content_type_none_invalid_utf8() {
         let payload = HttpErrorPayload {
             status: 420,
+            reason: None,
             content_type: None,
             content: vec![195, 40],
         };
 
         assert_eq!(
             format!("{}", AgentError::HttpError(payload)),
-            r#"The replica returned an HTTP Error: Http Error: status 420 <unknown status code>, content type "", content: (unable to decode content as UTF-8: [195, 40])"#,
+            r#"The replica returned an HTTP Error: Http Error: status 420 <unknown status code>, content type "", content: (2 bytes: c328)"#,
+        );
+    }
+
+    #[test]
+    fn formats_reason_phrase() {
+        let payload = HttpErrorPayload {
+            status: 503,
+            reason: Some("Down For Maintenance".to_string()),
+            content_type: None,
+            content: vec![],
+        };
+
+        assert_eq!(
+            format!("{}", AgentError::HttpError(payload)),
+            r#"The replica returned an HTTP Error: Http Error: status 503 Down For Maintenance, content type "", content: "#,
         );
     }
 
@@ -303,6 +558,7 @@ mod tests {
     fn formats_text_plain() {
         let payload = HttpErrorPayload {
             status: 420,
+            reason: None,
             content_type: Some("text/plain".to_string()),
             content: vec![104, 101, 108, 108, 111],
         };
@@ -317,6 +573,7 @@ mod tests {
     fn formats_text_plain_charset_utf8() {
         let payload = HttpErrorPayload {
             status: 420,
+            reason: None,
             content_type: Some("text/plain; charset=utf-8".to_string()),
             content: vec![104, 101, 108, 108, 111],
         };
@@ -331,6 +588,7 @@ mod tests {
     fn formats_text_html() {
         let payload = HttpErrorPayload {
             status: 420,
+            reason: None,
             content_type: Some("text/html".to_string()),
             content: vec![119, 111, 114, 108, 100],
         };
@@ -340,4 +598,62 @@ mod tests {
             r#"The replica returned an HTTP Error: Http Error: status 420 <unknown status code>, content type "text/html", content: world"#,
         );
     }
+
+    #[test]
+    fn formats_cbor_reject() {
+        let reject = serde_cbor::Value::Map(
+            [
+                ("reject_code", serde_cbor::Value::Integer(3)),
+                (
+                    "reject_message",
+                    serde_cbor::Value::Text("canister not found".to_string()),
+                ),
+                ("error_code", serde_cbor::Value::Text("IC0301".to_string())),
+            ]
+            .into_iter()
+            .map(|(key, value)| (serde_cbor::Value::Text(key.to_string()), value))
+            .collect(),
+        );
+        let payload = HttpErrorPayload {
+            status: 400,
+            reason: None,
+            content_type: Some("application/cbor".to_string()),
+            content: serde_cbor::to_vec(&reject).unwrap(),
+        };
+
+        assert_eq!(
+            format!("{}", AgentError::HttpError(payload)),
+            r#"The replica returned an HTTP Error: Http Error: status 400 Bad Request, content type "application/cbor", content: reject code DestinationInvalid, reject message canister not found, error code Some("IC0301")"#,
+        );
+
+        // other CBOR is shown decoded
+        let payload = HttpErrorPayload {
+            status: 400,
+            reason: None,
+            content_type: Some("application/cbor".to_string()),
+            content: vec![0x82, 0x01, 0x02],
+        };
+        assert_eq!(
+            format!("{payload}"),
+            r#"Http Error: status 400 Bad Request, content type "application/cbor", content: Array([Integer(1), Integer(2)])"#,
+        );
+    }
+
+    #[test]
+    fn summarizes_binary_content() {
+        let payload = HttpErrorPayload {
+            status: 500,
+            reason: None,
+            content_type: Some("application/octet-stream".to_string()),
+            content: vec![0xff; 40],
+        };
+
+        assert_eq!(
+            format!("{payload:?}"),
+            format!(
+                r#"Http Error: status 500 Internal Server Error, content type "application/octet-stream", content: (40 bytes: {}...)"#,
+                "ff".repeat(32)
+            ),
+        );
+    }
 }
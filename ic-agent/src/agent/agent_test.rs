@@ -5,15 +5,21 @@ use self::mock::{
     assert_mock, assert_single_mock, assert_single_mock_count, mock, mock_additional,
 };
 use crate::{
-    agent::{http_transport::ReqwestTransport, Status},
+    agent::{
+        http_transport::{Jitter, ReqwestTransport},
+        CallResponse, Priority, QueryRequest, RetryPolicy, Status,
+    },
     export::Principal,
+    test_utils::{sign_certificate, sign_certificate_at, sign_certificate_by, test_root_key},
     Agent, AgentError, Certificate,
 };
 use candid::{Encode, Nat};
 use futures_util::FutureExt;
-use ic_certification::{Delegation, Label};
+use http::{HeaderMap, HeaderValue};
+use ic_certification::{Delegation, HashTree, Label};
 use ic_transport_types::{
-    NodeSignature, QueryResponse, RejectCode, RejectResponse, ReplyResponse, TransportCallResponse,
+    NodeSignature, QueryResponse, ReadStateResponse, RejectCode, RejectResponse, ReplyResponse,
+    TransportCallResponse,
 };
 use reqwest::Client;
 use std::{collections::BTreeMap, str::FromStr, sync::Arc, time::Duration};
@@ -99,6 +105,7 @@ fn make_untimed_agent(url: &str) -> Agent {
         .with_transport(ReqwestTransport::create(url).unwrap())
         .with_verify_query_signatures(false)
         .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .with_max_cert_age(Duration::from_secs(u32::MAX as _))
         .build()
         .unwrap()
 }
@@ -107,6 +114,7 @@ fn make_certifying_agent(url: &str) -> Agent {
     Agent::builder()
         .with_transport(ReqwestTransport::create(url).unwrap())
         .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .with_max_cert_age(Duration::from_secs(u32::MAX as _))
         .build()
         .unwrap()
 }
@@ -139,6 +147,10 @@ async fn query() -> Result<(), AgentError> {
             None,
             false,
             None,
+            HeaderMap::new(),
+            None,
+            RetryPolicy::Transport,
+            Priority::Normal,
         )
         .await;
 
@@ -149,6 +161,123 @@ async fn query() -> Result<(), AgentError> {
     Ok(())
 }
 
+#[cfg(feature = "otel")]
+#[tokio::test]
+async fn request_carries_trace_context() -> Result<(), AgentError> {
+    use opentelemetry::{
+        global,
+        propagation::{text_map_propagator::FieldIter, Extractor, Injector, TextMapPropagator},
+        trace::TraceContextExt,
+        Context,
+    };
+
+    /// Injects the current span's context as a W3C `traceparent` header.
+    #[derive(Debug)]
+    struct TraceParent;
+
+    impl TextMapPropagator for TraceParent {
+        fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+            let span = cx.span();
+            let context = span.span_context();
+            injector.set(
+                "traceparent",
+                format!(
+                    "00-{}-{}-{:02x}",
+                    context.trace_id(),
+                    context.span_id(),
+                    context.trace_flags()
+                ),
+            );
+        }
+        fn extract_with_context(&self, cx: &Context, _: &dyn Extractor) -> Context {
+            cx.clone()
+        }
+        fn fields(&self) -> FieldIter<'_> {
+            FieldIter::new(&[])
+        }
+    }
+
+    global::set_text_map_propagator(TraceParent);
+    let mut server = mockito::Server::new_async().await;
+    let status_mock = server
+        .mock("GET", "/api/v2/status")
+        .match_header(
+            "traceparent",
+            mockito::Matcher::Regex("^00-[0-9a-f]{32}-[0-9a-f]{16}-[0-9a-f]{2}$".into()),
+        )
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(serde_cbor::to_vec(
+            &serde_cbor::Value::Map(BTreeMap::new()),
+        )?)
+        .expect(1)
+        .create_async()
+        .await;
+
+    make_agent(&server.url()).status().await?;
+    status_mock.assert_async().await;
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn query_batch() -> Result<(), AgentError> {
+    let canisters = [
+        "ryjl3-tyaaa-aaaaa-aaaba-cai",
+        "rrkah-fqaaa-aaaaa-aaaaq-cai",
+        "224od-giaaa-aaaao-ae5vq-cai",
+    ];
+    let reply = |canister: &str| {
+        let response = QueryResponse::Replied {
+            reply: ReplyResponse {
+                arg: canister.as_bytes().to_vec(),
+            },
+            signatures: vec![],
+        };
+        serde_cbor::to_vec(&response).unwrap()
+    };
+    let (mut query_mock, url) = mock(
+        "POST",
+        &format!("/api/v2/canister/{}/query", canisters[0]),
+        200,
+        reply(canisters[0]),
+        Some("application/cbor"),
+    )
+    .await;
+    for canister in &canisters[1..] {
+        mock_additional(
+            &mut query_mock,
+            "POST",
+            &format!("/api/v2/canister/{canister}/query"),
+            200,
+            reply(canister),
+            Some("application/cbor"),
+        )
+        .await;
+    }
+
+    let agent = make_agent(&url);
+    let results = agent
+        .query_batch(
+            canisters
+                .iter()
+                .map(|canister| {
+                    QueryRequest::new(Principal::from_text(canister).unwrap(), "main", vec![])
+                })
+                .collect(),
+        )
+        .await;
+
+    assert_mock(query_mock).await;
+    let results = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        results,
+        canisters.map(|canister| canister.as_bytes().to_vec())
+    );
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
 #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
 async fn query_error() -> Result<(), AgentError> {
@@ -165,6 +294,10 @@ async fn query_error() -> Result<(), AgentError> {
             None,
             false,
             None,
+            HeaderMap::new(),
+            None,
+            RetryPolicy::Transport,
+            Priority::Normal,
         )
         .await;
 
@@ -175,6 +308,277 @@ async fn query_error() -> Result<(), AgentError> {
     Ok(())
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn query_error_empty_body_has_reason() -> Result<(), AgentError> {
+    let (query_mock, url) =
+        mock("POST", "/api/v2/canister/aaaaa-aa/query", 500, vec![], None).await;
+    let agent = make_agent(&url);
+
+    let result = agent
+        .query(&Principal::management_canister(), "greet")
+        .call()
+        .await;
+
+    assert_mock(query_mock).await;
+
+    match result {
+        Err(AgentError::HttpError(payload)) => {
+            assert_eq!(payload.status, 500);
+            assert!(payload.content.is_empty());
+            assert_eq!(payload.reason.as_deref(), Some("Internal Server Error"));
+        }
+        result => unreachable!("{:?}", result),
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn request_over_size_limit_is_not_sent() -> Result<(), AgentError> {
+    let mut server = mockito::Server::new_async().await;
+    let update_mock = server
+        .mock("POST", mockito::Matcher::Any)
+        .with_status(202)
+        .expect(0)
+        .create_async()
+        .await;
+
+    let agent = Agent::builder()
+        .with_transport(make_transport(&server.url()).with_max_request_size(1024))
+        .build()?;
+    let result = agent
+        .update(&Principal::management_canister(), "install_code")
+        .with_arg(vec![0; 1024])
+        .call()
+        .await;
+
+    update_mock.assert_async().await;
+    assert!(matches!(
+        result,
+        Err(AgentError::RequestTooLarge { size, limit: 1024 }) if size > 1024
+    ));
+
+    Ok(())
+}
+
+// The service worker mock used for wasm cannot match on request headers.
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn query_with_headers() -> Result<(), AgentError> {
+    let response = QueryResponse::Replied {
+        reply: ReplyResponse { arg: vec![] },
+        signatures: vec![],
+    };
+    let body = serde_cbor::to_vec(&response)?;
+
+    let mut server = mockito::Server::new_async().await;
+    let with_header = server
+        .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+        .match_header("x-trace-id", "abc123")
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(&body)
+        .expect(1)
+        .create_async()
+        .await;
+    let without_header = server
+        .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+        .match_header("x-trace-id", mockito::Matcher::Missing)
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(&body)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let agent = make_agent(&server.url());
+    let mut headers = HeaderMap::new();
+    headers.insert("x-trace-id", HeaderValue::from_static("abc123"));
+    agent
+        .query_with_headers(&Principal::management_canister(), "greet", vec![], headers)
+        .await?;
+    agent
+        .query(&Principal::management_canister(), "greet")
+        .call()
+        .await?;
+
+    with_header.assert_async().await;
+    without_header.assert_async().await;
+
+    Ok(())
+}
+
+// The service worker mock used for wasm cannot match on request headers.
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn update_with_headers() -> Result<(), AgentError> {
+    let version = if cfg!(feature = "experimental_sync_call") {
+        "3"
+    } else {
+        "2"
+    };
+    let path = format!("/api/v{version}/canister/aaaaa-aa/call");
+
+    let mut server = mockito::Server::new_async().await;
+    let with_header = server
+        .mock("POST", path.as_str())
+        .match_header("x-trace-id", "abc123")
+        .with_status(202)
+        .expect(1)
+        .create_async()
+        .await;
+    let without_header = server
+        .mock("POST", path.as_str())
+        .match_header("x-trace-id", mockito::Matcher::Missing)
+        .with_status(202)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let agent = make_agent(&server.url());
+    let mut headers = HeaderMap::new();
+    headers.insert("x-trace-id", HeaderValue::from_static("abc123"));
+    let result = agent
+        .update(&Principal::management_canister(), "greet")
+        .with_headers(headers)
+        .call()
+        .await?;
+    assert!(matches!(result, CallResponse::Poll(_)));
+    agent
+        .update(&Principal::management_canister(), "greet")
+        .call()
+        .await?;
+
+    with_header.assert_async().await;
+    without_header.assert_async().await;
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn query_with_correlation_id() -> Result<(), AgentError> {
+    let mut server = mockito::Server::new_async().await;
+    let rejected = server
+        .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+        .match_header("x-correlation-id", "ticket-4711")
+        .with_status(500)
+        .with_body("internal error")
+        .expect(1)
+        .create_async()
+        .await;
+
+    let agent = make_agent(&server.url());
+    let err = agent
+        .query(&Principal::management_canister(), "greet")
+        .with_correlation_id("ticket-4711")
+        .call()
+        .await
+        .unwrap_err();
+    rejected.assert_async().await;
+
+    let AgentError::Correlated {
+        correlation_id,
+        error,
+    } = &err
+    else {
+        panic!("{err:?}");
+    };
+    assert_eq!(correlation_id, "ticket-4711");
+    assert!(matches!(**error, AgentError::HttpError(_)));
+    assert!(err.to_string().ends_with("(correlation id: ticket-4711)"));
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn custom_wire_format() -> Result<(), AgentError> {
+    use crate::agent::{CborLimits, Envelope, WireFormat};
+
+    #[derive(Debug)]
+    struct Json;
+
+    fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, AgentError> {
+        serde_json::from_slice(bytes).map_err(|err| AgentError::MessageError(err.to_string()))
+    }
+
+    impl WireFormat for Json {
+        fn content_type(&self) -> &str {
+            "application/json"
+        }
+        fn encode_envelope(&self, envelope: &Envelope) -> Result<Vec<u8>, AgentError> {
+            serde_json::to_vec(envelope).map_err(|err| AgentError::MessageError(err.to_string()))
+        }
+        fn decode_envelope(&self, bytes: &[u8]) -> Result<Envelope<'static>, AgentError> {
+            decode(bytes)
+        }
+        fn decode_query_response(
+            &self,
+            bytes: &[u8],
+            _limits: &CborLimits,
+        ) -> Result<QueryResponse, AgentError> {
+            decode(bytes)
+        }
+        fn decode_read_state_response(
+            &self,
+            bytes: &[u8],
+            _limits: &CborLimits,
+        ) -> Result<ReadStateResponse, AgentError> {
+            decode(bytes)
+        }
+        fn decode_call_response(
+            &self,
+            bytes: &[u8],
+            _limits: &CborLimits,
+        ) -> Result<TransportCallResponse, AgentError> {
+            decode(bytes)
+        }
+        fn decode_reject_response(&self, bytes: &[u8]) -> Result<RejectResponse, AgentError> {
+            decode(bytes)
+        }
+    }
+
+    let response = QueryResponse::Replied {
+        reply: ReplyResponse {
+            arg: b"hello".to_vec(),
+        },
+        signatures: vec![],
+    };
+    let mut server = mockito::Server::new_async().await;
+    let query_mock = server
+        .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+        .match_header("content-type", "application/json")
+        .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+            "content": { "request_type": "query", "method_name": "greet" }
+        })))
+        .with_status(200)
+        .with_header("Content-Type", "application/json")
+        .with_body(serde_json::to_vec(&response).unwrap())
+        .expect(1)
+        .create_async()
+        .await;
+
+    let json: Arc<dyn WireFormat> = Arc::new(Json);
+    let agent = Agent::builder()
+        .with_transport(make_transport(&server.url()).with_wire_format(json.clone()))
+        .with_wire_format(json)
+        .with_verify_query_signatures(false)
+        .build()
+        .unwrap();
+    let result = agent
+        .query(&Principal::management_canister(), "greet")
+        .call()
+        .await?;
+
+    query_mock.assert_async().await;
+    assert_eq!(result, b"hello");
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
 #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
 async fn query_rejected() -> Result<(), AgentError> {
@@ -207,6 +611,10 @@ async fn query_rejected() -> Result<(), AgentError> {
             None,
             false,
             None,
+            HeaderMap::new(),
+            None,
+            RetryPolicy::Transport,
+            Priority::Normal,
         )
         .await;
 
@@ -257,6 +665,109 @@ async fn call_error() -> Result<(), AgentError> {
     Ok(())
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn call_raw_defaults_effective_canister_id() -> Result<(), AgentError> {
+    let version = if cfg!(feature = "experimental_sync_call") {
+        "3"
+    } else {
+        "2"
+    };
+    let canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+    let (call_mock, url) = mock(
+        "POST",
+        format!("/api/v{version}/canister/{canister_id}/call").as_str(),
+        202,
+        vec![],
+        None,
+    )
+    .await;
+
+    let agent = make_agent(&url);
+    let result = agent.call_raw(&canister_id, "greet", vec![]).await?;
+
+    assert_mock(call_mock).await;
+    assert!(matches!(result, CallResponse::Poll(_)));
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn call_raw_to_overrides_effective_canister_id() -> Result<(), AgentError> {
+    let version = if cfg!(feature = "experimental_sync_call") {
+        "3"
+    } else {
+        "2"
+    };
+    let canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+    let (call_mock, url) = mock(
+        "POST",
+        format!("/api/v{version}/canister/{canister_id}/call").as_str(),
+        202,
+        vec![],
+        None,
+    )
+    .await;
+
+    let agent = make_agent(&url);
+    let result = agent
+        .call_raw_to(
+            &canister_id,
+            &Principal::management_canister(),
+            "start_canister",
+            vec![],
+        )
+        .await?;
+
+    assert_mock(call_mock).await;
+    assert!(matches!(result, CallResponse::Poll(_)));
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn call_oneway_does_not_poll() -> Result<(), AgentError> {
+    let version = if cfg!(feature = "experimental_sync_call") {
+        "3"
+    } else {
+        "2"
+    };
+    let canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+
+    let mut server = mockito::Server::new_async().await;
+    let call_mock = server
+        .mock(
+            "POST",
+            format!("/api/v{version}/canister/{canister_id}/call").as_str(),
+        )
+        .with_status(202)
+        .expect(1)
+        .create_async()
+        .await;
+    let read_state_mock = server
+        .mock("POST", mockito::Matcher::Regex("read_state".into()))
+        .expect(0)
+        .create_async()
+        .await;
+
+    let agent = make_agent(&server.url());
+    let request_id = agent.call_oneway(&canister_id, "notify", vec![]).await?;
+
+    call_mock.assert_async().await;
+    read_state_mock.assert_async().await;
+    // the sender is remembered, so the caller can poll the request later
+    assert_eq!(
+        agent.request_sender(&request_id),
+        Some(agent.get_principal().unwrap())
+    );
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
 #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
 async fn call_rejected() -> Result<(), AgentError> {
@@ -301,6 +812,75 @@ async fn call_rejected() -> Result<(), AgentError> {
     Ok(())
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn call_rejected_notifies_observer() -> Result<(), AgentError> {
+    use crate::{agent::AgentObserver, RequestId};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RejectionObserver(Mutex<Vec<(RequestId, RejectCode, String)>>);
+
+    impl AgentObserver for RejectionObserver {
+        fn on_rejection(
+            &self,
+            request_id: &RequestId,
+            reject_code: RejectCode,
+            reject_message: &str,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((*request_id, reject_code, reject_message.to_string()));
+        }
+    }
+
+    let reject_body = TransportCallResponse::NonReplicatedRejection(RejectResponse {
+        reject_code: RejectCode::DestinationInvalid,
+        reject_message: "Canister has no update method 'greet'".to_string(),
+        error_code: None,
+    });
+    let version = if cfg!(feature = "experimental_sync_call") {
+        "3"
+    } else {
+        "2"
+    };
+    let (call_mock, url) = mock(
+        "POST",
+        format!("/api/v{version}/canister/aaaaa-aa/call").as_str(),
+        200,
+        serde_cbor::to_vec(&reject_body)?,
+        Some("application/cbor"),
+    )
+    .await;
+
+    let observer = Arc::new(RejectionObserver::default());
+    let agent = Agent::builder()
+        .with_transport(make_transport(&url))
+        .with_arc_observer(observer.clone())
+        .build()?;
+    let signed = agent
+        .update(&Principal::management_canister(), "greet")
+        .with_arg([])
+        .sign()?;
+    let result = agent
+        .update_signed(signed.effective_canister_id, signed.signed_update)
+        .await;
+
+    assert_mock(call_mock).await;
+    assert!(matches!(result, Err(AgentError::UncertifiedReject(_))));
+    assert_eq!(
+        *observer.0.lock().unwrap(),
+        vec![(
+            signed.request_id,
+            RejectCode::DestinationInvalid,
+            "Canister has no update method 'greet'".to_string()
+        )]
+    );
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
 #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
 async fn call_rejected_without_error_code() -> Result<(), AgentError> {
@@ -394,10 +974,145 @@ async fn status_okay() -> Result<(), AgentError> {
     Ok(())
 }
 
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn root_key_cache_expires() -> Result<(), AgentError> {
+    use crate::agent::{Clock, IC_ROOT_KEY};
+    use ic_certification::hash_tree::empty;
+    use std::sync::Mutex;
+    use time::OffsetDateTime;
+
+    #[derive(Debug)]
+    struct MockClock(Mutex<OffsetDateTime>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    let status = serde_cbor::Value::Map(BTreeMap::from([(
+        serde_cbor::Value::Text("root_key".to_string()),
+        serde_cbor::Value::Bytes(IC_ROOT_KEY.to_vec()),
+    )]));
+    let mut server = mockito::Server::new_async().await;
+    let status_mock = server
+        .mock("GET", "/api/v2/status")
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(serde_cbor::to_vec(&status)?)
+        .expect(3)
+        .create_async()
+        .await;
+
+    let clock = Arc::new(MockClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+    let agent = Agent::builder()
+        .with_transport(make_transport(&server.url()))
+        .with_clock(clock.clone())
+        .build()
+        .unwrap();
+    let ttl = Duration::from_secs(60);
+
+    agent.fetch_root_key_cached(ttl).await?;
+    agent.fetch_root_key_cached(ttl).await?;
+    *clock.0.lock().unwrap() += Duration::from_secs(59);
+    agent.fetch_root_key_cached(ttl).await?;
+    // past the ttl, the key is fetched again
+    *clock.0.lock().unwrap() += Duration::from_secs(1);
+    agent.fetch_root_key_cached(ttl).await?;
+    agent.fetch_root_key_cached(ttl).await?;
+
+    // a certificate that fails verification against the root key forces a refetch
+    let cert = Certificate {
+        tree: empty(),
+        signature: vec![0; 48],
+        delegation: None,
+    };
+    assert!(matches!(
+        agent.verify(&cert, Principal::management_canister()),
+        Err(AgentError::CertificateVerificationFailed())
+    ));
+    agent.fetch_root_key_cached(ttl).await?;
+
+    status_mock.assert_async().await;
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
-async fn reqwest_client_status_okay_when_request_retried() -> Result<(), AgentError> {
-    let map = BTreeMap::new();
-    let response = serde_cbor::Value::Map(map);
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn status_rejects_deeply_nested_cbor() -> Result<(), AgentError> {
+    // a map whose single value is an array nested 10,000 levels deep
+    let mut body = vec![0xa1, 0x61, b'x'];
+    body.extend(vec![0x81; 10_000]);
+    body.push(0x00);
+    let (read_mock, url) = mock("GET", "/api/v2/status", 200, body, Some("application/cbor")).await;
+
+    let agent = make_agent(&url);
+    let result = agent.status().await;
+
+    assert_mock(read_mock).await;
+    assert!(matches!(result, Err(AgentError::CborLimitExceeded(_))));
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn warmup_connection_is_reused() -> Result<(), AgentError> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // A minimal HTTP/1.1 server that counts the connections it accepts and keeps them alive.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let body = serde_cbor::to_vec(&serde_cbor::Value::Map(BTreeMap::new()))?;
+    let server = {
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                        request.extend_from_slice(&buf[..n]);
+                        // status requests have no body
+                        while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            let head = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/cbor\r\nContent-Length: {}\r\n\r\n",
+                                body.len()
+                            );
+                            stream.write_all(head.as_bytes()).await.unwrap();
+                            stream.write_all(&body).await.unwrap();
+                        }
+                    }
+                });
+            }
+        })
+    };
+
+    let agent = make_agent(&url);
+    agent.warmup().await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    agent.status().await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    server.abort();
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+async fn reqwest_client_status_okay_when_request_retried() -> Result<(), AgentError> {
+    let map = BTreeMap::new();
+    let response = serde_cbor::Value::Map(map);
     let (read_mock, url) = mock(
         "GET",
         "/api/v2/status",
@@ -578,6 +1293,32 @@ async fn check_subnet_range_with_pruned_range() {
     assert!(result.is_err());
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn submit_read_state_envelope() -> Result<(), AgentError> {
+    let (read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/ivg37-qiaaa-aaaab-aaaga-cai/read_state",
+        200,
+        REQ_WITH_DELEGATED_CERT_RESPONSE.into(),
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_untimed_agent(&url);
+    let certificate = agent
+        .submit_read_state_envelope(
+            Principal::from_text(REQ_WITH_DELEGATED_CERT_CANISTER).unwrap(),
+            vec![1, 2, 3],
+        )
+        .await?;
+
+    assert_mock(read_mock).await;
+    let expected: ReadStateResponse = serde_cbor::from_slice(REQ_WITH_DELEGATED_CERT_RESPONSE)?;
+    assert_eq!(certificate, expected.certificate);
+
+    Ok(())
+}
+
 const WRONG_SUBNET_CERT: &[u8] = include_bytes!("agent_test/wrong_subnet.bin");
 
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
@@ -658,6 +1399,74 @@ async fn no_cert() {
     assert_mock(read_mock).await;
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn per_query_verification() {
+    let canister = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let (mut read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        200,
+        GOOD_SUBNET_KEYS.into(),
+        Some("application/cbor"),
+    )
+    .await;
+    // a reply whose signature has been stripped, as if tampered with
+    let blob = Encode!(&Nat::from(12u8)).unwrap();
+    let response = QueryResponse::Replied {
+        reply: ReplyResponse { arg: blob.clone() },
+        signatures: vec![],
+    };
+    mock_additional(
+        &mut read_mock,
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/query",
+        200,
+        serde_cbor::to_vec(&response).unwrap(),
+        Some("application/cbor"),
+    )
+    .await;
+
+    let agent = make_certifying_agent(&url);
+    let result = agent
+        .query(&canister, "getVersion")
+        .call_without_verification()
+        .await;
+    assert_eq!(result.unwrap(), blob);
+    let result = agent
+        .query(&canister, "getVersion")
+        .call_with_verification()
+        .await;
+    assert!(matches!(result.unwrap_err(), AgentError::MissingSignature));
+
+    let agent = make_untimed_agent(&url);
+    let result = agent.query(&canister, "getVersion").await;
+    assert_eq!(result.unwrap(), blob);
+    let result = agent
+        .query(&canister, "getVersion")
+        .call_with_verification()
+        .await;
+    assert!(matches!(result.unwrap_err(), AgentError::MissingSignature));
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn subnet_node_keys() {
+    let canister = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let (read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        200,
+        GOOD_SUBNET_KEYS.into(),
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_certifying_agent(&url);
+    let node_keys = agent.get_subnet_node_keys(&canister).await.unwrap();
+    assert_eq!(node_keys.len(), 13);
+    assert_mock(read_mock).await;
+}
+
 const RESP_WITH_SUBNET_KEY: &[u8] = include_bytes!("agent_test/with_subnet_key.bin");
 
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
@@ -708,6 +1517,1253 @@ async fn too_many_delegations() {
     ));
 }
 
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn read_subnet_info() -> Result<(), AgentError> {
+    use crate::agent::SubnetInfo;
+    use ic_certification::hash_tree::{fork, label, leaf};
+
+    let root_key = test_root_key();
+    // the root subnet's certificate is signed by the root key, so that is its ID
+    let subnet_id = Principal::self_authenticating(&root_key);
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let node_id = Principal::from_text("2vxsx-fae").unwrap();
+    let mut node_key = vec![48, 42, 48, 5, 6, 3, 43, 101, 112, 3, 33, 0];
+    node_key.extend_from_slice(&[1; 32]);
+    let (cert, _) = sign_certificate(label(
+        "subnet",
+        label(
+            Label::from_bytes(subnet_id.as_slice()),
+            fork(
+                fork(
+                    label(
+                        "canister_ranges",
+                        leaf(serde_cbor::to_vec(&[(canister_id, canister_id)])?),
+                    ),
+                    label(
+                        "node",
+                        label(
+                            Label::from_bytes(node_id.as_slice()),
+                            label("public_key", leaf(node_key.clone())),
+                        ),
+                    ),
+                ),
+                label("public_key", leaf(root_key.clone())),
+            ),
+        ),
+    ));
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+
+    let (read_mock, url) = mock(
+        "POST",
+        format!("/api/v2/subnet/{subnet_id}/read_state").as_str(),
+        200,
+        serde_cbor::to_vec(&response)?,
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_agent(&url);
+    agent.set_root_key(root_key.clone());
+
+    let info = agent.read_state_subnet_info(subnet_id).await?.value;
+    assert_mock(read_mock).await;
+    assert_eq!(
+        info,
+        SubnetInfo {
+            subnet_id,
+            public_key: root_key,
+            canister_ranges: vec![(canister_id, canister_id)],
+            node_keys: [(node_id, node_key)].into(),
+        }
+    );
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn read_state_returns_certified_time() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label("module_hash", leaf([7; 32])),
+        ),
+    ));
+    let time = super::response_authentication::lookup_time(&cert)?;
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+
+    let (read_mock, url) = mock(
+        "POST",
+        format!("/api/v2/canister/{canister_id}/read_state").as_str(),
+        200,
+        serde_cbor::to_vec(&response)?,
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_agent(&url);
+    agent.set_root_key(root_key);
+
+    let module_hash = agent
+        .read_state_canister_info(canister_id, "module_hash")
+        .await?;
+    assert_mock(read_mock).await;
+    assert_eq!(module_hash.value, [7; 32]);
+    assert_eq!(
+        module_hash.timestamp,
+        std::time::UNIX_EPOCH + Duration::from_nanos(time)
+    );
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn read_metadata_batch() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{fork, label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label(
+                "metadata",
+                fork(
+                    label("candid:args", leaf("(nat)")),
+                    label("candid:service", leaf("service : {}")),
+                ),
+            ),
+        ),
+    ));
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+
+    let (read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        200,
+        serde_cbor::to_vec(&response)?,
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_agent(&url);
+    agent.set_root_key(root_key);
+
+    let metadata = agent
+        .read_canister_metadata_batch(
+            canister_id,
+            &["candid:service", "candid:args", "dfx:wasm_url"],
+        )
+        .await?;
+    assert_mock(read_mock).await;
+    assert_eq!(
+        metadata,
+        [
+            ("candid:service".to_string(), b"service : {}".to_vec()),
+            ("candid:args".to_string(), b"(nat)".to_vec()),
+        ]
+        .into()
+    );
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn subnet_id_from_delegation() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{fork, label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let subnet_id =
+        Principal::from_text("uzr34-akd3s-xrdag-3ql62-ocgoh-ld2ao-tamcv-54e7j-krwgb-2gm4z-oqe")
+            .unwrap();
+    let subnet_key = ic_verify_bls_signature::PrivateKey::deserialize(&[0x22; 32]).unwrap();
+    let mut subnet_der = super::IC_ROOT_KEY[..37].to_vec();
+    subnet_der.extend_from_slice(&subnet_key.public_key().serialize());
+
+    // the root key delegates the canister's range to the subnet, whose key signs the state
+    let (delegation, root_key) = sign_certificate(label(
+        "subnet",
+        label(
+            Label::from_bytes(subnet_id.as_slice()),
+            fork(
+                label(
+                    "canister_ranges",
+                    leaf(serde_cbor::to_vec(&[(canister_id, canister_id)])?),
+                ),
+                label("public_key", leaf(subnet_der)),
+            ),
+        ),
+    ));
+    let controllers = vec![serde_bytes::ByteBuf::from(
+        Principal::anonymous().as_slice(),
+    )];
+    let mut cert = sign_certificate_by(
+        label(
+            "canister",
+            label(
+                Label::from_bytes(canister_id.as_slice()),
+                label("controllers", leaf(serde_cbor::to_vec(&controllers)?)),
+            ),
+        ),
+        time::OffsetDateTime::now_utc(),
+        &subnet_key,
+    );
+    cert.delegation = Some(Delegation {
+        subnet_id: subnet_id.as_slice().to_vec(),
+        certificate: serde_cbor::to_vec(&delegation)?,
+    });
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+
+    let (read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        200,
+        serde_cbor::to_vec(&response)?,
+        Some("application/cbor"),
+    )
+    .await;
+    let agent = make_agent(&url);
+    agent.set_root_key(root_key);
+
+    assert_eq!(agent.get_subnet_id(canister_id).await?, subnet_id);
+    assert_mock(read_mock).await;
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn rejects_backdated_certificate() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let tree = || {
+        label(
+            "canister",
+            label(
+                Label::from_bytes(canister_id.as_slice()),
+                label("module_hash", leaf([7; 32])),
+            ),
+        )
+    };
+    let two_minutes_ago = time::OffsetDateTime::now_utc() - Duration::from_secs(120);
+    let (cert, root_key) = sign_certificate_at(tree(), two_minutes_ago);
+    let agent = Agent::builder()
+        .with_url("http://not-a-real-url")
+        .with_max_cert_age(Duration::from_secs(60))
+        .build()?;
+    agent.set_root_key(root_key);
+
+    match agent.verify(&cert, canister_id) {
+        Err(AgentError::CertificateTooOld { age }) => {
+            assert!(age >= Duration::from_secs(120) && age < Duration::from_secs(130))
+        }
+        result => panic!("{result:?}"),
+    }
+    // a fresh certificate passes
+    let (cert, _) = sign_certificate(tree());
+    agent.verify(&cert, canister_id)?;
+    // as does the backdated one with the default limit
+    let agent = make_agent("http://not-a-real-url");
+    agent.set_root_key(test_root_key());
+    let (cert, _) = sign_certificate_at(tree(), two_minutes_ago);
+    agent.verify(&cert, canister_id)?;
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn certificate_time_is_checked_against_agent_clock() -> Result<(), AgentError> {
+    use crate::agent::Clock;
+    use ic_certification::hash_tree::{label, leaf};
+    use time::OffsetDateTime;
+
+    #[derive(Debug)]
+    struct FixedClock(OffsetDateTime);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> OffsetDateTime {
+            self.0
+        }
+    }
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let tree = || {
+        label(
+            "canister",
+            label(
+                Label::from_bytes(canister_id.as_slice()),
+                label("module_hash", leaf([7; 32])),
+            ),
+        )
+    };
+    let agent_at = |now| -> Result<Agent, AgentError> {
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_clock(Arc::new(FixedClock(now)))
+            .build()?;
+        agent.set_root_key(test_root_key());
+        Ok(agent)
+    };
+    let an_hour_ago = OffsetDateTime::now_utc() - Duration::from_secs(3600);
+
+    // a certificate from the agent's point in time passes, however far it is from the wall clock
+    let (cert, _) = sign_certificate_at(tree(), an_hour_ago);
+    agent_at(an_hour_ago)?.verify(&cert, canister_id)?;
+    // and one from the wall clock's point in time is too far ahead of the agent's
+    let (cert, _) = sign_certificate(tree());
+    assert!(matches!(
+        agent_at(an_hour_ago)?.verify(&cert, canister_id),
+        Err(AgentError::CertificateOutdated(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn read_state_splits_paths() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let (cert, root_key) = sign_certificate(label("path0", leaf(vec![])));
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+    let mut server = mockito::Server::new_async().await;
+    let read_mock = server
+        .mock(
+            "POST",
+            format!("/api/v2/canister/{canister_id}/read_state").as_str(),
+        )
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(serde_cbor::to_vec(&response)?)
+        .expect(3)
+        .create_async()
+        .await;
+
+    let agent = Agent::builder()
+        .with_transport(make_transport(&server.url()))
+        .with_max_read_state_paths(2)
+        .build()?;
+    agent.set_root_key(root_key);
+    let paths = (0..5)
+        .map(|n| vec![Label::from(format!("path{n}"))])
+        .collect();
+    let certs = agent.read_state_raw_chunked(paths, canister_id).await?;
+
+    // 5 paths are sent as 2, 2, and 1
+    read_mock.assert_async().await;
+    assert_eq!(certs.len(), 3);
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn read_canister_metadata() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{label, leaf};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    // the tree proves that "candid:args" is absent, as it has no other label between its neighbors
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label(
+                "metadata",
+                label("candid:service", leaf(b"service : {}".to_vec())),
+            ),
+        ),
+    ));
+    let response = ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    };
+    let (mut _read_mock, url) = mock(
+        "POST",
+        "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        200,
+        serde_cbor::to_vec(&response)?,
+        Some("application/cbor"),
+    )
+    .await;
+    mock_additional(
+        &mut _read_mock,
+        "POST",
+        "/api/v2/canister/ryjl3-tyaaa-aaaaa-aaaba-cai/read_state",
+        403,
+        b"Caller is not a controller of the canister".to_vec(),
+        Some("text/plain"),
+    )
+    .await;
+
+    let agent = make_untimed_agent(&url);
+    agent.set_root_key(root_key);
+    let service = agent
+        .read_canister_metadata(canister_id, "candid:service")
+        .await?;
+    assert_eq!(service, b"service : {}");
+    assert!(matches!(
+        agent.read_canister_metadata(canister_id, "candid:args").await,
+        Err(AgentError::MetadataNotFound(section)) if section == "candid:args"
+    ));
+    let private_canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+    assert!(matches!(
+        agent.read_canister_metadata(private_canister, "secret").await,
+        Err(AgentError::MetadataAccessDenied(section)) if section == "secret"
+    ));
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn verify_module_hash() -> Result<(), AgentError> {
+    use ic_certification::hash_tree::{empty, label, leaf};
+
+    let installed = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let empty_canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+    let hash = [0xab; 32];
+    let read_state = |canister_id: Principal, module_hash: Option<[u8; 32]>| {
+        // an empty canister has no module hash, and an empty tree proves it absent
+        let canister: HashTree = match module_hash {
+            Some(hash) => label("module_hash", leaf(hash.to_vec())),
+            None => empty(),
+        };
+        let (cert, _) = sign_certificate(label(
+            "canister",
+            label(Label::from_bytes(canister_id.as_slice()), canister),
+        ));
+        serde_cbor::to_vec(&ReadStateResponse {
+            certificate: serde_cbor::to_vec(&cert).unwrap(),
+        })
+        .unwrap()
+    };
+    let (mut _read_mock, url) = mock(
+        "POST",
+        &format!("/api/v2/canister/{installed}/read_state"),
+        200,
+        read_state(installed, Some(hash)),
+        Some("application/cbor"),
+    )
+    .await;
+    mock_additional(
+        &mut _read_mock,
+        "POST",
+        &format!("/api/v2/canister/{empty_canister}/read_state"),
+        200,
+        read_state(empty_canister, None),
+        Some("application/cbor"),
+    )
+    .await;
+
+    let agent = make_untimed_agent(&url);
+    agent.set_root_key(test_root_key());
+    agent.verify_module_hash(installed, hash).await?;
+    assert_eq!(
+        agent.verify_module_hash(installed, [0; 32]).await,
+        Err(AgentError::ModuleHashMismatch {
+            expected: [0; 32],
+            actual: hash,
+        })
+    );
+    assert_eq!(
+        agent.verify_module_hash(empty_canister, hash).await,
+        Err(AgentError::NoModuleInstalled)
+    );
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test(start_paused = true)]
+async fn wait_polls_until_request_is_known() -> Result<(), AgentError> {
+    use crate::{test_utils::MockTransport, RequestId};
+    use ic_certification::hash_tree::{empty, fork, label, leaf, pruned};
+    use std::sync::Mutex;
+
+    let request_id = RequestId::new(&[7; 32]);
+    let reply = |tree: HashTree| {
+        let (cert, root_key) = sign_certificate(label("request_status", tree));
+        let response = ReadStateResponse {
+            certificate: serde_cbor::to_vec(&cert).unwrap(),
+        };
+        (serde_cbor::to_vec(&response).unwrap(), root_key)
+    };
+    // the request is first absent, then pruned, and finally replied to
+    let (absent, root_key) = reply(empty());
+    let (unknown, _) = reply(pruned([0; 32]));
+    let (replied, _) = reply(label(
+        Label::from_bytes(request_id.as_slice()),
+        fork(
+            label("reply", leaf(b"done".to_vec())),
+            label("status", leaf(b"replied".to_vec())),
+        ),
+    ));
+
+    // answers read_state requests with the responses in order
+    let responses = Mutex::new(vec![absent, unknown, replied]);
+    let transport = MockTransport::new()
+        .on_read_state(move |_| std::future::ready(Ok(responses.lock().unwrap().remove(0))));
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .build()?;
+    agent.set_root_key(root_key);
+    let reply = agent
+        .wait(&request_id, Principal::management_canister())
+        .await?;
+    assert_eq!(reply, b"done");
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn sync_call_rejection_is_certified() -> Result<(), AgentError> {
+    use crate::test_utils::MockTransport;
+    use ic_certification::hash_tree::{fork, label, leaf};
+
+    // answers calls as the v3 endpoint does, with a certificate of their rejection
+    let transport = MockTransport::new().on_call(|request| {
+        let request_id = request.content().to_request_id();
+        let (cert, _) = sign_certificate(label(
+            "request_status",
+            label(
+                Label::from_bytes(request_id.as_slice()),
+                fork(
+                    fork(
+                        label("reject_code", leaf(vec![RejectCode::CanisterReject as u8])),
+                        label("reject_message", leaf(b"no greetings today".to_vec())),
+                    ),
+                    label("status", leaf(b"rejected".to_vec())),
+                ),
+            ),
+        ));
+        let certificate = serde_cbor::to_vec(&cert).unwrap();
+        std::future::ready(Ok(TransportCallResponse::Replied { certificate }))
+    });
+
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .build()?;
+    agent.set_root_key(test_root_key());
+
+    // the same error as a rejection found by polling, without any read_state
+    let result = agent
+        .update(&Principal::management_canister(), "greet")
+        .call_and_wait()
+        .await;
+    assert_eq!(
+        result,
+        Err(AgentError::CertifiedReject(RejectResponse {
+            reject_code: RejectCode::CanisterReject,
+            reject_message: "no greetings today".to_string(),
+            error_code: None,
+        }))
+    );
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn abandoned_call_is_polled_not_resubmitted() -> Result<(), AgentError> {
+    use crate::{test_utils::MockTransport, RequestId};
+    use futures_util::future::{self, Either};
+    use ic_certification::hash_tree::{label, leaf};
+    use std::sync::Mutex;
+
+    // never answers the first call, records the request ids of all calls, and certifies that the
+    // first is processing
+    let calls = Arc::new(Mutex::new(Vec::<RequestId>::new()));
+    let transport = {
+        let on_call = calls.clone();
+        let on_read_state = calls.clone();
+        MockTransport::new()
+            .on_call(move |request| {
+                let mut calls = on_call.lock().unwrap();
+                calls.push(request.content().to_request_id());
+                if calls.len() == 1 {
+                    Either::Left(future::pending())
+                } else {
+                    Either::Right(future::ready(Ok(TransportCallResponse::Accepted)))
+                }
+            })
+            .on_read_state(move |_| {
+                let request_id = on_read_state.lock().unwrap()[0];
+                let (cert, _) = sign_certificate(label(
+                    "request_status",
+                    label(
+                        Label::from_bytes(request_id.as_slice()),
+                        label("status", leaf(b"processing".to_vec())),
+                    ),
+                ));
+                let response = ReadStateResponse {
+                    certificate: serde_cbor::to_vec(&cert).unwrap(),
+                };
+                future::ready(Ok(serde_cbor::to_vec(&response).unwrap()))
+            })
+    };
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .build()?;
+    agent.set_root_key(test_root_key());
+    let canister_id = Principal::management_canister();
+
+    // the call is submitted, and then dropped before the replica answers
+    let call = agent
+        .update(&canister_id, "greet")
+        .with_arg(b"world")
+        .call();
+    assert!(call.now_or_never().is_none());
+    assert_eq!(calls.lock().unwrap().len(), 1);
+
+    // making it again finds it processing instead of submitting it a second time
+    let request_id = calls.lock().unwrap()[0];
+    let response = agent
+        .update(&canister_id, "greet")
+        .with_arg(b"world")
+        .call()
+        .await?;
+    assert_eq!(response, CallResponse::Poll(request_id));
+    assert_eq!(calls.lock().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn call_timed_out_after_sending_is_polled_not_resubmitted() -> Result<(), AgentError> {
+    use crate::{test_utils::MockTransport, RequestId};
+    use ic_certification::hash_tree::{label, leaf};
+    use std::sync::Mutex;
+
+    // the first call times out waiting for its response, after its envelope was sent; the
+    // request ids of all calls are recorded, and the first is certified as processing
+    let calls = Arc::new(Mutex::new(Vec::<RequestId>::new()));
+    let transport = {
+        let on_call = calls.clone();
+        let on_read_state = calls.clone();
+        MockTransport::new()
+            .on_call(move |request| {
+                let mut calls = on_call.lock().unwrap();
+                calls.push(request.content().to_request_id());
+                std::future::ready(if calls.len() == 1 {
+                    Err(AgentError::TimeoutError("read timed out".into()))
+                } else {
+                    Ok(TransportCallResponse::Accepted)
+                })
+            })
+            .on_read_state(move |_| {
+                let request_id = on_read_state.lock().unwrap()[0];
+                let (cert, _) = sign_certificate(label(
+                    "request_status",
+                    label(
+                        Label::from_bytes(request_id.as_slice()),
+                        label("status", leaf(b"processing".to_vec())),
+                    ),
+                ));
+                let response = ReadStateResponse {
+                    certificate: serde_cbor::to_vec(&cert).unwrap(),
+                };
+                std::future::ready(Ok(serde_cbor::to_vec(&response).unwrap()))
+            })
+    };
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_ingress_expiry(Some(Duration::from_secs(u32::MAX as _)))
+        .build()?;
+    agent.set_root_key(test_root_key());
+    let canister_id = Principal::management_canister();
+    let call = || agent.update(&canister_id, "greet").with_arg(b"world");
+
+    let result = call().call().await;
+    assert!(matches!(result, Err(AgentError::TimeoutError(_))));
+
+    // the call may have landed, so making it again polls it rather than submitting it again
+    let request_id = calls.lock().unwrap()[0];
+    assert_eq!(call().call().await?, CallResponse::Poll(request_id));
+    assert_eq!(calls.lock().unwrap().len(), 1);
+
+    // unless the same call is meant to be made twice
+    let response = call().with_resume_abandoned(false).call().await?;
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(response, CallResponse::Poll(calls[1]));
+    assert_ne!(calls[1], request_id);
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn query_verified_with_trusted_node_keys() -> Result<(), AgentError> {
+    use crate::test_utils::MockTransport;
+    use ic_certification::hash_tree::{fork, label, leaf};
+
+    /// Answers every read_state request with the same response.
+    fn trusted_transport(response: Vec<u8>) -> MockTransport {
+        MockTransport::new().on_read_state(move |_| std::future::ready(Ok(response.clone())))
+    }
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let trusted_node = Principal::from_text("2vxsx-fae").unwrap();
+    let forged_node =
+        Principal::from_text("oo4np-rrvnz-5vram-kglex-enhkp-uew6q-vdf6z-whj4x-v44jd-tebaw-nqe")
+            .unwrap();
+
+    // the trusted source certifies the keys of the subnet, which only has the trusted node
+    let root_key = test_root_key();
+    let subnet_id = Principal::self_authenticating(&root_key);
+    let mut node_key = vec![48, 42, 48, 5, 6, 3, 43, 101, 112, 3, 33, 0];
+    node_key.extend_from_slice(&[1; 32]);
+    let (cert, _) = sign_certificate(label(
+        "subnet",
+        label(
+            Label::from_bytes(subnet_id.as_slice()),
+            fork(
+                fork(
+                    label(
+                        "canister_ranges",
+                        leaf(serde_cbor::to_vec(&[(canister_id, canister_id)])?),
+                    ),
+                    label(
+                        "node",
+                        label(
+                            Label::from_bytes(trusted_node.as_slice()),
+                            label("public_key", leaf(node_key)),
+                        ),
+                    ),
+                ),
+                label("public_key", leaf(root_key.clone())),
+            ),
+        ),
+    ));
+    let trusted = trusted_transport(serde_cbor::to_vec(&ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    })?);
+
+    // the queried replica signs its response with a key of its own
+    let response = QueryResponse::Replied {
+        reply: ReplyResponse {
+            arg: b"forged".to_vec(),
+        },
+        signatures: vec![NodeSignature {
+            timestamp: time::OffsetDateTime::now_utc().unix_timestamp_nanos() as u64,
+            signature: vec![0; 64],
+            identity: forged_node,
+        }],
+    };
+    let mut server = mockito::Server::new_async().await;
+    let query = server
+        .mock("POST", "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/query")
+        .with_status(200)
+        .with_header("Content-Type", "application/cbor")
+        .with_body(serde_cbor::to_vec(&response)?)
+        .expect(1)
+        .create_async()
+        .await;
+    let read_state = server
+        .mock(
+            "POST",
+            "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/read_state",
+        )
+        .expect(0)
+        .create_async()
+        .await;
+
+    let agent = Agent::builder()
+        .with_transport(ReqwestTransport::create(server.url())?)
+        .with_verification_key_source(Arc::new(trusted))
+        .build()?;
+    agent.set_root_key(root_key);
+    let result = agent.query(&canister_id, "greet").call().await;
+    assert!(matches!(
+        result.unwrap_err(),
+        AgentError::CertificateNotAuthorized()
+    ));
+    query.assert_async().await;
+    read_state.assert_async().await;
+
+    Ok(())
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn fetch_certified_asset() {
+    use super::asset::HttpResponse;
+    use base64::prelude::*;
+    use ic_certification::hash_tree::{label, leaf};
+    use sha2::{Digest, Sha256};
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let body = b"<h1>Hello</h1>".to_vec();
+
+    // the canister certifies the hash of the body, and the root key signs its certified data
+    let asset_tree: HashTree = label(
+        "http_assets",
+        label("/index.html", leaf(Sha256::digest(&body).to_vec())),
+    );
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label("certified_data", leaf(asset_tree.digest().to_vec())),
+        ),
+    ));
+    let header = format!(
+        "certificate=:{}:, tree=:{}:",
+        BASE64_STANDARD.encode(serde_cbor::to_vec(&cert).unwrap()),
+        BASE64_STANDARD.encode(serde_cbor::to_vec(&asset_tree).unwrap()),
+    );
+
+    let fetch = |body: Vec<u8>| {
+        let header = header.clone();
+        let root_key = root_key.clone();
+        async move {
+            let response = HttpResponse {
+                status_code: 200,
+                headers: vec![("IC-Certificate".to_string(), header)],
+                body,
+            };
+            let response = QueryResponse::Replied {
+                reply: ReplyResponse {
+                    arg: Encode!(&response).unwrap(),
+                },
+                signatures: vec![],
+            };
+            let (query_mock, url) = mock(
+                "POST",
+                "/api/v2/canister/224od-giaaa-aaaao-ae5vq-cai/query",
+                200,
+                serde_cbor::to_vec(&response).unwrap(),
+                Some("application/cbor"),
+            )
+            .await;
+            let agent = make_untimed_agent(&url);
+            agent.set_root_key(root_key);
+            let result = agent.fetch_asset(canister_id, "/index.html?v=1").await;
+            assert_mock(query_mock).await;
+            result
+        }
+    };
+
+    let asset = fetch(body.clone()).await.unwrap();
+    assert_eq!(asset.status_code, 200);
+    assert_eq!(asset.body, body);
+
+    let result = fetch(b"<h1>Tampered</h1>".to_vec()).await;
+    assert!(matches!(
+        result.unwrap_err(),
+        AgentError::AssetVerificationFailed(_)
+    ));
+}
+
+#[test]
+fn verify_embedded_certificate() {
+    use candid::CandidType;
+    use ic_certification::hash_tree::{label, leaf};
+    use serde_bytes::ByteBuf;
+
+    #[derive(CandidType)]
+    struct CounterReply {
+        count: u64,
+        certificate: ByteBuf,
+        witness: ByteBuf,
+    }
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let witness: HashTree = label("counter", leaf(7u64.to_be_bytes().to_vec()));
+    let certified_data = || {
+        label(
+            "canister",
+            label(
+                Label::from_bytes(canister_id.as_slice()),
+                label("certified_data", leaf(witness.digest().to_vec())),
+            ),
+        )
+    };
+    let (cert, root_key) = sign_certificate(certified_data());
+    let reply = |cert: &Certificate, witness: &HashTree| {
+        Encode!(&CounterReply {
+            count: 7,
+            certificate: ByteBuf::from(serde_cbor::to_vec(cert).unwrap()),
+            witness: ByteBuf::from(serde_cbor::to_vec(witness).unwrap()),
+        })
+        .unwrap()
+    };
+    let agent = make_untimed_agent("http://localhost");
+    agent.set_root_key(root_key);
+    let path: &[&[u8]] = &[b"counter"];
+
+    let count = agent
+        .verify_response_certificate(canister_id, &reply(&cert, &witness), path)
+        .unwrap();
+    assert_eq!(count, 7u64.to_be_bytes());
+
+    // the canister reports a count other than the one it certified
+    let tampered: HashTree = label("counter", leaf(8u64.to_be_bytes().to_vec()));
+    let result = agent.verify_response_certificate(canister_id, &reply(&cert, &tampered), path);
+    assert!(matches!(
+        result.unwrap_err(),
+        AgentError::ResponseVerificationFailed(_)
+    ));
+
+    // the certificate is for another canister's certified data
+    let other = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+    let result = agent.verify_response_certificate(other, &reply(&cert, &witness), path);
+    assert!(matches!(
+        result.unwrap_err(),
+        AgentError::LookupPathAbsent(_)
+    ));
+
+    // the certificate is not signed by the root key
+    let forger = ic_verify_bls_signature::PrivateKey::deserialize(&[0x33; 32]).unwrap();
+    let forged = sign_certificate_by(certified_data(), time::OffsetDateTime::now_utc(), &forger);
+    let result = agent.verify_response_certificate(canister_id, &reply(&forged, &witness), path);
+    assert!(matches!(
+        result.unwrap_err(),
+        AgentError::CertificateVerificationFailed()
+    ));
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn fetch_streamed_asset() {
+    use super::EnvelopeContent;
+    use crate::test_utils::{query_reply, MockTransport};
+    use base64::prelude::*;
+    use candid::{define_function, CandidType, Decode, Deserialize};
+    use futures_util::{StreamExt, TryStreamExt};
+    use ic_certification::hash_tree::{label, leaf};
+    use sha2::{Digest, Sha256};
+
+    define_function!(CallbackFunc : () -> ());
+    #[derive(CandidType, Deserialize)]
+    struct Token {
+        index: u64,
+    }
+    #[derive(CandidType)]
+    enum StreamingStrategy {
+        Callback {
+            callback: CallbackFunc,
+            token: Token,
+        },
+    }
+    #[derive(CandidType)]
+    struct HttpResponse {
+        status_code: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        streaming_strategy: Option<StreamingStrategy>,
+    }
+    #[derive(CandidType)]
+    struct StreamingCallbackHttpResponse {
+        body: Vec<u8>,
+        token: Option<Token>,
+    }
+
+    /// Serves an asset in chunks: the first with `http_request`, the rest with `next_chunk`.
+    fn asset_transport(
+        canister_id: Principal,
+        header: Option<String>,
+        chunks: Vec<Vec<u8>>,
+    ) -> MockTransport {
+        MockTransport::new().on_query(move |request| {
+            let EnvelopeContent::Query {
+                method_name, arg, ..
+            } = request.content()
+            else {
+                unreachable!()
+            };
+            let index = match method_name.as_str() {
+                "http_request" => 0,
+                "next_chunk" => Decode!(&arg, Token).unwrap().index as usize,
+                _ => unreachable!(),
+            };
+            let next = (index + 1 < chunks.len()).then_some(Token {
+                index: index as u64 + 1,
+            });
+            let body = chunks[index].clone();
+            let arg = if index == 0 {
+                Encode!(&HttpResponse {
+                    status_code: 200,
+                    headers: header
+                        .iter()
+                        .map(|header| ("IC-Certificate".to_string(), header.clone()))
+                        .collect(),
+                    body,
+                    streaming_strategy: next.map(|token| StreamingStrategy::Callback {
+                        callback: CallbackFunc::new(canister_id, "next_chunk".to_string()),
+                        token,
+                    }),
+                })
+            } else {
+                Encode!(&StreamingCallbackHttpResponse { body, token: next })
+            };
+            std::future::ready(Ok(query_reply(arg.unwrap())))
+        })
+    }
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let chunks = vec![b"<video>first".to_vec(), b"second</video>".to_vec()];
+
+    // the canister certifies the hash of the whole body
+    let asset_tree: HashTree = label(
+        "http_assets",
+        label(
+            "/video.html",
+            leaf(Sha256::digest(chunks.concat()).to_vec()),
+        ),
+    );
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label("certified_data", leaf(asset_tree.digest().to_vec())),
+        ),
+    ));
+    let header = format!(
+        "certificate=:{}:, tree=:{}:",
+        BASE64_STANDARD.encode(serde_cbor::to_vec(&cert).unwrap()),
+        BASE64_STANDARD.encode(serde_cbor::to_vec(&asset_tree).unwrap()),
+    );
+    let make_agent = |header: Option<String>, chunks: Vec<Vec<u8>>| {
+        let agent = Agent::builder()
+            .with_transport(asset_transport(canister_id, header, chunks))
+            .with_verify_query_signatures(false)
+            .with_max_cert_age(Duration::from_secs(u32::MAX as _))
+            .build()
+            .unwrap();
+        agent.set_root_key(root_key.clone());
+        agent
+    };
+
+    let agent = make_agent(Some(header.clone()), chunks.clone());
+    let streamed: Vec<Vec<u8>> = agent
+        .fetch_asset_streaming(canister_id, "/video.html")
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(streamed, chunks);
+
+    // the tampered chunk is yielded, but the stream then ends with an error
+    let agent = make_agent(
+        Some(header.clone()),
+        vec![chunks[0].clone(), b"tampered</video>".to_vec()],
+    );
+    let streamed: Vec<_> = agent
+        .fetch_asset_streaming(canister_id, "/video.html")
+        .collect()
+        .await;
+    assert_eq!(streamed.len(), 3);
+    assert!(streamed[..2].iter().all(Result::is_ok));
+    assert!(matches!(
+        streamed[2],
+        Err(AgentError::AssetVerificationFailed(_))
+    ));
+
+    // an uncertified response yields no chunks at all
+    let agent = make_agent(None, chunks.clone());
+    let streamed: Vec<_> = agent
+        .fetch_asset_streaming(canister_id, "/video.html")
+        .collect()
+        .await;
+    assert!(matches!(
+        &streamed[..],
+        [Err(AgentError::AssetVerificationFailed(_))]
+    ));
+}
+
+#[cfg_attr(not(target_family = "wasm"), tokio::test)]
+#[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+async fn canister_info_cache() -> Result<(), AgentError> {
+    use crate::test_utils::MockTransport;
+    use ic_certification::hash_tree::{fork, label, leaf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Accepts every call, and answers every read_state request with `certificate`, counting them.
+    fn state_transport(certificate: Vec<u8>, reads: Arc<AtomicUsize>) -> MockTransport {
+        let response = serde_cbor::to_vec(&ReadStateResponse { certificate }).unwrap();
+        MockTransport::new()
+            .on_call(|_| std::future::ready(Ok(TransportCallResponse::Accepted)))
+            .on_read_state(move |_| {
+                reads.fetch_add(1, Ordering::Relaxed);
+                std::future::ready(Ok(response.clone()))
+            })
+    }
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let controllers = vec![serde_bytes::ByteBuf::from(
+        Principal::anonymous().as_slice(),
+    )];
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            fork(
+                label("controllers", leaf(serde_cbor::to_vec(&controllers)?)),
+                label("module_hash", leaf([7; 32])),
+            ),
+        ),
+    ));
+    let reads = Arc::new(AtomicUsize::new(0));
+    let agent = Agent::builder()
+        .with_transport(state_transport(serde_cbor::to_vec(&cert)?, reads.clone()))
+        .with_canister_info_cache(Duration::from_secs(60))
+        .build()?;
+    agent.set_root_key(root_key);
+
+    // the second reads are served from the cache
+    for _ in 0..2 {
+        assert_eq!(
+            agent.get_controllers(canister_id).await?,
+            [Principal::anonymous()]
+        );
+        agent.verify_module_hash(canister_id, [7; 32]).await?;
+    }
+    assert_eq!(reads.load(Ordering::Relaxed), 2);
+
+    // an update call to the canister drops its cached values
+    agent.update(&canister_id, "greet").call().await?;
+    agent.get_controllers(canister_id).await?;
+    agent.get_module_hashes(&[canister_id]).await?;
+    assert_eq!(reads.load(Ordering::Relaxed), 4);
+
+    agent.invalidate_canister(canister_id);
+    agent.get_controllers(canister_id).await?;
+    assert_eq!(reads.load(Ordering::Relaxed), 5);
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn canister_info_cache_drops_values_read_during_call() -> Result<(), AgentError> {
+    use crate::{agent::EnvelopeContent, test_utils::MockTransport, RequestId};
+    use ic_certification::hash_tree::{fork, label, leaf};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+    use tokio::sync::Notify;
+
+    let canister_id = Principal::from_text("224od-giaaa-aaaao-ae5vq-cai").unwrap();
+    let controllers = vec![serde_bytes::ByteBuf::from(
+        Principal::anonymous().as_slice(),
+    )];
+    let (cert, root_key) = sign_certificate(label(
+        "canister",
+        label(
+            Label::from_bytes(canister_id.as_slice()),
+            label("controllers", leaf(serde_cbor::to_vec(&controllers)?)),
+        ),
+    ));
+    let info = serde_cbor::to_vec(&ReadStateResponse {
+        certificate: serde_cbor::to_vec(&cert)?,
+    })?;
+    let replied = |request_id: RequestId| {
+        let (cert, _) = sign_certificate(label(
+            "request_status",
+            label(
+                Label::from_bytes(request_id.as_slice()),
+                fork(
+                    label("reply", leaf(vec![])),
+                    label("status", leaf(b"replied".to_vec())),
+                ),
+            ),
+        ));
+        serde_cbor::to_vec(&cert).unwrap()
+    };
+
+    // the call is answered synchronously, or polled for
+    for synchronous in [true, false] {
+        // holds the call until released, and counts the reads of the canister's info
+        let request_id = Arc::new(Mutex::new(None));
+        let (received, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let transport = {
+            let (on_call, on_read_state) = (request_id.clone(), request_id.clone());
+            let (received, release, reads, info) = (
+                received.clone(),
+                release.clone(),
+                reads.clone(),
+                info.clone(),
+            );
+            MockTransport::new()
+                .on_call(move |request| {
+                    let request_id = request.content().to_request_id();
+                    *on_call.lock().unwrap() = Some(request_id);
+                    received.notify_one();
+                    let release = release.clone();
+                    async move {
+                        release.notified().await;
+                        Ok(if synchronous {
+                            TransportCallResponse::Replied {
+                                certificate: replied(request_id),
+                            }
+                        } else {
+                            TransportCallResponse::Accepted
+                        })
+                    }
+                })
+                .on_read_state(move |request| {
+                    let EnvelopeContent::ReadState { paths, .. } = request.content() else {
+                        unreachable!()
+                    };
+                    let response = if paths[0][0] == "request_status".into() {
+                        let request_id = on_read_state.lock().unwrap().unwrap();
+                        serde_cbor::to_vec(&ReadStateResponse {
+                            certificate: replied(request_id),
+                        })
+                        .unwrap()
+                    } else {
+                        reads.fetch_add(1, Ordering::Relaxed);
+                        info.clone()
+                    };
+                    std::future::ready(Ok(response))
+                })
+        };
+        let agent = Agent::builder()
+            .with_transport(transport)
+            .with_canister_info_cache(Duration::from_secs(60))
+            .build()?;
+        agent.set_root_key(root_key.clone());
+
+        let call = tokio::spawn({
+            let agent = agent.clone();
+            async move { agent.update(&canister_id, "greet").call_and_wait().await }
+        });
+        received.notified().await;
+        // read before the call takes effect
+        agent.get_controllers(canister_id).await?;
+        release.notify_one();
+        call.await.unwrap()?;
+
+        // the value read during the call is not served from the cache
+        agent.get_controllers(canister_id).await?;
+        assert_eq!(reads.load(Ordering::Relaxed), 2);
+    }
+
+    Ok(())
+}
+
 #[cfg_attr(not(target_family = "wasm"), tokio::test)]
 #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
 async fn retry_ratelimit() {
@@ -733,6 +2789,83 @@ async fn retry_ratelimit() {
     .await;
 }
 
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn retry_ratelimit_stops_after_max_attempts() {
+    let mut server = mockito::Server::new_async().await;
+    let ratelimit_mock = server
+        .mock("POST", "/api/v2/canister/ryjl3-tyaaa-aaaaa-aaaba-cai/query")
+        .with_status(429)
+        .expect(2)
+        .create_async()
+        .await;
+    let agent = Agent::builder()
+        .with_transport(
+            make_transport(&server.url())
+                .with_jitter(Jitter::None)
+                .with_max_backoff(Duration::from_millis(10))
+                .with_max_rate_limit_attempts(2),
+        )
+        .with_verify_query_signatures(false)
+        .build()
+        .unwrap();
+
+    let result = agent
+        .query(&"ryjl3-tyaaa-aaaaa-aaaba-cai".parse().unwrap(), "greet")
+        .call()
+        .await;
+
+    ratelimit_mock.assert_async().await;
+    assert!(matches!(
+        result,
+        Err(AgentError::HttpError(payload)) if payload.status == 429
+    ));
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[tokio::test]
+async fn retry_policy_overrides_transport() {
+    let mut server = mockito::Server::new_async().await;
+    let ratelimit_mock = server
+        .mock("POST", "/api/v2/canister/ryjl3-tyaaa-aaaaa-aaaba-cai/query")
+        .with_status(429)
+        .expect(1 + 3)
+        .create_async()
+        .await;
+    let agent = Agent::builder()
+        .with_transport(
+            make_transport(&server.url())
+                .with_jitter(Jitter::None)
+                .with_max_backoff(Duration::from_millis(10))
+                .with_max_rate_limit_attempts(5),
+        )
+        .with_verify_query_signatures(false)
+        .build()
+        .unwrap();
+    let canister_id = "ryjl3-tyaaa-aaaaa-aaaba-cai".parse().unwrap();
+
+    // the transport would make five attempts
+    let result = agent
+        .query(&canister_id, "greet")
+        .with_retry_policy(RetryPolicy::Never)
+        .call()
+        .await;
+    assert!(matches!(
+        result,
+        Err(AgentError::HttpError(payload)) if payload.status == 429
+    ));
+    let result = agent
+        .query(&canister_id, "greet")
+        .with_retry_policy(RetryPolicy::MaxAttempts(3))
+        .call()
+        .await;
+    assert!(matches!(
+        result,
+        Err(AgentError::HttpError(payload)) if payload.status == 429
+    ));
+    ratelimit_mock.assert_async().await;
+}
+
 #[cfg(not(target_family = "wasm"))]
 mod mock {
 
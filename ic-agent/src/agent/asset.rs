@@ -0,0 +1,208 @@
+//! Fetching assets certified with the HTTP gateway protocol from an asset canister.
+use candid::{
+    types::{reference::FuncVisitor, value::IDLValueVisitor, Serializer, Type, TypeInner},
+    CandidType, Deserialize, Func, IDLValue,
+};
+use ic_certification::{Certificate, HashTree};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    agent::{response_authentication::lookup_value, Agent, AgentError, CertificateHeader},
+    export::Principal,
+};
+
+/// An asset fetched with [`Agent::fetch_asset`], whose body has been verified against the
+/// canister's certified data.
+///
+/// Only the body is certified; the status code and headers are returned as the canister sent them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    /// The HTTP status code of the response.
+    pub status_code: u16,
+    /// The response headers, including `IC-Certificate`.
+    pub headers: Vec<(String, String)>,
+    /// The response body, as encoded by the canister.
+    pub body: Vec<u8>,
+}
+
+/// The argument of an asset canister's `http_request` method.
+#[derive(CandidType)]
+pub(crate) struct HttpRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub headers: Vec<(&'a str, &'a str)>,
+    pub body: &'a [u8],
+    pub certificate_version: Option<u16>,
+}
+
+/// The result of an asset canister's `http_request` method. Streaming and upgrade fields are
+/// ignored.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl From<HttpResponse> for Asset {
+    fn from(response: HttpResponse) -> Self {
+        Self {
+            status_code: response.status_code,
+            headers: response.headers,
+            body: response.body,
+        }
+    }
+}
+
+/// The result of an asset canister's `http_request` method for an asset it may stream in chunks.
+/// The upgrade field is ignored.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct StreamingHttpResponse {
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+/// How the rest of a streamed asset is fetched.
+#[derive(CandidType, Deserialize)]
+pub(crate) enum StreamingStrategy {
+    Callback { callback: Callback, token: Token },
+}
+
+/// The next chunk of a streamed asset, returned by its streaming callback.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct StreamingCallbackHttpResponse {
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+    pub token: Option<Token>,
+}
+
+/// A streaming callback of any type. It can only be deserialized.
+pub(crate) struct Callback(pub Func);
+
+impl CandidType for Callback {
+    fn _ty() -> Type {
+        TypeInner::Reserved.into()
+    }
+    fn idl_serialize<S: Serializer>(&self, _serializer: S) -> Result<(), S::Error> {
+        // the type must be `reserved` to accept callbacks of any type, so it cannot be serialized
+        Err(serde::ser::Error::custom("Callback is not serializable"))
+    }
+}
+
+impl<'de> Deserialize<'de> for Callback {
+    fn deserialize<D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_ignored_any(FuncVisitor).map(Self)
+    }
+}
+
+/// A streaming token of any type, passed back to the callback as is. It can only be deserialized.
+pub(crate) struct Token(pub IDLValue);
+
+impl CandidType for Token {
+    fn _ty() -> Type {
+        TypeInner::Reserved.into()
+    }
+    fn idl_serialize<S: Serializer>(&self, _serializer: S) -> Result<(), S::Error> {
+        // the type must be `reserved` to accept tokens of any type, so it cannot be serialized
+        Err(serde::ser::Error::custom("Token is not serializable"))
+    }
+}
+
+impl<'de> Deserialize<'de> for Token {
+    fn deserialize<D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_ignored_any(IDLValueVisitor)
+            .map(Self)
+    }
+}
+
+/// Verifies that the body of `response` is the asset `url` certified by `canister_id`, following
+/// version 1 of response verification: the `IC-Certificate` header carries a certificate of the
+/// canister's certified data, which is the root hash of a tree mapping `http_assets/<path>` to the
+/// SHA-256 hash of the body.
+pub(crate) fn verify(
+    agent: &Agent,
+    canister_id: Principal,
+    url: &str,
+    response: &HttpResponse,
+) -> Result<(), AgentError> {
+    let header = certificate_header(&response.headers)?;
+    let asset_hash = certified_hash(agent, canister_id, url, header)?;
+    if asset_hash != Sha256::digest(&response.body).as_slice() {
+        return Err(failed(format!(
+            "the body of {} does not match its certified hash",
+            asset_path(url)
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the value of the `IC-Certificate` header among `headers`, failing if there is none.
+pub(crate) fn certificate_header(headers: &[(String, String)]) -> Result<&str, AgentError> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CertificateHeader::NAME))
+        .map(|(_, value)| value.as_str())
+        .ok_or_else(|| failed("the response has no IC-Certificate header".to_string()))
+}
+
+/// Returns the SHA-256 hash that `canister_id` certifies the body of the asset `url` with, in the
+/// `IC-Certificate` header `header`.
+pub(crate) fn certified_hash(
+    agent: &Agent,
+    canister_id: Principal,
+    url: &str,
+    header: &str,
+) -> Result<Vec<u8>, AgentError> {
+    let header = CertificateHeader::parse(header)?;
+    if header.version != 1 {
+        return Err(failed(format!(
+            "response verification version {} is not supported",
+            header.version
+        )));
+    }
+
+    let cert: Certificate = agent.cbor_limits.decode(&header.certificate)?;
+    agent.verify(&cert, canister_id)?;
+    let tree: HashTree =
+        serde_cbor::from_slice(&header.tree).map_err(AgentError::InvalidCborData)?;
+    let certified_data_path = [
+        "canister".as_bytes(),
+        canister_id.as_slice(),
+        "certified_data".as_bytes(),
+    ];
+    if lookup_value(&cert.tree, certified_data_path)? != tree.digest() {
+        return Err(failed(
+            "the tree is not the canister's certified data".to_string(),
+        ));
+    }
+
+    let path = asset_path(url);
+    let asset_hash = lookup_value(&tree, ["http_assets".as_bytes(), path.as_bytes()])
+        .map_err(|_| failed(format!("{path} is not certified")))?;
+    Ok(asset_hash.to_vec())
+}
+
+/// The path of the asset `url`, which the query string is not part of.
+pub(crate) fn asset_path(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or_default()
+}
+
+pub(crate) fn failed(message: String) -> AgentError {
+    AgentError::AssetVerificationFailed(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_not_serializable() {
+        let token = Token(IDLValue::Nat64(1));
+        let err = candid::encode_one(&token).unwrap_err();
+        assert!(err.to_string().contains("Token is not serializable"));
+    }
+}
@@ -0,0 +1,185 @@
+//! A blocking facade over [`Agent`], for programs that do not otherwise use async.
+use std::{future::Future, sync::OnceLock};
+
+use tokio::runtime::{Builder, Handle, Runtime};
+
+use crate::{
+    agent::{status::Status, Agent},
+    export::Principal,
+    AgentError,
+};
+
+/// The runtime shared by every [`BlockingAgent`] created with [`BlockingAgent::new`].
+static SHARED_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+enum RuntimeRef {
+    Shared,
+    Handle(Handle),
+}
+
+/// Wraps an [`Agent`], running each of its requests to completion on the calling thread.
+///
+/// All requests run on the same runtime, so connections are pooled across calls. Calling any of
+/// these methods from within a tokio runtime returns [`AgentError::BlockingInAsyncContext`], as
+/// blocking there could deadlock it; use the [`Agent`] directly instead.
+#[derive(Debug, Clone)]
+pub struct BlockingAgent {
+    agent: Agent,
+    runtime: RuntimeRef,
+}
+
+impl BlockingAgent {
+    /// Wraps `agent`, running its requests on a current-thread runtime that is created on first use
+    /// and shared by all `BlockingAgent`s.
+    pub fn new(agent: Agent) -> Self {
+        Self {
+            agent,
+            runtime: RuntimeRef::Shared,
+        }
+    }
+
+    /// Wraps `agent`, running its requests on the runtime behind `handle`. If that runtime is a
+    /// current-thread runtime, it must be driven by another thread for requests to make progress.
+    pub fn with_runtime(agent: Agent, handle: Handle) -> Self {
+        Self {
+            agent,
+            runtime: RuntimeRef::Handle(handle),
+        }
+    }
+
+    /// Returns the wrapped agent.
+    pub fn agent(&self) -> &Agent {
+        &self.agent
+    }
+
+    /// Runs `future` to completion on this agent's runtime.
+    pub fn block_on<F: Future>(&self, future: F) -> Result<F::Output, AgentError> {
+        if Handle::try_current().is_ok() {
+            return Err(AgentError::BlockingInAsyncContext);
+        }
+        Ok(match &self.runtime {
+            RuntimeRef::Shared => SHARED_RUNTIME
+                .get_or_init(|| {
+                    Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("Could not create runtime for BlockingAgent.")
+                })
+                .block_on(future),
+            RuntimeRef::Handle(handle) => handle.block_on(future),
+        })
+    }
+
+    /// See [`Agent::fetch_root_key`].
+    pub fn fetch_root_key(&self) -> Result<(), AgentError> {
+        self.block_on(self.agent.fetch_root_key())?
+    }
+
+    /// See [`Agent::status`].
+    pub fn status(&self) -> Result<Status, AgentError> {
+        self.block_on(self.agent.status())?
+    }
+
+    /// Performs a query call. See [`Agent::query`].
+    pub fn query(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        arg: Vec<u8>,
+    ) -> Result<Vec<u8>, AgentError> {
+        self.block_on(
+            self.agent
+                .query(canister_id, method_name)
+                .with_arg(arg)
+                .call(),
+        )?
+    }
+
+    /// Performs an update call and waits for its result. See [`Agent::update`].
+    pub fn update(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        arg: Vec<u8>,
+    ) -> Result<Vec<u8>, AgentError> {
+        self.block_on(
+            self.agent
+                .update(canister_id, method_name)
+                .with_arg(arg)
+                .call_and_wait(),
+        )?
+    }
+}
+
+#[cfg(all(test, feature = "reqwest"))]
+mod tests {
+    use super::*;
+    use std::{
+        collections::BTreeMap,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    /// Serves empty status responses over keep-alive connections, counting the connections.
+    fn status_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let body = serde_cbor::to_vec(&serde_cbor::Value::Map(BTreeMap::new())).unwrap();
+        let counter = connections.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let body = body.clone();
+                thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf) {
+                        request.extend_from_slice(&buf[..n]);
+                        while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            request.drain(..end + 4);
+                            let head = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: application/cbor\r\nContent-Length: {}\r\n\r\n",
+                                body.len()
+                            );
+                            stream.write_all(head.as_bytes()).unwrap();
+                            stream.write_all(&body).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    #[test]
+    fn sequential_calls_reuse_runtime() {
+        let (url, connections) = status_server();
+        let agent = BlockingAgent::new(Agent::builder().with_url(url).build().unwrap());
+        for _ in 0..50 {
+            agent.status().unwrap();
+        }
+        // A runtime per call would drop the pooled connection after every call.
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_inside_runtime() {
+        let agent = BlockingAgent::new(
+            Agent::builder()
+                .with_url("http://not-a-real-url")
+                .build()
+                .unwrap(),
+        );
+        assert!(matches!(
+            agent.status(),
+            Err(AgentError::BlockingInAsyncContext)
+        ));
+    }
+}
@@ -1,8 +1,12 @@
 use crate::{
-    agent::{agent_config::AgentConfig, Agent, Transport},
+    agent::{
+        agent_config::AgentConfig, Agent, AgentObserver, CborLimits, Clock, Sleeper, Transport,
+        WireFormat,
+    },
+    export::Principal,
     AgentError, Identity, NonceFactory, NonceGenerator,
 };
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 /// A builder for an [`Agent`].
 #[derive(Default)]
@@ -36,7 +40,11 @@ impl AgentBuilder {
         self
     }
 
-    /// Add a NonceFactory to this Agent. By default, no nonce is produced.
+    /// Add a NonceFactory to this Agent, which produces the nonce of each update call. By default,
+    /// a random nonce is produced; use [`NonceFactory::empty`] to send calls without one. Queries only
+    /// have a nonce if requested with
+    /// [`QueryBuilder::with_nonce_generation`](super::QueryBuilder::with_nonce_generation), and
+    /// `read_state` requests never do.
     pub fn with_nonce_factory(self, nonce_factory: NonceFactory) -> AgentBuilder {
         self.with_nonce_generator(nonce_factory)
     }
@@ -80,6 +88,33 @@ impl AgentBuilder {
         self
     }
 
+    /// Sets whether update calls may be made anonymously, when no identity is set or the identity
+    /// is the anonymous one. With this off, such calls fail with
+    /// [`AgentError::IdentityRequired`](crate::AgentError::IdentityRequired) without being sent,
+    /// so a missing identity is noticed rather than calls quietly being made as the anonymous
+    /// principal; queries and reads of the state tree are still made anonymously. Defaults to `true`.
+    pub fn with_default_to_anonymous(mut self, default_to_anonymous: bool) -> Self {
+        self.config.default_to_anonymous = default_to_anonymous;
+        self
+    }
+
+    /// Signs requests with `primary`, or with `fallback` whenever `primary` fails to sign, e.g.
+    /// because the HSM holding its key is unavailable. Each fallback is reported to the
+    /// [observer](Self::with_observer).
+    ///
+    /// Requests are sent as the principal of `primary`, so `fallback` must sign as the same
+    /// principal, e.g. with a delegation from `primary`'s key to a key of its own. Signing fails if
+    /// it does not.
+    pub fn with_fallback_identity(
+        mut self,
+        primary: Arc<dyn Identity>,
+        fallback: Arc<dyn Identity>,
+    ) -> Self {
+        self.config.identity = primary;
+        self.config.fallback_identity = Some(fallback);
+        self
+    }
+
     /// Provides a _default_ ingress expiry. This is the delta that will be applied
     /// at the time an update or query is made. The default expiry cannot be a
     /// fixed system time. This is also used when checking certificate timestamps.
@@ -92,6 +127,18 @@ impl AgentBuilder {
         self
     }
 
+    /// Sets how old a certificate may be, by its `time` leaf, for the agent to accept it. Older
+    /// certificates fail verification with [`AgentError::CertificateTooOld`](crate::AgentError::CertificateTooOld),
+    /// guarding against a replica replaying an old but validly signed certificate. Age is measured
+    /// with the agent's [clock](Self::with_clock). Defaults to 5 minutes.
+    ///
+    /// Certificates must also be within the [ingress expiry](Self::with_ingress_expiry) of the local
+    /// clock, so a limit longer than it has no effect.
+    pub fn with_max_cert_age(mut self, max_cert_age: std::time::Duration) -> Self {
+        self.config.max_cert_age = max_cert_age;
+        self
+    }
+
     /// Allows disabling query signature verification. Query signatures improve resilience but require
     /// a separate read-state call to fetch node keys.
     pub fn with_verify_query_signatures(mut self, verify_query_signatures: bool) -> Self {
@@ -106,4 +153,93 @@ impl AgentBuilder {
         self.config.max_concurrent_requests = max_concurrent_requests;
         self
     }
+
+    /// Sets the maximum number of paths [`Agent::read_state_raw_chunked`] puts in a single read_state
+    /// request. Replicas may reject requests with many more paths. Defaults to 1000.
+    pub fn with_max_read_state_paths(mut self, max_paths: usize) -> Self {
+        self.config.max_read_state_paths = max_paths;
+        self
+    }
+
+    /// Sets names canisters can be referred to by, e.g. `ledger`, for
+    /// [`Agent::query_by_name`] and [`Agent::update_by_name`]. None are set by default.
+    pub fn with_canister_aliases(mut self, aliases: HashMap<String, Principal>) -> Self {
+        self.config.canister_aliases = aliases;
+        self
+    }
+
+    /// Caches the certified controllers and module hashes of canisters read with
+    /// [`Agent::get_controllers`], [`Agent::get_module_hashes`] and [`Agent::verify_module_hash`]
+    /// for `ttl`, so that tools reading them repeatedly make one read_state request per canister.
+    ///
+    /// The values of a canister are dropped from the cache when an update call to it is made
+    /// through the agent, e.g. a management canister call that installs code in it or changes its
+    /// settings, and when [`Agent::invalidate_canister`] is called. Changes made by other agents are
+    /// only seen once `ttl` has passed. There is no cache by default.
+    ///
+    /// `ttl` is rounded up to whole seconds. The values of at most 10,000 canisters are cached.
+    pub fn with_canister_info_cache(mut self, ttl: std::time::Duration) -> Self {
+        self.config.canister_info_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the limits on nesting depth and collection length applied to CBOR data in status and
+    /// read_state responses before they are decoded. The defaults are generous enough for any
+    /// legitimate response.
+    pub fn with_cbor_limits(mut self, cbor_limits: CborLimits) -> Self {
+        self.config.cbor_limits = cbor_limits;
+        self
+    }
+
+    /// Sets the format envelopes are encoded and responses decoded in. Defaults to
+    /// [`Cbor`](super::Cbor), the only format the Internet Computer speaks. Whatever the format, it
+    /// is passed the [CBOR limits](Self::with_cbor_limits) to decode read_state responses within.
+    ///
+    /// The transport must be configured with the same format.
+    pub fn with_wire_format(mut self, wire_format: Arc<dyn WireFormat>) -> Self {
+        self.config.wire_format = Some(wire_format);
+        self
+    }
+
+    /// Registers an observer, which is notified of the outcome of requests such as rejected update calls.
+    pub fn with_observer<O>(self, observer: O) -> Self
+    where
+        O: 'static + AgentObserver,
+    {
+        self.with_arc_observer(Arc::new(observer))
+    }
+
+    /// Same as [Self::with_observer], but provides a `Arc` boxed implementation instead
+    /// of a direct type.
+    pub fn with_arc_observer(mut self, observer: Arc<dyn AgentObserver>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Sets the clock used to expire cached data, such as a root key fetched with
+    /// [`fetch_root_key_cached`](Agent::fetch_root_key_cached), and to check the time of
+    /// certificates and query signatures. Defaults to the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// Sets how the agent waits between polls of a request's status. Defaults to [`DefaultSleeper`](super::DefaultSleeper),
+    /// which needs a tokio runtime, or the `wasm-bindgen` feature on `wasm32`.
+    ///
+    /// Retries of rate-limited requests are timed by the transport, which takes its own sleeper,
+    /// e.g. [`ReqwestTransport::with_sleeper`](super::http_transport::ReqwestTransport::with_sleeper).
+    pub fn with_sleeper(mut self, sleeper: Arc<dyn Sleeper>) -> Self {
+        self.config.sleeper = sleeper;
+        self
+    }
+
+    /// Fetches the node keys that query signatures are verified with through `source` rather than
+    /// the transport queries are sent to, e.g. a replica or boundary node that is trusted more than
+    /// the one answering queries. The keys are still read from a certificate verified against the
+    /// root key, wherever they come from.
+    pub fn with_verification_key_source(mut self, source: Arc<dyn Transport>) -> Self {
+        self.config.verification_key_source = Some(source);
+        self
+    }
 }
@@ -0,0 +1,270 @@
+//! Limits applied to CBOR responses before they are decoded.
+use crate::AgentError;
+use serde::de::DeserializeOwned;
+
+/// Limits on the shape of CBOR data received from the replica, checked before decoding it.
+///
+/// A maliciously crafted response could otherwise nest values deeply or declare huge collections
+/// to exhaust memory or stack space during decoding. Exceeding a limit results in
+/// [`AgentError::CborLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CborLimits {
+    /// The maximum nesting depth of arrays, maps, and tags. Defaults to 128.
+    pub max_depth: usize,
+    /// The maximum number of elements in an array, or of entries in a map. Defaults to 1,000,000.
+    pub max_collection_length: usize,
+}
+
+impl Default for CborLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 128,
+            max_collection_length: 1_000_000,
+        }
+    }
+}
+
+impl CborLimits {
+    /// Checks `bytes` against the limits, then decodes them.
+    pub(crate) fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, AgentError> {
+        self.check(bytes)?;
+        serde_cbor::from_slice(bytes).map_err(AgentError::InvalidCborData)
+    }
+
+    /// Walks the items in `bytes` without allocating, failing if a limit is exceeded.
+    /// Malformed data is left for the decoder to report.
+    ///
+    /// This is for [`WireFormat`](super::WireFormat)s that decode CBOR themselves.
+    pub fn check(&self, bytes: &[u8]) -> Result<(), AgentError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        match self.check_item(&mut reader, 0) {
+            Err(Invalid::Limit(message)) => Err(AgentError::CborLimitExceeded(message)),
+            Ok(()) | Err(Invalid::Malformed) => Ok(()),
+        }
+    }
+
+    fn check_item(&self, reader: &mut Reader<'_>, depth: usize) -> Result<(), Invalid> {
+        let initial = reader.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if info == 31 {
+            return match major {
+                // indefinite-length byte and text strings are a sequence of definite-length chunks
+                2 | 3 => loop {
+                    let chunk = reader.byte()?;
+                    if chunk == 0xff {
+                        break Ok(());
+                    }
+                    if chunk >> 5 != major {
+                        break Err(Invalid::Malformed);
+                    }
+                    let len = reader.argument(chunk & 0x1f)?;
+                    reader.skip(len)?;
+                },
+                4 | 5 => {
+                    let depth = self.enter(depth)?;
+                    let mut items = 0;
+                    while reader.peek()? != 0xff {
+                        items += 1;
+                        if items
+                            > self.max_collection_length.saturating_mul(if major == 5 {
+                                2
+                            } else {
+                                1
+                            })
+                        {
+                            return Err(self.too_long());
+                        }
+                        self.check_item(reader, depth)?;
+                    }
+                    reader.pos += 1;
+                    Ok(())
+                }
+                _ => Err(Invalid::Malformed),
+            };
+        }
+        let argument = reader.argument(info)?;
+        match major {
+            0 | 1 | 7 => Ok(()),
+            2 | 3 => reader.skip(argument),
+            4 | 5 => {
+                if argument > self.max_collection_length as u64 {
+                    return Err(self.too_long());
+                }
+                let depth = self.enter(depth)?;
+                let items = if major == 5 {
+                    argument.saturating_mul(2)
+                } else {
+                    argument
+                };
+                for _ in 0..items {
+                    self.check_item(reader, depth)?;
+                }
+                Ok(())
+            }
+            6 => self.check_item(reader, self.enter(depth)?),
+            _ => unreachable!(),
+        }
+    }
+
+    fn enter(&self, depth: usize) -> Result<usize, Invalid> {
+        if depth >= self.max_depth {
+            Err(Invalid::Limit(format!(
+                "nesting depth exceeds {}",
+                self.max_depth
+            )))
+        } else {
+            Ok(depth + 1)
+        }
+    }
+
+    fn too_long(&self) -> Invalid {
+        Invalid::Limit(format!(
+            "collection length exceeds {}",
+            self.max_collection_length
+        ))
+    }
+}
+
+enum Invalid {
+    Limit(String),
+    Malformed,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn peek(&self) -> Result<u8, Invalid> {
+        self.bytes.get(self.pos).copied().ok_or(Invalid::Malformed)
+    }
+
+    fn byte(&mut self) -> Result<u8, Invalid> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn skip(&mut self, len: u64) -> Result<(), Invalid> {
+        let remaining = (self.bytes.len() - self.pos) as u64;
+        if len > remaining {
+            return Err(Invalid::Malformed);
+        }
+        self.pos += len as usize;
+        Ok(())
+    }
+
+    fn argument(&mut self, info: u8) -> Result<u64, Invalid> {
+        let len = match info {
+            0..=23 => return Ok(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(Invalid::Malformed),
+        };
+        let mut argument = 0;
+        for _ in 0..len {
+            argument = argument << 8 | self.byte()? as u64;
+        }
+        Ok(argument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CborLimits;
+    use crate::AgentError;
+    use serde_cbor::Value;
+
+    fn nested_arrays(depth: usize) -> Vec<u8> {
+        let mut bytes = vec![0x81; depth];
+        bytes.push(0x00);
+        bytes
+    }
+
+    #[test]
+    fn accepts_data_within_limits() {
+        let value = Value::Array(vec![
+            Value::Map([(Value::Text("a".into()), Value::Bytes(vec![1, 2]))].into()),
+            Value::Integer(-5),
+            Value::Float(1.5),
+        ]);
+        let mut bytes = vec![0xd9, 0xd9, 0xf7];
+        bytes.extend(serde_cbor::to_vec(&value).unwrap());
+        let decoded: Value = CborLimits::default().decode(&bytes).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let limits = CborLimits {
+            max_depth: 16,
+            ..Default::default()
+        };
+        assert!(limits.check(&nested_arrays(16)).is_ok());
+        assert!(matches!(
+            limits.check(&nested_arrays(17)),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+        // indefinite-length arrays count too
+        let mut indefinite = vec![0x9f; 17];
+        indefinite.extend([0xff; 17]);
+        assert!(matches!(
+            limits.check(&indefinite),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+        // far deeper than the decoder's own recursion would tolerate
+        assert!(matches!(
+            CborLimits::default().decode::<Value>(&nested_arrays(100_000)),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_long_collections() {
+        let limits = CborLimits {
+            max_collection_length: 3,
+            ..Default::default()
+        };
+        assert!(limits.check(&[0x83, 1, 2, 3]).is_ok());
+        assert!(matches!(
+            limits.check(&[0x84, 1, 2, 3, 4]),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+        // an array declaring 2^32 elements is rejected without reading them
+        assert!(matches!(
+            limits.check(&[0x9a, 0xff, 0xff, 0xff, 0xff]),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+        assert!(matches!(
+            limits.check(&[0x9f, 1, 2, 3, 4, 0xff]),
+            Err(AgentError::CborLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn allows_unbounded_collections() {
+        let limits = CborLimits {
+            max_collection_length: usize::MAX,
+            ..Default::default()
+        };
+        assert!(limits.check(&[0xa1, 1, 2]).is_ok());
+        assert!(limits.check(&[0xbf, 1, 2, 0xff]).is_ok());
+        // a map declaring 2^64 - 1 entries is too short rather than too long
+        assert!(limits
+            .check(&[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+            .is_ok());
+    }
+
+    #[test]
+    fn leaves_malformed_data_to_decoder() {
+        let limits = CborLimits::default();
+        assert!(limits.check(&[0x83, 1]).is_ok());
+        assert!(matches!(
+            limits.decode::<Value>(&[0x83, 1]),
+            Err(AgentError::InvalidCborData(_))
+        ));
+    }
+}
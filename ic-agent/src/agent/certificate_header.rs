@@ -0,0 +1,143 @@
+//! Parsing of the `IC-Certificate` header that HTTP gateways attach to certified responses.
+use base64::prelude::*;
+use http::HeaderMap;
+
+use crate::AgentError;
+
+/// The contents of an `IC-Certificate` response header.
+///
+/// When an asset canister's response is served over HTTP, the certification that lets clients
+/// verify it travels in this header rather than in the body. The certificate can be decoded into a
+/// [`Certificate`](crate::Certificate) and checked with [`Agent::verify`](super::Agent::verify); the
+/// tree then certifies the response itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateHeader {
+    /// The CBOR-encoded certificate.
+    pub certificate: Vec<u8>,
+    /// The CBOR-encoded hash tree certifying the response.
+    pub tree: Vec<u8>,
+    /// The version of response verification the header is for. Headers without a version are
+    /// version 1.
+    pub version: u16,
+    /// The CBOR-encoded path in `tree` of the expression that certifies the response, from
+    /// version 2 on.
+    pub expr_path: Option<Vec<u8>>,
+}
+
+impl CertificateHeader {
+    /// The name of the header.
+    pub const NAME: &'static str = "ic-certificate";
+
+    /// Parses the `IC-Certificate` header in `headers`, if there is one.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, AgentError> {
+        let Some(value) = headers.get(Self::NAME) else {
+            return Ok(None);
+        };
+        let value = value
+            .to_str()
+            .map_err(|_| AgentError::InvalidCertificateHeader("not ASCII".to_string()))?;
+        Self::parse(value).map(Some)
+    }
+
+    /// Parses the value of an `IC-Certificate` header, a structured field of the form
+    /// `certificate=:<base64>:, tree=:<base64>:[, version=<n>][, expr_path=:<base64>:]`.
+    pub fn parse(value: &str) -> Result<Self, AgentError> {
+        let mut certificate = None;
+        let mut tree = None;
+        let mut version = 1;
+        let mut expr_path = None;
+        for field in value.split(',') {
+            let (name, value) = field
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("malformed field `{}`", field.trim())))?;
+            match name {
+                "certificate" => certificate = Some(decode_bytes(name, value)?),
+                "tree" => tree = Some(decode_bytes(name, value)?),
+                "expr_path" => expr_path = Some(decode_bytes(name, value)?),
+                "version" => {
+                    version = value
+                        .parse()
+                        .map_err(|_| invalid(format!("malformed version `{value}`")))?
+                }
+                // unknown fields are ignored, as new ones may be added
+                _ => {}
+            }
+        }
+        Ok(Self {
+            certificate: certificate.ok_or_else(|| invalid("missing certificate".to_string()))?,
+            tree: tree.ok_or_else(|| invalid("missing tree".to_string()))?,
+            version,
+            expr_path,
+        })
+    }
+}
+
+/// Decodes a byte sequence, which is base64 between colons.
+fn decode_bytes(name: &str, value: &str) -> Result<Vec<u8>, AgentError> {
+    value
+        .strip_prefix(':')
+        .and_then(|value| value.strip_suffix(':'))
+        .and_then(|value| BASE64_STANDARD.decode(value).ok())
+        .ok_or_else(|| invalid(format!("malformed {name}")))
+}
+
+fn invalid(message: String) -> AgentError {
+    AgentError::InvalidCertificateHeader(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CertificateHeader;
+    use crate::AgentError;
+    use http::{HeaderMap, HeaderValue};
+
+    #[test]
+    fn parses_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(CertificateHeader::from_headers(&headers).unwrap(), None);
+
+        headers.insert(
+            "IC-Certificate",
+            HeaderValue::from_static("certificate=:2dn3oA==:, tree=:gwE=:"),
+        );
+        assert_eq!(
+            CertificateHeader::from_headers(&headers).unwrap(),
+            Some(CertificateHeader {
+                certificate: vec![0xd9, 0xd9, 0xf7, 0xa0],
+                tree: vec![0x83, 0x01],
+                version: 1,
+                expr_path: None,
+            })
+        );
+
+        headers.insert(
+            "IC-Certificate",
+            HeaderValue::from_static(
+                "version=2, certificate=:2dn3oA==:, tree=:gwE=:, expr_path=:gWlodHRwX2V4cHI=:",
+            ),
+        );
+        let header = CertificateHeader::from_headers(&headers).unwrap().unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.expr_path.unwrap(), b"\x81\x69http_expr".to_vec());
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        for value in [
+            "tree=:gwE=:",
+            "certificate=:2dn3oA==:",
+            "certificate=2dn3oA==, tree=:gwE=:",
+            "certificate=:not base64:, tree=:gwE=:",
+            "certificate=:2dn3oA==:, tree=:gwE=:, version=two",
+        ] {
+            assert!(
+                matches!(
+                    CertificateHeader::parse(value),
+                    Err(AgentError::InvalidCertificateHeader(_))
+                ),
+                "{value}"
+            );
+        }
+    }
+}
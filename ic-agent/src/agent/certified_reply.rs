@@ -0,0 +1,45 @@
+//! Verifying certified data that a canister returns in its reply to a query, along with the
+//! certificate that covers it.
+use candid::{CandidType, Decode, Deserialize};
+use ic_certification::{Certificate, HashTree};
+
+use crate::{
+    agent::{response_authentication::lookup_value, Agent, AgentError},
+    export::Principal,
+};
+
+/// The fields of a reply that certify its data. The reply may have other fields too.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct CertifiedReply {
+    /// The CBOR-encoded certificate the canister got from `ic0.data_certificate`.
+    #[serde(with = "serde_bytes")]
+    pub certificate: Vec<u8>,
+    /// The CBOR-encoded hash tree whose root hash is the canister's certified data.
+    #[serde(with = "serde_bytes")]
+    pub witness: Vec<u8>,
+}
+
+/// Verifies the certificate and witness embedded in `reply` for `canister_id`, and returns the value
+/// the witness has at `expected_path`.
+pub(crate) fn verify(
+    agent: &Agent,
+    canister_id: Principal,
+    reply: &[u8],
+    expected_path: &[&[u8]],
+) -> Result<Vec<u8>, AgentError> {
+    let reply = Decode!(reply, CertifiedReply)?;
+    let cert: Certificate = agent.cbor_limits.decode(&reply.certificate)?;
+    agent.verify(&cert, canister_id)?;
+    let witness: HashTree = agent.cbor_limits.decode(&reply.witness)?;
+    let certified_data_path = [
+        "canister".as_bytes(),
+        canister_id.as_slice(),
+        "certified_data".as_bytes(),
+    ];
+    if lookup_value(&cert.tree, certified_data_path)? != witness.digest() {
+        return Err(AgentError::ResponseVerificationFailed(
+            "the witness is not the canister's certified data".to_string(),
+        ));
+    }
+    lookup_value(&witness, expected_path).map(<[u8]>::to_vec)
+}
@@ -0,0 +1,21 @@
+//! The source of the current time for the agent's caches.
+use time::OffsetDateTime;
+
+/// A source of the current time, used to decide when cached data such as a
+/// [fetched root key](super::Agent::fetch_root_key_cached) has gone stale.
+///
+/// The default is [`WallClock`]; tests can substitute a clock they advance by hand.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The system clock, in UTC.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
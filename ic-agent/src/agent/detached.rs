@@ -0,0 +1,74 @@
+//! Signing of requests outside of any [`Identity`](crate::Identity), by custody systems that take a
+//! digest and return only the signature.
+use ic_transport_types::{Envelope, EnvelopeContent};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+/// Returns the SHA-256 digest of the domain-separated request ID of `content`, i.e. of
+/// `"\x0Aic-request"` followed by the request ID.
+///
+/// This is the digest that ECDSA signatures (secp256k1 and P-256) of the request are made over, so
+/// a custody system can sign it as a prehashed message. Ed25519 signs the message itself rather
+/// than a digest of it; for Ed25519 keys, sign [`RequestId::signable`](crate::RequestId::signable)
+/// instead.
+pub fn request_id_to_sign(content: &EnvelopeContent) -> [u8; 32] {
+    Sha256::digest(content.to_request_id().signable()).into()
+}
+
+/// Assembles the envelope of `content` from a signature produced outside the agent, by the key with
+/// the DER-encoded public key `public_key`.
+///
+/// The envelope can be encoded with [`Envelope::encode_bytes`] and sent with a
+/// [`Transport`](crate::agent::Transport).
+pub fn attach_signature(
+    content: &EnvelopeContent,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+) -> Envelope<'_> {
+    Envelope {
+        content: Cow::Borrowed(content),
+        sender_pubkey: Some(public_key),
+        sender_sig: Some(signature),
+        sender_delegation: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{export::Principal, identity::Secp256k1Identity, Identity};
+    use k256::ecdsa::{
+        signature::{hazmat::PrehashSigner, Verifier},
+        Signature, SigningKey, VerifyingKey,
+    };
+
+    #[test]
+    fn external_signature_verifies() {
+        let key = k256::SecretKey::from_slice(&[7; 32]).unwrap();
+        let identity = Secp256k1Identity::from_private_key(key.clone());
+        let content = EnvelopeContent::Call {
+            nonce: None,
+            ingress_expiry: 1_700_000_000_000_000_000,
+            sender: identity.sender().unwrap(),
+            canister_id: Principal::management_canister(),
+            method_name: "greet".to_string(),
+            arg: b"DIDL\0\0".to_vec(),
+        };
+
+        // the custody system sees only the digest
+        let digest = request_id_to_sign(&content);
+        let signature: Signature = SigningKey::from(&key).sign_prehash(&digest).unwrap();
+        let public_key = identity.public_key().unwrap();
+        let envelope = attach_signature(&content, public_key, signature.to_vec());
+
+        let verifying_key = VerifyingKey::from(key.public_key());
+        let sender_sig = Signature::from_slice(envelope.sender_sig.as_ref().unwrap()).unwrap();
+        verifying_key
+            .verify(&envelope.content.to_request_id().signable(), &sender_sig)
+            .unwrap();
+        // signing is deterministic, so the envelope is the one the identity would have made
+        let signed = identity.sign(&content).unwrap();
+        assert_eq!(envelope.sender_sig, signed.signature);
+        assert_eq!(envelope.sender_pubkey, signed.public_key);
+    }
+}
@@ -0,0 +1,539 @@
+//! A [`Transport`] decorator that stops sending requests to a failing replica for a while.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use http::HeaderMap;
+use ic_transport_types::TransportCallResponse;
+use time::OffsetDateTime;
+
+use crate::{
+    agent::{AgentFuture, Clock, RetryPolicy, Transport, WallClock},
+    export::Principal,
+    AgentError,
+};
+
+/// The state of a [`CircuitBreakerTransport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent, and their outcomes are tracked.
+    Closed,
+    /// Requests fail immediately with [`AgentError::CircuitOpen`] until the cooldown elapses.
+    Open,
+    /// The cooldown has elapsed. A single probe request is sent; if it succeeds the circuit closes,
+    /// and if it fails the circuit opens again. Other requests fail immediately meanwhile.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    /// `outcomes` holds whether each of the most recent requests failed, oldest first.
+    Closed {
+        outcomes: VecDeque<bool>,
+    },
+    Open {
+        until: OffsetDateTime,
+    },
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+/// Wraps a [`Transport`], and fails requests fast with [`AgentError::CircuitOpen`] while the
+/// wrapped transport is failing.
+///
+/// The breaker tracks the outcomes of the most recent requests. Once enough of them have failed,
+/// it opens the circuit for a cooldown period, then lets a single probe request through to decide
+/// whether to close it again. Transport errors, 5xx responses, and malformed HTTP responses count as
+/// failures; rejections by canisters and other 4xx responses do not.
+#[derive(Debug)]
+pub struct CircuitBreakerTransport<T> {
+    inner: T,
+    failure_threshold: f64,
+    minimum_requests: usize,
+    window_size: usize,
+    cooldown: Duration,
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<State>>,
+}
+
+impl<T: Transport> CircuitBreakerTransport<T> {
+    /// Wraps `inner`. By default the circuit opens when at least half of the last 20 requests
+    /// failed, counting only once 10 requests have been made, and stays open for 30 seconds.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            failure_threshold: 0.5,
+            minimum_requests: 10,
+            window_size: 20,
+            cooldown: Duration::from_secs(30),
+            clock: Arc::new(WallClock),
+            state: Arc::new(Mutex::new(State::Closed {
+                outcomes: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Sets the fraction of failed requests, between 0 and 1, at which the circuit opens.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failure_threshold` is not greater than 0 and at most 1. At 0, the circuit would
+    /// open without any failures.
+    pub fn with_failure_threshold(self, failure_threshold: f64) -> Self {
+        assert!(
+            failure_threshold > 0.0 && failure_threshold <= 1.0,
+            "failure threshold must be greater than 0 and at most 1, not {failure_threshold}"
+        );
+        Self {
+            failure_threshold,
+            ..self
+        }
+    }
+
+    /// Sets how many requests must have been made before the failure rate can open the circuit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `minimum_requests` is greater than the [window size](Self::with_window_size), as
+    /// the window would never hold enough requests to open the circuit. To raise both, set the
+    /// window size first.
+    pub fn with_minimum_requests(self, minimum_requests: usize) -> Self {
+        check_window(self.window_size, minimum_requests);
+        Self {
+            minimum_requests,
+            ..self
+        }
+    }
+
+    /// Sets how many of the most recent requests the failure rate is computed over.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window_size` is 0, or less than the
+    /// [minimum number of requests](Self::with_minimum_requests). To lower both, set the minimum
+    /// number of requests first.
+    pub fn with_window_size(self, window_size: usize) -> Self {
+        check_window(window_size, self.minimum_requests);
+        Self {
+            window_size,
+            ..self
+        }
+    }
+
+    /// Sets how long the circuit stays open before a probe request is let through.
+    pub fn with_cooldown(self, cooldown: Duration) -> Self {
+        Self { cooldown, ..self }
+    }
+
+    /// Sets the clock the cooldown is measured with, e.g. the one passed to
+    /// [`AgentBuilder::with_clock`](crate::agent::AgentBuilder::with_clock). Defaults to the system
+    /// clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Returns the current state of the circuit.
+    pub fn state(&self) -> CircuitState {
+        match &*self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if self.clock.now() < *until => CircuitState::Open,
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Decides whether a request may be sent. Returns a probe if it is the half-open probe.
+    fn admit(&self) -> Result<Option<Probe>, AgentError> {
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            State::Closed { .. } => Ok(None),
+            State::Open { until } if self.clock.now() < *until => Err(AgentError::CircuitOpen),
+            State::HalfOpen { probing: true } => Err(AgentError::CircuitOpen),
+            State::Open { .. } | State::HalfOpen { probing: false } => {
+                *state = State::HalfOpen { probing: true };
+                Ok(Some(Probe(self.state.clone())))
+            }
+        }
+    }
+
+    /// Records the outcome of a request. `probe` is dropped only after the lock is released.
+    fn record(&self, probe: Option<Probe>, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, probe.is_some()) {
+            (State::HalfOpen { .. }, true) => {
+                *state = if failed {
+                    self.open()
+                } else {
+                    State::Closed {
+                        outcomes: VecDeque::new(),
+                    }
+                };
+            }
+            (State::Closed { outcomes }, false) => {
+                outcomes.push_back(failed);
+                while outcomes.len() > self.window_size {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|failed| **failed).count();
+                if outcomes.len() >= self.minimum_requests
+                    && failures as f64 >= self.failure_threshold * outcomes.len() as f64
+                {
+                    *state = self.open();
+                }
+            }
+            // Requests admitted before the circuit opened carry no information about recovery.
+            _ => {}
+        }
+    }
+
+    fn open(&self) -> State {
+        State::Open {
+            until: self.clock.now() + self.cooldown,
+        }
+    }
+
+    fn guard<'a, V: 'a>(
+        &'a self,
+        request: impl FnOnce() -> AgentFuture<'a, V> + Send + 'a,
+    ) -> AgentFuture<'a, V> {
+        Box::pin(async move {
+            let probe = self.admit()?;
+            let result = request().await;
+            self.record(probe, result.as_ref().is_err_and(is_failure));
+            result
+        })
+    }
+}
+
+/// Rejects a window that could never hold the minimum number of requests, or any request at all.
+fn check_window(window_size: usize, minimum_requests: usize) {
+    assert!(
+        window_size > 0 && window_size >= minimum_requests,
+        "window size {window_size} must be at least 1 and the minimum of {minimum_requests} requests"
+    );
+}
+
+/// Lets another probe through if the half-open probe is dropped before its outcome is recorded.
+struct Probe(Arc<Mutex<State>>);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.lock() {
+            if let State::HalfOpen { probing } = &mut *state {
+                *probing = false;
+            }
+        }
+    }
+}
+
+fn is_failure(err: &AgentError) -> bool {
+    match err {
+        AgentError::TransportError(_)
+        | AgentError::TimeoutError(_)
+        | AgentError::ConnectTimeout(_)
+        | AgentError::InvalidHttpResponse(_) => true,
+        AgentError::HttpError(payload) => payload.status >= 500,
+        _ => false,
+    }
+}
+
+impl<T: Transport> Transport for CircuitBreakerTransport<T> {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.guard(move || self.inner.call(effective_canister_id, envelope))
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        self.guard(move || self.inner.read_state(effective_canister_id, envelope))
+    }
+
+    fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.guard(move || self.inner.read_subnet_state(subnet_id, envelope))
+    }
+
+    fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.guard(move || self.inner.query(effective_canister_id, envelope))
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.guard(move || {
+            self.inner
+                .query_with_headers(effective_canister_id, envelope, headers)
+        })
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        self.guard(move || {
+            self.inner.query_with_retry_policy(
+                effective_canister_id,
+                envelope,
+                headers,
+                retry_policy,
+            )
+        })
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.guard(move || {
+            self.inner
+                .call_with_headers(effective_canister_id, envelope, headers)
+        })
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.guard(move || {
+            self.inner.call_with_retry_policy(
+                effective_canister_id,
+                envelope,
+                headers,
+                retry_policy,
+            )
+        })
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        self.guard(move || self.inner.status())
+    }
+
+    fn is_mainnet(&self) -> bool {
+        self.inner.is_mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{agent::agent_error::HttpErrorPayload, test_utils::MockTransport};
+    use futures_util::FutureExt;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// The state of a transport made by [`flaky_transport`].
+    #[derive(Default)]
+    struct Flaky {
+        failing: AtomicBool,
+        requests: AtomicUsize,
+    }
+
+    /// Answers status requests immediately, failing while `failing` is set.
+    fn flaky_transport(flaky: Arc<Flaky>) -> MockTransport {
+        MockTransport::new().on_status(move || {
+            flaky.requests.fetch_add(1, Ordering::SeqCst);
+            let result = if flaky.failing.load(Ordering::SeqCst) {
+                Err(AgentError::HttpError(HttpErrorPayload {
+                    status: 503,
+                    reason: None,
+                    content_type: None,
+                    content: vec![],
+                }))
+            } else {
+                Ok(vec![])
+            };
+            async move { result }
+        })
+    }
+
+    fn status<T: Transport>(transport: &T) -> Result<Vec<u8>, AgentError> {
+        transport.status().now_or_never().unwrap()
+    }
+
+    /// A clock that only moves when told to.
+    #[derive(Debug)]
+    struct MockClock(Mutex<OffsetDateTime>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    const COOLDOWN: Duration = Duration::from_secs(3600);
+
+    fn breaker(inner: &Arc<Flaky>) -> (CircuitBreakerTransport<MockTransport>, Arc<MockClock>) {
+        let clock = Arc::new(MockClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let transport = CircuitBreakerTransport::new(flaky_transport(inner.clone()))
+            .with_failure_threshold(0.5)
+            .with_minimum_requests(4)
+            .with_window_size(4)
+            .with_cooldown(COOLDOWN)
+            .with_clock(clock.clone());
+        (transport, clock)
+    }
+
+    /// Makes the cooldown elapse.
+    fn end_cooldown(clock: &MockClock) {
+        *clock.0.lock().unwrap() += COOLDOWN;
+    }
+
+    #[test]
+    fn opens_and_recovers() {
+        let inner = Arc::new(Flaky::default());
+        let (transport, clock) = breaker(&inner);
+
+        status(&transport).unwrap();
+        status(&transport).unwrap();
+        inner.failing.store(true, Ordering::SeqCst);
+        status(&transport).unwrap_err();
+        assert_eq!(transport.state(), CircuitState::Closed);
+        status(&transport).unwrap_err();
+        assert_eq!(transport.state(), CircuitState::Open);
+
+        // fails fast without reaching the inner transport
+        assert!(matches!(status(&transport), Err(AgentError::CircuitOpen)));
+        assert_eq!(inner.requests.load(Ordering::SeqCst), 4);
+
+        // the cooldown is measured by the transport's clock
+        *clock.0.lock().unwrap() += COOLDOWN - Duration::from_secs(1);
+        assert_eq!(transport.state(), CircuitState::Open);
+        *clock.0.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(transport.state(), CircuitState::HalfOpen);
+        inner.failing.store(false, Ordering::SeqCst);
+        status(&transport).unwrap();
+        assert_eq!(transport.state(), CircuitState::Closed);
+        assert_eq!(inner.requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let inner = Arc::new(Flaky::default());
+        inner.failing.store(true, Ordering::SeqCst);
+        let (transport, clock) = breaker(&inner);
+        for _ in 0..4 {
+            status(&transport).unwrap_err();
+        }
+        assert_eq!(transport.state(), CircuitState::Open);
+
+        end_cooldown(&clock);
+        assert!(matches!(status(&transport), Err(AgentError::HttpError(_))));
+        assert_eq!(transport.state(), CircuitState::Open);
+        assert!(matches!(status(&transport), Err(AgentError::CircuitOpen)));
+        assert_eq!(inner.requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn only_one_probe_at_a_time() {
+        let inner = Arc::new(Flaky::default());
+        inner.failing.store(true, Ordering::SeqCst);
+        let (transport, clock) = breaker(&inner);
+        for _ in 0..4 {
+            status(&transport).unwrap_err();
+        }
+        end_cooldown(&clock);
+
+        // the probe is admitted, but does not complete
+        let mut probe =
+            transport.guard(|| Box::pin(futures_util::future::pending::<Result<(), _>>()));
+        assert!(probe.as_mut().now_or_never().is_none());
+        assert_eq!(transport.state(), CircuitState::HalfOpen);
+        assert!(matches!(status(&transport), Err(AgentError::CircuitOpen)));
+
+        // a probe abandoned before completing lets the next request probe instead
+        drop(probe);
+        inner.failing.store(false, Ordering::SeqCst);
+        status(&transport).unwrap();
+        assert_eq!(transport.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn forwards_retry_policy() {
+        let policies = Arc::new(Mutex::new(vec![]));
+        let (on_query, on_call) = (policies.clone(), policies.clone());
+        let transport = CircuitBreakerTransport::new(
+            MockTransport::new()
+                .on_query(move |request| {
+                    on_query.lock().unwrap().push(request.retry_policy);
+                    async { Ok(vec![]) }
+                })
+                .on_call(move |request| {
+                    on_call.lock().unwrap().push(request.retry_policy);
+                    async { Ok(TransportCallResponse::Accepted) }
+                }),
+        );
+        let canister_id = Principal::management_canister();
+        transport
+            .query_with_retry_policy(canister_id, vec![], HeaderMap::new(), RetryPolicy::Never)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        transport
+            .call_with_retry_policy(
+                canister_id,
+                vec![],
+                HeaderMap::new(),
+                RetryPolicy::MaxAttempts(3),
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *policies.lock().unwrap(),
+            [RetryPolicy::Never, RetryPolicy::MaxAttempts(3)]
+        );
+    }
+
+    #[test]
+    #[should_panic = "failure threshold must be greater than 0 and at most 1, not 0"]
+    fn rejects_zero_failure_threshold() {
+        CircuitBreakerTransport::new(MockTransport::new()).with_failure_threshold(0.0);
+    }
+
+    #[test]
+    #[should_panic = "failure threshold must be greater than 0 and at most 1, not 1.5"]
+    fn rejects_failure_threshold_above_one() {
+        CircuitBreakerTransport::new(MockTransport::new()).with_failure_threshold(1.5);
+    }
+
+    #[test]
+    #[should_panic = "window size 5 must be at least 1 and the minimum of 10 requests"]
+    fn rejects_window_smaller_than_minimum_requests() {
+        CircuitBreakerTransport::new(MockTransport::new()).with_window_size(5);
+    }
+
+    #[test]
+    #[should_panic = "window size 20 must be at least 1 and the minimum of 30 requests"]
+    fn rejects_minimum_requests_beyond_window() {
+        CircuitBreakerTransport::new(MockTransport::new()).with_minimum_requests(30);
+    }
+
+    #[test]
+    fn ignores_non_transport_failures() {
+        assert!(!is_failure(&AgentError::HttpError(HttpErrorPayload {
+            status: 400,
+            reason: None,
+            content_type: None,
+            content: vec![],
+        })));
+        assert!(!is_failure(&AgentError::InvalidReplicaStatus));
+        assert!(is_failure(&AgentError::InvalidHttpResponse(String::new())));
+    }
+}
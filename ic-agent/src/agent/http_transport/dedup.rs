@@ -0,0 +1,268 @@
+//! A [`Transport`] decorator that coalesces identical concurrent requests into one.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_lock::{RwLock, RwLockWriteGuardArc};
+use http::HeaderMap;
+use ic_certification::Label;
+use ic_transport_types::{Envelope, EnvelopeContent, TransportCallResponse};
+
+use crate::{
+    agent::{AgentFuture, RetryPolicy, Transport},
+    export::Principal,
+    AgentError,
+};
+
+/// What identifies a `read_state` request: its effective canister id, its sender, and its paths in
+/// sorted order.
+///
+/// The sender is part of the key because the replica only lets the sender of a call read its
+/// status, so requests from different senders may legitimately get different answers.
+type ReadStateKey = (Principal, Principal, Vec<Vec<Label>>);
+
+/// The response to an in-flight request, once its sender has it. It stays `None` if the request
+/// failed or was dropped.
+type InFlight = Arc<RwLock<Option<Vec<u8>>>>;
+
+/// Wraps a [`Transport`], sending a single request for concurrent `read_state` requests of the same
+/// paths of the same canister, and returning its response to all of them.
+///
+/// The response is a certificate of exactly the requested paths, so it is as valid for each waiter
+/// as the response to its own request would have been; each waiter still verifies it. If the shared
+/// request fails, the waiters send their own requests rather than all failing with the same error.
+///
+/// Envelopes are decoded as CBOR to find their paths; requests that cannot be decoded are sent
+/// as-is. Calls, queries, and status requests are passed through.
+#[derive(Debug)]
+pub struct DeduplicatingTransport<T> {
+    inner: T,
+    read_states: Mutex<HashMap<ReadStateKey, InFlight>>,
+}
+
+impl<T: Transport> DeduplicatingTransport<T> {
+    /// Wraps `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            read_states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn read_state_coalesced(
+        &self,
+        key: ReadStateKey,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Result<Vec<u8>, AgentError> {
+        let mut slot = match self.start_or_join(&key) {
+            Ok(slot) => slot,
+            Err(in_flight) => {
+                if let Some(response) = &*in_flight.read_arc().await {
+                    return Ok(response.clone());
+                }
+                // the shared request failed, so this one is sent by itself
+                return self.inner.read_state(effective_canister_id, envelope).await;
+            }
+        };
+        // declared after `slot`, so the entry is removed before waiters are released
+        let _entry = InFlightEntry {
+            read_states: &self.read_states,
+            key,
+        };
+        let response = self
+            .inner
+            .read_state(effective_canister_id, envelope)
+            .await?;
+        *slot = Some(response.clone());
+        Ok(response)
+    }
+
+    /// Registers a request for `key`, returning the slot for its response, or returns the request
+    /// already in flight for `key`.
+    fn start_or_join(
+        &self,
+        key: &ReadStateKey,
+    ) -> Result<RwLockWriteGuardArc<Option<Vec<u8>>>, InFlight> {
+        let mut read_states = self.read_states.lock().unwrap();
+        if let Some(in_flight) = read_states.get(key) {
+            return Err(in_flight.clone());
+        }
+        let in_flight: InFlight = Arc::new(RwLock::new(None));
+        let slot = in_flight.try_write_arc().expect("a new lock is unlocked");
+        read_states.insert(key.clone(), in_flight);
+        Ok(slot)
+    }
+}
+
+/// Removes an in-flight request from the map when its sender finishes or is dropped.
+struct InFlightEntry<'a> {
+    read_states: &'a Mutex<HashMap<ReadStateKey, InFlight>>,
+    key: ReadStateKey,
+}
+
+impl Drop for InFlightEntry<'_> {
+    fn drop(&mut self) {
+        self.read_states.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Returns the key of a `read_state` envelope, or `None` if it cannot be decoded.
+fn read_state_key(effective_canister_id: Principal, envelope: &[u8]) -> Option<ReadStateKey> {
+    let envelope: Envelope = serde_cbor::from_slice(envelope).ok()?;
+    match envelope.content.into_owned() {
+        EnvelopeContent::ReadState {
+            sender, mut paths, ..
+        } => {
+            paths.sort();
+            paths.dedup();
+            Some((effective_canister_id, sender, paths))
+        }
+        _ => None,
+    }
+}
+
+impl<T: Transport> Transport for DeduplicatingTransport<T> {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inner.call(effective_canister_id, envelope)
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        match read_state_key(effective_canister_id, &envelope) {
+            Some(key) => Box::pin(self.read_state_coalesced(key, effective_canister_id, envelope)),
+            None => self.inner.read_state(effective_canister_id, envelope),
+        }
+    }
+
+    fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.inner.read_subnet_state(subnet_id, envelope)
+    }
+
+    fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.inner.query(effective_canister_id, envelope)
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.inner
+            .query_with_headers(effective_canister_id, envelope, headers)
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        self.inner
+            .query_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inner
+            .call_with_headers(effective_canister_id, envelope, headers)
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inner
+            .call_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        self.inner.status()
+    }
+
+    fn is_mainnet(&self) -> bool {
+        self.inner.is_mainnet()
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockTransport;
+    use futures_util::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers read_state requests with the envelope's first path, after yielding once, counting
+    /// them.
+    fn echo_transport(requests: Arc<AtomicUsize>) -> MockTransport {
+        MockTransport::new().on_read_state(move |request| {
+            requests.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::task::yield_now().await;
+                let (_, _, paths) =
+                    read_state_key(request.effective_canister_id, &request.envelope).unwrap();
+                Ok(paths[0][0].as_bytes().to_vec())
+            }
+        })
+    }
+
+    fn read_state(paths: &[&str], nonce: u8) -> Vec<u8> {
+        Envelope {
+            content: std::borrow::Cow::Owned(EnvelopeContent::ReadState {
+                ingress_expiry: nonce as u64,
+                sender: Principal::anonymous(),
+                paths: paths.iter().map(|path| vec![Label::from(*path)]).collect(),
+            }),
+            sender_pubkey: None,
+            sender_sig: None,
+            sender_delegation: None,
+        }
+        .encode_bytes()
+    }
+
+    #[tokio::test]
+    async fn coalesces_identical_read_states() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let transport = DeduplicatingTransport::new(echo_transport(requests.clone()));
+        let canister_id = Principal::management_canister();
+
+        let responses = join_all((0..20).map(|n| {
+            // envelopes differ in their expiry, and list the paths in different orders
+            let paths: &[&str] = if n % 2 == 0 { &["a", "b"] } else { &["b", "a"] };
+            transport.read_state(canister_id, read_state(paths, n))
+        }))
+        .await;
+        assert!(responses.iter().all(|r| r.as_ref().unwrap() == b"a"));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(transport.read_states.lock().unwrap().is_empty());
+
+        // different paths are not coalesced, and finished requests are not reused
+        join_all([
+            transport.read_state(canister_id, read_state(&["a"], 0)),
+            transport.read_state(canister_id, read_state(&["b"], 0)),
+        ])
+        .await;
+        transport
+            .read_state(canister_id, read_state(&["a"], 0))
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,350 @@
+//! A [`Transport`] decorator that injects failures and latency, for testing resilience.
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use http::HeaderMap;
+use ic_transport_types::TransportCallResponse;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    agent::{
+        agent_error::HttpErrorPayload, AgentFuture, DefaultSleeper, RetryPolicy, Sleeper, Transport,
+    },
+    export::Principal,
+    AgentError,
+};
+
+/// Wraps a [`Transport`], normally a real one, and makes some of its requests fail or slow down at
+/// random, to test how an application copes with an unreliable network.
+///
+/// Each request is first delayed with the [latency probability](Self::with_latency), then fails as a
+/// dropped connection with the [drop probability](Self::with_dropped_connections), or else fails with
+/// one of the configured [status codes](Self::with_status_codes) with their probability. Requests
+/// that fail this way never reach the wrapped transport. Nothing is injected by default.
+///
+/// The faults are drawn from a random number generator that can be [seeded](Self::with_seed), so
+/// that a sequence of requests meets the same faults on every run.
+#[derive(Debug)]
+pub struct FaultInjectionTransport<T> {
+    inner: T,
+    drop_probability: f64,
+    status_probability: f64,
+    status_codes: Vec<u16>,
+    latency_probability: f64,
+    max_latency: Duration,
+    sleeper: Arc<dyn Sleeper>,
+    rng: Mutex<StdRng>,
+}
+
+/// Rejects a probability before it reaches the random number generator, which would panic on it
+/// while its lock is held, poisoning it.
+fn check_probability(probability: f64) {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "fault probability must be between 0 and 1, not {probability}"
+    );
+}
+
+/// The faults drawn for a request.
+struct Faults {
+    latency: Option<Duration>,
+    error: Option<AgentError>,
+}
+
+impl<T: Transport> FaultInjectionTransport<T> {
+    /// Wraps `inner`, injecting no faults until configured to.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            drop_probability: 0.0,
+            status_probability: 0.0,
+            status_codes: vec![],
+            latency_probability: 0.0,
+            max_latency: Duration::ZERO,
+            sleeper: Arc::new(DefaultSleeper),
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    /// Seeds the random number generator faults are drawn from, making them reproducible.
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            ..self
+        }
+    }
+
+    /// Sets the probability, between 0 and 1, of a request failing with
+    /// [`AgentError::TransportError`] as if its connection was dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_dropped_connections(self, probability: f64) -> Self {
+        check_probability(probability);
+        Self {
+            drop_probability: probability,
+            ..self
+        }
+    }
+
+    /// Sets the probability, between 0 and 1, of a request failing with [`AgentError::HttpError`]
+    /// with a status code chosen uniformly from `codes`, e.g. 429 or 503.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_status_codes(self, probability: f64, codes: impl IntoIterator<Item = u16>) -> Self {
+        check_probability(probability);
+        Self {
+            status_probability: probability,
+            status_codes: codes.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Sets the probability, between 0 and 1, of a request being delayed, by a duration chosen
+    /// uniformly up to `max`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn with_latency(self, probability: f64, max: Duration) -> Self {
+        check_probability(probability);
+        Self {
+            latency_probability: probability,
+            max_latency: max,
+            ..self
+        }
+    }
+
+    /// Sets how injected latency is waited out. Defaults to [`DefaultSleeper`].
+    pub fn with_sleeper(self, sleeper: Arc<dyn Sleeper>) -> Self {
+        Self { sleeper, ..self }
+    }
+
+    fn draw(&self) -> Faults {
+        let mut rng = self.rng.lock().unwrap();
+        let latency = rng
+            .gen_bool(self.latency_probability)
+            .then(|| self.max_latency.mul_f64(rng.gen::<f64>()));
+        let error = if rng.gen_bool(self.drop_probability) {
+            Some(AgentError::TransportError(
+                "connection dropped by fault injection".into(),
+            ))
+        } else if rng.gen_bool(self.status_probability) && !self.status_codes.is_empty() {
+            let status = self.status_codes[rng.gen_range(0..self.status_codes.len())];
+            Some(AgentError::HttpError(HttpErrorPayload {
+                status,
+                reason: None,
+                content_type: None,
+                content: vec![],
+            }))
+        } else {
+            None
+        };
+        Faults { latency, error }
+    }
+
+    fn inject<'a, V: 'a>(
+        &'a self,
+        request: impl FnOnce() -> AgentFuture<'a, V> + Send + 'a,
+    ) -> AgentFuture<'a, V> {
+        let faults = self.draw();
+        Box::pin(async move {
+            if let Some(latency) = faults.latency {
+                self.sleeper.sleep(latency).await;
+            }
+            match faults.error {
+                Some(err) => Err(err),
+                None => request().await,
+            }
+        })
+    }
+}
+
+impl<T: Transport> Transport for FaultInjectionTransport<T> {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inject(move || self.inner.call(effective_canister_id, envelope))
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        self.inject(move || self.inner.read_state(effective_canister_id, envelope))
+    }
+
+    fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.inject(move || self.inner.read_subnet_state(subnet_id, envelope))
+    }
+
+    fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.inject(move || self.inner.query(effective_canister_id, envelope))
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.inject(move || {
+            self.inner
+                .query_with_headers(effective_canister_id, envelope, headers)
+        })
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        self.inject(move || {
+            self.inner.query_with_retry_policy(
+                effective_canister_id,
+                envelope,
+                headers,
+                retry_policy,
+            )
+        })
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inject(move || {
+            self.inner
+                .call_with_headers(effective_canister_id, envelope, headers)
+        })
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.inject(move || {
+            self.inner.call_with_retry_policy(
+                effective_canister_id,
+                envelope,
+                headers,
+                retry_policy,
+            )
+        })
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        self.inject(move || self.inner.status())
+    }
+
+    fn is_mainnet(&self) -> bool {
+        self.inner.is_mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{MockTransport, RecordingSleeper};
+    use futures_util::FutureExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers status requests immediately, counting them.
+    fn counting_transport(requests: Arc<AtomicUsize>) -> Arc<MockTransport> {
+        Arc::new(MockTransport::new().on_status(move || {
+            requests.fetch_add(1, Ordering::SeqCst);
+            async { Ok(vec![]) }
+        }))
+    }
+
+    fn status<T: Transport>(transport: &T) -> Result<Vec<u8>, AgentError> {
+        transport.status().now_or_never().unwrap()
+    }
+
+    #[test]
+    fn injects_configured_failures() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let inner = counting_transport(requests.clone());
+        let transport = FaultInjectionTransport::new(inner.clone()).with_dropped_connections(1.0);
+        assert!(matches!(
+            status(&transport),
+            Err(AgentError::TransportError(_))
+        ));
+
+        let transport = FaultInjectionTransport::new(inner.clone()).with_status_codes(1.0, [503]);
+        match status(&transport) {
+            Err(AgentError::HttpError(payload)) => assert_eq!(payload.status, 503),
+            result => panic!("{result:?}"),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+
+        // nothing is injected by default
+        status(&FaultInjectionTransport::new(inner.clone())).unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn injects_latency() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let inner = counting_transport(requests.clone());
+        let sleeper = Arc::new(RecordingSleeper::default());
+        let transport = FaultInjectionTransport::new(inner.clone())
+            .with_latency(1.0, Duration::from_millis(100))
+            .with_sleeper(sleeper.clone());
+        status(&transport).unwrap();
+        status(&transport).unwrap();
+        let delays = sleeper.delays();
+        assert_eq!(delays.len(), 2);
+        assert!(delays.iter().all(|d| *d <= Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn seeded_faults_are_reproducible() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let inner = counting_transport(requests.clone());
+        let outcomes = || {
+            let transport = FaultInjectionTransport::new(inner.clone())
+                .with_seed(42)
+                .with_dropped_connections(0.5);
+            (0..32)
+                .map(|_| status(&transport).is_ok())
+                .collect::<Vec<_>>()
+        };
+        let first = outcomes();
+        assert!(first.contains(&true) && first.contains(&false));
+        assert_eq!(outcomes(), first);
+    }
+
+    #[test]
+    #[should_panic = "fault probability must be between 0 and 1"]
+    fn rejects_probability_above_one() {
+        FaultInjectionTransport::new(MockTransport::new()).with_dropped_connections(1.5);
+    }
+
+    #[test]
+    #[should_panic = "fault probability must be between 0 and 1"]
+    fn rejects_negative_probability() {
+        FaultInjectionTransport::new(MockTransport::new()).with_status_codes(-0.1, [503]);
+    }
+
+    #[test]
+    #[should_panic = "fault probability must be between 0 and 1"]
+    fn rejects_nan_probability() {
+        FaultInjectionTransport::new(MockTransport::new()).with_latency(f64::NAN, Duration::ZERO);
+    }
+}
@@ -1,5 +1,5 @@
 //! A [`Transport`] that connects using a [`hyper`] client.
-use http::StatusCode;
+use http::{HeaderMap, StatusCode};
 pub use hyper;
 
 use std::sync::Arc;
@@ -11,16 +11,27 @@ use http_body_to_bytes::{http_body_to_bytes, http_body_to_bytes_with_max_length}
 use http_body_util::LengthLimitError;
 use hyper::{header::CONTENT_TYPE, Method, Request, Response};
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::client::legacy::{
+    connect::{Connect, HttpConnector},
+    Client,
+};
 use hyper_util::rt::TokioExecutor;
-use ic_transport_types::{RejectResponse, TransportCallResponse};
+use ic_transport_types::TransportCallResponse;
+use rand::rngs::OsRng;
 use tower::Service;
 
+#[cfg(feature = "otel")]
+use crate::agent::http_transport::otel::RequestSpan;
 use crate::{
     agent::{
         agent_error::HttpErrorPayload,
-        http_transport::route_provider::{RoundRobinRouteProvider, RouteProvider},
-        AgentFuture, Transport,
+        http_transport::{
+            canister_endpoint, reason_phrase,
+            retry::{Jitter, RetryBackoff, RetryBudget, DEFAULT_MAX_BACKOFF},
+            route_provider::{RoundRobinRouteProvider, RouteProvider},
+            ByteCounter, DEFAULT_MAX_REQUEST_SIZE,
+        },
+        AgentFuture, Cbor, CborLimits, DefaultSleeper, RetryPolicy, Sleeper, Transport, WireFormat,
     },
     export::Principal,
     AgentError,
@@ -32,10 +43,19 @@ pub struct HyperTransport<B1, S = Client<HttpsConnector<HttpConnector>, B1>> {
     _marker: PhantomData<AtomicPtr<B1>>,
     route_provider: Arc<dyn RouteProvider>,
     max_response_body_size: Option<usize>,
+    max_request_size: usize,
     #[allow(dead_code)]
     max_tcp_error_retries: usize,
     service: S,
     use_call_v3_endpoint: bool,
+    jitter: Jitter,
+    max_backoff: Duration,
+    max_rate_limit_attempts: Option<usize>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    bytes: ByteCounter,
+    sleeper: Arc<dyn Sleeper>,
+    wire_format: Arc<dyn WireFormat>,
+    cbor_limits: CborLimits,
 }
 
 /// Trait representing the contraints on [`HttpBody`] that [`HyperTransport`] requires
@@ -104,6 +124,24 @@ impl<B1: HyperBody + From<Vec<u8>>> HyperTransport<B1> {
     }
 }
 
+impl<B1, C> HyperTransport<B1, Client<C, B1>>
+where
+    B1: HyperBody + From<Vec<u8>>,
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Creates a replica transport from a HTTP URL, which opens its connections through `connector`
+    /// rather than over TCP, e.g. to an in-process server over an in-memory stream in hermetic tests.
+    ///
+    /// The URL still sets the `Host` header and the paths of requests.
+    pub fn create_with_connector<U: Into<String>>(
+        url: U,
+        connector: C,
+    ) -> Result<Self, AgentError> {
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        Self::create_with_service(url, client)
+    }
+}
+
 impl<B1, S> HyperTransport<B1, S>
 where
     B1: HyperBody + From<Vec<u8>>,
@@ -125,8 +163,17 @@ where
             route_provider,
             service,
             max_response_body_size: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
             max_tcp_error_retries: 0,
             use_call_v3_endpoint: false,
+            jitter: Jitter::default(),
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_rate_limit_attempts: None,
+            retry_budget: None,
+            bytes: ByteCounter::default(),
+            sleeper: Arc::new(DefaultSleeper),
+            wire_format: Arc::new(Cbor),
+            cbor_limits: CborLimits::default(),
         })
     }
 
@@ -138,6 +185,36 @@ where
         }
     }
 
+    /// Sets a max request body size limit. Larger requests fail with [`AgentError::RequestTooLarge`]
+    /// without being sent. Defaults to 2 MiB plus 64 KiB for the envelope, since the IC rejects larger
+    /// ingress messages anyway.
+    pub fn with_max_request_size(self, bytes: usize) -> Self {
+        Self {
+            max_request_size: bytes,
+            ..self
+        }
+    }
+
+    /// Sets the format requests are labelled with in their `Content-Type` header, and call responses
+    /// are decoded from. Defaults to [`Cbor`]. The agent must be configured with the same format, via
+    /// [`AgentBuilder::with_wire_format`](crate::agent::AgentBuilder::with_wire_format).
+    pub fn with_wire_format(self, wire_format: Arc<dyn WireFormat>) -> Self {
+        Self {
+            wire_format,
+            ..self
+        }
+    }
+
+    /// Sets the limits call responses are checked against before they are decoded. Defaults to
+    /// [`CborLimits::default`]; the agent's own limits are set with
+    /// [`AgentBuilder::with_cbor_limits`](crate::agent::AgentBuilder::with_cbor_limits).
+    pub fn with_cbor_limits(self, cbor_limits: CborLimits) -> Self {
+        Self {
+            cbor_limits,
+            ..self
+        }
+    }
+
     /// Sets a max number of retries for tcp connection errors.
     pub fn with_max_tcp_errors_retries(self, retries: usize) -> Self {
         HyperTransport {
@@ -146,6 +223,73 @@ where
         }
     }
 
+    /// Sets the jitter applied to the backoff between retries of rate-limited requests.
+    /// Defaults to [`Jitter::Full`].
+    pub fn with_jitter(self, jitter: Jitter) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// Sets the ceiling for any single delay between retries of rate-limited requests. Defaults to 5 seconds.
+    pub fn with_max_backoff(self, max_backoff: Duration) -> Self {
+        Self {
+            max_backoff,
+            ..self
+        }
+    }
+
+    /// Sets the max number of attempts for a rate-limited request, including the first one.
+    /// Once exhausted, the rate-limit error is returned immediately, without a final backoff.
+    /// By default, rate-limited requests are retried indefinitely.
+    pub fn with_max_rate_limit_attempts(self, attempts: usize) -> Self {
+        Self {
+            max_rate_limit_attempts: Some(attempts),
+            ..self
+        }
+    }
+
+    /// Sets a budget that the retries of all requests, of rate-limited requests and of failed
+    /// connections alike, take from. Once it is spent, requests fail on their first error until it
+    /// refills. By default, retries are limited only per request.
+    pub fn with_retry_budget(self, retry_budget: Arc<RetryBudget>) -> Self {
+        Self {
+            retry_budget: Some(retry_budget),
+            ..self
+        }
+    }
+
+    /// Returns the budget set with [`with_retry_budget`](Self::with_retry_budget), if any.
+    pub fn retry_budget(&self) -> Option<&Arc<RetryBudget>> {
+        self.retry_budget.as_ref()
+    }
+
+    /// Caps the bytes of request and response bodies the transport sends and receives, in total.
+    /// A request, or a retry of it, whose body would take the total over `bytes` fails with
+    /// [`AgentError::ByteBudgetExceeded`] without being sent. Since the size of a response is only
+    /// known once it arrives, the response that crosses the cap is still returned, and requests
+    /// fail from then on. By default, there is no cap.
+    pub fn with_byte_budget(self, bytes: u64) -> Self {
+        Self {
+            bytes: ByteCounter::with_budget(bytes),
+            ..self
+        }
+    }
+
+    /// Returns the total bytes of request bodies the transport has sent, retries included.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes.sent()
+    }
+
+    /// Returns the total bytes of response bodies the transport has received.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes.received()
+    }
+
+    /// Sets how the transport waits between retries of rate-limited requests. Defaults to
+    /// [`DefaultSleeper`], which needs a tokio runtime, or the `wasm-bindgen` feature on `wasm32`.
+    pub fn with_sleeper(self, sleeper: Arc<dyn Sleeper>) -> Self {
+        Self { sleeper, ..self }
+    }
+
     /// Use call v3 endpoint for synchronous update calls.
     /// __This is an experimental feature, and should not be used in production,
     /// as the endpoint is not available yet on the mainnet IC.__
@@ -166,6 +310,47 @@ where
         method: Method,
         endpoint: &str,
         body: Option<Vec<u8>>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> Result<(StatusCode, Vec<u8>), AgentError> {
+        if let Some(size) = body.as_ref().map(Vec::len) {
+            if size > self.max_request_size {
+                return Err(AgentError::RequestTooLarge {
+                    size,
+                    limit: self.max_request_size,
+                });
+            }
+        }
+        #[cfg(feature = "otel")]
+        {
+            let (span, headers) = RequestSpan::start(&method, endpoint, headers);
+            let mut retries = 0;
+            let result = self
+                .request_rate_limited(method, endpoint, body, headers, retry_policy, &mut retries)
+                .await;
+            span.end(&result, retries);
+            result
+        }
+        #[cfg(not(feature = "otel"))]
+        self.request_rate_limited(method, endpoint, body, headers, retry_policy, &mut 0)
+            .await
+    }
+
+    /// Takes a token from the retry budget for a retry, if there is a budget.
+    fn spend_retry(&self) -> bool {
+        self.retry_budget
+            .as_ref()
+            .map_or(true, |budget| budget.try_spend())
+    }
+
+    async fn request_rate_limited(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<Vec<u8>>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        retries: &mut usize,
     ) -> Result<(StatusCode, Vec<u8>), AgentError> {
         let body = body.unwrap_or_default();
         fn map_error<E: Error + Send + Sync + 'static>(err: E) -> AgentError {
@@ -190,20 +375,28 @@ where
         let create_request_with_generated_url = || -> Result<Request<_>, AgentError> {
             let url = self.route_provider.route()?.join(endpoint)?;
             println!("{url}");
-            let http_request = Request::builder()
+            let mut http_request = Request::builder()
                 .method(&method)
                 .uri(url.as_str())
-                .header(CONTENT_TYPE, "application/cbor")
+                .header(CONTENT_TYPE, self.wire_format.content_type())
                 .body(body.clone().into())
                 .map_err(|err| AgentError::TransportError(Box::new(err)))?;
+            http_request.headers_mut().extend(headers.clone());
             Ok(http_request)
         };
 
+        let mut backoff = RetryBackoff::new(
+            self.jitter,
+            self.max_backoff,
+            retry_policy.max_rate_limit_attempts(self.max_rate_limit_attempts),
+        );
+        let max_tcp_error_retries = retry_policy.max_tcp_error_retries(self.max_tcp_error_retries);
         let response = loop {
             let response = {
                 #[cfg(target_family = "wasm")]
                 {
                     let http_request = create_request_with_generated_url()?;
+                    self.bytes.reserve_sent(body.len())?;
                     match self.client.execute(http_request).await {
                         Ok(response) => response,
                         Err(err) => return Err(AgentError::TransportError(Box::new(err))),
@@ -217,6 +410,7 @@ where
                     let mut retry_count = 0;
                     loop {
                         let http_request = create_request_with_generated_url()?;
+                        self.bytes.reserve_sent(body.len())?;
 
                         match self.service.clone().call(http_request).await {
                             Ok(response) => break response,
@@ -225,10 +419,13 @@ where
                                     .downcast_ref::<hyper_util::client::legacy::Error>()
                                     .is_some_and(|e| e.is_connect())
                                 {
-                                    if retry_count >= self.max_tcp_error_retries {
+                                    // nothing was sent
+                                    self.bytes.release_sent(body.len());
+                                    if retry_count >= max_tcp_error_retries || !self.spend_retry() {
                                         return Err(map_error(err));
                                     }
                                     retry_count += 1;
+                                    *retries += 1;
                                     continue;
                                 }
                                 return Err(map_error(err));
@@ -241,7 +438,12 @@ where
             if response.status() != StatusCode::TOO_MANY_REQUESTS {
                 break response;
             }
-            crate::util::sleep(Duration::from_millis(250)).await;
+            match backoff.next_delay(&mut OsRng) {
+                Some(_) if !self.spend_retry() => break response,
+                Some(delay) => self.sleeper.sleep(delay).await,
+                None => break response,
+            }
+            *retries += 1;
         };
         let (parts, body) = response.into_parts();
         let body = if let Some(limit) = self.max_response_body_size {
@@ -260,10 +462,13 @@ where
                 .map_err(|err| AgentError::TransportError(err.into()))?
         };
 
+        self.bytes.add_received(body.len());
+        let reason = reason_phrase(parts.status, &parts.extensions);
         let (status, headers, body) = (parts.status, parts.headers, body.to_vec());
         if status.is_client_error() || status.is_server_error() {
             Err(AgentError::HttpError(HttpErrorPayload {
                 status: status.into(),
+                reason,
                 content_type: headers
                     .get(CONTENT_TYPE)
                     .and_then(|value| value.to_str().ok())
@@ -285,6 +490,30 @@ where
         &self,
         effective_canister_id: Principal,
         envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.call_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.call_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
     ) -> AgentFuture<TransportCallResponse> {
         Box::pin(async move {
             let api_version = if self.use_call_v3_endpoint {
@@ -293,13 +522,15 @@ where
                 "v2"
             };
 
-            let endpoint = format!(
-                "api/{}/canister/{}/call",
-                &api_version,
-                effective_canister_id.to_text()
-            );
+            let endpoint = canister_endpoint(api_version, effective_canister_id, "call")?;
             let (status_code, response_body) = self
-                .request(Method::POST, &endpoint, Some(envelope))
+                .request(
+                    Method::POST,
+                    &endpoint,
+                    Some(envelope),
+                    headers,
+                    retry_policy,
+                )
                 .await?;
 
             if status_code == StatusCode::ACCEPTED {
@@ -308,10 +539,10 @@ where
 
             // status_code == OK (200)
             if self.use_call_v3_endpoint {
-                serde_cbor::from_slice(&response_body).map_err(AgentError::InvalidCborData)
+                self.wire_format
+                    .decode_call_response(&response_body, &self.cbor_limits)
             } else {
-                let reject_response = serde_cbor::from_slice::<RejectResponse>(&response_body)
-                    .map_err(AgentError::InvalidCborData)?;
+                let reject_response = self.wire_format.decode_reject_response(&response_body)?;
 
                 Err(AgentError::UncertifiedReject(reject_response))
             }
@@ -324,50 +555,113 @@ where
         envelope: Vec<u8>,
     ) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
-            let endpoint = format!("api/v2/canister/{effective_canister_id}/read_state",);
-            self.request(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|(_, body)| body)
+            let endpoint = canister_endpoint("v2", effective_canister_id, "read_state")?;
+            self.request(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|(_, body)| body)
         })
     }
 
     fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
             let endpoint = format!("api/v2/subnet/{subnet_id}/read_state",);
-            self.request(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|(_, body)| body)
+            self.request(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|(_, body)| body)
         })
     }
 
     fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.query_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.query_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
-            let endpoint = format!("api/v2/canister/{effective_canister_id}/query",);
-            self.request(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|(_, body)| body)
+            let endpoint = canister_endpoint("v2", effective_canister_id, "query")?;
+            self.request(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                headers,
+                retry_policy,
+            )
+            .await
+            .map(|(_, body)| body)
         })
     }
 
     fn status(&self) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
             let endpoint = "api/v2/status";
-            self.request(Method::GET, endpoint, None)
-                .await
-                .map(|(_, body)| body)
+            self.request(
+                Method::GET,
+                endpoint,
+                None,
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|(_, body)| body)
         })
     }
+
+    fn is_mainnet(&self) -> bool {
+        self.route_provider.is_mainnet()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::HyperTransport;
+    use crate::{agent::Transport, AgentError};
+    use futures_util::future;
+    use http::Uri;
     use http_body_util::Full;
     use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::client::legacy::connect::{Connected, Connection, HttpConnector};
     use hyper_util::client::legacy::Client;
-    use hyper_util::rt::TokioExecutor;
-    use std::collections::VecDeque;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::{
+        collections::VecDeque,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{
+        duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+    };
+    use tower::service_fn;
     use url::Url;
 
     #[test]
@@ -408,4 +702,171 @@ mod test {
         test("https://fooic0.app", "https://fooic0.app/");
         test("https://fooic0.app.ic0.app", "https://ic0.app/");
     }
+
+    /// One end of an in-memory stream, as a connection to the replica.
+    struct MemoryStream(DuplexStream);
+
+    impl Connection for MemoryStream {
+        fn connected(&self) -> Connected {
+            Connected::new()
+        }
+    }
+
+    impl AsyncRead for MemoryStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MemoryStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_through_custom_connector() {
+        let status = b"canned status";
+        let connector = service_fn(move |uri: Uri| {
+            assert_eq!(uri.host(), Some("replica.test"));
+            let (client, mut server) = duplex(4096);
+            // answers the single request on the connection with a canned status
+            tokio::spawn(async move {
+                let mut request = vec![0; 4096];
+                let read = server.read(&mut request).await.unwrap();
+                assert!(request[..read].starts_with(b"GET /api/v2/status HTTP/1.1\r\n"));
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/cbor\r\ncontent-length: {}\r\n\r\n",
+                    status.len()
+                );
+                server.write_all(response.as_bytes()).await.unwrap();
+                server.write_all(status).await.unwrap();
+            });
+            future::ready(Ok::<_, std::io::Error>(TokioIo::new(MemoryStream(client))))
+        });
+
+        let transport: HyperTransport<Full<VecDeque<u8>>, _> =
+            HyperTransport::create_with_connector("http://replica.test", connector).unwrap();
+        assert_eq!(transport.status().await.unwrap(), status);
+    }
+
+    #[tokio::test]
+    async fn keeps_custom_reason_phrase() {
+        let connector = service_fn(move |_: Uri| {
+            let (client, mut server) = duplex(4096);
+            tokio::spawn(async move {
+                // the status request has no body, so it ends with its headers
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let read = server.read(&mut buf).await.unwrap();
+                    assert_ne!(read, 0, "connection closed before the request ended");
+                    request.extend_from_slice(&buf[..read]);
+                }
+                server
+                    .write_all(b"HTTP/1.1 503 Down For Maintenance\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+            });
+            future::ready(Ok::<_, std::io::Error>(TokioIo::new(MemoryStream(client))))
+        });
+
+        let transport: HyperTransport<Full<VecDeque<u8>>, _> =
+            HyperTransport::create_with_connector("http://replica.test", connector).unwrap();
+        let err = transport.status().await.unwrap_err();
+        let AgentError::HttpError(payload) = &err else {
+            panic!("expected an HTTP error, got {err:?}");
+        };
+        assert_eq!(payload.reason.as_deref(), Some("Down For Maintenance"));
+        assert!(err.to_string().contains("status 503 Down For Maintenance"));
+    }
+
+    #[tokio::test]
+    async fn byte_budget_caps_requests() {
+        use crate::export::Principal;
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        /// Answers each request on the connection with `hello`, counting the requests.
+        async fn answer_hello(mut server: DuplexStream, requests: Arc<AtomicUsize>) {
+            let mut received = vec![];
+            loop {
+                let complete = received
+                    .windows(4)
+                    .position(|window| window == b"\r\n\r\n")
+                    .and_then(|end| {
+                        let head = String::from_utf8_lossy(&received[..end]).to_lowercase();
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map_or(0, |length| length.trim().parse().unwrap());
+                        let len = end + 4 + length;
+                        (received.len() >= len).then_some(len)
+                    });
+                let Some(len) = complete else {
+                    let mut buf = [0; 1024];
+                    match server.read(&mut buf).await.unwrap() {
+                        0 => return,
+                        read => received.extend_from_slice(&buf[..read]),
+                    }
+                    continue;
+                };
+                received.drain(..len);
+                requests.fetch_add(1, Ordering::SeqCst);
+                server
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello")
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let connector = {
+            let requests = requests.clone();
+            service_fn(move |_: Uri| {
+                let (client, server) = duplex(4096);
+                tokio::spawn(answer_hello(server, requests.clone()));
+                future::ready(Ok::<_, std::io::Error>(TokioIo::new(MemoryStream(client))))
+            })
+        };
+        let transport: HyperTransport<Full<VecDeque<u8>>, _> =
+            HyperTransport::create_with_connector("http://replica.test", connector)
+                .unwrap()
+                .with_byte_budget(250);
+
+        // the third request would take the total over the budget, so it is not sent
+        let canister_id = Principal::management_canister();
+        for _ in 0..2 {
+            transport.query(canister_id, vec![0; 100]).await.unwrap();
+        }
+        assert!(matches!(
+            transport.query(canister_id, vec![0; 100]).await,
+            Err(AgentError::ByteBudgetExceeded { budget: 250 })
+        ));
+        assert_eq!(transport.bytes_sent(), 200);
+        assert_eq!(transport.bytes_received(), 10);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // a smaller request still fits
+        transport.query(canister_id, vec![0; 40]).await.unwrap();
+        assert_eq!(transport.bytes_sent(), 240);
+    }
 }
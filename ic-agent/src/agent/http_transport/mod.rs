@@ -1,5 +1,10 @@
 //! [`Transport`](super::Transport) implementations.
 
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::{export::Principal, AgentError};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
 #[cfg(feature = "reqwest")]
 pub mod reqwest_transport;
 
@@ -14,6 +19,164 @@ pub mod hyper_transport;
 #[doc(inline)]
 pub use hyper_transport::HyperTransport;
 
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub mod retry;
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[doc(inline)]
+pub use retry::{Jitter, RetryBudget};
+
+/// A kind of endpoint of the HTTP interface, for restricting which ones a transport may use; see
+/// e.g. [`ReqwestTransport::with_allowed_endpoints`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// Submitting update calls.
+    Call,
+    /// Making query calls.
+    Query,
+    /// Reading the state tree of a canister or subnet, including the status of calls.
+    ReadState,
+    /// Reading the status of the replica.
+    Status,
+}
+
+impl Endpoint {
+    /// Returns the kind of the endpoint at `path`, which is named by its last segment.
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn of(path: &str) -> Option<Self> {
+        match path.rsplit('/').next()? {
+            "call" => Some(Self::Call),
+            "query" => Some(Self::Query),
+            "read_state" => Some(Self::ReadState),
+            "status" => Some(Self::Status),
+            _ => None,
+        }
+    }
+}
+
+/// The default limit on the size of request bodies: the IC accepts ingress messages of up to 2 MiB,
+/// and the envelope's signature and delegations are allowed a further 64 KiB.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub(crate) const DEFAULT_MAX_REQUEST_SIZE: usize = 2 * 1024 * 1024 + 64 * 1024;
+
+/// The bytes of request and response bodies a transport has sent and received, and the cap on
+/// their sum, if any.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter {
+    sent: AtomicU64,
+    received: AtomicU64,
+    budget: Option<u64>,
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+impl ByteCounter {
+    pub(crate) fn with_budget(budget: u64) -> Self {
+        Self {
+            budget: Some(budget),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request body of `bytes` as sent before it is sent, so that concurrent requests
+    /// cannot together exceed the budget. Fails with [`AgentError::ByteBudgetExceeded`], without
+    /// counting them, if the bytes sent and received have reached the budget or would exceed it.
+    pub(crate) fn reserve_sent(&self, bytes: usize) -> Result<(), AgentError> {
+        let bytes = bytes as u64;
+        let sent = self.sent.fetch_add(bytes, Ordering::Relaxed);
+        match self.budget {
+            Some(budget)
+                if sent + self.received() >= budget || sent + bytes + self.received() > budget =>
+            {
+                self.release_sent(bytes as usize);
+                Err(AgentError::ByteBudgetExceeded { budget })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Uncounts the bytes reserved for a request body that was not sent after all.
+    pub(crate) fn release_sent(&self, bytes: usize) {
+        self.sent.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Returns the path of the `method` endpoint of `effective_canister_id`, e.g.
+/// `api/v2/canister/<id>/query`.
+///
+/// Canister ids are opaque principals, and the management canister is addressed by the empty one;
+/// any other principal is rejected here rather than by the replica after a round-trip.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub(crate) fn canister_endpoint(
+    api_version: &str,
+    effective_canister_id: Principal,
+    method: &str,
+) -> Result<String, AgentError> {
+    const OPAQUE_ID_TAG: u8 = 0x01;
+    match effective_canister_id.as_slice().last() {
+        None | Some(&OPAQUE_ID_TAG) => Ok(format!(
+            "api/{api_version}/canister/{effective_canister_id}/{method}"
+        )),
+        Some(_) => Err(AgentError::InvalidCanisterId(effective_canister_id)),
+    }
+}
+
+/// The reason phrase of a response with `status`: the one the replica sent, if hyper kept it for
+/// differing from the canonical one, or else the canonical one.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub(crate) fn reason_phrase(
+    status: http::StatusCode,
+    extensions: &http::Extensions,
+) -> Option<String> {
+    #[cfg(not(target_family = "wasm"))]
+    if let Some(reason) = extensions.get::<hyper::ext::ReasonPhrase>() {
+        return Some(String::from_utf8_lossy(reason.as_bytes()).into_owned());
+    }
+    #[cfg(target_family = "wasm")]
+    let _ = extensions;
+    status.canonical_reason().map(str::to_string)
+}
+
+pub mod circuit_breaker;
+
+#[doc(inline)]
+pub use circuit_breaker::CircuitBreakerTransport;
+
+pub mod dedup;
+
+#[doc(inline)]
+pub use dedup::DeduplicatingTransport;
+
+pub mod fault_injection;
+
+#[doc(inline)]
+pub use fault_injection::FaultInjectionTransport;
+
+pub mod quorum;
+
+#[doc(inline)]
+pub use quorum::QuorumTransport;
+
+pub mod sharding;
+
+#[doc(inline)]
+pub use sharding::ShardingTransport;
+
+#[cfg(all(feature = "otel", any(feature = "reqwest", feature = "hyper")))]
+pub(crate) mod otel;
+
 #[allow(dead_code)]
 const IC0_DOMAIN: &str = "ic0.app";
 #[allow(dead_code)]
@@ -0,0 +1,253 @@
+//! OpenTelemetry instrumentation of the requests made by the HTTP transports.
+//!
+//! Every request to the replica becomes a client span from the global tracer provider, and the
+//! span's context is injected into the request headers by the global text map propagator. Both are
+//! no-ops until the application installs them, e.g. with
+//! [`set_tracer_provider`](opentelemetry::global::set_tracer_provider) and
+//! [`set_text_map_propagator`](opentelemetry::global::set_text_map_propagator).
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use opentelemetry::{
+    global,
+    propagation::{Injector, TextMapPropagator},
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::AgentError;
+
+/// The name of the tracer that request spans are recorded with.
+const TRACER_NAME: &str = "ic-agent";
+
+/// The span covering one request to the replica, including any retries of it after it was rate
+/// limited or failed to connect.
+pub(crate) struct RequestSpan {
+    cx: Context,
+}
+
+impl RequestSpan {
+    /// Starts a span for a request to `endpoint`, and returns it along with `headers` extended
+    /// with the propagated trace context.
+    pub(crate) fn start(method: &Method, endpoint: &str, headers: HeaderMap) -> (Self, HeaderMap) {
+        let span = Self::start_with(&global::tracer(TRACER_NAME), method, endpoint);
+        let mut headers = headers;
+        global::get_text_map_propagator(|propagator| span.inject(propagator, &mut headers));
+        (span, headers)
+    }
+
+    /// Starts a span for a request to `endpoint` with `tracer`.
+    fn start_with<T>(tracer: &T, method: &Method, endpoint: &str) -> Self
+    where
+        T: Tracer,
+        T::Span: Send + Sync + 'static,
+    {
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", method.to_string()),
+            KeyValue::new("ic.endpoint", endpoint.to_string()),
+        ];
+        let segments: Vec<_> = endpoint.split('/').collect();
+        // api/<version>/<canister|subnet>/<id>/<operation>
+        if let ["api", _, kind @ ("canister" | "subnet"), id, _] = segments[..] {
+            attributes.push(KeyValue::new(format!("ic.{kind}_id"), id.to_string()));
+        }
+        let span = tracer
+            .span_builder(format!("{method} {}", segments[segments.len() - 1]))
+            .with_kind(SpanKind::Client)
+            .with_attributes(attributes)
+            .start(tracer);
+        Self {
+            cx: Context::current_with_span(span),
+        }
+    }
+
+    /// Adds the span's context to `headers` with `propagator`.
+    fn inject(&self, propagator: &dyn TextMapPropagator, headers: &mut HeaderMap) {
+        propagator.inject_context(&self.cx, &mut HeaderInjector(headers));
+    }
+
+    /// Records the outcome of the request and ends the span. `retries` counts the retries of the
+    /// request, both after rate limiting and after failing to connect, as `ic.retry_count`.
+    pub(crate) fn end(self, result: &Result<(StatusCode, Vec<u8>), AgentError>, retries: usize) {
+        let span = self.cx.span();
+        span.set_attribute(KeyValue::new("ic.retry_count", retries as i64));
+        let status = match result {
+            Ok((status, _)) => Some(status.as_u16()),
+            Err(AgentError::HttpError(payload)) => Some(payload.status),
+            Err(_) => None,
+        };
+        if let Some(status) = status {
+            span.set_attribute(KeyValue::new("http.response.status_code", status as i64));
+        }
+        if let Err(err) = result {
+            span.set_status(Status::error(err.to_string()));
+        }
+        span.end();
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+
+    use opentelemetry::{
+        propagation::{text_map_propagator::FieldIter, Extractor},
+        trace::{Span, SpanBuilder, SpanContext, SpanId, TraceFlags, TraceId, TraceState},
+        Value,
+    };
+
+    use super::*;
+
+    /// What was recorded of a span.
+    #[derive(Debug, Default)]
+    struct Recorded {
+        name: String,
+        attributes: Vec<KeyValue>,
+        status: Status,
+        ended: bool,
+    }
+
+    /// A tracer recording the spans it starts, so that no tracer provider has to be installed.
+    #[derive(Debug, Default)]
+    struct RecordingTracer(Arc<Mutex<Vec<Recorded>>>);
+
+    struct RecordingSpan {
+        context: SpanContext,
+        spans: Arc<Mutex<Vec<Recorded>>>,
+        index: usize,
+    }
+
+    impl RecordingSpan {
+        fn record(&self, f: impl FnOnce(&mut Recorded)) {
+            f(&mut self.spans.lock().unwrap()[self.index]);
+        }
+    }
+
+    impl Span for RecordingSpan {
+        fn add_event_with_timestamp<T>(&mut self, _: T, _: SystemTime, _: Vec<KeyValue>)
+        where
+            T: Into<Cow<'static, str>>,
+        {
+        }
+        fn span_context(&self) -> &SpanContext {
+            &self.context
+        }
+        fn is_recording(&self) -> bool {
+            true
+        }
+        fn set_attribute(&mut self, attribute: KeyValue) {
+            self.record(|span| span.attributes.push(attribute));
+        }
+        fn set_status(&mut self, status: Status) {
+            self.record(|span| span.status = status);
+        }
+        fn update_name<T>(&mut self, new_name: T)
+        where
+            T: Into<Cow<'static, str>>,
+        {
+            self.record(|span| span.name = new_name.into().into_owned());
+        }
+        fn add_link(&mut self, _: SpanContext, _: Vec<KeyValue>) {}
+        fn end_with_timestamp(&mut self, _: SystemTime) {
+            self.record(|span| span.ended = true);
+        }
+    }
+
+    impl Tracer for RecordingTracer {
+        type Span = RecordingSpan;
+
+        fn build_with_context(&self, builder: SpanBuilder, _: &Context) -> RecordingSpan {
+            let mut spans = self.0.lock().unwrap();
+            spans.push(Recorded {
+                name: builder.name.into_owned(),
+                attributes: builder.attributes.unwrap_or_default(),
+                ..Recorded::default()
+            });
+            RecordingSpan {
+                context: SpanContext::new(
+                    TraceId::from(1),
+                    SpanId::from(spans.len() as u64),
+                    TraceFlags::SAMPLED,
+                    false,
+                    TraceState::NONE,
+                ),
+                spans: self.0.clone(),
+                index: spans.len() - 1,
+            }
+        }
+    }
+
+    /// Propagates the id of the current span in the `x-span-id` header.
+    #[derive(Debug)]
+    struct SpanIdPropagator;
+
+    impl TextMapPropagator for SpanIdPropagator {
+        fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+            injector.set("x-span-id", cx.span().span_context().span_id().to_string());
+        }
+        fn extract_with_context(&self, cx: &Context, _: &dyn Extractor) -> Context {
+            cx.clone()
+        }
+        fn fields(&self) -> FieldIter<'_> {
+            FieldIter::new(&[])
+        }
+    }
+
+    #[test]
+    fn records_request_span() {
+        let tracer = RecordingTracer::default();
+        let start = |endpoint| RequestSpan::start_with(&tracer, &Method::POST, endpoint);
+
+        let span = start("api/v2/canister/ryjl3-tyaaa-aaaaa-aaaba-cai/query");
+        let mut headers = HeaderMap::new();
+        span.inject(&SpanIdPropagator, &mut headers);
+        assert_eq!(headers["x-span-id"], SpanId::from(1).to_string());
+        span.end(&Ok((StatusCode::OK, vec![])), 0);
+        let span = start("api/v2/status");
+        span.end(&Err(AgentError::CircuitOpen), 2);
+
+        let spans = tracer.0.lock().unwrap();
+        let attribute = |span: &Recorded, key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        let [query, status] = &spans[..] else {
+            panic!("{spans:?}");
+        };
+        assert_eq!(query.name, "POST query");
+        assert_eq!(
+            attribute(query, "ic.canister_id"),
+            Some(Value::from("ryjl3-tyaaa-aaaaa-aaaba-cai"))
+        );
+        assert_eq!(
+            attribute(query, "http.response.status_code"),
+            Some(Value::I64(200))
+        );
+        assert_eq!(attribute(query, "ic.retry_count"), Some(Value::I64(0)));
+        assert_eq!(query.status, Status::Unset);
+        assert!(query.ended);
+
+        assert_eq!(status.name, "POST status");
+        assert_eq!(attribute(status, "http.response.status_code"), None);
+        assert_eq!(attribute(status, "ic.retry_count"), Some(Value::I64(2)));
+        assert!(matches!(status.status, Status::Error { .. }));
+        assert!(status.ended);
+    }
+}
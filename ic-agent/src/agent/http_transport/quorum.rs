@@ -0,0 +1,296 @@
+//! A [`Transport`] decorator that only accepts query responses a quorum of replicas agree on.
+use std::collections::HashMap;
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use http::HeaderMap;
+use ic_transport_types::{QueryResponse, TransportCallResponse};
+
+use crate::{
+    agent::{AgentFuture, RetryPolicy, Transport},
+    export::Principal,
+    AgentError,
+};
+
+/// Wraps several [`Transport`]s, each normally pointing at a different replica, and sends every
+/// query to all of them, accepting a response only once a quorum of replicas has returned it.
+///
+/// Responses are compared byte-for-byte, except for node signatures: each node signs the response
+/// separately, so the signatures of identical responses differ. The response returned is that of one
+/// of the agreeing replicas, signatures included. If no response reaches the quorum, the query fails
+/// with [`AgentError::QuorumNotReached`], or with the first error if no replica responded at all.
+///
+/// Calls, `read_state` requests, and status requests are certified or not worth cross-checking, and
+/// are sent to the first transport only.
+#[derive(Debug)]
+pub struct QuorumTransport<T> {
+    transports: Vec<T>,
+    quorum: usize,
+}
+
+impl<T: Transport> QuorumTransport<T> {
+    /// Wraps `transports`, requiring a majority of them to agree on each query response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `transports` is empty.
+    pub fn new(transports: Vec<T>) -> Self {
+        assert!(
+            !transports.is_empty(),
+            "QuorumTransport needs at least one transport"
+        );
+        let quorum = transports.len() / 2 + 1;
+        Self { transports, quorum }
+    }
+
+    /// Sets how many transports must return the same query response for it to be accepted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quorum` is 0 or greater than the number of transports, which would accept any
+    /// response or none.
+    pub fn with_quorum(self, quorum: usize) -> Self {
+        assert!(
+            (1..=self.transports.len()).contains(&quorum),
+            "quorum must be between 1 and {}, not {quorum}",
+            self.transports.len()
+        );
+        Self { quorum, ..self }
+    }
+
+    fn first(&self) -> &T {
+        &self.transports[0]
+    }
+
+    fn fan_out<'a>(
+        &'a self,
+        request: impl Fn(&'a T) -> AgentFuture<'a, Vec<u8>>,
+    ) -> AgentFuture<'a, Vec<u8>> {
+        let mut pending: FuturesUnordered<_> = self.transports.iter().map(request).collect();
+        Box::pin(async move {
+            // responses by their unsigned form, with how many replicas returned each
+            let mut responses = HashMap::<Vec<u8>, (Vec<u8>, usize)>::new();
+            let mut first_error = None;
+            while let Some(result) = pending.next().await {
+                match result {
+                    Ok(response) => {
+                        let (response, agreements) = responses
+                            .entry(unsigned(&response))
+                            .or_insert_with(|| (response, 0));
+                        *agreements += 1;
+                        if *agreements >= self.quorum {
+                            return Ok(response.clone());
+                        }
+                    }
+                    Err(err) => {
+                        first_error.get_or_insert(err);
+                    }
+                }
+            }
+            match (responses.values().map(|(_, n)| *n).max(), first_error) {
+                (None, Some(err)) => Err(err),
+                (agreements, _) => Err(AgentError::QuorumNotReached {
+                    agreements: agreements.unwrap_or(0),
+                    quorum: self.quorum,
+                }),
+            }
+        })
+    }
+}
+
+/// Returns `response` with any node signatures removed, for comparison.
+fn unsigned(response: &[u8]) -> Vec<u8> {
+    match serde_cbor::from_slice(response) {
+        Ok(mut parsed) => {
+            let (QueryResponse::Replied { signatures, .. }
+            | QueryResponse::Rejected { signatures, .. }) = &mut parsed;
+            signatures.clear();
+            serde_cbor::to_vec(&parsed).unwrap_or_else(|_| response.to_vec())
+        }
+        // not a query response; it will fail to decode later regardless
+        Err(_) => response.to_vec(),
+    }
+}
+
+impl<T: Transport> Transport for QuorumTransport<T> {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.first().call(effective_canister_id, envelope)
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        self.first().read_state(effective_canister_id, envelope)
+    }
+
+    fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.first().read_subnet_state(subnet_id, envelope)
+    }
+
+    fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.fan_out(move |transport| transport.query(effective_canister_id, envelope.clone()))
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.fan_out(move |transport| {
+            transport.query_with_headers(effective_canister_id, envelope.clone(), headers.clone())
+        })
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        self.fan_out(move |transport| {
+            transport.query_with_retry_policy(
+                effective_canister_id,
+                envelope.clone(),
+                headers.clone(),
+                retry_policy,
+            )
+        })
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.first()
+            .call_with_headers(effective_canister_id, envelope, headers)
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.first()
+            .call_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        self.first().status()
+    }
+
+    fn is_mainnet(&self) -> bool {
+        self.transports.iter().any(Transport::is_mainnet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockTransport;
+    use futures_util::FutureExt;
+    use ic_transport_types::{NodeSignature, ReplyResponse};
+
+    /// Answers every query with the same response.
+    fn fixed(response: Result<Vec<u8>, ()>) -> MockTransport {
+        MockTransport::new().on_query(move |_| {
+            let result = response
+                .clone()
+                .map_err(|()| AgentError::TransportError("unreachable".into()));
+            async move { result }
+        })
+    }
+
+    /// A reply of `arg`, signed by node `node`.
+    fn reply(arg: &[u8], node: u8) -> Vec<u8> {
+        serde_cbor::to_vec(&QueryResponse::Replied {
+            reply: ReplyResponse { arg: arg.to_vec() },
+            signatures: vec![NodeSignature {
+                timestamp: node as u64,
+                signature: vec![node; 64],
+                identity: Principal::from_slice(&[node]),
+            }],
+        })
+        .unwrap()
+    }
+
+    fn query<T: Transport>(transport: &T) -> Result<Vec<u8>, AgentError> {
+        transport
+            .query(Principal::anonymous(), vec![])
+            .now_or_never()
+            .unwrap()
+    }
+
+    #[test]
+    fn accepts_majority_response() {
+        let transport = QuorumTransport::new(vec![
+            fixed(Ok(reply(b"odd", 0))),
+            fixed(Ok(reply(b"agreed", 1))),
+            fixed(Ok(reply(b"agreed", 2))),
+        ]);
+        let response = query(&transport).unwrap();
+        assert!(response == reply(b"agreed", 1) || response == reply(b"agreed", 2));
+    }
+
+    #[test]
+    fn rejects_response_short_of_quorum() {
+        let transport = QuorumTransport::new(vec![
+            fixed(Ok(reply(b"odd", 0))),
+            fixed(Ok(reply(b"agreed", 1))),
+            fixed(Ok(reply(b"agreed", 2))),
+        ])
+        .with_quorum(3);
+        assert!(matches!(
+            query(&transport),
+            Err(AgentError::QuorumNotReached {
+                agreements: 2,
+                quorum: 3
+            })
+        ));
+
+        // failed replicas do not count towards the quorum
+        let transport = QuorumTransport::new(vec![
+            fixed(Err(())),
+            fixed(Err(())),
+            fixed(Ok(reply(b"agreed", 2))),
+        ]);
+        assert!(matches!(
+            query(&transport),
+            Err(AgentError::QuorumNotReached {
+                agreements: 1,
+                quorum: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn reports_error_when_no_replica_responds() {
+        let transport = QuorumTransport::new(vec![fixed(Err(())), fixed(Err(()))]);
+        assert!(matches!(
+            query(&transport),
+            Err(AgentError::TransportError(_))
+        ));
+    }
+
+    #[test]
+    #[should_panic = "quorum must be between 1 and 3, not 0"]
+    fn rejects_zero_quorum() {
+        QuorumTransport::new(vec![fixed(Err(())), fixed(Err(())), fixed(Err(()))]).with_quorum(0);
+    }
+
+    #[test]
+    #[should_panic = "quorum must be between 1 and 3, not 4"]
+    fn rejects_quorum_above_transport_count() {
+        QuorumTransport::new(vec![fixed(Err(())), fixed(Err(())), fixed(Err(()))]).with_quorum(4);
+    }
+}
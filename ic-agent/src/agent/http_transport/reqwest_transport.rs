@@ -1,21 +1,35 @@
 //! A [`Transport`] that connects using a [`reqwest`] client.
 #![cfg(feature = "reqwest")]
 
-use ic_transport_types::{RejectResponse, TransportCallResponse};
+use ic_transport_types::TransportCallResponse;
 pub use reqwest;
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use time::OffsetDateTime;
 
 use futures_util::StreamExt;
+use rand::rngs::OsRng;
 use reqwest::{
-    header::{HeaderMap, CONTENT_TYPE},
-    Body, Client, Method, Request, StatusCode,
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, DATE},
+    Body, Client, Method, Request, StatusCode, Url,
 };
 
+#[cfg(feature = "otel")]
+use crate::agent::http_transport::otel::RequestSpan;
 use crate::{
     agent::{
         agent_error::HttpErrorPayload,
-        http_transport::route_provider::{RoundRobinRouteProvider, RouteProvider},
-        AgentFuture, Transport,
+        http_transport::{
+            canister_endpoint, reason_phrase,
+            retry::{Jitter, RetryBackoff, RetryBudget, DEFAULT_MAX_BACKOFF},
+            route_provider::{RoundRobinRouteProvider, RouteProvider},
+            ByteCounter, Endpoint, DEFAULT_MAX_REQUEST_SIZE,
+        },
+        AgentFuture, AgentObserver, Cbor, CborLimits, DefaultSleeper, RetryPolicy, Sleeper,
+        Transport, WireFormat,
     },
     export::Principal,
     AgentError,
@@ -25,38 +39,120 @@ use crate::{
 #[derive(Debug)]
 pub struct ReqwestTransport {
     route_provider: Arc<dyn RouteProvider>,
-    client: Client,
+    /// The URL the transport was created from, as supplied and as normalized, if any.
+    url: Option<(String, Url)>,
+    client: RwLock<Client>,
+    client_factory: Option<ClientFactory>,
     max_response_body_size: Option<usize>,
+    max_request_size: usize,
     #[allow(dead_code)]
     max_tcp_error_retries: usize,
     use_call_v3_endpoint: bool,
+    jitter: Jitter,
+    max_backoff: Duration,
+    max_rate_limit_attempts: Option<usize>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    bytes: ByteCounter,
+    sleeper: Arc<dyn Sleeper>,
+    #[cfg(not(target_family = "wasm"))]
+    upload_progress: Option<UploadProgress>,
+    clock_skew: Option<ClockSkewCheck>,
+    wire_format: Arc<dyn WireFormat>,
+    cbor_limits: CborLimits,
+    allowed_endpoints: Option<HashSet<Endpoint>>,
+    method_overrides: HashMap<Endpoint, Method>,
+    strict_content_type: bool,
+}
+
+/// Builds a new HTTP client for [`ReqwestTransport::refresh_connections`].
+#[derive(Clone)]
+struct ClientFactory(Arc<dyn Fn() -> Client + Send + Sync>);
+
+impl std::fmt::Debug for ClientFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientFactory")
+    }
+}
+
+/// A callback reporting how many bytes of a request body have been sent, and the body's total size.
+#[cfg(not(target_family = "wasm"))]
+#[derive(Clone)]
+struct UploadProgress(Arc<dyn Fn(usize, usize) + Send + Sync>);
+
+#[cfg(not(target_family = "wasm"))]
+impl std::fmt::Debug for UploadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UploadProgress")
+    }
+}
+
+/// Reports responses whose `Date` header is further than `threshold` from the local clock.
+struct ClockSkewCheck {
+    observer: Arc<dyn AgentObserver>,
+    threshold: Duration,
+}
+
+impl std::fmt::Debug for ClockSkewCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClockSkewCheck")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClockSkewCheck {
+    fn check(&self, headers: &HeaderMap) {
+        let Some(server_time) = headers
+            .get(DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+        else {
+            return;
+        };
+        let skew = OffsetDateTime::from(server_time) - OffsetDateTime::now_utc();
+        if skew.unsigned_abs() > self.threshold {
+            self.observer.on_clock_skew(skew);
+        }
+    }
 }
 
+/// The client of transports created with [`ReqwestTransport::create`], with a request timeout of
+/// 6 minutes.
+fn default_client() -> Client {
+    #[cfg(not(target_family = "wasm"))]
+    {
+        Client::builder()
+            .use_rustls_tls()
+            .timeout(Duration::from_secs(360))
+            .build()
+            .expect("Could not create HTTP client.")
+    }
+    #[cfg(all(target_family = "wasm", feature = "wasm-bindgen"))]
+    {
+        Client::new()
+    }
+}
+
+/// The size of the chunks request bodies are sent in when reporting upload progress.
+#[cfg(not(target_family = "wasm"))]
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
 impl ReqwestTransport {
     /// Creates a replica transport from a HTTP URL. By default a request timeout of 6 minutes is used.
     /// Use `create_with_client` to configure this and other client options.
     pub fn create<U: Into<String>>(url: U) -> Result<Self, AgentError> {
-        #[cfg(not(target_family = "wasm"))]
-        {
-            Self::create_with_client(
-                url,
-                Client::builder()
-                    .use_rustls_tls()
-                    .timeout(Duration::from_secs(360))
-                    .build()
-                    .expect("Could not create HTTP client."),
-            )
-        }
-        #[cfg(all(target_family = "wasm", feature = "wasm-bindgen"))]
-        {
-            Self::create_with_client(url, Client::new())
-        }
+        Ok(Self::create_with_client(url, default_client())?.with_client_factory(default_client))
     }
 
     /// Creates a replica transport from a HTTP URL and a [`reqwest::Client`].
     pub fn create_with_client<U: Into<String>>(url: U, client: Client) -> Result<Self, AgentError> {
-        let route_provider = Arc::new(RoundRobinRouteProvider::new(vec![url.into()])?);
-        Self::create_with_client_route(route_provider, client)
+        let url = url.into();
+        let route_provider = RoundRobinRouteProvider::new(vec![&url])?;
+        let base_url = route_provider.routes()[0].clone();
+        Ok(ReqwestTransport {
+            url: Some((url, base_url)),
+            ..Self::create_with_client_route(Arc::new(route_provider), client)?
+        })
     }
 
     /// Creates a replica transport from a [`RouteProvider`] and a [`reqwest::Client`].
@@ -66,13 +162,111 @@ impl ReqwestTransport {
     ) -> Result<Self, AgentError> {
         Ok(Self {
             route_provider,
-            client,
+            url: None,
+            client: RwLock::new(client),
+            client_factory: None,
             max_response_body_size: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
             max_tcp_error_retries: 0,
             use_call_v3_endpoint: false,
+            jitter: Jitter::default(),
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_rate_limit_attempts: None,
+            retry_budget: None,
+            bytes: ByteCounter::default(),
+            sleeper: Arc::new(DefaultSleeper),
+            #[cfg(not(target_family = "wasm"))]
+            upload_progress: None,
+            clock_skew: None,
+            wire_format: Arc::new(Cbor),
+            cbor_limits: CborLimits::default(),
+            allowed_endpoints: None,
+            method_overrides: HashMap::new(),
+            strict_content_type: false,
         })
     }
 
+    /// Returns the base URL that requests are sent to, e.g. `https://ic0.app/` for a transport
+    /// created from `https://foo.ic0.app`. Returns `None` if the transport was created from a
+    /// [`RouteProvider`], which may route each request to a different URL.
+    pub fn url(&self) -> Option<&Url> {
+        self.url.as_ref().map(|(_, url)| url)
+    }
+
+    /// Returns the URL the transport was created from, as supplied. Returns `None` if the transport
+    /// was created from a [`RouteProvider`].
+    pub fn original_url(&self) -> Option<&str> {
+        self.url.as_ref().map(|(url, _)| &**url)
+    }
+
+    /// Returns the HTTP client requests are made with, e.g. to make a related request with the same
+    /// TLS and proxy settings.
+    ///
+    /// This is an escape hatch: requests made with it directly bypass the transport entirely,
+    /// including its size limits and retries, and are neither signed nor verified by the agent.
+    /// The client is replaced by [`refresh_connections`](Self::refresh_connections), so this
+    /// returns a handle to the current one, which shares its connections.
+    pub fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Sets how to build a new HTTP client when [`refresh_connections`](Self::refresh_connections)
+    /// is called. It should build the client the transport was created with, or one configured
+    /// like it. Transports created with [`create`](Self::create) already rebuild their default
+    /// client.
+    pub fn with_client_factory(self, factory: impl Fn() -> Client + Send + Sync + 'static) -> Self {
+        ReqwestTransport {
+            client_factory: Some(ClientFactory(Arc::new(factory))),
+            ..self
+        }
+    }
+
+    /// Replaces the HTTP client with a new one from the [client factory](Self::with_client_factory),
+    /// so that later requests open new connections rather than reusing pooled ones, e.g. after a
+    /// boundary node is redeployed and its old connections only return errors. Requests in flight
+    /// complete normally on the old client, whose connections are closed once they are done.
+    ///
+    /// Fails if the transport has no client factory.
+    pub fn refresh_connections(&self) -> Result<(), AgentError> {
+        let factory = self.client_factory.as_ref().ok_or_else(|| {
+            AgentError::MessageError(
+                "The transport has no client factory to rebuild its client with.".to_string(),
+            )
+        })?;
+        *self.client.write().unwrap() = (factory.0)();
+        Ok(())
+    }
+
+    /// Sends a `method` request with `body` to `endpoint`, a path relative to the base URL such as
+    /// `api/v2/status`, and returns the response's status, headers and body as they are, whatever the
+    /// status.
+    ///
+    /// This is an escape hatch for tooling that handles non-standard responses itself. Like the
+    /// transport's own requests, it is routed by the [`RouteProvider`], retried on connection errors,
+    /// and subject to the [allowed endpoints](Self::with_allowed_endpoints) and response size limit.
+    /// Unlike them, it is not retried when rate-limited, not checked against the request size limit,
+    /// and a status other than 2xx is not turned into an error. The body is sent as is, so requests
+    /// that need authentication must be signed by the caller.
+    pub async fn raw_request(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), AgentError> {
+        self.check_endpoint(Endpoint::of(endpoint))?;
+        let (status, headers, body, _) = self
+            .request(
+                method,
+                endpoint,
+                body,
+                &HeaderMap::new(),
+                self.max_tcp_error_retries,
+                &mut 0,
+            )
+            .await?;
+        Ok((status, headers, body))
+    }
+
     /// Sets a max response body size limit
     pub fn with_max_response_body_size(self, max_response_body_size: usize) -> Self {
         ReqwestTransport {
@@ -81,6 +275,102 @@ impl ReqwestTransport {
         }
     }
 
+    /// Sets a max request body size limit. Larger requests fail with [`AgentError::RequestTooLarge`]
+    /// without being sent. Defaults to 2 MiB plus 64 KiB for the envelope, since the IC rejects larger
+    /// ingress messages anyway.
+    pub fn with_max_request_size(self, bytes: usize) -> Self {
+        ReqwestTransport {
+            max_request_size: bytes,
+            ..self
+        }
+    }
+
+    /// Sets a callback to report the progress of uploading request bodies, e.g. of a large Wasm
+    /// module being installed. Bodies are then sent in chunks of 64 KiB, and after each chunk is
+    /// handed to the connection, the callback is called with the number of bytes sent so far and the
+    /// body's total size. If a request is retried, its progress starts again from zero.
+    ///
+    /// Not available on WebAssembly targets.
+    #[cfg(not(target_family = "wasm"))]
+    pub fn with_upload_progress(
+        self,
+        callback: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        ReqwestTransport {
+            upload_progress: Some(UploadProgress(Arc::new(callback))),
+            ..self
+        }
+    }
+
+    /// Compares the `Date` header of each response with the local clock, and calls
+    /// [`AgentObserver::on_clock_skew`] on `observer` whenever they differ by more than `threshold`.
+    /// `Date` headers only have a resolution of one second, and are set when the response is sent,
+    /// so `threshold` should allow for that and for latency.
+    pub fn with_clock_skew_observer(
+        self,
+        observer: Arc<dyn AgentObserver>,
+        threshold: Duration,
+    ) -> Self {
+        ReqwestTransport {
+            clock_skew: Some(ClockSkewCheck {
+                observer,
+                threshold,
+            }),
+            ..self
+        }
+    }
+
+    /// Sets the format requests are labelled with in their `Content-Type` header, and call responses
+    /// are decoded from. Defaults to [`Cbor`]. The agent must be configured with the same format, via
+    /// [`AgentBuilder::with_wire_format`](crate::agent::AgentBuilder::with_wire_format).
+    pub fn with_wire_format(self, wire_format: Arc<dyn WireFormat>) -> Self {
+        ReqwestTransport {
+            wire_format,
+            ..self
+        }
+    }
+
+    /// Sets the limits call responses are checked against before they are decoded. Defaults to
+    /// [`CborLimits::default`]; the agent's own limits are set with
+    /// [`AgentBuilder::with_cbor_limits`](crate::agent::AgentBuilder::with_cbor_limits).
+    pub fn with_cbor_limits(self, cbor_limits: CborLimits) -> Self {
+        ReqwestTransport {
+            cbor_limits,
+            ..self
+        }
+    }
+
+    /// Restricts the transport to the given kinds of endpoint, e.g. to [`Endpoint::Query`] and
+    /// [`Endpoint::Status`] in a context that must not make changes. Requests to any other endpoint
+    /// fail with [`AgentError::EndpointNotAllowed`] without being sent. All endpoints are allowed by
+    /// default.
+    pub fn with_allowed_endpoints(self, endpoints: impl IntoIterator<Item = Endpoint>) -> Self {
+        ReqwestTransport {
+            allowed_endpoints: Some(endpoints.into_iter().collect()),
+            ..self
+        }
+    }
+
+    /// Sends requests to the given kind of endpoint with `method` instead of the protocol's, e.g. for
+    /// a gateway that expects a custom verb. The protocol uses `POST` for all endpoints but
+    /// [`Endpoint::Status`], which uses `GET`.
+    pub fn with_method_override(mut self, endpoint: Endpoint, method: Method) -> Self {
+        self.method_overrides.insert(endpoint, method);
+        self
+    }
+
+    /// Sets whether to check that successful responses are of the [wire format](Self::with_wire_format)'s
+    /// content type, `application/cbor` by default. A response with a body of any other type, such as
+    /// an HTML page served by a misconfigured proxy, then fails with
+    /// [`AgentError::UnexpectedContentType`] instead of failing to decode further on. Responses
+    /// without a body, such as those accepting a call, are not checked. Off by default.
+    pub fn with_strict_content_type(self, strict: bool) -> Self {
+        ReqwestTransport {
+            strict_content_type: strict,
+            ..self
+        }
+    }
+
     /// Sets a max number of retries for tcp connection errors.
     pub fn with_max_tcp_errors_retries(self, retries: usize) -> Self {
         ReqwestTransport {
@@ -89,6 +379,73 @@ impl ReqwestTransport {
         }
     }
 
+    /// Sets the jitter applied to the backoff between retries of rate-limited requests.
+    /// Defaults to [`Jitter::Full`].
+    pub fn with_jitter(self, jitter: Jitter) -> Self {
+        ReqwestTransport { jitter, ..self }
+    }
+
+    /// Sets the ceiling for any single delay between retries of rate-limited requests. Defaults to 5 seconds.
+    pub fn with_max_backoff(self, max_backoff: Duration) -> Self {
+        ReqwestTransport {
+            max_backoff,
+            ..self
+        }
+    }
+
+    /// Sets the max number of attempts for a rate-limited request, including the first one.
+    /// Once exhausted, the rate-limit error is returned immediately, without a final backoff.
+    /// By default, rate-limited requests are retried indefinitely.
+    pub fn with_max_rate_limit_attempts(self, attempts: usize) -> Self {
+        ReqwestTransport {
+            max_rate_limit_attempts: Some(attempts),
+            ..self
+        }
+    }
+
+    /// Sets a budget that the retries of all requests, of rate-limited requests and of failed
+    /// connections alike, take from. Once it is spent, requests fail on their first error until it
+    /// refills. By default, retries are limited only per request.
+    pub fn with_retry_budget(self, retry_budget: Arc<RetryBudget>) -> Self {
+        Self {
+            retry_budget: Some(retry_budget),
+            ..self
+        }
+    }
+
+    /// Returns the budget set with [`with_retry_budget`](Self::with_retry_budget), if any.
+    pub fn retry_budget(&self) -> Option<&Arc<RetryBudget>> {
+        self.retry_budget.as_ref()
+    }
+
+    /// Caps the bytes of request and response bodies the transport sends and receives, in total.
+    /// A request, or a retry of it, whose body would take the total over `bytes` fails with
+    /// [`AgentError::ByteBudgetExceeded`] without being sent. Since the size of a response is only
+    /// known once it arrives, the response that crosses the cap is still returned, and requests
+    /// fail from then on. By default, there is no cap.
+    pub fn with_byte_budget(self, bytes: u64) -> Self {
+        Self {
+            bytes: ByteCounter::with_budget(bytes),
+            ..self
+        }
+    }
+
+    /// Returns the total bytes of request bodies the transport has sent, retries included.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes.sent()
+    }
+
+    /// Returns the total bytes of response bodies the transport has received.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes.received()
+    }
+
+    /// Sets how the transport waits between retries of rate-limited requests. Defaults to
+    /// [`DefaultSleeper`], which needs a tokio runtime, or the `wasm-bindgen` feature on `wasm32`.
+    pub fn with_sleeper(self, sleeper: Arc<dyn Sleeper>) -> Self {
+        ReqwestTransport { sleeper, ..self }
+    }
+
     /// Use call v3 endpoint for synchronous update calls.
     /// __This is an experimental feature, and should not be used in production,
     /// as the endpoint is not available yet on the mainnet IC.__
@@ -109,14 +466,36 @@ impl ReqwestTransport {
         method: Method,
         endpoint: &str,
         body: Option<Vec<u8>>,
-    ) -> Result<(StatusCode, HeaderMap, Vec<u8>), AgentError> {
+        headers: &HeaderMap,
+        max_tcp_error_retries: usize,
+        retries: &mut usize,
+    ) -> Result<(StatusCode, HeaderMap, Vec<u8>, Option<String>), AgentError> {
+        // a refresh of the client while this request is in flight does not affect it
+        let client = self.client();
+        let body_size = body.as_ref().map_or(0, Vec::len);
         let create_request_with_generated_url = || -> Result<Request, AgentError> {
             let url = self.route_provider.route()?.join(endpoint)?;
             let mut http_request = Request::new(method.clone(), url);
+            let content_type = self
+                .wire_format
+                .content_type()
+                .parse()
+                .map_err(|err| AgentError::TransportError(Box::new(err)))?;
             http_request
                 .headers_mut()
-                .insert(CONTENT_TYPE, "application/cbor".parse().unwrap());
-            *http_request.body_mut() = body.as_ref().cloned().map(Body::from);
+                .insert(CONTENT_TYPE, content_type);
+            http_request.headers_mut().extend(headers.clone());
+            if let Some(body) = body.as_ref().cloned() {
+                #[cfg(not(target_family = "wasm"))]
+                if let Some(progress) = &self.upload_progress {
+                    http_request
+                        .headers_mut()
+                        .insert(CONTENT_LENGTH, body.len().into());
+                    *http_request.body_mut() = Some(progress_body(body, progress.clone()));
+                    return Ok(http_request);
+                }
+                *http_request.body_mut() = Some(Body::from(body));
+            }
             Ok(http_request)
         };
 
@@ -124,11 +503,13 @@ impl ReqwestTransport {
         let response = {
             #[cfg(target_family = "wasm")]
             {
+                let _ = (max_tcp_error_retries, retries);
                 let http_request = create_request_with_generated_url()?;
-                match self.client.execute(http_request).await {
-                    Ok(response) => response,
-                    Err(err) => return Err(AgentError::TransportError(Box::new(err))),
-                }
+                self.bytes.reserve_sent(body_size)?;
+                client
+                    .execute(http_request)
+                    .await
+                    .map_err(from_reqwest_error)?
             }
             #[cfg(not(target_family = "wasm"))]
             {
@@ -139,19 +520,23 @@ impl ReqwestTransport {
 
                 loop {
                     let http_request = create_request_with_generated_url()?;
+                    self.bytes.reserve_sent(body_size)?;
 
-                    match self.client.execute(http_request).await {
+                    match client.execute(http_request).await {
                         Ok(response) => break response,
                         Err(err) => {
                             // Network-related errors can be retried.
                             if err.is_connect() {
-                                if retry_count >= self.max_tcp_error_retries {
-                                    return Err(AgentError::TransportError(Box::new(err)));
+                                // nothing was sent
+                                self.bytes.release_sent(body_size);
+                                if retry_count >= max_tcp_error_retries || !self.spend_retry() {
+                                    return Err(from_reqwest_error(err));
                                 }
                                 retry_count += 1;
+                                *retries += 1;
                                 continue;
                             }
-                            return Err(AgentError::TransportError(Box::new(err)));
+                            return Err(from_reqwest_error(err));
                         }
                     }
                 }
@@ -160,6 +545,10 @@ impl ReqwestTransport {
 
         let http_status = response.status();
         let response_headers = response.headers().clone();
+        let reason = reason_phrase(http_status, response.extensions());
+        if let Some(clock_skew) = &self.clock_skew {
+            clock_skew.check(&response_headers);
+        }
 
         // Size Check (Content-Length)
         if matches!(self
@@ -176,7 +565,7 @@ impl ReqwestTransport {
         let mut stream = response.bytes_stream();
 
         while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|x| AgentError::TransportError(Box::new(x)))?;
+            let chunk = chunk.map_err(from_reqwest_error)?;
 
             // Size Check (Body Size)
             if matches!(self
@@ -187,8 +576,18 @@ impl ReqwestTransport {
 
             body.extend_from_slice(chunk.as_ref());
         }
+        self.bytes.add_received(body.len());
 
-        Ok((http_status, response_headers, body))
+        Ok((http_status, response_headers, body, reason))
+    }
+
+    fn check_endpoint(&self, kind: Option<Endpoint>) -> Result<(), AgentError> {
+        match (&self.allowed_endpoints, kind) {
+            (Some(allowed), Some(kind)) if !allowed.contains(&kind) => {
+                Err(AgentError::EndpointNotAllowed(kind))
+            }
+            _ => Ok(()),
+        }
     }
 
     async fn execute(
@@ -196,23 +595,89 @@ impl ReqwestTransport {
         method: Method,
         endpoint: &str,
         body: Option<Vec<u8>>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> Result<(StatusCode, Vec<u8>), AgentError> {
+        let kind = Endpoint::of(endpoint);
+        self.check_endpoint(kind)?;
+        let method = kind
+            .and_then(|kind| self.method_overrides.get(&kind).cloned())
+            .unwrap_or(method);
+        if let Some(size) = body.as_ref().map(Vec::len) {
+            if size > self.max_request_size {
+                return Err(AgentError::RequestTooLarge {
+                    size,
+                    limit: self.max_request_size,
+                });
+            }
+        }
+        #[cfg(feature = "otel")]
+        {
+            let (span, headers) = RequestSpan::start(&method, endpoint, headers);
+            let mut retries = 0;
+            let result = self
+                .execute_rate_limited(method, endpoint, body, headers, retry_policy, &mut retries)
+                .await;
+            span.end(&result, retries);
+            result
+        }
+        #[cfg(not(feature = "otel"))]
+        self.execute_rate_limited(method, endpoint, body, headers, retry_policy, &mut 0)
+            .await
+    }
+
+    /// Takes a token from the retry budget for a retry, if there is a budget.
+    fn spend_retry(&self) -> bool {
+        self.retry_budget
+            .as_ref()
+            .map_or(true, |budget| budget.try_spend())
+    }
+
+    async fn execute_rate_limited(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<Vec<u8>>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        retries: &mut usize,
     ) -> Result<(StatusCode, Vec<u8>), AgentError> {
+        let mut backoff = RetryBackoff::new(
+            self.jitter,
+            self.max_backoff,
+            retry_policy.max_rate_limit_attempts(self.max_rate_limit_attempts),
+        );
+        let max_tcp_error_retries = retry_policy.max_tcp_error_retries(self.max_tcp_error_retries);
         let request_result = loop {
             let result = self
-                .request(method.clone(), endpoint, body.as_ref().cloned())
+                .request(
+                    method.clone(),
+                    endpoint,
+                    body.as_ref().cloned(),
+                    &headers,
+                    max_tcp_error_retries,
+                    retries,
+                )
                 .await?;
             if result.0 != StatusCode::TOO_MANY_REQUESTS {
                 break result;
             }
-            crate::util::sleep(Duration::from_millis(250)).await;
+            match backoff.next_delay(&mut OsRng) {
+                Some(_) if !self.spend_retry() => break result,
+                Some(delay) => self.sleeper.sleep(delay).await,
+                None => break result,
+            }
+            *retries += 1;
         };
         let status = request_result.0;
         let headers = request_result.1;
         let body = request_result.2;
+        let reason = request_result.3;
 
         if status.is_client_error() || status.is_server_error() {
             Err(AgentError::HttpError(HttpErrorPayload {
                 status: status.into(),
+                reason,
                 content_type: headers
                     .get(CONTENT_TYPE)
                     .and_then(|value| value.to_str().ok())
@@ -225,9 +690,55 @@ impl ReqwestTransport {
                 status
             )))
         } else {
+            if self.strict_content_type && !body.is_empty() {
+                self.check_content_type(&headers)?;
+            }
             Ok((status, body))
         }
     }
+
+    fn check_content_type(&self, headers: &HeaderMap) -> Result<(), AgentError> {
+        let got = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        // parameters such as a charset do not change the type
+        let media_type = got.and_then(|value| value.split(';').next()).map(str::trim);
+        match media_type {
+            Some(media_type)
+                if media_type.eq_ignore_ascii_case(self.wire_format.content_type()) =>
+            {
+                Ok(())
+            }
+            _ => Err(AgentError::UnexpectedContentType {
+                got: got.map(str::to_string),
+            }),
+        }
+    }
+}
+
+/// Wraps `body` in a stream of chunks that reports each one to `progress` as it is taken.
+#[cfg(not(target_family = "wasm"))]
+fn progress_body(body: Vec<u8>, progress: UploadProgress) -> Body {
+    let total = body.len();
+    let chunks = (0..total).step_by(UPLOAD_CHUNK_SIZE).map(move |start| {
+        let end = total.min(start + UPLOAD_CHUNK_SIZE);
+        (progress.0)(end, total);
+        Ok::<_, std::convert::Infallible>(body[start..end].to_vec())
+    });
+    Body::wrap_stream(futures_util::stream::iter(chunks))
+}
+
+/// Converts a [`reqwest`] error into the most specific [`AgentError`] describing it.
+fn from_reqwest_error(err: reqwest::Error) -> AgentError {
+    #[cfg(not(target_family = "wasm"))]
+    if err.is_timeout() && err.is_connect() {
+        return AgentError::ConnectTimeout(Box::new(err));
+    }
+    if err.is_timeout() {
+        AgentError::TimeoutError(Box::new(err))
+    } else {
+        AgentError::TransportError(Box::new(err))
+    }
 }
 
 impl Transport for ReqwestTransport {
@@ -235,6 +746,30 @@ impl Transport for ReqwestTransport {
         &self,
         effective_canister_id: Principal,
         envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.call_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.call_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
     ) -> AgentFuture<TransportCallResponse> {
         Box::pin(async move {
             let api_version = if self.use_call_v3_endpoint {
@@ -243,13 +778,15 @@ impl Transport for ReqwestTransport {
                 "v2"
             };
 
-            let endpoint = format!(
-                "api/{}/canister/{}/call",
-                api_version,
-                effective_canister_id.to_text()
-            );
+            let endpoint = canister_endpoint(api_version, effective_canister_id, "call")?;
             let (status_code, response_body) = self
-                .execute(Method::POST, &endpoint, Some(envelope))
+                .execute(
+                    Method::POST,
+                    &endpoint,
+                    Some(envelope),
+                    headers,
+                    retry_policy,
+                )
                 .await?;
 
             if status_code == StatusCode::ACCEPTED {
@@ -258,10 +795,10 @@ impl Transport for ReqwestTransport {
 
             // status_code == OK (200)
             if self.use_call_v3_endpoint {
-                serde_cbor::from_slice(&response_body).map_err(AgentError::InvalidCborData)
+                self.wire_format
+                    .decode_call_response(&response_body, &self.cbor_limits)
             } else {
-                let reject_response = serde_cbor::from_slice::<RejectResponse>(&response_body)
-                    .map_err(AgentError::InvalidCborData)?;
+                let reject_response = self.wire_format.decode_reject_response(&response_body)?;
 
                 Err(AgentError::UncertifiedReject(reject_response))
             }
@@ -273,42 +810,92 @@ impl Transport for ReqwestTransport {
         effective_canister_id: Principal,
         envelope: Vec<u8>,
     ) -> AgentFuture<Vec<u8>> {
-        let endpoint = format!(
-            "api/v2/canister/{}/read_state",
-            effective_canister_id.to_text()
-        );
-
         Box::pin(async move {
-            self.execute(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|r| r.1)
+            let endpoint = canister_endpoint("v2", effective_canister_id, "read_state")?;
+            self.execute(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|r| r.1)
         })
     }
 
     fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
             let endpoint = format!("api/v2/subnet/{}/read_state", subnet_id.to_text());
-            self.execute(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|r| r.1)
+            self.execute(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|r| r.1)
         })
     }
 
     fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.query_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.query_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
-            let endpoint = format!("api/v2/canister/{}/query", effective_canister_id.to_text());
-            self.execute(Method::POST, &endpoint, Some(envelope))
-                .await
-                .map(|r| r.1)
+            let endpoint = canister_endpoint("v2", effective_canister_id, "query")?;
+            self.execute(
+                Method::POST,
+                &endpoint,
+                Some(envelope),
+                headers,
+                retry_policy,
+            )
+            .await
+            .map(|r| r.1)
         })
     }
 
     fn status(&self) -> AgentFuture<Vec<u8>> {
         Box::pin(async move {
             let endpoint = "api/v2/status";
-            self.execute(Method::GET, endpoint, None).await.map(|r| r.1)
+            self.execute(
+                Method::GET,
+                endpoint,
+                None,
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+            )
+            .await
+            .map(|r| r.1)
         })
     }
+
+    fn is_mainnet(&self) -> bool {
+        self.route_provider.is_mainnet()
+    }
 }
 
 #[cfg(test)]
@@ -318,7 +905,8 @@ mod test {
     #[cfg(all(target_family = "wasm", feature = "wasm-bindgen"))]
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
-    use super::ReqwestTransport;
+    use super::{ReqwestTransport, RoundRobinRouteProvider};
+    use crate::{agent::Transport, AgentError};
 
     #[cfg_attr(not(target_family = "wasm"), test)]
     #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
@@ -374,4 +962,599 @@ mod test {
             "http://localhost:4943/",
         );
     }
+
+    #[cfg_attr(not(target_family = "wasm"), test)]
+    #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+    fn url() {
+        let t = ReqwestTransport::create("https://foo.ic0.app").unwrap();
+        assert_eq!(t.url().unwrap().as_str(), "https://ic0.app/");
+        assert_eq!(t.original_url(), Some("https://foo.ic0.app"));
+
+        let t = ReqwestTransport::create("http://localhost:4943/api").unwrap();
+        assert_eq!(t.url().unwrap().as_str(), "http://localhost:4943/api");
+        assert_eq!(t.original_url(), Some("http://localhost:4943/api"));
+
+        let route_provider = RoundRobinRouteProvider::new(vec!["https://ic0.app"]).unwrap();
+        let t = ReqwestTransport::create_with_client_route(
+            std::sync::Arc::new(route_provider),
+            reqwest::Client::new(),
+        )
+        .unwrap();
+        assert_eq!(t.url(), None);
+        assert_eq!(t.original_url(), None);
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn reports_upload_progress() {
+        use crate::{agent::Transport, export::Principal};
+        use std::sync::{Arc, Mutex};
+
+        let body = vec![7; 1_000_000];
+        let mut server = mockito::Server::new_async().await;
+        let read_state = server
+            .mock("POST", "/api/v2/canister/aaaaa-aa/read_state")
+            .match_body(body.clone())
+            .with_status(200)
+            .create_async()
+            .await;
+        let reports = Arc::new(Mutex::new(vec![]));
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_upload_progress({
+                let reports = reports.clone();
+                move |sent, total| reports.lock().unwrap().push((sent, total))
+            });
+        transport
+            .read_state(Principal::management_canister(), body.clone())
+            .await
+            .unwrap();
+        read_state.assert_async().await;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 16);
+        assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(reports.iter().all(|&(_, total)| total == body.len()));
+        assert_eq!(reports.last().unwrap().0, body.len());
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn rejects_non_canister_ids() {
+        use crate::{agent::Transport, export::Principal, AgentError};
+
+        let mut server = mockito::Server::new_async().await;
+        let query = server
+            .mock("POST", mockito::Matcher::Any)
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let transport = ReqwestTransport::create(server.url()).unwrap();
+
+        let canister = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        transport.query(canister, vec![]).await.unwrap();
+        for principal in [
+            Principal::anonymous(),
+            Principal::self_authenticating(b"public key"),
+        ] {
+            assert!(matches!(
+                transport.query(principal, vec![]).await,
+                Err(AgentError::InvalidCanisterId(p)) if p == principal
+            ));
+            assert!(matches!(
+                transport.call(principal, vec![]).await,
+                Err(AgentError::InvalidCanisterId(_))
+            ));
+            assert!(matches!(
+                transport.read_state(principal, vec![]).await,
+                Err(AgentError::InvalidCanisterId(_))
+            ));
+        }
+        // only the valid request reached the replica
+        query.assert_async().await;
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn borrowed_client() {
+        let mut server = mockito::Server::new_async().await;
+        let asset = server
+            .mock("GET", "/index.html")
+            .with_status(200)
+            .with_body("<html></html>")
+            .create_async()
+            .await;
+        let transport = ReqwestTransport::create(server.url()).unwrap();
+        let response = transport
+            .client()
+            .get(format!("{}/index.html", server.url()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "<html></html>");
+        asset.assert_async().await;
+    }
+
+    #[cfg_attr(not(target_family = "wasm"), test)]
+    #[cfg_attr(target_family = "wasm", wasm_bindgen_test)]
+    fn is_mainnet() {
+        use crate::agent::Transport;
+
+        for url in [
+            "https://ic0.app",
+            "https://IC0.app",
+            "https://foo.ic0.app",
+            "https://icp0.io",
+            "https://icp-api.io",
+            "https://icp-api.io/foo/",
+        ] {
+            assert!(ReqwestTransport::create(url).unwrap().is_mainnet(), "{url}");
+        }
+        for url in [
+            "http://localhost:4943",
+            "http://foo.localhost:4943",
+            "http://127.0.0.1:4943",
+            "https://example.com",
+            "https://notic0.app",
+            "https://ic0.app.example.com",
+        ] {
+            assert!(
+                !ReqwestTransport::create(url).unwrap().is_mainnet(),
+                "{url}"
+            );
+        }
+
+        // any mainnet route makes the transport a mainnet one
+        let route_provider =
+            RoundRobinRouteProvider::new(vec!["http://localhost:4943", "https://ic0.app"]).unwrap();
+        let transport = ReqwestTransport::create_with_client_route(
+            std::sync::Arc::new(route_provider),
+            reqwest::Client::new(),
+        )
+        .unwrap();
+        assert!(transport.is_mainnet());
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn rejects_disallowed_endpoints() {
+        use crate::{
+            agent::{http_transport::Endpoint, Transport},
+            export::Principal,
+            AgentError,
+        };
+        use reqwest::Method;
+
+        let mut server = mockito::Server::new_async().await;
+        let query = server
+            .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let call = server
+            .mock("POST", "/api/v2/canister/aaaaa-aa/call")
+            .with_status(202)
+            .expect(0)
+            .create_async()
+            .await;
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_allowed_endpoints([Endpoint::Query, Endpoint::Status]);
+
+        let canister_id = Principal::management_canister();
+        transport.query(canister_id, vec![]).await.unwrap();
+        assert!(matches!(
+            transport.call(canister_id, vec![]).await,
+            Err(AgentError::EndpointNotAllowed(Endpoint::Call))
+        ));
+        assert!(matches!(
+            transport
+                .raw_request(Method::POST, "api/v2/canister/aaaaa-aa/call", Some(vec![]))
+                .await,
+            Err(AgentError::EndpointNotAllowed(Endpoint::Call))
+        ));
+        query.assert_async().await;
+        call.assert_async().await;
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn overrides_method() {
+        use crate::{
+            agent::{http_transport::Endpoint, Transport},
+            export::Principal,
+        };
+        use reqwest::Method;
+
+        let mut server = mockito::Server::new_async().await;
+        let query = server
+            .mock("QUERY", "/api/v2/canister/aaaaa-aa/query")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let status = server
+            .mock("GET", "/api/v2/status")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_method_override(Endpoint::Query, Method::from_bytes(b"QUERY").unwrap());
+
+        transport
+            .query(Principal::management_canister(), vec![])
+            .await
+            .unwrap();
+        transport.status().await.unwrap();
+        query.assert_async().await;
+        status.assert_async().await;
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn raw_request_returns_any_status() {
+        use reqwest::{Method, StatusCode};
+
+        let mut server = mockito::Server::new_async().await;
+        let teapot = server
+            .mock("PUT", "/api/v2/teapot")
+            .match_body("tea")
+            .with_status(418)
+            .with_header("X-Brew", "earl grey")
+            .with_body("short and stout")
+            .create_async()
+            .await;
+
+        let transport = ReqwestTransport::create(server.url()).unwrap();
+        let (status, headers, body) = transport
+            .raw_request(Method::PUT, "api/v2/teapot", Some(b"tea".to_vec()))
+            .await
+            .unwrap();
+        teapot.assert_async().await;
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+        assert_eq!(headers["X-Brew"], "earl grey");
+        assert_eq!(body, b"short and stout");
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn keeps_custom_reason_phrase() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // the status request has no body, so it ends with its headers
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1024];
+                let read = stream.read(&mut buf).await.unwrap();
+                assert_ne!(read, 0, "connection closed before the request ended");
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 503 Down For Maintenance\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let transport = ReqwestTransport::create(url).unwrap();
+        let err = transport.status().await.unwrap_err();
+        let AgentError::HttpError(payload) = &err else {
+            panic!("expected an HTTP error, got {err:?}");
+        };
+        assert_eq!(payload.reason.as_deref(), Some("Down For Maintenance"));
+        assert!(err.to_string().contains("status 503 Down For Maintenance"));
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn refreshes_connections() {
+        use futures_util::FutureExt;
+        use reqwest::{Client, Method};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+            sync::Notify,
+        };
+
+        // answers each request on a connection with `ok`, holding the first answer until notified
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let server = {
+            let connections = connections.clone();
+            let release = release.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut hold = connections.fetch_add(1, Ordering::SeqCst) == 0;
+                    let release = release.clone();
+                    tokio::spawn(async move {
+                        let mut request = vec![];
+                        let mut buf = [0; 1024];
+                        loop {
+                            while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+                                match stream.read(&mut buf).await {
+                                    Ok(0) | Err(_) => return,
+                                    Ok(read) => request.extend_from_slice(&buf[..read]),
+                                }
+                            }
+                            request.clear();
+                            if hold {
+                                release.notified().await;
+                                hold = false;
+                            }
+                            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                            if stream.write_all(response).await.is_err() {
+                                return;
+                            }
+                        }
+                    });
+                }
+            }
+        };
+        tokio::spawn(server);
+
+        let transport = ReqwestTransport::create(url).unwrap();
+        let status = || transport.raw_request(Method::GET, "api/v2/status", None);
+        let mut in_flight = Box::pin(status());
+        while connections.load(Ordering::SeqCst) == 0 {
+            assert!((&mut in_flight).now_or_never().is_none());
+            tokio::task::yield_now().await;
+        }
+
+        transport.refresh_connections().unwrap();
+        release.notify_one();
+        assert_eq!(in_flight.await.unwrap().2, b"ok");
+        // the old client's connection is idle now, but the new client opens its own
+        assert_eq!(status().await.unwrap().2, b"ok");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // a borrowed client cannot be rebuilt without a factory
+        let transport = ReqwestTransport::create_with_client("http://localhost", Client::new());
+        assert!(transport.unwrap().refresh_connections().is_err());
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn stops_retrying_once_budget_is_spent() {
+        use crate::agent::{http_transport::RetryBudget, Transport};
+        use std::{sync::Arc, time::Duration};
+
+        let mut server = mockito::Server::new_async().await;
+        // the first request retries twice, the second once, and the rest not at all
+        let status = server
+            .mock("GET", "/api/v2/status")
+            .with_status(429)
+            .expect(8)
+            .create_async()
+            .await;
+
+        let budget = Arc::new(RetryBudget::new(3, 0.0));
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_max_backoff(Duration::ZERO)
+            .with_max_rate_limit_attempts(3)
+            .with_retry_budget(budget.clone());
+        for _ in 0..5 {
+            assert!(transport.status().await.is_err());
+        }
+        status.assert_async().await;
+        assert_eq!(budget.remaining(), 0);
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn counts_bytes_against_budget() {
+        use crate::{agent::Transport, export::Principal, AgentError};
+
+        let mut server = mockito::Server::new_async().await;
+        let canister_id = Principal::management_canister();
+        let query = server
+            .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+            .with_status(200)
+            .with_body("hello")
+            .expect(3)
+            .create_async()
+            .await;
+
+        let transport = ReqwestTransport::create(server.url()).unwrap();
+        for _ in 0..3 {
+            transport.query(canister_id, vec![0; 100]).await.unwrap();
+        }
+        assert_eq!(transport.bytes_sent(), 300);
+        assert_eq!(transport.bytes_received(), 15);
+        query.assert_async().await;
+
+        // the third request would take the total over the budget, so it is not sent
+        let query = server
+            .mock("POST", "/api/v2/canister/aaaaa-aa/query")
+            .with_status(200)
+            .with_body("hello")
+            .expect(3)
+            .create_async()
+            .await;
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_byte_budget(250);
+        for _ in 0..2 {
+            transport.query(canister_id, vec![0; 100]).await.unwrap();
+        }
+        assert!(matches!(
+            transport.query(canister_id, vec![0; 100]).await,
+            Err(AgentError::ByteBudgetExceeded { budget: 250 })
+        ));
+        assert_eq!(transport.bytes_sent(), 200);
+        // a smaller request still fits
+        transport.query(canister_id, vec![0; 40]).await.unwrap();
+        assert_eq!(transport.bytes_sent(), 240);
+        query.assert_async().await;
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn rejects_unexpected_content_type() {
+        use crate::{agent::Transport, AgentError};
+
+        let mut server = mockito::Server::new_async().await;
+        let _status = server
+            .mock("GET", "/api/v2/status")
+            .with_status(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body("<html>Welcome to nginx!</html>")
+            .create_async()
+            .await;
+
+        let transport = ReqwestTransport::create(server.url()).unwrap();
+        assert!(transport.status().await.is_ok());
+        let transport = transport.with_strict_content_type(true);
+        match transport.status().await {
+            Err(AgentError::UnexpectedContentType { got }) => {
+                assert_eq!(got.as_deref(), Some("text/html; charset=utf-8"))
+            }
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test]
+    async fn reports_clock_skew() {
+        use crate::agent::{AgentObserver, Transport};
+        use std::{
+            sync::{Arc, Mutex},
+            time::Duration,
+        };
+
+        #[derive(Default)]
+        struct SkewRecorder(Mutex<Vec<time::Duration>>);
+        impl AgentObserver for SkewRecorder {
+            fn on_clock_skew(&self, skew: time::Duration) {
+                self.0.lock().unwrap().push(skew);
+            }
+        }
+
+        let mut server = mockito::Server::new_async().await;
+        let observer = Arc::new(SkewRecorder::default());
+        let transport = ReqwestTransport::create(server.url())
+            .unwrap()
+            .with_clock_skew_observer(observer.clone(), Duration::from_secs(30));
+
+        // the server's own Date header is current
+        let status = server
+            .mock("GET", "/api/v2/status")
+            .with_status(200)
+            .create_async()
+            .await;
+        transport.status().await.unwrap();
+        status.remove_async().await;
+        assert!(observer.0.lock().unwrap().is_empty());
+
+        server
+            .mock("GET", "/api/v2/status")
+            .with_status(200)
+            .with_header("date", "Fri, 01 Jan 2100 00:00:00 GMT")
+            .create_async()
+            .await;
+        transport.status().await.unwrap();
+        let skews = observer.0.lock().unwrap();
+        assert_eq!(skews.len(), 1);
+        assert!(skews[0] > time::Duration::days(365));
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    mod errors {
+        use super::super::from_reqwest_error;
+        use crate::AgentError;
+        use reqwest::Client;
+        use std::time::Duration;
+        use tokio::{
+            io::AsyncWriteExt,
+            net::{TcpListener, TcpSocket, TcpStream},
+            time::timeout,
+        };
+
+        async fn error_for(client: Client, url: String) -> AgentError {
+            from_reqwest_error(client.get(url).send().await.unwrap_err())
+        }
+
+        #[tokio::test]
+        async fn request_timeout() {
+            // accepts the connection but never responds
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let _streams = [listener.accept().await, listener.accept().await];
+                std::future::pending::<()>().await
+            });
+            let client = Client::builder()
+                .timeout(Duration::from_millis(50))
+                .build()
+                .unwrap();
+            let err = error_for(client, url).await;
+            assert!(matches!(err, AgentError::TimeoutError(_)), "{err:?}");
+        }
+
+        #[tokio::test]
+        async fn connect_timeout() {
+            // a listener whose backlog is full, so that further connection attempts hang
+            let socket = TcpSocket::new_v4().unwrap();
+            socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let listener = socket.listen(0).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut backlog = vec![];
+            for _ in 0..2 {
+                if let Ok(Ok(stream)) =
+                    timeout(Duration::from_millis(50), TcpStream::connect(addr)).await
+                {
+                    backlog.push(stream);
+                }
+            }
+            let client = Client::builder()
+                .connect_timeout(Duration::from_millis(50))
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap();
+            let err = error_for(client, format!("http://{addr}")).await;
+            assert!(matches!(err, AgentError::ConnectTimeout(_)), "{err:?}");
+        }
+
+        #[tokio::test]
+        async fn connection_refused() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            drop(listener);
+            let err = error_for(Client::new(), url).await;
+            assert!(matches!(err, AgentError::TransportError(_)), "{err:?}");
+        }
+
+        #[tokio::test]
+        async fn truncated_body() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort")
+                    .await
+                    .unwrap();
+            });
+            let response = Client::new().get(url).send().await.unwrap();
+            let err = from_reqwest_error(response.bytes().await.unwrap_err());
+            assert!(matches!(err, AgentError::TransportError(_)), "{err:?}");
+        }
+    }
 }
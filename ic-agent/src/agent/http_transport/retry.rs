@@ -0,0 +1,309 @@
+//! Backoff between retries of rate-limited requests.
+use rand::Rng;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use time::OffsetDateTime;
+
+use crate::agent::{Clock, WallClock};
+
+/// The delay before the first retry of a rate-limited request, before jitter is applied.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// The default for the largest delay between two retries of a rate-limited request.
+pub(crate) const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The randomization applied to the exponential backoff between retries of rate-limited requests.
+///
+/// Without jitter, many clients that were rate-limited at the same moment will retry at the same
+/// moments too, producing load spikes. In the formulas below, `base` is the initial delay, `cap` is
+/// the maximum delay, `n` is the number of retries so far (starting at 0), `exp` is
+/// `min(cap, base * 2^n)`, and `random(a, b)` is a uniformly random duration between `a` and `b`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Jitter {
+    /// No randomization: `delay = exp`.
+    None,
+    /// `delay = random(0, exp)`. This spreads retries the most, and is the default.
+    #[default]
+    Full,
+    /// `delay = exp / 2 + random(0, exp / 2)`. Always waits at least half the exponential delay.
+    Equal,
+    /// `delay = min(cap, random(base, previous_delay * 3))`, where `previous_delay` starts at `base`.
+    /// The delay grows based on the previous delay rather than the retry count.
+    Decorrelated,
+}
+
+/// Computes successive delays between retries according to a [`Jitter`] strategy,
+/// and decides when to stop retrying.
+#[derive(Debug, Clone)]
+pub(crate) struct RetryBackoff {
+    jitter: Jitter,
+    base: Duration,
+    cap: Duration,
+    max_attempts: Option<usize>,
+    attempts: usize,
+    previous: Duration,
+}
+
+impl RetryBackoff {
+    /// `max_attempts` counts the initial request; `None` retries indefinitely.
+    pub(crate) fn new(jitter: Jitter, max_backoff: Duration, max_attempts: Option<usize>) -> Self {
+        Self::with_bounds(jitter, INITIAL_BACKOFF, max_backoff, max_attempts)
+    }
+
+    fn with_bounds(
+        jitter: Jitter,
+        base: Duration,
+        cap: Duration,
+        max_attempts: Option<usize>,
+    ) -> Self {
+        Self {
+            jitter,
+            // A ceiling below the initial delay lowers the initial delay too.
+            base: base.min(cap),
+            cap,
+            max_attempts,
+            attempts: 0,
+            previous: base.min(cap),
+        }
+    }
+
+    /// Records a failed attempt and returns the delay to wait before the next one,
+    /// or `None` if that was the last attempt, in which case there is no point in waiting.
+    pub(crate) fn next_delay<R: Rng + ?Sized>(&mut self, rng: &mut R) -> Option<Duration> {
+        let retries = self.attempts;
+        self.attempts = self.attempts.saturating_add(1);
+        if self.max_attempts.is_some_and(|max| self.attempts >= max) {
+            return None;
+        }
+        let exp = u32::try_from(retries)
+            .ok()
+            .and_then(|n| 2u32.checked_pow(n))
+            .and_then(|factor| self.base.checked_mul(factor))
+            .map_or(self.cap, |d| d.min(self.cap));
+        let delay = match self.jitter {
+            Jitter::None => exp,
+            Jitter::Full => random_between(rng, Duration::ZERO, exp),
+            Jitter::Equal => exp / 2 + random_between(rng, Duration::ZERO, exp / 2),
+            Jitter::Decorrelated => random_between(
+                rng,
+                self.base,
+                self.previous.saturating_mul(3).max(self.base),
+            )
+            .min(self.cap),
+        };
+        self.previous = delay;
+        Some(delay)
+    }
+}
+
+/// A token bucket limiting the retries made across all the requests of a transport, so that a
+/// widespread outage does not have every client retry every request.
+///
+/// Each retry of a rate-limited request or of a failed connection takes a token; once none are
+/// left, requests fail on their first error instead of being retried. Tokens are added back at a
+/// steady rate, up to the capacity the bucket starts with. Share one budget between the transports
+/// of several agents by passing clones of the same [`Arc`].
+///
+/// Only the retries made by the transport count against the budget. The agent's own repeated
+/// requests, such as polling the status of an update until it completes, do not take tokens.
+#[derive(Debug)]
+pub struct RetryBudget {
+    capacity: f64,
+    refill_per_second: f64,
+    clock: Arc<dyn Clock>,
+    /// The tokens left, as of the time they were last counted.
+    state: Mutex<(f64, OffsetDateTime)>,
+}
+
+impl RetryBudget {
+    /// Creates a full budget of `capacity` retries, which regains `refill_per_second` retries per
+    /// second once spent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `refill_per_second` is negative or not a number.
+    pub fn new(capacity: u32, refill_per_second: f64) -> Self {
+        assert!(
+            refill_per_second >= 0.0,
+            "retry budget refill rate must not be negative, not {refill_per_second}"
+        );
+        Self {
+            capacity: capacity.into(),
+            refill_per_second,
+            clock: Arc::new(WallClock),
+            state: Mutex::new((capacity.into(), OffsetDateTime::now_utc())),
+        }
+    }
+
+    /// Sets the clock the refill is measured with, and refills the budget. Defaults to the system
+    /// clock.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        let state = Mutex::new((self.capacity, clock.now()));
+        Self {
+            clock,
+            state,
+            ..self
+        }
+    }
+
+    /// Returns the number of retries that can be made now.
+    pub fn remaining(&self) -> u32 {
+        self.refill(&mut self.state.lock().unwrap()) as u32
+    }
+
+    /// Takes a token for a retry, returning `false` without taking one if none are left.
+    pub(crate) fn try_spend(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if self.refill(&mut state) < 1.0 {
+            return false;
+        }
+        state.0 -= 1.0;
+        true
+    }
+
+    fn refill(&self, state: &mut (f64, OffsetDateTime)) -> f64 {
+        let now = self.clock.now();
+        let elapsed = (now - state.1).as_seconds_f64().max(0.0);
+        *state = (
+            (state.0 + elapsed * self.refill_per_second).min(self.capacity),
+            now,
+        );
+        state.0
+    }
+}
+
+fn random_between<R: Rng + ?Sized>(rng: &mut R, low: Duration, high: Duration) -> Duration {
+    if high <= low {
+        return low;
+    }
+    rng.gen_range(low..=high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const BASE: Duration = Duration::from_millis(100);
+    const CAP: Duration = Duration::from_secs(3);
+
+    fn exp(n: u32) -> Duration {
+        (BASE * 2u32.pow(n)).min(CAP)
+    }
+
+    fn delays(jitter: Jitter) -> Vec<Duration> {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut backoff = RetryBackoff::with_bounds(jitter, BASE, CAP, None);
+        (0..12)
+            .map(|_| backoff.next_delay(&mut rng).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn no_jitter() {
+        for (n, delay) in delays(Jitter::None).into_iter().enumerate() {
+            assert_eq!(delay, exp(n as u32));
+        }
+    }
+
+    #[test]
+    fn full_jitter() {
+        for (n, delay) in delays(Jitter::Full).into_iter().enumerate() {
+            assert!(delay <= exp(n as u32), "retry {n}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn equal_jitter() {
+        for (n, delay) in delays(Jitter::Equal).into_iter().enumerate() {
+            let exp = exp(n as u32);
+            assert!(delay >= exp / 2 && delay <= exp, "retry {n}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn decorrelated_jitter() {
+        let mut previous = BASE;
+        for (n, delay) in delays(Jitter::Decorrelated).into_iter().enumerate() {
+            assert!(
+                delay >= BASE && delay <= (previous * 3).min(CAP),
+                "retry {n}: {delay:?}"
+            );
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn seeded_delays_are_deterministic() {
+        for jitter in [Jitter::Full, Jitter::Equal, Jitter::Decorrelated] {
+            assert_eq!(delays(jitter), delays(jitter));
+        }
+    }
+
+    #[test]
+    fn no_delay_after_last_attempt() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut backoff = RetryBackoff::with_bounds(Jitter::None, BASE, CAP, Some(3));
+        assert_eq!(backoff.next_delay(&mut rng), Some(BASE));
+        assert_eq!(backoff.next_delay(&mut rng), Some(BASE * 2));
+        assert_eq!(backoff.next_delay(&mut rng), None);
+
+        let mut backoff = RetryBackoff::with_bounds(Jitter::Full, BASE, CAP, Some(1));
+        assert_eq!(backoff.next_delay(&mut rng), None);
+    }
+
+    #[derive(Debug)]
+    struct MockClock(Mutex<OffsetDateTime>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> OffsetDateTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn budget_refills_by_its_clock() {
+        let clock = Arc::new(MockClock(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let budget = RetryBudget::new(2, 0.5).with_clock(clock.clone());
+        assert!(budget.try_spend());
+        assert!(budget.try_spend());
+        assert!(!budget.try_spend());
+
+        *clock.0.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(budget.remaining(), 1);
+        *clock.0.lock().unwrap() += Duration::from_secs(60);
+        assert_eq!(budget.remaining(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be negative")]
+    fn budget_rejects_negative_refill() {
+        RetryBudget::new(1, -1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be negative")]
+    fn budget_rejects_nan_refill() {
+        RetryBudget::new(1, f64::NAN);
+    }
+
+    #[test]
+    fn delay_never_exceeds_ceiling() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for jitter in [
+            Jitter::None,
+            Jitter::Full,
+            Jitter::Equal,
+            Jitter::Decorrelated,
+        ] {
+            for cap in [Duration::from_millis(50), CAP] {
+                let mut backoff = RetryBackoff::with_bounds(jitter, BASE, cap, None);
+                for n in 0..200 {
+                    let delay = backoff.next_delay(&mut rng).unwrap();
+                    assert!(delay <= cap, "{jitter:?} retry {n}: {delay:?}");
+                }
+            }
+        }
+    }
+}
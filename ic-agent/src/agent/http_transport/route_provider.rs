@@ -17,6 +17,12 @@ use crate::agent::{
 pub trait RouteProvider: std::fmt::Debug + Send + Sync {
     /// Generate next routing url
     fn route(&self) -> Result<Url, AgentError>;
+
+    /// Returns whether any of the urls routed to is a boundary node of the Internet Computer
+    /// mainnet. The default implementation returns `false`.
+    fn is_mainnet(&self) -> bool {
+        false
+    }
 }
 
 /// A simple implementation of the [`RouteProvider`] which produces an even distribution of the urls from the input ones.
@@ -38,6 +44,24 @@ impl RouteProvider for RoundRobinRouteProvider {
         let prev_idx = self.current_idx.fetch_add(1, Ordering::Relaxed);
         Ok(self.routes[prev_idx % self.routes.len()].clone())
     }
+
+    fn is_mainnet(&self) -> bool {
+        self.routes.iter().any(is_mainnet_url)
+    }
+}
+
+/// Returns whether `url` is on a domain of the Internet Computer mainnet's boundary nodes.
+fn is_mainnet_url(url: &Url) -> bool {
+    url.domain().map_or(false, |domain| {
+        let domain = domain.to_ascii_lowercase();
+        [
+            (IC0_DOMAIN, IC0_SUB_DOMAIN),
+            (ICP0_DOMAIN, ICP0_SUB_DOMAIN),
+            (ICP_API_DOMAIN, ICP_API_SUB_DOMAIN),
+        ]
+        .iter()
+        .any(|(root, sub)| domain == *root || domain.ends_with(sub))
+    })
 }
 
 impl RoundRobinRouteProvider {
@@ -68,6 +92,12 @@ impl RoundRobinRouteProvider {
             current_idx: AtomicUsize::new(0),
         })
     }
+
+    /// The urls routed to, after normalization.
+    #[cfg(feature = "reqwest")]
+    pub(crate) fn routes(&self) -> &[Url] {
+        &self.routes
+    }
 }
 
 #[cfg(test)]
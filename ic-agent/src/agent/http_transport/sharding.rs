@@ -0,0 +1,339 @@
+//! A [`Transport`] decorator that pins the traffic of each canister to one of several transports.
+use http::HeaderMap;
+use ic_transport_types::TransportCallResponse;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    agent::{AgentFuture, RetryPolicy, Transport},
+    export::Principal,
+};
+
+/// The number of points on the hash ring per unit of a shard's weight.
+const POINTS_PER_WEIGHT: u32 = 100;
+
+/// The largest weight a shard can have, keeping the ring at a reasonable size.
+const MAX_SHARD_WEIGHT: u32 = 10_000;
+
+/// Wraps several [`Transport`]s, each normally pointing at a different replica or boundary node, and
+/// sends all requests for a canister to the same one of them, e.g. to make the most of its caches.
+///
+/// Effective canister ids are assigned to transports by consistent hashing of the ids given to the
+/// transports, so the assignment is the same across agents and processes, and adding or removing a
+/// transport only moves the canisters of its share. Each transport can be given a weight, and
+/// receives a share of the canisters in proportion to it. Subnet `read_state` requests are
+/// assigned by subnet id in the same way, and status requests go to the first transport.
+#[derive(Debug)]
+pub struct ShardingTransport<T> {
+    shards: Vec<T>,
+    /// Points on the hash ring, sorted, with the index of the shard each belongs to.
+    ring: Vec<(u64, usize)>,
+}
+
+impl<T: Transport> ShardingTransport<T> {
+    /// Wraps `shards`, each with its id and the same weight.
+    ///
+    /// The id of a shard places it on the hash ring, so it must stay the same for the same backend,
+    /// e.g. its URL, for canisters to stay with it as other shards are added or removed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is empty, or if two shards have the same id.
+    pub fn new(shards: Vec<(String, T)>) -> Self {
+        Self::weighted(
+            shards
+                .into_iter()
+                .map(|(id, shard)| (id, shard, 1))
+                .collect(),
+        )
+    }
+
+    /// Wraps `shards`, each with its id, as for [`new`](Self::new), and its weight. A shard of
+    /// weight 0 receives no requests but status requests, if it is the first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all weights are 0, including if `shards` is empty, if a weight is greater than
+    /// 10,000, or if two shards have the same id.
+    pub fn weighted(shards: Vec<(String, T, u32)>) -> Self {
+        let mut ring = vec![];
+        for (index, (id, _, weight)) in shards.iter().enumerate() {
+            assert!(
+                *weight <= MAX_SHARD_WEIGHT,
+                "shard weight {weight} is greater than {MAX_SHARD_WEIGHT}"
+            );
+            assert!(
+                shards[..index].iter().all(|(other, _, _)| other != id),
+                "shard id {id:?} is used twice"
+            );
+            for point in 0..weight * POINTS_PER_WEIGHT {
+                let mut hasher = Sha256::new();
+                hasher.update((id.len() as u64).to_be_bytes());
+                hasher.update(id.as_bytes());
+                hasher.update(point.to_be_bytes());
+                ring.push((ring_position(&hasher.finalize()), index));
+            }
+        }
+        assert!(
+            !ring.is_empty(),
+            "ShardingTransport needs at least one transport of non-zero weight"
+        );
+        ring.sort_unstable();
+        Self {
+            shards: shards.into_iter().map(|(_, shard, _)| shard).collect(),
+            ring,
+        }
+    }
+
+    /// Returns the transport that requests for `id` are sent to.
+    fn shard(&self, id: &Principal) -> &T {
+        let position = ring_position(&Sha256::digest(id.as_slice()));
+        let point = self.ring.partition_point(|(point, _)| *point < position);
+        // past the last point, the ring wraps around to the first
+        let (_, index) = self.ring.get(point).unwrap_or(&self.ring[0]);
+        &self.shards[*index]
+    }
+}
+
+fn ring_position(hash: &[u8]) -> u64 {
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+impl<T: Transport> Transport for ShardingTransport<T> {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.shard(&effective_canister_id)
+            .call(effective_canister_id, envelope)
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        self.shard(&effective_canister_id)
+            .read_state(effective_canister_id, envelope)
+    }
+
+    fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.shard(&subnet_id)
+            .read_subnet_state(subnet_id, envelope)
+    }
+
+    fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        self.shard(&effective_canister_id)
+            .query(effective_canister_id, envelope)
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.shard(&effective_canister_id).query_with_headers(
+            effective_canister_id,
+            envelope,
+            headers,
+        )
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        self.shard(&effective_canister_id).query_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            retry_policy,
+        )
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.shard(&effective_canister_id).call_with_headers(
+            effective_canister_id,
+            envelope,
+            headers,
+        )
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        self.shard(&effective_canister_id).call_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            retry_policy,
+        )
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        self.shards[0].status()
+    }
+
+    fn is_mainnet(&self) -> bool {
+        self.shards.iter().any(Transport::is_mainnet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockTransport;
+    use futures_util::FutureExt;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers every query with its own index, which its id is made from.
+    fn backend(index: u8) -> (String, MockTransport) {
+        let transport = MockTransport::new().on_query(move |_| async move { Ok(vec![index]) });
+        (format!("backend-{index}"), transport)
+    }
+
+    fn weighted(index: u8, weight: u32) -> (String, MockTransport, u32) {
+        let (id, transport) = backend(index);
+        (id, transport, weight)
+    }
+
+    /// Returns the index of the backend that `transport` sends queries for `canister_id` to.
+    fn backend_of<T: Transport>(transport: &T, canister_id: Principal) -> u8 {
+        transport
+            .query(canister_id, vec![])
+            .now_or_never()
+            .unwrap()
+            .unwrap()[0]
+    }
+
+    fn canister(n: u64) -> Principal {
+        let mut id = n.to_be_bytes().to_vec();
+        id.extend_from_slice(&[1, 1]);
+        Principal::from_slice(&id)
+    }
+
+    #[test]
+    fn routes_canister_to_same_backend() {
+        let transport = ShardingTransport::new((0..4).map(backend).collect());
+        let mut used = [false; 4];
+        for n in 0..100 {
+            let backend = backend_of(&transport, canister(n));
+            for _ in 0..20 {
+                assert_eq!(backend_of(&transport, canister(n)), backend);
+            }
+            used[backend as usize] = true;
+        }
+        assert_eq!(used, [true; 4]);
+
+        // the assignment does not depend on the instance
+        let other = ShardingTransport::new((0..4).map(backend).collect());
+        for n in 0..100 {
+            assert_eq!(
+                backend_of(&other, canister(n)),
+                backend_of(&transport, canister(n))
+            );
+        }
+    }
+
+    #[test]
+    fn shares_canisters_by_weight() {
+        let transport = ShardingTransport::weighted(vec![weighted(0, 3), weighted(1, 1)]);
+        let mut counts = [0; 2];
+        for n in 0..1000 {
+            counts[backend_of(&transport, canister(n)) as usize] += 1;
+        }
+        assert!((650..850).contains(&counts[0]), "{counts:?}");
+
+        let transport = ShardingTransport::weighted(vec![weighted(0, 0), weighted(1, 1)]);
+        assert!((0..100).all(|n| backend_of(&transport, canister(n)) == 1));
+    }
+
+    #[test]
+    fn adding_backend_moves_only_its_share() {
+        let three = ShardingTransport::new((0..3).map(backend).collect());
+        let four = ShardingTransport::new((0..4).map(backend).collect());
+        for n in 0..1000 {
+            let backend = backend_of(&four, canister(n));
+            if backend != 3 {
+                assert_eq!(backend, backend_of(&three, canister(n)));
+            }
+        }
+    }
+
+    #[test]
+    fn removing_backend_moves_only_its_share() {
+        let four = ShardingTransport::new((0..4).map(backend).collect());
+        let without_1 = ShardingTransport::new([0, 2, 3].into_iter().map(backend).collect());
+        for n in 0..1000 {
+            let backend = backend_of(&four, canister(n));
+            if backend != 1 {
+                assert_eq!(backend, backend_of(&without_1, canister(n)));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic = "shard weight 4294967295 is greater than 10000"]
+    fn rejects_huge_weight() {
+        ShardingTransport::weighted(vec![weighted(0, u32::MAX)]);
+    }
+
+    #[test]
+    #[should_panic = "is used twice"]
+    fn rejects_duplicate_ids() {
+        ShardingTransport::new(vec![backend(0), backend(0)]);
+    }
+
+    #[test]
+    fn forwards_retry_policy() {
+        let policies = Arc::new(Mutex::new(vec![]));
+        let shards = (0..2)
+            .map(|index| {
+                let (on_query, on_call) = (policies.clone(), policies.clone());
+                let transport = MockTransport::new()
+                    .on_query(move |request| {
+                        on_query.lock().unwrap().push(request.retry_policy);
+                        async { Ok(vec![]) }
+                    })
+                    .on_call(move |request| {
+                        on_call.lock().unwrap().push(request.retry_policy);
+                        async { Ok(TransportCallResponse::Accepted) }
+                    });
+                (format!("backend-{index}"), transport)
+            })
+            .collect();
+        let transport = ShardingTransport::new(shards);
+        transport
+            .query_with_retry_policy(canister(0), vec![], HeaderMap::new(), RetryPolicy::Never)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        transport
+            .call_with_retry_policy(
+                canister(1),
+                vec![],
+                HeaderMap::new(),
+                RetryPolicy::MaxAttempts(3),
+            )
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *policies.lock().unwrap(),
+            [RetryPolicy::Never, RetryPolicy::MaxAttempts(3)]
+        );
+    }
+}
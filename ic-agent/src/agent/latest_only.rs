@@ -0,0 +1,133 @@
+//! Queries where only the latest one started under a key is of interest.
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures_util::future::AbortHandle;
+
+use crate::agent::{abortable, AgentFuture, QueryBuilder};
+
+/// The query in flight under a key, and the number it was started with.
+type InFlight<K> = Arc<Mutex<HashMap<K, (u64, AbortHandle)>>>;
+
+/// Sends queries under user-chosen keys, aborting the query still in flight under a key when a new
+/// one is started under it, e.g. the search of the previous keystroke when the user types another.
+///
+/// An aborted query's future resolves to [`AgentError::RequestCancelled`](crate::AgentError::RequestCancelled)
+/// and its request is dropped along with its connection, so only the latest query under each key
+/// delivers a result. Queries under different keys do not affect each other.
+#[derive(Debug)]
+pub struct LatestOnlyQuery<K> {
+    in_flight: InFlight<K>,
+    started: AtomicU64,
+}
+
+impl<K> Default for LatestOnlyQuery<K> {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::default(),
+            started: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> LatestOnlyQuery<K> {
+    /// Creates a helper with no queries in flight.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts `query` under `key`, aborting the query in flight under `key`, if any.
+    ///
+    /// The query is sent once the returned future is polled. A future dropped before it completes
+    /// is forgotten only when the next query under its key is started.
+    pub fn call<'agent>(&self, key: K, query: QueryBuilder<'agent>) -> AgentFuture<'agent, Vec<u8>>
+    where
+        K: 'agent,
+    {
+        let (future, handle) = abortable(query.call());
+        let started = self.started.fetch_add(1, Ordering::Relaxed);
+        let previous = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (started, handle));
+        if let Some((_, previous)) = previous {
+            previous.abort();
+        }
+
+        let in_flight = self.in_flight.clone();
+        Box::pin(async move {
+            let result = future.await;
+            let mut in_flight = in_flight.lock().unwrap();
+            // a newer query under the key has replaced this one's entry already
+            if matches!(in_flight.get(&key), Some((current, _)) if *current == started) {
+                in_flight.remove(&key);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::EnvelopeContent,
+        export::Principal,
+        test_utils::{query_reply, MockTransport},
+        Agent, AgentError,
+    };
+    use futures_util::FutureExt;
+
+    #[tokio::test]
+    async fn delivers_only_the_latest_query() {
+        // answers queries with their argument, after yielding once
+        let sent = Arc::new(Mutex::new(0));
+        let counter = sent.clone();
+        let transport = MockTransport::new().on_query(move |request| {
+            *counter.lock().unwrap() += 1;
+            async move {
+                tokio::task::yield_now().await;
+                let EnvelopeContent::Query { arg, .. } = request.content() else {
+                    unreachable!()
+                };
+                Ok(query_reply(arg))
+            }
+        });
+        let agent = Agent::builder()
+            .with_transport(transport)
+            .with_verify_query_signatures(false)
+            .build()
+            .unwrap();
+        let canister_id = Principal::management_canister();
+        let latest = LatestOnlyQuery::new();
+        let search = |text: &str| {
+            latest.call(
+                "search",
+                agent
+                    .query(&canister_id, "search")
+                    .with_arg(text.as_bytes()),
+            )
+        };
+
+        // each keystroke sends a query, which the next one aborts
+        let mut first = search("a");
+        assert!((&mut first).now_or_never().is_none());
+        let mut second = search("ab");
+        assert!((&mut second).now_or_never().is_none());
+        let third = search("abc");
+        assert_eq!(*sent.lock().unwrap(), 2);
+
+        assert!(matches!(first.await, Err(AgentError::RequestCancelled)));
+        assert!(matches!(second.await, Err(AgentError::RequestCancelled)));
+        assert_eq!(third.await.unwrap(), b"abc");
+        assert_eq!(*sent.lock().unwrap(), 3);
+        assert!(latest.in_flight.lock().unwrap().is_empty());
+    }
+}
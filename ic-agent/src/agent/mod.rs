@@ -1,34 +1,67 @@
 //! The main Agent module. Contains the [Agent] type and all associated structures.
 pub(crate) mod agent_config;
 pub mod agent_error;
+pub(crate) mod asset;
+#[cfg(all(feature = "blocking", not(target_family = "wasm")))]
+pub mod blocking;
 pub(crate) mod builder;
+pub(crate) mod cbor_limits;
+pub(crate) mod certificate_header;
+pub(crate) mod certified_reply;
+pub(crate) mod clock;
+pub(crate) mod detached;
+#[cfg(all(feature = "reqwest", feature = "pem", not(target_family = "wasm")))]
+pub(crate) mod env;
 pub mod http_transport;
+pub(crate) mod latest_only;
 pub(crate) mod nonce;
+pub mod observer;
+pub(crate) mod pending_call;
+pub(crate) mod priority;
 pub(crate) mod response_authentication;
+pub(crate) mod retry_policy;
+pub(crate) mod sleeper;
 pub mod status;
+pub(crate) mod wire_format;
 
 pub use agent_config::AgentConfig;
 pub use agent_error::AgentError;
-use async_lock::Semaphore;
+pub use asset::Asset;
 pub use builder::AgentBuilder;
-use cached::{Cached, TimedCache};
+use cached::{Cached, TimedCache, TimedSizedCache};
+use candid::{Decode, Encode, IDLArgs, IDLValue};
+pub use cbor_limits::CborLimits;
+pub use certificate_header::CertificateHeader;
+pub use clock::{Clock, WallClock};
+pub use detached::{attach_signature, request_id_to_sign};
 use ed25519_consensus::{Error as Ed25519Error, Signature, VerificationKey};
+pub use futures_util::future::AbortHandle;
+use http::{HeaderMap, HeaderValue};
 #[doc(inline)]
 pub use ic_transport_types::{
     signed, CallResponse, Envelope, EnvelopeContent, RejectCode, RejectResponse, ReplyResponse,
     RequestStatusResponse,
 };
+pub use latest_only::LatestOnlyQuery;
 pub use nonce::{NonceFactory, NonceGenerator};
+pub use observer::AgentObserver;
+use pending_call::PendingCalls;
+pub use priority::Priority;
+use priority::PriorityLimiter;
 use rangemap::{RangeInclusiveMap, RangeInclusiveSet, StepFns};
+pub use retry_policy::RetryPolicy;
+pub use sleeper::{DefaultSleeper, SleepFuture, Sleeper};
 use time::OffsetDateTime;
+pub use wire_format::{Cbor, WireFormat};
 
 #[cfg(test)]
 mod agent_test;
 
 use crate::{
     agent::response_authentication::{
-        extract_der, lookup_canister_info, lookup_canister_metadata, lookup_request_status,
-        lookup_subnet, lookup_subnet_metrics, lookup_time, lookup_value,
+        extract_der, lookup_canister_controllers, lookup_canister_info, lookup_canister_metadata,
+        lookup_canister_module_hash, lookup_request_status, lookup_subnet, lookup_subnet_info,
+        lookup_subnet_metrics, lookup_time, lookup_value,
     },
     export::Principal,
     identity::Identity,
@@ -36,12 +69,16 @@ use crate::{
 };
 use backoff::{backoff::Backoff, ExponentialBackoffBuilder};
 use backoff::{exponential::ExponentialBackoff, SystemClock};
+use futures_util::{
+    future::{self, try_join_all},
+    stream, Stream, StreamExt,
+};
 use ic_certification::{Certificate, Delegation, Label};
 use ic_transport_types::{
     signed::{SignedQuery, SignedRequestStatus, SignedUpdate},
     QueryResponse, ReadStateResponse, SubnetMetrics, TransportCallResponse,
 };
-use serde::Serialize;
+use sha2::{Digest, Sha256};
 use status::Status;
 use std::{
     borrow::Cow,
@@ -52,14 +89,16 @@ use std::{
     pin::Pin,
     sync::{Arc, Mutex, RwLock},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::agent::response_authentication::lookup_api_boundary_nodes;
 
-const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8; 14] = b"\x0Dic-state-root";
+const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+pub(crate) const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8; 14] = b"\x0Dic-state-root";
 
-const IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
+pub(crate) const IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
 
 #[cfg(not(target_family = "wasm"))]
 type AgentFuture<'a, V> = Pin<Box<dyn Future<Output = Result<V, AgentError>> + Send + 'a>>;
@@ -108,10 +147,78 @@ pub trait Transport: Send + Sync {
     /// This normally corresponds to the `/api/v2/canister/<effective_canister_id>/query` endpoint.
     fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>>;
 
+    /// Sends a synchronous request to a replica, like [`query`](Transport::query), additionally
+    /// attaching `headers` to this request only.
+    ///
+    /// The default implementation ignores `headers` and calls [`query`](Transport::query).
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        let _ = headers;
+        self.query(effective_canister_id, envelope)
+    }
+
+    /// Sends a synchronous request to a replica, like
+    /// [`query_with_headers`](Transport::query_with_headers), retrying it as `retry_policy` says
+    /// rather than as the transport is configured to.
+    ///
+    /// The default implementation ignores `retry_policy` and calls
+    /// [`query_with_headers`](Transport::query_with_headers).
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        let _ = retry_policy;
+        self.query_with_headers(effective_canister_id, envelope, headers)
+    }
+
+    /// Sends a call request to a replica, like [`call`](Transport::call), additionally attaching
+    /// `headers` to this request only.
+    ///
+    /// The default implementation ignores `headers` and calls [`call`](Transport::call).
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        let _ = headers;
+        self.call(effective_canister_id, envelope)
+    }
+
+    /// Sends a call request to a replica, like [`call_with_headers`](Transport::call_with_headers),
+    /// retrying it as `retry_policy` says rather than as the transport is configured to.
+    ///
+    /// The default implementation ignores `retry_policy` and calls
+    /// [`call_with_headers`](Transport::call_with_headers).
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        let _ = retry_policy;
+        self.call_with_headers(effective_canister_id, envelope, headers)
+    }
+
     /// Sends a status request to the replica, returning whatever the replica returns.
     /// In the current spec v2, this is a CBOR encoded status message, but we are not
     /// making this API attach semantics to the response.
     fn status(&self) -> AgentFuture<Vec<u8>>;
+
+    /// Returns whether requests go to the Internet Computer mainnet rather than to a local or
+    /// test replica, e.g. to refuse destructive operations. The default implementation returns
+    /// `false`.
+    fn is_mainnet(&self) -> bool {
+        false
+    }
 }
 
 impl<I: Transport + ?Sized> Transport for Box<I> {
@@ -133,12 +240,49 @@ impl<I: Transport + ?Sized> Transport for Box<I> {
     fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         (**self).query(effective_canister_id, envelope)
     }
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        (**self).query_with_headers(effective_canister_id, envelope, headers)
+    }
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        (**self).query_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        (**self).call_with_headers(effective_canister_id, envelope, headers)
+    }
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        (**self).call_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
     fn status(&self) -> AgentFuture<Vec<u8>> {
         (**self).status()
     }
     fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         (**self).read_subnet_state(subnet_id, envelope)
     }
+    fn is_mainnet(&self) -> bool {
+        (**self).is_mainnet()
+    }
 }
 impl<I: Transport + ?Sized> Transport for Arc<I> {
     fn call(
@@ -158,12 +302,49 @@ impl<I: Transport + ?Sized> Transport for Arc<I> {
     fn query(&self, effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         (**self).query(effective_canister_id, envelope)
     }
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        (**self).query_with_headers(effective_canister_id, envelope, headers)
+    }
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<Vec<u8>> {
+        (**self).query_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<TransportCallResponse> {
+        (**self).call_with_headers(effective_canister_id, envelope, headers)
+    }
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> AgentFuture<TransportCallResponse> {
+        (**self).call_with_retry_policy(effective_canister_id, envelope, headers, retry_policy)
+    }
     fn status(&self) -> AgentFuture<Vec<u8>> {
         (**self).status()
     }
     fn read_subnet_state(&self, subnet_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
         (**self).read_subnet_state(subnet_id, envelope)
     }
+    fn is_mainnet(&self) -> bool {
+        (**self).is_mainnet()
+    }
 }
 
 /// A low level Agent to make calls to a Replica endpoint.
@@ -236,12 +417,27 @@ impl<I: Transport + ?Sized> Transport for Arc<I> {
 pub struct Agent {
     nonce_factory: Arc<dyn NonceGenerator>,
     identity: Arc<dyn Identity>,
+    fallback_identity: Option<Arc<dyn Identity>>,
     ingress_expiry: Duration,
+    max_cert_age: Duration,
     root_key: Arc<RwLock<Vec<u8>>>,
+    root_key_fetched_at: Arc<Mutex<Option<OffsetDateTime>>>,
+    clock: Arc<dyn Clock>,
+    sleeper: Arc<dyn Sleeper>,
     transport: Arc<dyn Transport>,
+    verification_key_source: Option<Arc<dyn Transport>>,
     subnet_key_cache: Arc<Mutex<SubnetCache>>,
-    concurrent_requests_semaphore: Arc<Semaphore>,
+    concurrency_limiter: Arc<PriorityLimiter>,
+    max_read_state_paths: usize,
+    canister_aliases: Arc<HashMap<String, Principal>>,
+    canister_info_cache: Option<Arc<Mutex<CanisterInfoCache>>>,
+    default_to_anonymous: bool,
     verify_query_signatures: bool,
+    cbor_limits: CborLimits,
+    wire_format: Arc<dyn WireFormat>,
+    observer: Option<Arc<dyn AgentObserver>>,
+    request_senders: Arc<Mutex<TimedSizedCache<RequestId, Principal>>>,
+    pending_calls: Arc<PendingCalls>,
 }
 
 impl fmt::Debug for Agent {
@@ -264,17 +460,61 @@ impl Agent {
         Ok(Agent {
             nonce_factory: config.nonce_factory,
             identity: config.identity,
+            fallback_identity: config.fallback_identity,
             ingress_expiry: config.ingress_expiry.unwrap_or(DEFAULT_INGRESS_EXPIRY),
+            max_cert_age: config.max_cert_age,
             root_key: Arc::new(RwLock::new(IC_ROOT_KEY.to_vec())),
+            root_key_fetched_at: Arc::new(Mutex::new(None)),
+            clock: config.clock,
+            sleeper: config.sleeper,
             transport: config
                 .transport
                 .ok_or_else(AgentError::MissingReplicaTransport)?,
+            verification_key_source: config.verification_key_source,
             subnet_key_cache: Arc::new(Mutex::new(SubnetCache::new())),
             verify_query_signatures: config.verify_query_signatures,
-            concurrent_requests_semaphore: Arc::new(Semaphore::new(config.max_concurrent_requests)),
+            concurrency_limiter: Arc::new(PriorityLimiter::new(config.max_concurrent_requests)),
+            max_read_state_paths: config.max_read_state_paths,
+            canister_aliases: Arc::new(config.canister_aliases),
+            canister_info_cache: config
+                .canister_info_cache_ttl
+                .map(|ttl| Arc::new(Mutex::new(CanisterInfoCache::new(ttl)))),
+            default_to_anonymous: config.default_to_anonymous,
+            cbor_limits: config.cbor_limits,
+            wire_format: config.wire_format.unwrap_or_else(|| Arc::new(Cbor)),
+            observer: config.observer,
+            request_senders: Arc::new(Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                REQUEST_SENDER_CAPACITY,
+                REQUEST_SENDER_LIFESPAN.as_secs(),
+            ))),
+            pending_calls: Arc::new(PendingCalls::default()),
         })
     }
 
+    /// Create an [`Agent`] configured by environment variables, talking to its URL with a
+    /// [`ReqwestTransport`](http_transport::ReqwestTransport):
+    ///
+    /// - `IC_URL`: the URL of the replica or boundary node, e.g. `https://icp-api.io`. Required.
+    /// - `IC_IDENTITY_PEM`: the PEM-encoded Ed25519, secp256k1 or P-256 private key of the identity
+    ///   to sign requests with. Requests are anonymous if it is not set.
+    /// - `IC_PROXY`: the URL of a proxy to send all requests through. If it is not set, the usual
+    ///   `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` variables are honored.
+    /// - `IC_TIMEOUT_SECS`: the timeout of each HTTP request, in seconds. Defaults to 360.
+    ///
+    /// A missing `IC_URL`, or a value that is malformed or not valid Unicode, fails with
+    /// [`AgentError::InvalidEnvironmentVariable`], which names the variable. The root key is not
+    /// fetched; call [`fetch_root_key`](Self::fetch_root_key) for a local replica.
+    #[cfg(all(feature = "reqwest", feature = "pem", not(target_family = "wasm")))]
+    pub fn from_env() -> Result<Agent, AgentError> {
+        env::EnvSettings::read(|name| std::env::var(name))?.into_agent()
+    }
+
+    /// Returns whether the agent's transport [points at mainnet](Transport::is_mainnet), e.g. to
+    /// refuse calls only meant for local and test replicas.
+    pub fn is_mainnet(&self) -> bool {
+        self.transport.is_mainnet()
+    }
+
     /// Set the transport of the [`Agent`].
     pub fn set_transport<F: 'static + Transport>(&mut self, transport: F) {
         self.transport = Arc::new(transport);
@@ -308,7 +548,12 @@ impl Agent {
     ///
     /// *Only use this when you are  _not_ talking to the main Internet Computer, otherwise
     /// you are prone to man-in-the-middle attacks! Do not call this function by default.*
+    /// If the transport [points at mainnet](Transport::is_mainnet), this fails with
+    /// [`AgentError::FetchRootKeyOnMainnet`].
     pub async fn fetch_root_key(&self) -> Result<(), AgentError> {
+        if self.transport.is_mainnet() {
+            return Err(AgentError::FetchRootKeyOnMainnet);
+        }
         if self.read_root_key()[..] != IC_ROOT_KEY[..] {
             // already fetched the root key
             return Ok(());
@@ -322,6 +567,35 @@ impl Agent {
         Ok(())
     }
 
+    /// Like [`fetch_root_key`](Self::fetch_root_key), but fetches the key again once `ttl` has
+    /// passed since it was last fetched, e.g. to pick up the new key of a local replica that was
+    /// reset. Time is measured with the agent's [clock](AgentBuilder::with_clock).
+    ///
+    /// If a certificate fails verification against the fetched key, the key is considered stale,
+    /// and the next call fetches it again whatever the `ttl`.
+    ///
+    /// *Only use this when you are  _not_ talking to the main Internet Computer, otherwise
+    /// you are prone to man-in-the-middle attacks! Do not call this function by default.*
+    /// Like `fetch_root_key`, this fails on mainnet.
+    pub async fn fetch_root_key_cached(&self, ttl: Duration) -> Result<(), AgentError> {
+        if self.transport.is_mainnet() {
+            return Err(AgentError::FetchRootKeyOnMainnet);
+        }
+        let now = self.clock.now();
+        if matches!(*self.root_key_fetched_at.lock().unwrap(), Some(fetched_at) if now < fetched_at + ttl)
+        {
+            return Ok(());
+        }
+        let status = self.status().await?;
+        let root_key = match status.root_key {
+            Some(key) => key,
+            None => return Err(AgentError::NoRootKeyInStatus(status)),
+        };
+        self.set_root_key(root_key);
+        *self.root_key_fetched_at.lock().unwrap() = Some(now);
+        Ok(())
+    }
+
     /// By default, the agent is configured to talk to the main Internet Computer, and verifies
     /// responses using a hard-coded public key.
     ///
@@ -330,6 +604,12 @@ impl Agent {
         *self.root_key.write().unwrap() = root_key;
     }
 
+    /// Returns the sleeper the agent waits with, set with [`AgentBuilder::with_sleeper`], for code
+    /// built on the agent that waits between attempts of its own.
+    pub fn sleeper(&self) -> &Arc<dyn Sleeper> {
+        &self.sleeper
+    }
+
     /// Return the root key currently in use.
     pub fn read_root_key(&self) -> Vec<u8> {
         self.root_key.read().unwrap().clone()
@@ -344,68 +624,176 @@ impl Agent {
         rounded.unix_timestamp_nanos() as u64
     }
 
-    /// Return the principal of the identity.
-    pub fn get_principal(&self) -> Result<Principal, String> {
-        self.identity.sender()
+    /// Return the principal the agent sends requests as, i.e. the sender of its identity. This is the
+    /// anonymous principal if no identity was configured.
+    ///
+    /// Fails with [`AgentError::SigningError`] if the identity cannot tell its principal, e.g. a
+    /// hardware key that is not connected.
+    pub fn get_principal(&self) -> Result<Principal, AgentError> {
+        self.identity.sender().map_err(AgentError::SigningError)
+    }
+
+    /// Returns the size in bytes of the envelope of an update call to `method_name` of
+    /// `canister_id` with `arg`, as it would be sent: signed by the identity and encoded in the
+    /// agent's wire format. Nothing is sent.
+    ///
+    /// This lets a caller check a call against the ingress message size limit before making it.
+    /// The identity is asked to sign the envelope, so this fails as signing would, e.g. with
+    /// [`AgentError::SigningDeclined`].
+    pub fn envelope_size<S: Into<String>>(
+        &self,
+        canister_id: &Principal,
+        method_name: S,
+        arg: &[u8],
+    ) -> Result<usize, AgentError> {
+        let content = self.update_content(
+            *canister_id,
+            method_name.into(),
+            arg.to_vec(),
+            None,
+            self.nonce_factory.generate(),
+        )?;
+        Ok(self.sign_envelope(&content)?.len())
+    }
+
+    fn sign_envelope(&self, content: &EnvelopeContent) -> Result<Vec<u8>, AgentError> {
+        let signature = self.sign(content)?;
+
+        let envelope = Envelope {
+            content: Cow::Borrowed(content),
+            sender_pubkey: signature.public_key,
+            sender_sig: signature.signature,
+            sender_delegation: signature.delegations,
+        };
+
+        self.wire_format.encode_envelope(&envelope)
+    }
+
+    /// Signs `content` with the identity, or with the fallback identity if that fails for any reason
+    /// other than the signature being declined.
+    fn sign(&self, content: &EnvelopeContent) -> Result<crate::Signature, AgentError> {
+        let error = match self.identity.sign(content) {
+            Ok(signature) => return Ok(signature),
+            Err(error) if self.identity.is_declined(&error) => {
+                return Err(AgentError::SigningDeclined(error))
+            }
+            Err(error) => error,
+        };
+        let Some(fallback) = &self.fallback_identity else {
+            return Err(AgentError::SigningError(error));
+        };
+        if fallback.sender().map_err(AgentError::SigningError)? != *content.sender() {
+            return Err(AgentError::SigningError(format!(
+                "{error}; the fallback identity cannot sign instead, as it signs as a different principal"
+            )));
+        }
+        let signature = fallback.sign(content).map_err(AgentError::SigningError)?;
+        if let Some(observer) = &self.observer {
+            observer.on_identity_fallback(&error);
+        }
+        Ok(signature)
     }
 
-    async fn query_endpoint<A>(
+    async fn query_endpoint(
         &self,
         effective_canister_id: Principal,
         serialized_bytes: Vec<u8>,
-    ) -> Result<A, AgentError>
-    where
-        A: serde::de::DeserializeOwned,
-    {
-        let _permit = self.concurrent_requests_semaphore.acquire().await;
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        priority: Priority,
+    ) -> Result<QueryResponse, AgentError> {
+        let _permit = self.concurrency_limiter.acquire(priority).await;
         let bytes = self
             .transport
-            .query(effective_canister_id, serialized_bytes)
+            .query_with_retry_policy(
+                effective_canister_id,
+                serialized_bytes,
+                headers,
+                retry_policy,
+            )
             .await?;
-        serde_cbor::from_slice(&bytes).map_err(AgentError::InvalidCborData)
+        self.wire_format
+            .decode_query_response(&bytes, &self.cbor_limits)
     }
 
-    async fn read_state_endpoint<A>(
+    async fn read_state_endpoint(
         &self,
         effective_canister_id: Principal,
         serialized_bytes: Vec<u8>,
-    ) -> Result<A, AgentError>
-    where
-        A: serde::de::DeserializeOwned,
-    {
-        let _permit = self.concurrent_requests_semaphore.acquire().await;
-        let bytes = self
-            .transport
+    ) -> Result<ReadStateResponse, AgentError> {
+        self.read_state_endpoint_via(&*self.transport, effective_canister_id, serialized_bytes)
+            .await
+    }
+
+    async fn read_state_endpoint_via(
+        &self,
+        transport: &dyn Transport,
+        effective_canister_id: Principal,
+        serialized_bytes: Vec<u8>,
+    ) -> Result<ReadStateResponse, AgentError> {
+        let _permit = self.concurrency_limiter.acquire(Priority::Normal).await;
+        let bytes = transport
             .read_state(effective_canister_id, serialized_bytes)
             .await?;
-        serde_cbor::from_slice(&bytes).map_err(AgentError::InvalidCborData)
+        self.wire_format
+            .decode_read_state_response(&bytes, &self.cbor_limits)
     }
 
-    async fn read_subnet_state_endpoint<A>(
+    async fn read_subnet_state_endpoint(
         &self,
         subnet_id: Principal,
         serialized_bytes: Vec<u8>,
-    ) -> Result<A, AgentError>
-    where
-        A: serde::de::DeserializeOwned,
-    {
-        let _permit = self.concurrent_requests_semaphore.acquire().await;
+    ) -> Result<ReadStateResponse, AgentError> {
+        let _permit = self.concurrency_limiter.acquire(Priority::Normal).await;
         let bytes = self
             .transport
             .read_subnet_state(subnet_id, serialized_bytes)
             .await?;
-        serde_cbor::from_slice(&bytes).map_err(AgentError::InvalidCborData)
+        self.wire_format
+            .decode_read_state_response(&bytes, &self.cbor_limits)
     }
 
     async fn call_endpoint(
         &self,
         effective_canister_id: Principal,
         serialized_bytes: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        priority: Priority,
     ) -> Result<TransportCallResponse, AgentError> {
-        let _permit = self.concurrent_requests_semaphore.acquire().await;
-        self.transport
-            .call(effective_canister_id, serialized_bytes)
-            .await
+        let _permit = self.concurrency_limiter.acquire(priority).await;
+        self.invalidate_canister(effective_canister_id);
+        let response = self
+            .transport
+            .call_with_retry_policy(
+                effective_canister_id,
+                serialized_bytes,
+                headers,
+                retry_policy,
+            )
+            .await;
+        // values read while the call was in flight may predate its effects
+        if let Ok(TransportCallResponse::Replied { .. }) = &response {
+            self.invalidate_canister(effective_canister_id);
+        }
+        response
+    }
+
+    /// Drops the cached values of `effective_canister_id` once a call to it has finished, as
+    /// values read while the call was in flight may predate its effects.
+    fn invalidate_if_finished(
+        &self,
+        effective_canister_id: Principal,
+        status: &RequestStatusResponse,
+    ) {
+        if matches!(
+            status,
+            RequestStatusResponse::Replied(_)
+                | RequestStatusResponse::Rejected(_)
+                | RequestStatusResponse::Done
+        ) {
+            self.invalidate_canister(effective_canister_id);
+        }
     }
 
     /// The simplest way to do a query call; sends a byte array and will return a byte vector.
@@ -420,6 +808,10 @@ impl Agent {
         ingress_expiry_datetime: Option<u64>,
         use_nonce: bool,
         explicit_verify_query_signatures: Option<bool>,
+        headers: HeaderMap,
+        sender: Option<Principal>,
+        retry_policy: RetryPolicy,
+        priority: Priority,
     ) -> Result<Vec<u8>, AgentError> {
         let content = self.query_content(
             canister_id,
@@ -427,13 +819,17 @@ impl Agent {
             arg,
             ingress_expiry_datetime,
             use_nonce,
+            sender,
         )?;
-        let serialized_bytes = sign_envelope(&content, self.identity.clone())?;
+        let serialized_bytes = self.sign_envelope(&content)?;
         self.query_inner(
             effective_canister_id,
             serialized_bytes,
             content.to_request_id(),
             explicit_verify_query_signatures,
+            headers,
+            retry_policy,
+            priority,
         )
         .await
     }
@@ -446,13 +842,15 @@ impl Agent {
         effective_canister_id: Principal,
         signed_query: Vec<u8>,
     ) -> Result<Vec<u8>, AgentError> {
-        let envelope: Envelope =
-            serde_cbor::from_slice(&signed_query).map_err(AgentError::InvalidCborData)?;
+        let envelope = self.wire_format.decode_envelope(&signed_query)?;
         self.query_inner(
             effective_canister_id,
             signed_query,
             envelope.content.to_request_id(),
             None,
+            HeaderMap::new(),
+            RetryPolicy::Transport,
+            Priority::Normal,
         )
         .await
     }
@@ -460,16 +858,26 @@ impl Agent {
     /// Helper function for performing both the query call and possibly a read_state to check the subnet node keys.
     ///
     /// This should be used instead of `query_endpoint`. No validation is performed on `signed_query`.
+    #[allow(clippy::too_many_arguments)]
     async fn query_inner(
         &self,
         effective_canister_id: Principal,
         signed_query: Vec<u8>,
         request_id: RequestId,
         explicit_verify_query_signatures: Option<bool>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        priority: Priority,
     ) -> Result<Vec<u8>, AgentError> {
         let response = if explicit_verify_query_signatures.unwrap_or(self.verify_query_signatures) {
             let (response, mut subnet) = futures_util::try_join!(
-                self.query_endpoint::<QueryResponse>(effective_canister_id, signed_query),
+                self.query_endpoint(
+                    effective_canister_id,
+                    signed_query,
+                    headers,
+                    retry_policy,
+                    priority
+                ),
                 self.get_subnet_by_canister(&effective_canister_id)
             )?;
             if response.signatures().is_empty() {
@@ -481,7 +889,7 @@ impl Agent {
                 });
             }
             for signature in response.signatures() {
-                if OffsetDateTime::now_utc()
+                if self.clock.now()
                     - OffsetDateTime::from_unix_timestamp_nanos(signature.timestamp as _).unwrap()
                     > self.ingress_expiry
                 {
@@ -536,8 +944,14 @@ impl Agent {
             }
             response
         } else {
-            self.query_endpoint::<QueryResponse>(effective_canister_id, signed_query)
-                .await?
+            self.query_endpoint(
+                effective_canister_id,
+                signed_query,
+                headers,
+                retry_policy,
+                priority,
+            )
+            .await?
         };
 
         match response {
@@ -553,9 +967,14 @@ impl Agent {
         arg: Vec<u8>,
         ingress_expiry_datetime: Option<u64>,
         use_nonce: bool,
+        sender: Option<Principal>,
     ) -> Result<EnvelopeContent, AgentError> {
+        let sender = match sender {
+            Some(sender) => sender,
+            None => self.identity.sender().map_err(AgentError::SigningError)?,
+        };
         Ok(EnvelopeContent::Query {
-            sender: self.identity.sender().map_err(AgentError::SigningError)?,
+            sender,
             canister_id,
             method_name,
             arg,
@@ -565,6 +984,14 @@ impl Agent {
     }
 
     /// The simplest way to do an update call; sends a byte array and will return a response, [`CallResponse`], from the replica.
+    ///
+    /// Calls are submitted at most once. If an earlier identical call was abandoned before the agent
+    /// learned whether the replica received it, because its future was dropped or the transport
+    /// failed, this call takes it over instead of submitting a new message: it polls the earlier
+    /// call's status, and if the replica has not seen it, submits its original envelope again,
+    /// which the replica recognizes by its request id should the first submission still arrive.
+    /// Unless `resume_abandoned` is set, a new call is always submitted.
+    #[allow(clippy::too_many_arguments)]
     async fn update_raw(
         &self,
         canister_id: Principal,
@@ -572,6 +999,10 @@ impl Agent {
         method_name: String,
         arg: Vec<u8>,
         ingress_expiry_datetime: Option<u64>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+        priority: Priority,
+        resume_abandoned: bool,
     ) -> Result<CallResponse<Vec<u8>>, AgentError> {
         let nonce = self.nonce_factory.generate();
         let content = self.update_content(
@@ -581,56 +1012,117 @@ impl Agent {
             ingress_expiry_datetime,
             nonce,
         )?;
-        let request_id = to_request_id(&content)?;
-        let serialized_bytes = sign_envelope(&content, self.identity.clone())?;
+        let now = self.clock.now().unix_timestamp_nanos() as u64;
+        let claimed = resume_abandoned
+            .then(|| {
+                self.pending_calls
+                    .claim(effective_canister_id, &content, now)
+            })
+            .flatten();
+        let (request_id, serialized_bytes, submission) = match claimed {
+            Some((pending, submission)) => {
+                let status = self
+                    .request_status_raw(&pending.request_id, effective_canister_id)
+                    .await;
+                match status {
+                    // if the status could not be read, the envelope is submitted again anyway
+                    Ok(RequestStatusResponse::Unknown) | Err(_) => {}
+                    Ok(status) => {
+                        let response = self.update_status_response(&pending.request_id, status);
+                        submission.finish(&response);
+                        return response;
+                    }
+                }
+                (pending.request_id, pending.envelope, Some(submission))
+            }
+            None => {
+                let request_id = to_request_id(&content)?;
+                self.record_sender(request_id, &content);
+                let serialized_bytes = self.sign_envelope(&content)?;
+                let submission = self.pending_calls.start(
+                    effective_canister_id,
+                    &content,
+                    request_id,
+                    &serialized_bytes,
+                );
+                (request_id, serialized_bytes, submission)
+            }
+        };
 
         let response_body = self
-            .call_endpoint(effective_canister_id, serialized_bytes)
-            .await?;
+            .call_endpoint(
+                effective_canister_id,
+                serialized_bytes,
+                headers,
+                retry_policy,
+                priority,
+            )
+            .await;
+        if let Some(submission) = submission {
+            submission.finish(&response_body);
+        }
+        let response_body =
+            response_body.map_err(|err| self.observe_rejection(&request_id, err))?;
 
         match response_body {
             TransportCallResponse::Replied { certificate } => {
-                let certificate =
-                    serde_cbor::from_slice(&certificate).map_err(AgentError::InvalidCborData)?;
+                let certificate = self.cbor_limits.decode(&certificate)?;
 
                 self.verify(&certificate, effective_canister_id)?;
                 let status = lookup_request_status(certificate, &request_id)?;
-
-                match status {
-                    RequestStatusResponse::Replied(reply) => Ok(CallResponse::Response(reply.arg)),
-                    RequestStatusResponse::Rejected(reject_response) => {
-                        Err(AgentError::CertifiedReject(reject_response))?
-                    }
-                    _ => Ok(CallResponse::Poll(request_id)),
-                }
+                self.update_status_response(&request_id, status)
             }
             TransportCallResponse::Accepted => Ok(CallResponse::Poll(request_id)),
             TransportCallResponse::NonReplicatedRejection(reject_response) => {
+                self.notify_rejection(&request_id, &reject_response);
                 Err(AgentError::UncertifiedReject(reject_response))
             }
         }
     }
 
+    /// The response to an update call whose status is `status`.
+    fn update_status_response(
+        &self,
+        request_id: &RequestId,
+        status: RequestStatusResponse,
+    ) -> Result<CallResponse<Vec<u8>>, AgentError> {
+        match status {
+            RequestStatusResponse::Replied(reply) => Ok(CallResponse::Response(reply.arg)),
+            RequestStatusResponse::Rejected(reject_response) => {
+                self.notify_rejection(request_id, &reject_response);
+                Err(AgentError::CertifiedReject(reject_response))
+            }
+            _ => Ok(CallResponse::Poll(*request_id)),
+        }
+    }
+
     /// Send the signed update to the network. Will return a [`CallResponse<Vec<u8>>`].
     /// The bytes will be checked to verify that it is a valid update.
     /// If you want to inspect the fields of the update, use [`signed_update_inspect`] before calling this method.
+    ///
+    /// The request is retried as the transport is configured to ([`RetryPolicy::Transport`]).
     pub async fn update_signed(
         &self,
         effective_canister_id: Principal,
         signed_update: Vec<u8>,
     ) -> Result<CallResponse<Vec<u8>>, AgentError> {
-        let envelope: Envelope =
-            serde_cbor::from_slice(&signed_update).map_err(AgentError::InvalidCborData)?;
+        let envelope = self.wire_format.decode_envelope(&signed_update)?;
         let request_id = to_request_id(&envelope.content)?;
 
         let response_body = self
-            .call_endpoint(effective_canister_id, signed_update)
-            .await?;
+            .call_endpoint(
+                effective_canister_id,
+                signed_update,
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+                Priority::Normal,
+            )
+            .await
+            .map_err(|err| self.observe_rejection(&request_id, err))?;
 
         match response_body {
             TransportCallResponse::Replied { certificate } => {
-                let certificate =
-                    serde_cbor::from_slice(&certificate).map_err(AgentError::InvalidCborData)?;
+                let certificate = self.cbor_limits.decode(&certificate)?;
 
                 self.verify(&certificate, effective_canister_id)?;
                 let status = lookup_request_status(certificate, &request_id)?;
@@ -638,6 +1130,7 @@ impl Agent {
                 match status {
                     RequestStatusResponse::Replied(reply) => Ok(CallResponse::Response(reply.arg)),
                     RequestStatusResponse::Rejected(reject_response) => {
+                        self.notify_rejection(&request_id, &reject_response);
                         Err(AgentError::CertifiedReject(reject_response))?
                     }
                     _ => Ok(CallResponse::Poll(request_id)),
@@ -645,11 +1138,26 @@ impl Agent {
             }
             TransportCallResponse::Accepted => Ok(CallResponse::Poll(request_id)),
             TransportCallResponse::NonReplicatedRejection(reject_response) => {
+                self.notify_rejection(&request_id, &reject_response);
                 Err(AgentError::UncertifiedReject(reject_response))
             }
         }
     }
 
+    fn notify_rejection(&self, request_id: &RequestId, reject: &RejectResponse) {
+        if let Some(observer) = &self.observer {
+            observer.on_rejection(request_id, reject.reject_code, &reject.reject_message);
+        }
+    }
+
+    /// Notifies the observer if the transport reported a rejection as an error.
+    fn observe_rejection(&self, request_id: &RequestId, err: AgentError) -> AgentError {
+        if let AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) = &err {
+            self.notify_rejection(request_id, reject);
+        }
+        err
+    }
+
     fn update_content(
         &self,
         canister_id: Principal,
@@ -658,12 +1166,16 @@ impl Agent {
         ingress_expiry_datetime: Option<u64>,
         nonce: Option<Vec<u8>>,
     ) -> Result<EnvelopeContent, AgentError> {
+        let sender = self.identity.sender().map_err(AgentError::SigningError)?;
+        if !self.default_to_anonymous && sender == Principal::anonymous() {
+            return Err(AgentError::IdentityRequired);
+        }
         Ok(EnvelopeContent::Call {
             canister_id,
             method_name,
             arg,
             nonce,
-            sender: self.identity.sender().map_err(AgentError::SigningError)?,
+            sender,
             ingress_expiry: ingress_expiry_datetime.unwrap_or_else(|| self.get_expiry_date()),
         })
     }
@@ -688,14 +1200,14 @@ impl Agent {
 
         let mut request_accepted = false;
         loop {
-            match self
+            let status = self
                 .request_status_signed(
                     request_id,
                     effective_canister_id,
                     signed_request_status.clone(),
                 )
-                .await?
-            {
+                .await;
+            match unknown_if_pruned(status, request_id)? {
                 RequestStatusResponse::Unknown => {}
 
                 RequestStatusResponse::Received | RequestStatusResponse::Processing => {
@@ -708,7 +1220,8 @@ impl Agent {
                 RequestStatusResponse::Replied(ReplyResponse { arg, .. }) => return Ok(arg),
 
                 RequestStatusResponse::Rejected(response) => {
-                    return Err(AgentError::CertifiedReject(response))
+                    self.notify_rejection(request_id, &response);
+                    return Err(AgentError::CertifiedReject(response));
                 }
 
                 RequestStatusResponse::Done => {
@@ -719,7 +1232,7 @@ impl Agent {
             };
 
             match retry_policy.next_backoff() {
-                Some(duration) => crate::util::sleep(duration).await,
+                Some(duration) => self.sleeper.sleep(duration).await,
 
                 None => return Err(AgentError::TimeoutWaitingForResponse()),
             }
@@ -736,10 +1249,10 @@ impl Agent {
 
         let mut request_accepted = false;
         loop {
-            match self
+            let status = self
                 .request_status_raw(request_id, effective_canister_id)
-                .await?
-            {
+                .await;
+            match unknown_if_pruned(status, request_id)? {
                 RequestStatusResponse::Unknown => {}
 
                 RequestStatusResponse::Received | RequestStatusResponse::Processing => {
@@ -759,7 +1272,8 @@ impl Agent {
                 RequestStatusResponse::Replied(ReplyResponse { arg, .. }) => return Ok(arg),
 
                 RequestStatusResponse::Rejected(response) => {
-                    return Err(AgentError::CertifiedReject(response))
+                    self.notify_rejection(request_id, &response);
+                    return Err(AgentError::CertifiedReject(response));
                 }
 
                 RequestStatusResponse::Done => {
@@ -770,28 +1284,150 @@ impl Agent {
             };
 
             match retry_policy.next_backoff() {
-                Some(duration) => crate::util::sleep(duration).await,
+                Some(duration) => self.sleeper.sleep(duration).await,
 
                 None => return Err(AgentError::TimeoutWaitingForResponse()),
             }
         }
     }
 
+    /// Makes an update call to `canister_id`, then polls its status, yielding each status the call
+    /// goes through, e.g. to show its progress live.
+    ///
+    /// Once the call is accepted, [`RequestStatusResponse::Received`] is yielded, followed by each
+    /// status read that differs from the last one yielded. The stream ends after yielding
+    /// [`Replied`](RequestStatusResponse::Replied), [`Rejected`](RequestStatusResponse::Rejected), or
+    /// [`Done`](RequestStatusResponse::Done), or after yielding an error. If the replica answers the
+    /// call synchronously, only its reply is yielded.
+    ///
+    /// This is equivalent to `agent.update(canister_id, method_name).with_arg(arg).call_and_watch()`;
+    /// use [`UpdateBuilder::call_and_watch`] to set the effective canister id or other options.
+    pub fn call_and_watch<'a>(
+        &'a self,
+        canister_id: &Principal,
+        method_name: impl Into<String>,
+        arg: Vec<u8>,
+    ) -> impl Stream<Item = Result<RequestStatusResponse, AgentError>> + 'a {
+        self.update(canister_id, method_name)
+            .with_arg(arg)
+            .call_and_watch()
+    }
+
+    /// Yields `Received` for an accepted call, then each new status returned by `poll`, backing off
+    /// between polls like [`wait`](Self::wait).
+    fn watch_request_status<F, Fut>(
+        sleeper: Arc<dyn Sleeper>,
+        poll: F,
+    ) -> impl Stream<Item = Result<RequestStatusResponse, AgentError>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<RequestStatusResponse, AgentError>>,
+    {
+        struct Watch<F> {
+            sleeper: Arc<dyn Sleeper>,
+            poll: F,
+            last: RequestStatusResponse,
+            retry_policy: ExponentialBackoff<SystemClock>,
+            request_accepted: bool,
+        }
+        let watch = Watch {
+            sleeper,
+            poll,
+            last: RequestStatusResponse::Received,
+            retry_policy: Self::get_retry_policy(),
+            request_accepted: false,
+        };
+        let statuses = stream::unfold(Some(watch), |watch| async move {
+            let mut watch = watch?;
+            loop {
+                match watch.retry_policy.next_backoff() {
+                    Some(duration) => watch.sleeper.sleep(duration).await,
+                    None => return Some((Err(AgentError::TimeoutWaitingForResponse()), None)),
+                }
+                let status = match (watch.poll)().await {
+                    Ok(status) => status,
+                    Err(err) => return Some((Err(err), None)),
+                };
+                match status {
+                    RequestStatusResponse::Unknown => {}
+                    RequestStatusResponse::Received | RequestStatusResponse::Processing => {
+                        if !watch.request_accepted {
+                            // as in `wait`, give the call the full timeout once it is accepted
+                            watch.retry_policy.reset();
+                            watch.request_accepted = true;
+                        }
+                        if status != watch.last {
+                            watch.last = status.clone();
+                            return Some((Ok(status), Some(watch)));
+                        }
+                    }
+                    RequestStatusResponse::Replied(_)
+                    | RequestStatusResponse::Rejected(_)
+                    | RequestStatusResponse::Done => return Some((Ok(status), None)),
+                }
+            }
+        });
+        stream::once(future::ready(Ok(RequestStatusResponse::Received))).chain(statuses)
+    }
+
     /// Request the raw state tree directly, under an effective canister ID.
     /// See [the protocol docs](https://internetcomputer.org/docs/current/references/ic-interface-spec#http-read-state) for more information.
+    ///
+    /// All of `paths` are sent in one request. For more paths than a replica may accept, use
+    /// [`read_state_raw_chunked`](Self::read_state_raw_chunked).
     pub async fn read_state_raw(
         &self,
         paths: Vec<Vec<Label>>,
         effective_canister_id: Principal,
     ) -> Result<Certificate, AgentError> {
-        let content = self.read_state_content(paths)?;
-        let serialized_bytes = sign_envelope(&content, self.identity.clone())?;
+        self.read_state_as(paths, effective_canister_id, None).await
+    }
+
+    /// Same as [`read_state_raw`](Self::read_state_raw), but splits `paths` into requests of at most
+    /// [as many paths as configured](AgentBuilder::with_max_read_state_paths), returning a
+    /// certificate for each request in the order of `paths`.
+    ///
+    /// The requests are sent concurrently, and fail together if any of them fails. Certificates are
+    /// signed as a whole, so they cannot be merged into one; look each path up in the certificate of
+    /// its chunk, or in each certificate in turn.
+    pub async fn read_state_raw_chunked(
+        &self,
+        paths: Vec<Vec<Label>>,
+        effective_canister_id: Principal,
+    ) -> Result<Vec<Certificate>, AgentError> {
+        let max_paths = self.max_read_state_paths.max(1);
+        try_join_all(
+            paths
+                .chunks(max_paths)
+                .map(|chunk| self.read_state_raw(chunk.to_vec(), effective_canister_id)),
+        )
+        .await
+    }
+
+    async fn read_state_as(
+        &self,
+        paths: Vec<Vec<Label>>,
+        effective_canister_id: Principal,
+        sender: Option<Principal>,
+    ) -> Result<Certificate, AgentError> {
+        self.read_state_via(&*self.transport, paths, effective_canister_id, sender)
+            .await
+    }
+
+    async fn read_state_via(
+        &self,
+        transport: &dyn Transport,
+        paths: Vec<Vec<Label>>,
+        effective_canister_id: Principal,
+        sender: Option<Principal>,
+    ) -> Result<Certificate, AgentError> {
+        let content = self.read_state_content(paths, sender)?;
+        let serialized_bytes = self.sign_envelope(&content)?;
 
         let read_state_response: ReadStateResponse = self
-            .read_state_endpoint(effective_canister_id, serialized_bytes)
+            .read_state_endpoint_via(transport, effective_canister_id, serialized_bytes)
             .await?;
-        let cert: Certificate = serde_cbor::from_slice(&read_state_response.certificate)
-            .map_err(AgentError::InvalidCborData)?;
+        let cert: Certificate = self.cbor_limits.decode(&read_state_response.certificate)?;
         self.verify(&cert, effective_canister_id)?;
         Ok(cert)
     }
@@ -803,21 +1439,60 @@ impl Agent {
         paths: Vec<Vec<Label>>,
         subnet_id: Principal,
     ) -> Result<Certificate, AgentError> {
-        let content = self.read_state_content(paths)?;
-        let serialized_bytes = sign_envelope(&content, self.identity.clone())?;
+        let content = self.read_state_content(paths, None)?;
+        let serialized_bytes = self.sign_envelope(&content)?;
 
         let read_state_response: ReadStateResponse = self
             .read_subnet_state_endpoint(subnet_id, serialized_bytes)
             .await?;
-        let cert: Certificate = serde_cbor::from_slice(&read_state_response.certificate)
-            .map_err(AgentError::InvalidCborData)?;
+        let cert: Certificate = self.cbor_limits.decode(&read_state_response.certificate)?;
         self.verify_for_subnet(&cert, subnet_id)?;
         Ok(cert)
     }
 
-    fn read_state_content(&self, paths: Vec<Vec<Label>>) -> Result<EnvelopeContent, AgentError> {
+    /// Send a pre-serialized read_state envelope to the network, under an effective canister ID.
+    /// Will return the raw certificate bytes from the response.
+    /// The envelope is forwarded to the transport as-is, and the certificate is not verified;
+    /// use [`Agent::verify`] on the decoded certificate if needed.
+    pub async fn submit_read_state_envelope(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Result<Vec<u8>, AgentError> {
+        let read_state_response: ReadStateResponse = self
+            .read_state_endpoint(effective_canister_id, envelope)
+            .await?;
+        Ok(read_state_response.certificate)
+    }
+
+    /// Remembers who sent the call `request_id`, since only they may read its status.
+    fn record_sender(&self, request_id: RequestId, content: &EnvelopeContent) {
+        self.request_senders
+            .lock()
+            .unwrap()
+            .cache_set(request_id, *content.sender());
+    }
+
+    /// The sender of the call `request_id`, if it was made by this agent.
+    fn request_sender(&self, request_id: &RequestId) -> Option<Principal> {
+        self.request_senders
+            .lock()
+            .unwrap()
+            .cache_get(request_id)
+            .copied()
+    }
+
+    fn read_state_content(
+        &self,
+        paths: Vec<Vec<Label>>,
+        sender: Option<Principal>,
+    ) -> Result<EnvelopeContent, AgentError> {
+        let sender = match sender {
+            Some(sender) => sender,
+            None => self.identity.sender().map_err(AgentError::SigningError)?,
+        };
         Ok(EnvelopeContent::ReadState {
-            sender: self.identity.sender().map_err(AgentError::SigningError)?,
+            sender,
             paths,
             ingress_expiry: self.get_expiry_date(),
         })
@@ -851,7 +1526,7 @@ impl Agent {
         let key = extract_der(der_key)?;
 
         ic_verify_bls_signature::verify_bls_signature(sig, &msg, &key)
-            .map_err(|_| AgentError::CertificateVerificationFailed())?;
+            .map_err(|_| self.certificate_verification_failed(cert))?;
         Ok(())
     }
 
@@ -883,17 +1558,47 @@ impl Agent {
         let key = extract_der(der_key)?;
 
         ic_verify_bls_signature::verify_bls_signature(sig, &msg, &key)
-            .map_err(|_| AgentError::CertificateVerificationFailed())?;
+            .map_err(|_| self.certificate_verification_failed(cert))?;
         Ok(())
     }
 
+    /// Verifies a reply that carries its own certification, and returns its certified value at
+    /// `expected_path`.
+    ///
+    /// A canister can return certified variables from a query: its reply is then a Candid record
+    /// with a `certificate` field, the certificate it got from `ic0.data_certificate`, and a
+    /// `witness` field, a CBOR-encoded hash tree whose root hash is its certified data. Other fields
+    /// of the record are ignored. This checks the certificate against the root key and
+    /// `canister_id`, checks that it certifies the witness as the canister's certified data, and
+    /// looks up `expected_path` in the witness. Fails with
+    /// [`AgentError::ResponseVerificationFailed`] if the witness is not the certified data.
+    pub fn verify_response_certificate(
+        &self,
+        canister_id: Principal,
+        reply: &[u8],
+        expected_path: &[&[u8]],
+    ) -> Result<Vec<u8>, AgentError> {
+        certified_reply::verify(self, canister_id, reply, expected_path)
+    }
+
+    /// Certificates without a delegation are signed with the root key, so if one fails
+    /// verification, a cached root key may be stale and is fetched again on next use.
+    fn certificate_verification_failed(&self, cert: &Certificate) -> AgentError {
+        if cert.delegation.is_none() {
+            *self.root_key_fetched_at.lock().unwrap() = None;
+        }
+        AgentError::CertificateVerificationFailed()
+    }
+
     fn verify_cert_timestamp(&self, cert: &Certificate) -> Result<(), AgentError> {
-        let time = lookup_time(cert)?;
-        if (OffsetDateTime::now_utc()
-            - OffsetDateTime::from_unix_timestamp_nanos(time.into()).unwrap())
-        .abs()
-            > self.ingress_expiry
-        {
+        let time = OffsetDateTime::from_unix_timestamp_nanos(lookup_time(cert)?.into()).unwrap();
+        let age = self.clock.now() - time;
+        if age > self.max_cert_age {
+            return Err(AgentError::CertificateTooOld {
+                age: age.unsigned_abs(),
+            });
+        }
+        if age.abs() > self.ingress_expiry {
             Err(AgentError::CertificateOutdated(self.ingress_expiry))
         } else {
             Ok(())
@@ -908,8 +1613,7 @@ impl Agent {
         match delegation {
             None => Ok(self.read_root_key()),
             Some(delegation) => {
-                let cert: Certificate = serde_cbor::from_slice(&delegation.certificate)
-                    .map_err(AgentError::InvalidCborData)?;
+                let cert: Certificate = self.cbor_limits.decode(&delegation.certificate)?;
                 if cert.delegation.is_some() {
                     return Err(AgentError::CertificateHasTooManyDelegations);
                 }
@@ -945,8 +1649,7 @@ impl Agent {
         match delegation {
             None => Ok(self.read_root_key()),
             Some(delegation) => {
-                let cert: Certificate = serde_cbor::from_slice(&delegation.certificate)
-                    .map_err(AgentError::InvalidCborData)?;
+                let cert: Certificate = self.cbor_limits.decode(&delegation.certificate)?;
                 if cert.delegation.is_some() {
                     return Err(AgentError::CertificateHasTooManyDelegations);
                 }
@@ -964,13 +1667,14 @@ impl Agent {
         }
     }
 
-    /// Request information about a particular canister for a single state subkey.
+    /// Request information about a particular canister for a single state subkey, with the time it
+    /// was certified at.
     /// See [the protocol docs](https://internetcomputer.org/docs/current/references/ic-interface-spec#state-tree-canister-information) for more information.
     pub async fn read_state_canister_info(
         &self,
         canister_id: Principal,
         path: &str,
-    ) -> Result<Vec<u8>, AgentError> {
+    ) -> Result<Certified<Vec<u8>>, AgentError> {
         let paths: Vec<Vec<Label>> = vec![vec![
             "canister".into(),
             Label::from_bytes(canister_id.as_slice()),
@@ -978,16 +1682,151 @@ impl Agent {
         ]];
 
         let cert = self.read_state_raw(paths, canister_id).await?;
+        let timestamp = certified_time(&cert)?;
+
+        Ok(Certified {
+            value: lookup_canister_info(cert, canister_id, path)?,
+            timestamp,
+        })
+    }
+
+    /// Request the certified list of a canister's controllers.
+    ///
+    /// Unlike the management canister's `canister_status`, this can be called by anyone, not only controllers.
+    /// Returns [`AgentError::LookupPathAbsent`] if the canister does not exist, and
+    /// [`AgentError::LookupPathUnknown`] if the path was pruned from the certificate.
+    pub async fn get_controllers(
+        &self,
+        canister_id: Principal,
+    ) -> Result<Vec<Principal>, AgentError> {
+        if let Some(cache) = &self.canister_info_cache {
+            if let Some(controllers) = cache.lock().unwrap().controllers.cache_get(&canister_id) {
+                return Ok(controllers.clone());
+            }
+        }
+        let paths: Vec<Vec<Label>> = vec![vec![
+            "canister".into(),
+            Label::from_bytes(canister_id.as_slice()),
+            "controllers".into(),
+        ]];
+
+        let cert = self.read_state_raw(paths, canister_id).await?;
+
+        let controllers = lookup_canister_controllers(cert, canister_id)?;
+        if let Some(cache) = &self.canister_info_cache {
+            cache
+                .lock()
+                .unwrap()
+                .controllers
+                .cache_set(canister_id, controllers.clone());
+        }
+        Ok(controllers)
+    }
+
+    /// Drops the cached controllers and module hash of `canister_id`, so that they are read again
+    /// on next use. Does nothing if the agent has no
+    /// [canister info cache](crate::agent::AgentBuilder::with_canister_info_cache).
+    pub fn invalidate_canister(&self, canister_id: Principal) {
+        if let Some(cache) = &self.canister_info_cache {
+            cache.lock().unwrap().invalidate(&canister_id);
+        }
+    }
+
+    /// Request the ID of the subnet that hosts a canister.
+    ///
+    /// The subnet is the one whose key signed the certificate of the canister's state: the subnet
+    /// named by the certificate's delegation, or the root subnet if the certificate is signed by the
+    /// root key directly. Returns [`AgentError::CanisterNotAssigned`] if the canister does not exist
+    /// on the subnet its ID is routed to.
+    pub async fn get_subnet_id(&self, canister_id: Principal) -> Result<Principal, AgentError> {
+        let paths: Vec<Vec<Label>> = vec![vec![
+            "canister".into(),
+            Label::from_bytes(canister_id.as_slice()),
+            "controllers".into(),
+        ]];
+
+        let cert = self.read_state_raw(paths, canister_id).await?;
+        let subnet_id = match &cert.delegation {
+            Some(delegation) => Principal::from_slice(&delegation.subnet_id),
+            None => Principal::self_authenticating(self.read_root_key()),
+        };
+        match lookup_canister_controllers(cert, canister_id) {
+            Ok(_) => Ok(subnet_id),
+            Err(AgentError::LookupPathAbsent(_)) => {
+                Err(AgentError::CanisterNotAssigned(canister_id))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Request the certified module hashes of several canisters, concurrently.
+    ///
+    /// A canister without an installed module, or one that does not exist, maps to `None`.
+    /// The replica only certifies a canister's paths in a read_state request addressed to that
+    /// canister, so this makes one request per canister rather than a single combined one.
+    pub async fn get_module_hashes(
+        &self,
+        canisters: &[Principal],
+    ) -> Result<HashMap<Principal, Option<[u8; 32]>>, AgentError> {
+        let hashes = try_join_all(canisters.iter().map(|&canister_id| async move {
+            Ok::<_, AgentError>((canister_id, self.read_module_hash(canister_id).await?))
+        }))
+        .await?;
+        Ok(hashes.into_iter().collect())
+    }
+
+    /// Reads the certified module hash of `canister_id`, through the canister info cache if there
+    /// is one.
+    async fn read_module_hash(
+        &self,
+        canister_id: Principal,
+    ) -> Result<Option<[u8; 32]>, AgentError> {
+        if let Some(cache) = &self.canister_info_cache {
+            if let Some(hash) = cache.lock().unwrap().module_hashes.cache_get(&canister_id) {
+                return Ok(*hash);
+            }
+        }
+        let paths: Vec<Vec<Label>> = vec![vec![
+            "canister".into(),
+            Label::from_bytes(canister_id.as_slice()),
+            "module_hash".into(),
+        ]];
+        let cert = self.read_state_raw(paths, canister_id).await?;
+        let hash = lookup_canister_module_hash(&cert, canister_id)?;
+        if let Some(cache) = &self.canister_info_cache {
+            cache
+                .lock()
+                .unwrap()
+                .module_hashes
+                .cache_set(canister_id, hash);
+        }
+        Ok(hash)
+    }
 
-        lookup_canister_info(cert, canister_id, path)
+    /// Checks that the certified module hash of `canister_id` is `expected`, e.g. to verify in CI
+    /// that a deployed canister runs a reproducibly built module.
+    ///
+    /// Fails with [`AgentError::ModuleHashMismatch`] if the hashes differ, and with
+    /// [`AgentError::NoModuleInstalled`] if the canister is empty.
+    pub async fn verify_module_hash(
+        &self,
+        canister_id: Principal,
+        expected: [u8; 32],
+    ) -> Result<(), AgentError> {
+        match self.read_module_hash(canister_id).await? {
+            Some(actual) if actual == expected => Ok(()),
+            Some(actual) => Err(AgentError::ModuleHashMismatch { expected, actual }),
+            None => Err(AgentError::NoModuleInstalled),
+        }
     }
 
-    /// Request the bytes of the canister's custom section `icp:public <path>` or `icp:private <path>`.
+    /// Request the bytes of the canister's custom section `icp:public <path>` or `icp:private <path>`,
+    /// with the time they were certified at.
     pub async fn read_state_canister_metadata(
         &self,
         canister_id: Principal,
         path: &str,
-    ) -> Result<Vec<u8>, AgentError> {
+    ) -> Result<Certified<Vec<u8>>, AgentError> {
         let paths: Vec<Vec<Label>> = vec![vec![
             "canister".into(),
             Label::from_bytes(canister_id.as_slice()),
@@ -996,22 +1835,130 @@ impl Agent {
         ]];
 
         let cert = self.read_state_raw(paths, canister_id).await?;
+        let timestamp = certified_time(&cert)?;
+
+        Ok(Certified {
+            value: lookup_canister_metadata(&cert, canister_id, path)?,
+            timestamp,
+        })
+    }
+
+    /// Request the bytes of the canister's metadata section `section`, i.e. its custom section
+    /// `icp:public <section>` or `icp:private <section>`.
+    ///
+    /// Unlike [`read_state_canister_metadata`](Self::read_state_canister_metadata), this reports a
+    /// section the certificate proves absent as [`AgentError::MetadataNotFound`], and a private section
+    /// read by a sender that is not a controller of the canister as [`AgentError::MetadataAccessDenied`].
+    pub async fn read_canister_metadata(
+        &self,
+        canister_id: Principal,
+        section: &str,
+    ) -> Result<Vec<u8>, AgentError> {
+        match self
+            .read_state_canister_metadata(canister_id, section)
+            .await
+        {
+            Err(AgentError::LookupPathAbsent(_)) => {
+                Err(AgentError::MetadataNotFound(section.to_string()))
+            }
+            // the replica refuses the whole request rather than pruning the path
+            Err(AgentError::HttpError(payload)) if payload.status == 403 => {
+                Err(AgentError::MetadataAccessDenied(section.to_string()))
+            }
+            result => result.map(|metadata| metadata.value),
+        }
+    }
+
+    /// Request the bytes of several of the canister's metadata sections at once, e.g.
+    /// `candid:service` and `candid:args`, with a single read_state request.
+    ///
+    /// Sections the certificate proves absent are left out of the returned map. If any of the
+    /// sections is private and the sender is not a controller of the canister, the replica refuses
+    /// the whole request.
+    pub async fn read_canister_metadata_batch(
+        &self,
+        canister_id: Principal,
+        sections: &[&str],
+    ) -> Result<HashMap<String, Vec<u8>>, AgentError> {
+        let paths: Vec<Vec<Label>> = sections
+            .iter()
+            .map(|&section| {
+                vec![
+                    "canister".into(),
+                    Label::from_bytes(canister_id.as_slice()),
+                    "metadata".into(),
+                    section.into(),
+                ]
+            })
+            .collect();
+
+        let cert = self.read_state_raw(paths, canister_id).await?;
+        let mut metadata = HashMap::new();
+        for &section in sections {
+            match lookup_canister_metadata(&cert, canister_id, section) {
+                Ok(bytes) => {
+                    metadata.insert(section.to_string(), bytes);
+                }
+                Err(AgentError::LookupPathAbsent(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(metadata)
+    }
 
-        lookup_canister_metadata(cert, canister_id, path)
+    /// Request the certified current time of the IC, as read from the root subnet's state tree.
+    ///
+    /// The request itself carries an ingress expiry computed from the local clock, and the
+    /// certificate's time is checked against it, so this fails if the local clock is off by more
+    /// than the ingress expiry.
+    pub async fn get_time(&self) -> Result<SystemTime, AgentError> {
+        let root_subnet = Principal::self_authenticating(self.read_root_key());
+        let cert = self
+            .read_subnet_state_raw(vec![vec!["time".into()]], root_subnet)
+            .await?;
+        let time = lookup_time(&cert)?;
+        Ok(UNIX_EPOCH + Duration::from_nanos(time))
     }
 
-    /// Request a list of metrics about the subnet.
+    /// Request a list of metrics about the subnet, with the time they were certified at.
     pub async fn read_state_subnet_metrics(
         &self,
         subnet_id: Principal,
-    ) -> Result<SubnetMetrics, AgentError> {
+    ) -> Result<Certified<SubnetMetrics>, AgentError> {
         let paths = vec![vec![
             "subnet".into(),
             Label::from_bytes(subnet_id.as_slice()),
             "metrics".into(),
         ]];
         let cert = self.read_subnet_state_raw(paths, subnet_id).await?;
-        lookup_subnet_metrics(cert, subnet_id)
+        let timestamp = certified_time(&cert)?;
+        Ok(Certified {
+            value: lookup_subnet_metrics(cert, subnet_id)?,
+            timestamp,
+        })
+    }
+
+    /// Request the certified description of a subnet: its public key, the ranges of canister IDs
+    /// it hosts, and its nodes with their public keys, with the time it was certified at. The number
+    /// of nodes is the length of [`node_keys`](SubnetInfo::node_keys).
+    ///
+    /// Everything here is public and needs no controller access, unlike the status of a canister.
+    /// The state tree does not record a subnet's replica version; the version of the replica
+    /// answering a request is the `impl_version` of [`status`](Self::status). Usage metrics of the
+    /// subnet are read with [`read_state_subnet_metrics`](Self::read_state_subnet_metrics).
+    pub async fn read_state_subnet_info(
+        &self,
+        subnet_id: Principal,
+    ) -> Result<Certified<SubnetInfo>, AgentError> {
+        let paths = vec![vec![
+            "subnet".into(),
+            Label::from_bytes(subnet_id.as_slice()),
+        ]];
+        let cert = self.read_subnet_state_raw(paths, subnet_id).await?;
+        Ok(Certified {
+            value: lookup_subnet_info(&cert, subnet_id)?,
+            timestamp: certified_time(&cert)?,
+        })
     }
 
     /// Fetches the status of a particular request by its ID.
@@ -1023,9 +1970,14 @@ impl Agent {
         let paths: Vec<Vec<Label>> =
             vec![vec!["request_status".into(), request_id.to_vec().into()]];
 
-        let cert = self.read_state_raw(paths, effective_canister_id).await?;
+        let sender = self.request_sender(request_id);
+        let cert = self
+            .read_state_as(paths, effective_canister_id, sender)
+            .await?;
 
-        lookup_request_status(cert, request_id)
+        let status = lookup_request_status(cert, request_id)?;
+        self.invalidate_if_finished(effective_canister_id, &status);
+        Ok(status)
     }
 
     /// Send the signed request_status to the network. Will return [`RequestStatusResponse`].
@@ -1037,16 +1989,16 @@ impl Agent {
         effective_canister_id: Principal,
         signed_request_status: Vec<u8>,
     ) -> Result<RequestStatusResponse, AgentError> {
-        let _envelope: Envelope =
-            serde_cbor::from_slice(&signed_request_status).map_err(AgentError::InvalidCborData)?;
+        self.wire_format.decode_envelope(&signed_request_status)?;
         let read_state_response: ReadStateResponse = self
             .read_state_endpoint(effective_canister_id, signed_request_status)
             .await?;
 
-        let cert: Certificate = serde_cbor::from_slice(&read_state_response.certificate)
-            .map_err(AgentError::InvalidCborData)?;
+        let cert: Certificate = self.cbor_limits.decode(&read_state_response.certificate)?;
         self.verify(&cert, effective_canister_id)?;
-        lookup_request_status(cert, request_id)
+        let status = lookup_request_status(cert, request_id)?;
+        self.invalidate_if_finished(effective_canister_id, &status);
+        Ok(status)
     }
 
     /// Returns an UpdateBuilder enabling the construction of an update call without
@@ -1059,22 +2011,423 @@ impl Agent {
         UpdateBuilder::new(self, *canister_id, method_name.into())
     }
 
+    /// Like [`update`](Self::update), for the canister with the alias `name`.
+    ///
+    /// Fails with [`AgentError::UnknownCanisterAlias`] if no canister has the alias; see
+    /// [`AgentBuilder::with_canister_aliases`].
+    pub fn update_by_name<S: Into<String>>(
+        &self,
+        name: &str,
+        method_name: S,
+    ) -> Result<UpdateBuilder, AgentError> {
+        Ok(self.update(&self.resolve_canister_alias(name)?, method_name))
+    }
+
+    /// Returns the canister with the alias `name`, as set with
+    /// [`AgentBuilder::with_canister_aliases`].
+    pub fn resolve_canister_alias(&self, name: &str) -> Result<Principal, AgentError> {
+        self.canister_aliases
+            .get(name)
+            .copied()
+            .ok_or_else(|| AgentError::UnknownCanisterAlias(name.to_string()))
+    }
+
+    /// Makes an update call to `canister_id`, sending it to the replica as its own effective
+    /// canister id. This will return a [`CallResponse`] without waiting for the reply.
+    ///
+    /// This is equivalent to `agent.update(canister_id, method_name).with_arg(arg).call()`. Use
+    /// [`call_raw_to`](Self::call_raw_to) when the effective canister id must differ from the target.
+    pub fn call_raw<S: Into<String>>(
+        &self,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+    ) -> AgentFuture<CallResponse<Vec<u8>>> {
+        self.call_raw_to(canister_id, canister_id, method_name, arg)
+    }
+
+    /// Makes an update call to `canister_id`, sent to the replica under `effective_canister_id`.
+    /// This will return a [`CallResponse`] without waiting for the reply.
+    ///
+    /// The [effective canister id](https://internetcomputer.org/docs/current/references/ic-interface-spec#http-effective-canister-id)
+    /// routes the call to a subnet, so it must differ from the target when the target does not live
+    /// on one. Calls to the management canister are sent under the id of the canister they act on,
+    /// such as the canister being installed or stopped. Calls that aggregate over no particular
+    /// canister, such as `provisional_create_canister_with_cycles`, are sent under the id of any
+    /// canister on the subnet they should run on. The response is certified by the subnet of
+    /// `effective_canister_id`.
+    pub fn call_raw_to<S: Into<String>>(
+        &self,
+        effective_canister_id: &Principal,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+    ) -> AgentFuture<CallResponse<Vec<u8>>> {
+        Box::pin(self.update_raw(
+            *canister_id,
+            *effective_canister_id,
+            method_name.into(),
+            arg,
+            None,
+            HeaderMap::new(),
+            RetryPolicy::Transport,
+            Priority::Normal,
+            true,
+        ))
+    }
+
+    /// Submits an update call to `canister_id` and returns its [`RequestId`] as soon as the replica
+    /// has received it, without polling for the reply.
+    ///
+    /// Unlike [`UpdateBuilder::call_and_wait`], this makes a single `call` request and no
+    /// `read_state` requests. Use [`request_status_raw`](Self::request_status_raw) or
+    /// [`wait`](Self::wait) with the returned id to learn the outcome later. If the replica rejects
+    /// the call outright, this fails with [`AgentError::UncertifiedReject`]. A reply the replica returns
+    /// right away, as it may on the synchronous call endpoint, is discarded without being verified.
+    ///
+    /// The call is retried as the transport is configured to ([`RetryPolicy::Transport`]);
+    /// use [`UpdateBuilder::with_retry_policy`] and [`UpdateBuilder::call`] to retry it otherwise.
+    pub fn call_oneway<S: Into<String>>(
+        &self,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+    ) -> AgentFuture<RequestId> {
+        Box::pin(self.submit_oneway(*canister_id, method_name.into(), arg))
+    }
+
+    async fn submit_oneway(
+        &self,
+        canister_id: Principal,
+        method_name: String,
+        arg: Vec<u8>,
+    ) -> Result<RequestId, AgentError> {
+        let nonce = self.nonce_factory.generate();
+        let content = self.update_content(canister_id, method_name, arg, None, nonce)?;
+        let request_id = to_request_id(&content)?;
+        self.record_sender(request_id, &content);
+        let serialized_bytes = self.sign_envelope(&content)?;
+
+        let response_body = self
+            .call_endpoint(
+                canister_id,
+                serialized_bytes,
+                HeaderMap::new(),
+                RetryPolicy::Transport,
+                Priority::Normal,
+            )
+            .await
+            .map_err(|err| self.observe_rejection(&request_id, err))?;
+        match response_body {
+            TransportCallResponse::NonReplicatedRejection(reject_response) => {
+                self.notify_rejection(&request_id, &reject_response);
+                Err(AgentError::UncertifiedReject(reject_response))
+            }
+            TransportCallResponse::Accepted | TransportCallResponse::Replied { .. } => {
+                Ok(request_id)
+            }
+        }
+    }
+
+    /// Estimates the cycles charged to a canister for an update call, without contacting the replica.
+    ///
+    /// The IC has no endpoint for quoting the cost of a call, so this is computed locally from the
+    /// [documented fees](https://internetcomputer.org/docs/current/developer-docs/gas-cost) for a
+    /// 13-node application subnet: the ingress message reception fee of 1,200,000 cycles, 2,000
+    /// cycles per byte of method name and argument, and the update message execution fee of
+    /// 5,000,000 cycles.
+    ///
+    /// The estimate is a lower bound. It does not include the instructions executed by the method,
+    /// any inter-canister calls it makes, or memory it allocates, which usually dominate the cost
+    /// of expensive calls. On larger subnets every fee is scaled by the number of nodes divided by 13.
+    /// `canister_id` does not currently affect the estimate.
+    pub fn estimate_call_cost(
+        &self,
+        _canister_id: &Principal,
+        method_name: &str,
+        arg: &[u8],
+    ) -> u128 {
+        let bytes = (method_name.len() + arg.len()) as u128;
+        INGRESS_MESSAGE_RECEPTION_FEE
+            + INGRESS_BYTE_RECEPTION_FEE * bytes
+            + UPDATE_MESSAGE_EXECUTION_FEE
+    }
+
     /// Calls and returns the information returned by the status endpoint of a replica.
     pub async fn status(&self) -> Result<Status, AgentError> {
         let bytes = self.transport.status().await?;
 
-        let cbor: serde_cbor::Value =
-            serde_cbor::from_slice(&bytes).map_err(AgentError::InvalidCborData)?;
+        let cbor: serde_cbor::Value = self.cbor_limits.decode(&bytes)?;
 
         Status::try_from(&cbor).map_err(|_| AgentError::InvalidReplicaStatus)
     }
 
+    /// Polls the [status](Self::status) of the replica until it reports itself `healthy`, e.g. while
+    /// a freshly started local replica is still `starting`, and returns that status.
+    ///
+    /// Failed status requests, as when the replica is not listening yet, are polled again too. If the
+    /// replica is not healthy after `timeout`, fails with the error of the last status request, or
+    /// with [`AgentError::ReplicaUnhealthy`] if it reported a status other than `healthy`. A status
+    /// request still in flight at the deadline is abandoned. Time is measured with the agent's
+    /// [clock](AgentBuilder::with_clock), and waited out with its [sleeper](AgentBuilder::with_sleeper).
+    pub async fn wait_for_healthy(&self, timeout: Duration) -> Result<Status, AgentError> {
+        let mut retry_policy = Self::get_retry_policy();
+        retry_policy.max_elapsed_time = None;
+        let deadline = self.clock.now() + timeout;
+        let remaining =
+            || Duration::try_from(deadline - self.clock.now()).unwrap_or(Duration::ZERO);
+
+        let mut last_error = AgentError::ReplicaUnhealthy { last_status: None };
+        loop {
+            let status = std::pin::pin!(self.status());
+            match future::select(status, self.sleeper.sleep(remaining())).await {
+                future::Either::Left((Ok(status), _)) => {
+                    if status.replica_health_status.as_deref() == Some("healthy") {
+                        return Ok(status);
+                    }
+                    last_error = AgentError::ReplicaUnhealthy {
+                        last_status: status.replica_health_status,
+                    };
+                }
+                future::Either::Left((Err(err), _)) => last_error = err,
+                future::Either::Right(_) => return Err(last_error),
+            }
+
+            let remaining = remaining();
+            match retry_policy.next_backoff() {
+                Some(delay) if !remaining.is_zero() => {
+                    self.sleeper.sleep(delay.min(remaining)).await;
+                }
+                _ => return Err(last_error),
+            }
+        }
+    }
+
+    /// Opens a connection to the replica ahead of real traffic by requesting its status, so that the
+    /// first latency-sensitive request does not pay for connection setup and the TLS handshake.
+    ///
+    /// The connection is kept in the transport's connection pool, whose idle timeout still applies:
+    /// a connection left unused for longer is closed, and the next request opens a new one.
+    /// With a [`RouteProvider`](http_transport::route_provider::RouteProvider) that spreads requests
+    /// over several URLs, only the URL the status request was routed to is warmed up.
+    pub async fn warmup(&self) -> Result<(), AgentError> {
+        self.transport.status().await.map(|_| ())
+    }
+
     /// Returns a QueryBuilder enabling the construction of a query call without
     /// passing all arguments.
     pub fn query<S: Into<String>>(&self, canister_id: &Principal, method_name: S) -> QueryBuilder {
         QueryBuilder::new(self, *canister_id, method_name.into())
     }
 
+    /// Like [`query`](Self::query), for the canister with the alias `name`.
+    ///
+    /// Fails with [`AgentError::UnknownCanisterAlias`] if no canister has the alias; see
+    /// [`AgentBuilder::with_canister_aliases`].
+    pub fn query_by_name<S: Into<String>>(
+        &self,
+        name: &str,
+        method_name: S,
+    ) -> Result<QueryBuilder, AgentError> {
+        Ok(self.query(&self.resolve_canister_alias(name)?, method_name))
+    }
+
+    /// Make a query call, attaching `headers` to this request only. This will return a byte vector.
+    ///
+    /// This is equivalent to `agent.query(canister_id, method_name).with_arg(arg).with_headers(headers).call()`.
+    pub fn query_with_headers<S: Into<String>>(
+        &self,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.query(canister_id, method_name)
+            .with_arg(arg)
+            .with_headers(headers)
+            .into_future()
+    }
+
+    /// Make an update call, attaching `headers` to the request that submits it only, and wait for
+    /// its reply. Polling for the call's status is done without `headers`.
+    ///
+    /// This is equivalent to `agent.update(canister_id, method_name).with_arg(arg).with_headers(headers).call_and_wait()`.
+    pub fn update_with_headers<S: Into<String>>(
+        &self,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+        headers: HeaderMap,
+    ) -> AgentFuture<Vec<u8>> {
+        self.update(canister_id, method_name)
+            .with_arg(arg)
+            .with_headers(headers)
+            .into_future()
+    }
+
+    /// Sends `requests` concurrently, returning their results in the same order.
+    ///
+    /// The queries are subject to the agent's
+    /// [limit on concurrent requests](AgentBuilder::with_max_concurrent_requests) like any others,
+    /// so a batch larger than the limit is sent in waves. One query failing does not affect the rest.
+    pub async fn query_batch(
+        &self,
+        requests: Vec<QueryRequest>,
+    ) -> Vec<Result<Vec<u8>, AgentError>> {
+        future::join_all(requests.into_iter().map(|request| {
+            self.query(&request.canister_id, request.method_name)
+                .with_arg(request.arg)
+                .call()
+        }))
+        .await
+    }
+
+    /// Make a query call with `sender` in the envelope in place of the identity's principal. This
+    /// will return a byte vector.
+    ///
+    /// This is equivalent to `agent.query(canister_id, method_name).with_arg(arg).with_sender(sender).call()`.
+    /// See [`QueryBuilder::with_sender`] for when the replica accepts such a query.
+    pub fn query_as<S: Into<String>>(
+        &self,
+        sender: Principal,
+        canister_id: &Principal,
+        method_name: S,
+        arg: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        self.query(canister_id, method_name)
+            .with_arg(arg)
+            .with_sender(sender)
+            .into_future()
+    }
+
+    /// Fetches the asset at `path` from an asset canister with its `http_request` query method, and
+    /// verifies it against the `IC-Certificate` header the canister certifies it with, as an HTTP
+    /// gateway would.
+    ///
+    /// `path` is the URL path of the asset, e.g. `/index.html`. Only version 1 of response
+    /// verification is supported, which certifies the body of `GET` responses by path; if the
+    /// response is not certified, or its body does not match its certification, this fails with
+    /// [`AgentError::AssetVerificationFailed`]. Assets the canister streams in chunks are not
+    /// supported; fetch them with [`fetch_asset_streaming`](Self::fetch_asset_streaming).
+    pub async fn fetch_asset(
+        &self,
+        canister_id: Principal,
+        path: &str,
+    ) -> Result<Asset, AgentError> {
+        let arg = Encode!(&asset::HttpRequest {
+            method: "GET",
+            url: path,
+            headers: vec![],
+            body: &[],
+            certificate_version: Some(1),
+        })?;
+        let bytes = self
+            .query(&canister_id, "http_request")
+            .with_arg(arg)
+            .call()
+            .await?;
+        let response = Decode!(&bytes, asset::HttpResponse)?;
+        asset::verify(self, canister_id, path, &response)?;
+        Ok(response.into())
+    }
+
+    /// Fetches the asset at `path` from an asset canister like [`fetch_asset`](Self::fetch_asset),
+    /// following the canister's streaming callback for assets too large for one response, and
+    /// returns a stream of the chunks of the body.
+    ///
+    /// The first chunk is the body of the `http_request` response; each following chunk is the
+    /// result of calling the callback with the token of the previous one, until the canister
+    /// returns no token. The callback must be a method of `canister_id`.
+    ///
+    /// The certification in the response's `IC-Certificate` header is checked before the first chunk
+    /// is yielded; if the response is not certified, the stream yields only
+    /// [`AgentError::AssetVerificationFailed`]. The certified hash covers the whole body, so chunks
+    /// cannot be verified one at a time: the hash of all the chunks is checked once the last one
+    /// arrives, and if it does not match, the stream ends with
+    /// [`AgentError::AssetVerificationFailed`] instead. Chunks are thus only trustworthy once the
+    /// stream has ended without an error.
+    pub fn fetch_asset_streaming<'a>(
+        &'a self,
+        canister_id: Principal,
+        path: &'a str,
+    ) -> impl Stream<Item = Result<Vec<u8>, AgentError>> + 'a {
+        enum Step {
+            Request,
+            Callback { method: String, token: IDLValue },
+            Verify,
+        }
+        let state = (Step::Request, Sha256::new(), Vec::new());
+        stream::try_unfold(
+            state,
+            move |(step, mut hasher, certified_hash)| async move {
+                let (body, next, certified_hash) = match step {
+                    Step::Request => {
+                        let arg = Encode!(&asset::HttpRequest {
+                            method: "GET",
+                            url: path,
+                            headers: vec![],
+                            body: &[],
+                            certificate_version: Some(1),
+                        })?;
+                        let bytes = self
+                            .query(&canister_id, "http_request")
+                            .with_arg(arg)
+                            .call()
+                            .await?;
+                        let response = Decode!(&bytes, asset::StreamingHttpResponse)?;
+                        let header = asset::certificate_header(&response.headers)?;
+                        let certified_hash =
+                            asset::certified_hash(self, canister_id, path, header)?;
+                        let next = match response.streaming_strategy {
+                            Some(asset::StreamingStrategy::Callback { callback, token }) => {
+                                if callback.0.principal != canister_id {
+                                    return Err(asset::failed(format!(
+                                    "the streaming callback is a method of {}, not of the asset canister",
+                                    callback.0.principal
+                                )));
+                                }
+                                Step::Callback {
+                                    method: callback.0.method,
+                                    token: token.0,
+                                }
+                            }
+                            None => Step::Verify,
+                        };
+                        (response.body, next, certified_hash)
+                    }
+                    Step::Callback { method, token } => {
+                        let arg = IDLArgs::new(&[token]).to_bytes()?;
+                        let bytes = self
+                            .query(&canister_id, &method)
+                            .with_arg(arg)
+                            .call()
+                            .await?;
+                        let chunk = Decode!(&bytes, asset::StreamingCallbackHttpResponse)?;
+                        let next = match chunk.token {
+                            Some(token) => Step::Callback {
+                                method,
+                                token: token.0,
+                            },
+                            None => Step::Verify,
+                        };
+                        (chunk.body, next, certified_hash)
+                    }
+                    Step::Verify => {
+                        if hasher.finalize().as_slice() != certified_hash {
+                            return Err(asset::failed(format!(
+                                "the body of {} does not match its certified hash",
+                                asset::asset_path(path)
+                            )));
+                        }
+                        return Ok(None);
+                    }
+                };
+                hasher.update(&body);
+                Ok(Some((body, (next, hasher, certified_hash))))
+            },
+        )
+    }
+
     /// Sign a request_status call. This will return a [`signed::SignedRequestStatus`]
     /// which contains all fields of the request_status and the signed request_status in CBOR encoding
     pub fn sign_request_status(
@@ -1084,8 +2437,9 @@ impl Agent {
     ) -> Result<SignedRequestStatus, AgentError> {
         let paths: Vec<Vec<Label>> =
             vec![vec!["request_status".into(), request_id.to_vec().into()]];
-        let read_state_content = self.read_state_content(paths)?;
-        let signed_request_status = sign_envelope(&read_state_content, self.identity.clone())?;
+        let read_state_content =
+            self.read_state_content(paths, self.request_sender(&request_id))?;
+        let signed_request_status = self.sign_envelope(&read_state_content)?;
         let ingress_expiry = read_state_content.ingress_expiry();
         let sender = *read_state_content.sender();
         Ok(SignedRequestStatus {
@@ -1113,6 +2467,18 @@ impl Agent {
         }
     }
 
+    /// Request the public keys of the nodes making up the subnet that hosts `canister`, by node ID.
+    ///
+    /// These are the keys that query responses are signed with. The subnet is always read afresh
+    /// from the state tree, and also replaces the entry used to verify query signatures.
+    pub async fn get_subnet_node_keys(
+        &self,
+        canister: &Principal,
+    ) -> Result<HashMap<Principal, Vec<u8>>, AgentError> {
+        let subnet = self.fetch_subnet_by_canister(canister).await?;
+        Ok(subnet.node_keys.clone())
+    }
+
     /// Retrieve all existing API boundary nodes from the state tree via endpoint /api/v2/canister/<effective_canister_id>/read_state
     pub async fn fetch_api_boundary_nodes_by_canister_id(
         &self,
@@ -1139,8 +2505,12 @@ impl Agent {
         &self,
         canister: &Principal,
     ) -> Result<Arc<Subnet>, AgentError> {
+        let source = self
+            .verification_key_source
+            .as_ref()
+            .unwrap_or(&self.transport);
         let cert = self
-            .read_state_raw(vec![vec!["subnet".into()]], *canister)
+            .read_state_via(&**source, vec![vec!["subnet".into()]], *canister, None)
             .await?;
 
         let (subnet_id, subnet) = lookup_subnet(&cert, &self.root_key.read().unwrap())?;
@@ -1155,6 +2525,19 @@ impl Agent {
 
 const DEFAULT_INGRESS_EXPIRY: Duration = Duration::from_secs(240);
 
+// How many calls, and for how long, to remember the senders of for reading their status. The
+// replica forgets a call's status some minutes after its ingress expiry.
+const REQUEST_SENDER_CAPACITY: usize = 10_000;
+const REQUEST_SENDER_LIFESPAN: Duration = Duration::from_secs(600);
+
+// How many canisters to cache the controllers and module hash of.
+const CANISTER_INFO_CACHE_CAPACITY: usize = 10_000;
+
+// Fees on a 13-node application subnet, in cycles. See `Agent::estimate_call_cost`.
+const INGRESS_MESSAGE_RECEPTION_FEE: u128 = 1_200_000;
+const INGRESS_BYTE_RECEPTION_FEE: u128 = 2_000;
+const UPDATE_MESSAGE_EXECUTION_FEE: u128 = 5_000_000;
+
 // Checks if a principal is contained within a list of principal ranges
 // A range is a tuple: (low: Principal, high: Principal), as described here: https://internetcomputer.org/docs/current/references/ic-interface-spec#state-tree-subnet
 fn principal_is_within_ranges(principal: &Principal, ranges: &[(Principal, Principal)]) -> bool {
@@ -1163,25 +2546,13 @@ fn principal_is_within_ranges(principal: &Principal, ranges: &[(Principal, Princ
         .any(|r| principal >= &r.0 && principal <= &r.1)
 }
 
-fn sign_envelope(
-    content: &EnvelopeContent,
-    identity: Arc<dyn Identity>,
-) -> Result<Vec<u8>, AgentError> {
-    let signature = identity.sign(content).map_err(AgentError::SigningError)?;
-
-    let envelope = Envelope {
-        content: Cow::Borrowed(content),
-        sender_pubkey: signature.public_key,
-        sender_sig: signature.signature,
-        sender_delegation: signature.delegations,
-    };
-
-    let mut serialized_bytes = Vec::new();
-    let mut serializer = serde_cbor::Serializer::new(&mut serialized_bytes);
-    serializer.self_describe()?;
-    envelope.serialize(&mut serializer)?;
-
-    Ok(serialized_bytes)
+/// Makes `future` abortable, resolving to [`AgentError::RequestCancelled`] once aborted.
+fn abortable<'a, T>(
+    future: impl Future<Output = Result<T, AgentError>> + Send + 'a,
+) -> (AgentFuture<'a, T>, AbortHandle) {
+    let (future, handle) = future::abortable(future);
+    let future = async move { future.await.unwrap_or(Err(AgentError::RequestCancelled)) };
+    (Box::pin(future), handle)
 }
 
 /// Inspect the bytes to be sent as a query
@@ -1334,6 +2705,32 @@ pub fn signed_update_inspect(
     Ok(())
 }
 
+/// Returns the state tree paths to read the outcome of the call `request_id` from, for polling it
+/// with [`Agent::read_state_raw`] or a signed read_state of one's own.
+///
+/// These are `/request_status/<request_id>/` followed by `status` and `reply`, and by `reject_code`,
+/// `reject_message`, and `error_code`, without which a rejection cannot be told apart from a
+/// pruned certificate. The agent itself reads the whole `/request_status/<request_id>` subtree, which
+/// certifies the same values.
+pub fn request_status_paths(request_id: &RequestId) -> Vec<Vec<Label>> {
+    [
+        "status",
+        "reply",
+        "reject_code",
+        "reject_message",
+        "error_code",
+    ]
+    .into_iter()
+    .map(|leaf| {
+        vec![
+            "request_status".into(),
+            Label::from_bytes(request_id.as_slice()),
+            leaf.into(),
+        ]
+    })
+    .collect()
+}
+
 /// Inspect the bytes to be sent as a request_status
 /// Return Ok only when the bytes can be deserialized as a request_status and all fields match with the arguments
 pub fn signed_request_status_inspect(
@@ -1421,6 +2818,35 @@ impl SubnetCache {
     }
 }
 
+/// Certified controllers and module hashes of canisters, see
+/// [`AgentBuilder::with_canister_info_cache`].
+struct CanisterInfoCache {
+    controllers: TimedSizedCache<Principal, Vec<Principal>>,
+    module_hashes: TimedSizedCache<Principal, Option<[u8; 32]>>,
+}
+
+impl CanisterInfoCache {
+    fn new(ttl: Duration) -> Self {
+        // the cache counts whole seconds, so a TTL under one second would not cache at all
+        let lifespan = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+        Self {
+            controllers: TimedSizedCache::with_size_and_lifespan(
+                CANISTER_INFO_CACHE_CAPACITY,
+                lifespan,
+            ),
+            module_hashes: TimedSizedCache::with_size_and_lifespan(
+                CANISTER_INFO_CACHE_CAPACITY,
+                lifespan,
+            ),
+        }
+    }
+
+    fn invalidate(&mut self, canister_id: &Principal) {
+        self.controllers.cache_remove(canister_id);
+        self.module_hashes.cache_remove(canister_id);
+    }
+}
+
 #[derive(Clone, Copy)]
 struct PrincipalStep;
 
@@ -1460,7 +2886,35 @@ pub(crate) struct Subnet {
     canister_ranges: RangeInclusiveSet<Principal, PrincipalStep>,
 }
 
-/// API boundary node, which routes /api calls to IC replica nodes.
+/// A value read from the state tree, with the time of the certificate it was read from, e.g. to
+/// reject data older than some bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certified<T> {
+    /// The value read.
+    pub value: T,
+    /// The time the state tree was certified at, from the certificate's `time` leaf.
+    pub timestamp: SystemTime,
+}
+
+/// Returns the time `cert` was certified at.
+fn certified_time(cert: &Certificate) -> Result<SystemTime, AgentError> {
+    Ok(UNIX_EPOCH + Duration::from_nanos(lookup_time(cert)?))
+}
+
+/// A subnet as certified in its state tree, returned by [`Agent::read_state_subnet_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubnetInfo {
+    /// The ID of the subnet.
+    pub subnet_id: Principal,
+    /// The DER-encoded public key of the subnet.
+    pub public_key: Vec<u8>,
+    /// The inclusive ranges of canister IDs the subnet hosts.
+    pub canister_ranges: Vec<(Principal, Principal)>,
+    /// The DER-encoded public keys of the subnet's nodes, by node ID.
+    pub node_keys: HashMap<Principal, Vec<u8>>,
+}
+
+/// API boundary node, which routes /api calls to IC replica nodes.
 #[derive(Debug, Clone)]
 pub struct ApiBoundaryNode {
     /// Domain name
@@ -1471,6 +2925,50 @@ pub struct ApiBoundaryNode {
     pub ipv4_address: Option<String>,
 }
 
+/// Treats a request status that was pruned from the certificate like an absent one, as the replica
+/// may answer a read_state before it has registered a call it just received. Only the status path
+/// itself is considered, so a pruned reply or rejection of a known call is still an error.
+fn unknown_if_pruned(
+    status: Result<RequestStatusResponse, AgentError>,
+    request_id: &RequestId,
+) -> Result<RequestStatusResponse, AgentError> {
+    match status {
+        Err(AgentError::LookupPathUnknown(path))
+            if path
+                == [
+                    Label::from("request_status"),
+                    Label::from_bytes(request_id.as_slice()),
+                    Label::from("status"),
+                ] =>
+        {
+            Ok(RequestStatusResponse::Unknown)
+        }
+        status => status,
+    }
+}
+
+/// One of the queries sent together by [`Agent::query_batch`].
+#[derive(Debug, Clone)]
+pub struct QueryRequest {
+    /// The principal ID of the canister being called.
+    pub canister_id: Principal,
+    /// The name of the canister method being called.
+    pub method_name: String,
+    /// The argument blob to be passed to the method.
+    pub arg: Vec<u8>,
+}
+
+impl QueryRequest {
+    /// Creates a query of `method_name` on `canister_id` with the argument `arg`.
+    pub fn new(canister_id: Principal, method_name: impl Into<String>, arg: Vec<u8>) -> Self {
+        Self {
+            canister_id,
+            method_name: method_name.into(),
+            arg,
+        }
+    }
+}
+
 /// A Query Request Builder.
 ///
 /// This makes it easier to do query calls without actually passing all arguments.
@@ -1490,6 +2988,16 @@ pub struct QueryBuilder<'agent> {
     pub ingress_expiry_datetime: Option<u64>,
     /// Whether to include a nonce with the message.
     pub use_nonce: bool,
+    /// Additional HTTP headers to attach to this request only.
+    pub headers: HeaderMap,
+    /// The sender to put in the envelope in place of the identity's principal.
+    pub sender: Option<Principal>,
+    /// The id to send in the `x-correlation-id` header and to attach to any error.
+    pub correlation_id: Option<String>,
+    /// How the transport retries this query.
+    pub retry_policy: RetryPolicy,
+    /// The priority of this query while it waits for a concurrent request slot.
+    pub priority: Priority,
 }
 
 impl<'agent> QueryBuilder<'agent> {
@@ -1503,6 +3011,11 @@ impl<'agent> QueryBuilder<'agent> {
             arg: vec![],
             ingress_expiry_datetime: None,
             use_nonce: false,
+            headers: HeaderMap::new(),
+            sender: None,
+            correlation_id: None,
+            retry_policy: RetryPolicy::Transport,
+            priority: Priority::Normal,
         }
     }
 
@@ -1512,13 +3025,27 @@ impl<'agent> QueryBuilder<'agent> {
         self
     }
 
+    /// Sets the sender of the query, in place of the principal of the agent's identity.
+    ///
+    /// The envelope is still signed by the agent's identity, and the replica only accepts it if that
+    /// signature authenticates `sender`. This is therefore only useful if the identity produces no
+    /// signature, as [`AnonymousIdentity`](crate::identity::AnonymousIdentity) does, and the
+    /// envelope is re-signed for `sender` before reaching the replica, e.g. by a gateway that holds
+    /// its key. Such a gateway must itself decide whether the client may act as `sender`.
+    pub fn with_sender(mut self, sender: Principal) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
     /// Sets the argument blob to pass to the canister. For most canisters this should be a Candid-serialized tuple.
     pub fn with_arg<A: Into<Vec<u8>>>(mut self, arg: A) -> Self {
         self.arg = arg.into();
         self
     }
 
-    /// Sets ingress_expiry_datetime to the provided timestamp, at nanosecond precision.
+    /// Sets ingress_expiry_datetime to the provided timestamp, at nanosecond precision. Unlike
+    /// [`expire_after`](Self::expire_after), this does not depend on the current time, so signing
+    /// the same request twice produces the same envelope.
     pub fn expire_at(mut self, time: impl Into<OffsetDateTime>) -> Self {
         self.ingress_expiry_datetime = Some(time.into().unix_timestamp_nanos() as u64);
         self
@@ -1541,8 +3068,62 @@ impl<'agent> QueryBuilder<'agent> {
         self
     }
 
+    /// Adds HTTP headers to this request only, e.g. a trace ID. These are merged with any
+    /// headers the transport sets itself, and do not affect subsequent requests.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Tags this query with a correlation id, e.g. one quoted in a support ticket. The id is sent in
+    /// the `x-correlation-id` header, and any error the query fails with is wrapped in
+    /// [`AgentError::Correlated`], so that it is shown with the id.
+    ///
+    /// The id must be a valid HTTP header value, or the query fails without being sent.
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Sets how the transport retries this query if it is rate-limited or its connection fails,
+    /// overriding the transport's own retry settings for this query only. Queries are safe to
+    /// retry, so this is mostly useful to retry them harder.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the priority of this query while it waits for one of the agent's
+    /// [concurrent request slots](AgentBuilder::with_max_concurrent_requests). See [`Priority`] for
+    /// how waiting requests are ordered.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Make a query call. This will return a byte vector.
     pub async fn call(self) -> Result<Vec<u8>, AgentError> {
+        self.call_verifying(None).await
+    }
+
+    async fn call_verifying(
+        mut self,
+        verify_signatures: Option<bool>,
+    ) -> Result<Vec<u8>, AgentError> {
+        let Some(correlation_id) = self.correlation_id.take() else {
+            return self.query_raw(verify_signatures).await;
+        };
+        let result = match correlation_header(&correlation_id) {
+            Ok(value) => {
+                self.headers.insert(CORRELATION_ID_HEADER, value);
+                self.query_raw(verify_signatures).await
+            }
+            Err(error) => Err(error),
+        };
+        result.map_err(|error| correlate(Some(correlation_id), error))
+    }
+
+    async fn query_raw(self, verify_signatures: Option<bool>) -> Result<Vec<u8>, AgentError> {
         self.agent
             .query_raw(
                 self.canister_id,
@@ -1551,45 +3132,38 @@ impl<'agent> QueryBuilder<'agent> {
                 self.arg,
                 self.ingress_expiry_datetime,
                 self.use_nonce,
-                None,
+                verify_signatures,
+                self.headers,
+                self.sender,
+                self.retry_policy,
+                self.priority,
             )
             .await
     }
 
+    /// Same as [`call`](Self::call), but also returns an [`AbortHandle`] that cancels this query
+    /// alone. Once aborted, the request in flight is dropped along with its connection, and the
+    /// future resolves to [`AgentError::RequestCancelled`].
+    pub fn call_abortable(self) -> (AgentFuture<'agent, Vec<u8>>, AbortHandle) {
+        abortable(self.call())
+    }
+
     /// Make a query call with signature verification. This will return a byte vector.
     ///
     /// Compared with [call][Self::call], this method will **always** verify the signature of the query response
     /// regardless the Agent level configuration from [AgentBuilder::with_verify_query_signatures].
     pub async fn call_with_verification(self) -> Result<Vec<u8>, AgentError> {
-        self.agent
-            .query_raw(
-                self.canister_id,
-                self.effective_canister_id,
-                self.method_name,
-                self.arg,
-                self.ingress_expiry_datetime,
-                self.use_nonce,
-                Some(true),
-            )
-            .await
+        self.call_verifying(Some(true)).await
     }
 
     /// Make a query call without signature verification. This will return a byte vector.
     ///
     /// Compared with [call][Self::call], this method will **never** verify the signature of the query response
     /// regardless the Agent level configuration from [AgentBuilder::with_verify_query_signatures].
+    /// This saves fetching the subnet's node keys, but the response is then only as trustworthy as
+    /// the replica it came from.
     pub async fn call_without_verification(self) -> Result<Vec<u8>, AgentError> {
-        self.agent
-            .query_raw(
-                self.canister_id,
-                self.effective_canister_id,
-                self.method_name,
-                self.arg,
-                self.ingress_expiry_datetime,
-                self.use_nonce,
-                Some(false),
-            )
-            .await
+        self.call_verifying(Some(false)).await
     }
 
     /// Sign a query call. This will return a [`signed::SignedQuery`]
@@ -1601,8 +3175,9 @@ impl<'agent> QueryBuilder<'agent> {
             self.arg,
             self.ingress_expiry_datetime,
             self.use_nonce,
+            self.sender,
         )?;
-        let signed_query = sign_envelope(&content, self.agent.identity.clone())?;
+        let signed_query = self.agent.sign_envelope(&content)?;
         let EnvelopeContent::Query {
             ingress_expiry,
             sender,
@@ -1635,11 +3210,28 @@ impl<'agent> IntoFuture for QueryBuilder<'agent> {
     }
 }
 
+fn correlation_header(correlation_id: &str) -> Result<HeaderValue, AgentError> {
+    HeaderValue::from_str(correlation_id).map_err(|_| {
+        AgentError::MessageError("The correlation id is not a valid header value.".to_string())
+    })
+}
+
+fn correlate(correlation_id: Option<String>, error: AgentError) -> AgentError {
+    match correlation_id {
+        Some(correlation_id) => AgentError::Correlated {
+            correlation_id,
+            error: Box::new(error),
+        },
+        None => error,
+    }
+}
+
 /// An in-flight canister update call. Useful primarily as a `Future`.
 pub struct UpdateCall<'agent> {
     agent: &'agent Agent,
     response_future: AgentFuture<'agent, CallResponse<Vec<u8>>>,
     effective_canister_id: Principal,
+    correlation_id: Option<String>,
 }
 
 impl fmt::Debug for UpdateCall<'_> {
@@ -1647,6 +3239,7 @@ impl fmt::Debug for UpdateCall<'_> {
         f.debug_struct("UpdateCall")
             .field("agent", &self.agent)
             .field("effective_canister_id", &self.effective_canister_id)
+            .field("correlation_id", &self.correlation_id)
             .finish_non_exhaustive()
     }
 }
@@ -1654,27 +3247,34 @@ impl fmt::Debug for UpdateCall<'_> {
 impl Future for UpdateCall<'_> {
     type Output = Result<CallResponse<Vec<u8>>, AgentError>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.response_future.as_mut().poll(cx)
+        let poll = self.response_future.as_mut().poll(cx);
+        poll.map_err(|error| correlate(self.correlation_id.clone(), error))
     }
 }
 impl<'a> UpdateCall<'a> {
     async fn and_wait(self) -> Result<Vec<u8>, AgentError> {
-        let response = self.response_future.await?;
-
-        match response {
-            CallResponse::Response(response) => Ok(response),
-            CallResponse::Poll(request_id) => {
-                self.agent
-                    .wait(&request_id, self.effective_canister_id)
-                    .await
+        let result = async {
+            match self.response_future.await? {
+                CallResponse::Response(response) => Ok(response),
+                CallResponse::Poll(request_id) => {
+                    self.agent
+                        .wait(&request_id, self.effective_canister_id)
+                        .await
+                }
             }
         }
+        .await;
+        result.map_err(|error| correlate(self.correlation_id, error))
     }
 }
 /// An Update Request Builder.
 ///
 /// This makes it easier to do update calls without actually passing all arguments or specifying
 /// if you want to wait or not.
+///
+/// By default, a call the same as an earlier one that was abandoned mid-submission takes the
+/// earlier one over rather than being submitted anew; see [`call`](Self::call). Use
+/// [`with_resume_abandoned(false)`](Self::with_resume_abandoned) for calls meant to be made twice.
 #[derive(Debug)]
 pub struct UpdateBuilder<'agent> {
     agent: &'agent Agent,
@@ -1688,6 +3288,16 @@ pub struct UpdateBuilder<'agent> {
     pub arg: Vec<u8>,
     /// The Unix timestamp that the request will expire at.
     pub ingress_expiry_datetime: Option<u64>,
+    /// Additional HTTP headers to attach to the submission of this call only.
+    pub headers: HeaderMap,
+    /// How the transport retries submitting this call.
+    pub retry_policy: RetryPolicy,
+    /// The priority of this call's submission while it waits for a concurrent request slot.
+    pub priority: Priority,
+    /// The id to send in the `x-correlation-id` header and to attach to any error.
+    pub correlation_id: Option<String>,
+    /// Whether this call takes over an identical call abandoned mid-submission.
+    pub resume_abandoned: bool,
 }
 
 impl<'agent> UpdateBuilder<'agent> {
@@ -1700,6 +3310,11 @@ impl<'agent> UpdateBuilder<'agent> {
             method_name,
             arg: vec![],
             ingress_expiry_datetime: None,
+            headers: HeaderMap::new(),
+            retry_policy: RetryPolicy::Transport,
+            priority: Priority::Normal,
+            correlation_id: None,
+            resume_abandoned: true,
         }
     }
 
@@ -1715,7 +3330,9 @@ impl<'agent> UpdateBuilder<'agent> {
         self
     }
 
-    /// Sets ingress_expiry_datetime to the provided timestamp, at nanosecond precision.
+    /// Sets ingress_expiry_datetime to the provided timestamp, at nanosecond precision. Unlike
+    /// [`expire_after`](Self::expire_after), this does not depend on the current time, so signing
+    /// the same request twice produces the same envelope.
     pub fn expire_at(mut self, time: impl Into<OffsetDateTime>) -> Self {
         self.ingress_expiry_datetime = Some(time.into().unix_timestamp_nanos() as u64);
         self
@@ -1731,16 +3348,136 @@ impl<'agent> UpdateBuilder<'agent> {
         self
     }
 
+    /// Adds HTTP headers to the request that submits this call, e.g. a trace ID. These are merged
+    /// with any headers the transport sets itself, and do not affect subsequent requests, including
+    /// those that poll for the call's status.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// Tags this call with a correlation id, e.g. one quoted in a support ticket. The id is sent in
+    /// the `x-correlation-id` header of the request that submits the call, and any error the call
+    /// fails with, including while waiting for its reply, is wrapped in
+    /// [`AgentError::Correlated`], so that it is shown with the id.
+    ///
+    /// The id must be a valid HTTP header value, or the call fails without being sent.
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// Sets whether this call takes over an identical call abandoned before the agent learned
+    /// whether the replica received it, as described on [`call`](Self::call). Defaults to `true`.
+    /// Set it to `false` for a call that is intentionally the same as an earlier one, so that it
+    /// is submitted as a new message, with its own nonce and expiry.
+    pub fn with_resume_abandoned(mut self, resume_abandoned: bool) -> Self {
+        self.resume_abandoned = resume_abandoned;
+        self
+    }
+
+    /// Sets how the transport retries submitting this call if it is rate-limited or its connection
+    /// fails, overriding the transport's own retry settings for this call only, e.g.
+    /// [`RetryPolicy::Never`] to fail fast and leave retrying to the caller. Polling for the call's
+    /// status is not affected.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the priority of submitting this call while it waits for one of the agent's
+    /// [concurrent request slots](AgentBuilder::with_max_concurrent_requests). See [`Priority`] for
+    /// how waiting requests are ordered. Polling for the call's status has [`Priority::Normal`].
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     /// Make an update call. This will call request_status on the RequestId in a loop and return
     /// the response as a byte vector.
     pub async fn call_and_wait(self) -> Result<Vec<u8>, AgentError> {
         self.call().and_wait().await
     }
 
+    /// Same as [`call_and_wait`](Self::call_and_wait), but also returns an [`AbortHandle`] that
+    /// cancels this call alone. Once aborted, the request in flight is dropped along with its
+    /// connection, and the future resolves to [`AgentError::RequestCancelled`].
+    ///
+    /// Aborting only stops the agent waiting: a call the replica has already received may still
+    /// be executed.
+    pub fn call_and_wait_abortable(self) -> (AgentFuture<'agent, Vec<u8>>, AbortHandle) {
+        abortable(self.call_and_wait())
+    }
+
+    /// Make an update call, then poll its status, yielding each status the call goes through. See
+    /// [`Agent::call_and_watch`] for which statuses are yielded.
+    ///
+    /// Polling for the call's status uses this builder's effective canister id, and, like
+    /// [`call_and_wait`](Self::call_and_wait), is not affected by its headers, retry policy or
+    /// priority.
+    pub fn call_and_watch(
+        mut self,
+    ) -> impl Stream<Item = Result<RequestStatusResponse, AgentError>> + 'agent {
+        let agent = self.agent;
+        let effective_canister_id = self.effective_canister_id;
+        // the correlation id is attached to errors below, once, whether they are from submitting
+        // the call or from polling its status
+        let correlation_id = self.correlation_id.take();
+        let header = correlation_id
+            .as_deref()
+            .map(correlation_header)
+            .transpose();
+        let submit = async move {
+            if let Some(value) = header? {
+                self.headers.insert(CORRELATION_ID_HEADER, value);
+            }
+            self.call().await
+        };
+        stream::once(submit)
+            .flat_map(move |response| match response {
+                Ok(CallResponse::Poll(request_id)) => {
+                    Agent::watch_request_status(agent.sleeper.clone(), move || async move {
+                        let status = agent
+                            .request_status_raw(&request_id, effective_canister_id)
+                            .await;
+                        let status = unknown_if_pruned(status, &request_id)?;
+                        if let RequestStatusResponse::Rejected(response) = &status {
+                            agent.notify_rejection(&request_id, response);
+                        }
+                        Ok(status)
+                    })
+                    .left_stream()
+                }
+                Ok(CallResponse::Response(arg)) => stream::once(future::ready(Ok(
+                    RequestStatusResponse::Replied(ReplyResponse { arg }),
+                )))
+                .right_stream(),
+                Err(err) => stream::once(future::ready(Err(err))).right_stream(),
+            })
+            .map(move |status| status.map_err(|error| correlate(correlation_id.clone(), error)))
+    }
+
     /// Make an update call. This will return a RequestId.
     /// The RequestId should then be used for request_status (most likely in a loop).
+    ///
+    /// The agent submits each call at most once. If this future is dropped, or the transport fails,
+    /// after the call may have reached the replica, making the same call again with this agent before
+    /// the first expires resumes the first: its status is polled, and if the replica has not seen it,
+    /// its original message is sent again rather than a new one. Calls are the same if they have
+    /// the same sender, canister, effective canister id, method, and argument; the nonce and
+    /// expiry of the call taking over are ignored, as it is the earlier call that is completed. A
+    /// submission that failed with [`AgentError::TransportError`] or [`AgentError::TimeoutError`]
+    /// counts as abandoned, as the envelope may have been sent. To submit an identical call
+    /// separately, use [`with_resume_abandoned(false)`](Self::with_resume_abandoned).
     pub fn call(self) -> UpdateCall<'agent> {
+        let agent = self.agent;
+        let effective_canister_id = self.effective_canister_id;
+        let correlation_id = self.correlation_id.clone();
         let response_future = async move {
+            let mut headers = self.headers;
+            if let Some(correlation_id) = &self.correlation_id {
+                headers.insert(CORRELATION_ID_HEADER, correlation_header(correlation_id)?);
+            }
             self.agent
                 .update_raw(
                     self.canister_id,
@@ -1748,13 +3485,18 @@ impl<'agent> UpdateBuilder<'agent> {
                     self.method_name,
                     self.arg,
                     self.ingress_expiry_datetime,
+                    headers,
+                    self.retry_policy,
+                    self.priority,
+                    self.resume_abandoned,
                 )
                 .await
         };
         UpdateCall {
-            agent: self.agent,
+            agent,
             response_future: Box::pin(response_future),
-            effective_canister_id: self.effective_canister_id,
+            effective_canister_id,
+            correlation_id,
         }
     }
 
@@ -1769,8 +3511,9 @@ impl<'agent> UpdateBuilder<'agent> {
             self.ingress_expiry_datetime,
             nonce,
         )?;
-        let signed_update = sign_envelope(&content, self.agent.identity.clone())?;
+        let signed_update = self.agent.sign_envelope(&content)?;
         let request_id = to_request_id(&content)?;
+        self.agent.record_sender(request_id, &content);
         let EnvelopeContent::Call {
             nonce,
             ingress_expiry,
@@ -1807,9 +3550,151 @@ impl<'agent> IntoFuture for UpdateBuilder<'agent> {
 #[cfg(all(test, feature = "reqwest", not(target_family = "wasm")))]
 mod offline_tests {
     use super::*;
+    use crate::test_utils::{MockTransport, RecordingSleeper};
     use futures_util::future::pending;
     // Any tests that involve the network should go in agent_test, not here.
 
+    #[test]
+    fn refuses_to_fetch_mainnet_root_key() {
+        use futures_util::FutureExt;
+
+        let agent = Agent::builder()
+            .with_url("https://ic0.app")
+            .build()
+            .unwrap();
+        let result = agent.fetch_root_key().now_or_never().unwrap();
+        assert!(matches!(result, Err(AgentError::FetchRootKeyOnMainnet)));
+        assert_eq!(agent.read_root_key(), IC_ROOT_KEY);
+    }
+
+    #[test]
+    fn signs_with_fallback_identity() {
+        use crate::identity::Secp256k1Identity;
+
+        /// An identity whose key is out of reach, as with an HSM that is unplugged.
+        struct Unavailable(Principal);
+        impl Identity for Unavailable {
+            fn sender(&self) -> Result<Principal, String> {
+                Ok(self.0)
+            }
+            fn public_key(&self) -> Option<Vec<u8>> {
+                None
+            }
+            fn sign(&self, _: &EnvelopeContent) -> Result<crate::Signature, String> {
+                Err("the HSM is unavailable".to_string())
+            }
+        }
+
+        #[derive(Default)]
+        struct Fallbacks(Mutex<Vec<String>>);
+        impl AgentObserver for Fallbacks {
+            fn on_identity_fallback(&self, error: &str) {
+                self.0.lock().unwrap().push(error.to_string());
+            }
+        }
+
+        let fallback = Arc::new(Secp256k1Identity::from_private_key(
+            k256::SecretKey::from_slice(&[1; 32]).unwrap(),
+        ));
+        let sender = fallback.sender().unwrap();
+        let observer = Arc::new(Fallbacks::default());
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_fallback_identity(Arc::new(Unavailable(sender)), fallback.clone())
+            .with_arc_observer(observer.clone())
+            .build()
+            .unwrap();
+        let signed = agent
+            .update(&Principal::management_canister(), "greet")
+            .sign()
+            .unwrap();
+        assert_eq!(signed.sender, sender);
+        let envelope: Envelope = serde_cbor::from_slice(&signed.signed_update).unwrap();
+        assert_eq!(envelope.sender_pubkey, fallback.public_key());
+        let signature = fallback.sign(&envelope.content).unwrap();
+        assert_eq!(envelope.sender_sig, signature.signature);
+        assert_eq!(*observer.0.lock().unwrap(), ["the HSM is unavailable"]);
+
+        // a fallback that signs as a different principal cannot stand in
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_fallback_identity(
+                Arc::new(Unavailable(Principal::anonymous())),
+                fallback.clone(),
+            )
+            .build()
+            .unwrap();
+        let result = agent
+            .update(&Principal::management_canister(), "greet")
+            .sign();
+        assert!(matches!(result, Err(AgentError::SigningError(_))));
+    }
+
+    #[test]
+    fn declined_signature_is_not_retried() {
+        use crate::identity::Secp256k1Identity;
+
+        /// An identity whose user declines every signature they are asked to confirm.
+        struct Declining(Principal, Mutex<usize>);
+        impl Identity for Declining {
+            fn sender(&self) -> Result<Principal, String> {
+                Ok(self.0)
+            }
+            fn public_key(&self) -> Option<Vec<u8>> {
+                None
+            }
+            fn sign(&self, _: &EnvelopeContent) -> Result<crate::Signature, String> {
+                *self.1.lock().unwrap() += 1;
+                Err("the user declined the signature".to_string())
+            }
+            fn is_declined(&self, error: &str) -> bool {
+                error.contains("declined")
+            }
+        }
+
+        let fallback = Arc::new(Secp256k1Identity::from_private_key(
+            k256::SecretKey::from_slice(&[1; 32]).unwrap(),
+        ));
+        let declining = Arc::new(Declining(fallback.sender().unwrap(), Mutex::new(0)));
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_fallback_identity(declining.clone(), fallback)
+            .build()
+            .unwrap();
+        let result = agent
+            .update(&Principal::management_canister(), "greet")
+            .sign();
+        assert!(
+            matches!(result, Err(AgentError::SigningDeclined(error)) if error == "the user declined the signature")
+        );
+        assert_eq!(*declining.1.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn builds_request_status_paths() {
+        let request_id = RequestId::new(&[0xab; 32]);
+        let paths = request_status_paths(&request_id);
+        let leaves: Vec<&[u8]> = paths
+            .iter()
+            .map(|path| {
+                assert_eq!(path.len(), 3);
+                assert_eq!(path[0].as_bytes(), b"request_status");
+                assert_eq!(path[1].as_bytes(), [0xab; 32]);
+                path[2].as_bytes()
+            })
+            .collect();
+        assert_eq!(
+            leaves,
+            [
+                &b"status"[..],
+                b"reply",
+                b"reject_code",
+                b"reject_message",
+                b"error_code"
+            ]
+        );
+    }
+
     #[test]
     fn rounded_expiry() {
         let agent = Agent::builder()
@@ -1832,6 +3717,637 @@ mod offline_tests {
         assert!(num_timestamps <= 2, "num_timestamps:{num_timestamps} > 2");
     }
 
+    #[test]
+    fn query_with_sender() {
+        use crate::test_utils::query_reply;
+        use futures_util::FutureExt;
+
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .build()
+            .unwrap();
+        // a principal other than the anonymous identity's, so that it can only come from `with_sender`
+        let sender = Principal::self_authenticating(b"the key a gateway holds");
+        assert_ne!(sender, Principal::anonymous());
+        let canister_id = Principal::management_canister();
+        let signed = agent
+            .query(&canister_id, "greet")
+            .with_sender(sender)
+            .sign()
+            .unwrap();
+        assert_eq!(signed.sender, sender);
+        let envelope: Envelope = serde_cbor::from_slice(&signed.signed_query).unwrap();
+        let EnvelopeContent::Query {
+            sender: envelope_sender,
+            ..
+        } = envelope.content.as_ref()
+        else {
+            panic!("not a query");
+        };
+        assert_eq!(*envelope_sender, sender);
+        assert!(envelope.sender_sig.is_none());
+
+        // without an explicit sender, the identity's principal is used
+        let signed = agent.query(&canister_id, "greet").sign().unwrap();
+        assert_eq!(signed.sender, Principal::anonymous());
+        let envelope: Envelope = serde_cbor::from_slice(&signed.signed_query).unwrap();
+        assert_eq!(*envelope.content.sender(), Principal::anonymous());
+
+        // and `query_as` sends the sender it is given
+        let agent = Agent::builder()
+            .with_transport(MockTransport::new().on_query(move |request| {
+                assert_eq!(*request.content().sender(), sender);
+                future::ready(Ok(query_reply(b"hi")))
+            }))
+            .with_verify_query_signatures(false)
+            .build()
+            .unwrap();
+        let reply = agent
+            .query_as(sender, &canister_id, "greet", vec![])
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"hi");
+    }
+
+    #[test]
+    fn rotates_identities() {
+        use crate::identity::{IdentityRotator, Secp256k1Identity};
+        let identities: Vec<Arc<dyn Identity>> = (1..=3)
+            .map(|n| {
+                Arc::new(Secp256k1Identity::from_private_key(
+                    k256::SecretKey::from_slice(&[n; 32]).unwrap(),
+                )) as _
+            })
+            .collect();
+        let senders: Vec<_> = identities.iter().map(|i| i.sender().unwrap()).collect();
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_identity(IdentityRotator::new(identities))
+            .build()
+            .unwrap();
+        let updates: Vec<_> = (0..4)
+            .map(|_| {
+                agent
+                    .update(&Principal::management_canister(), "greet")
+                    .sign()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            updates.iter().map(|u| u.sender).collect::<Vec<_>>(),
+            [senders[0], senders[1], senders[2], senders[0]]
+        );
+        // the status of each call is read as its sender, regardless of the rotation
+        for update in updates.iter().rev() {
+            let status = agent
+                .sign_request_status(Principal::management_canister(), update.request_id)
+                .unwrap();
+            assert_eq!(status.sender, update.sender);
+        }
+    }
+
+    #[tokio::test]
+    async fn aborts_one_call() {
+        use futures_util::FutureExt;
+
+        /// Counts the requests in flight that have been dropped.
+        struct DropCounter(Arc<Mutex<usize>>);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() += 1;
+            }
+        }
+        /// Never answers.
+        fn never_answers<T: Send>(
+            dropped: Arc<Mutex<usize>>,
+        ) -> impl Future<Output = Result<T, AgentError>> {
+            let counter = DropCounter(dropped);
+            async move {
+                let _counter = counter;
+                pending().await
+            }
+        }
+
+        let dropped = Arc::new(Mutex::new(0));
+        let transport = {
+            let on_call = dropped.clone();
+            let on_query = dropped.clone();
+            let on_read_state = dropped.clone();
+            MockTransport::new()
+                .on_call(move |_| never_answers(on_call.clone()))
+                .on_query(move |_| never_answers(on_query.clone()))
+                .on_read_state(move |_| never_answers(on_read_state.clone()))
+        };
+        let agent = Agent::builder().with_transport(transport).build().unwrap();
+        let canister_id = Principal::management_canister();
+        let (mut first, abort_first) = agent
+            .update(&canister_id, "greet")
+            .call_and_wait_abortable();
+        let (mut second, _abort_second) = agent.query(&canister_id, "greet").call_abortable();
+        // send both requests
+        assert!((&mut first).now_or_never().is_none());
+        assert!((&mut second).now_or_never().is_none());
+
+        abort_first.abort();
+        assert!(matches!(first.await, Err(AgentError::RequestCancelled)));
+        assert_eq!(*dropped.lock().unwrap(), 1);
+        // the other call is still in flight
+        assert!((&mut second).now_or_never().is_none());
+        assert_eq!(*dropped.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn nonce_per_request_type() {
+        fn has_nonce(envelope: &[u8]) -> bool {
+            let envelope: serde_cbor::Value = serde_cbor::from_slice(envelope).unwrap();
+            let serde_cbor::Value::Map(envelope) = envelope else {
+                panic!("envelope is not a map")
+            };
+            let Some(serde_cbor::Value::Map(content)) =
+                envelope.get(&serde_cbor::Value::Text("content".into()))
+            else {
+                panic!("envelope has no content")
+            };
+            content.contains_key(&serde_cbor::Value::Text("nonce".into()))
+        }
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_nonce_factory(NonceFactory::random())
+            .build()
+            .unwrap();
+        let canister_id = Principal::management_canister();
+
+        // calls take a nonce from the agent's nonce factory
+        let update = agent.update(&canister_id, "greet").sign().unwrap();
+        assert!(has_nonce(&update.signed_update));
+        // queries only have a nonce if asked for one
+        let query = agent.query(&canister_id, "greet").sign().unwrap();
+        assert!(!has_nonce(&query.signed_query));
+        let query = agent
+            .query(&canister_id, "greet")
+            .with_nonce_generation()
+            .sign()
+            .unwrap();
+        assert!(has_nonce(&query.signed_query));
+        // read_state requests never do
+        let status = agent
+            .sign_request_status(canister_id, update.request_id)
+            .unwrap();
+        assert!(!has_nonce(&status.signed_request_status));
+
+        // with an empty nonce factory, calls have no nonce either
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_nonce_factory(NonceFactory::empty())
+            .build()
+            .unwrap();
+        let update = agent.update(&canister_id, "greet").sign().unwrap();
+        assert!(!has_nonce(&update.signed_update));
+    }
+
+    #[test]
+    fn expiry_at_exact_time() {
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .build()
+            .unwrap();
+        let expiry = 1_700_000_123_456_789_012;
+        let time = OffsetDateTime::from_unix_timestamp_nanos(expiry.into()).unwrap();
+        let update = agent
+            .update(&Principal::management_canister(), "greet")
+            .expire_at(time)
+            .sign()
+            .unwrap();
+        assert_eq!(update.ingress_expiry, expiry);
+        let envelope: Envelope = serde_cbor::from_slice(&update.signed_update).unwrap();
+        assert_eq!(envelope.content.ingress_expiry(), expiry);
+
+        let query = agent
+            .query(&Principal::management_canister(), "greet")
+            .expire_at(time)
+            .sign()
+            .unwrap();
+        assert_eq!(query.ingress_expiry, expiry);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watches_request_status() {
+        let mut polled = vec![
+            RequestStatusResponse::Replied(ReplyResponse { arg: vec![1] }),
+            RequestStatusResponse::Processing,
+            RequestStatusResponse::Processing,
+            RequestStatusResponse::Unknown,
+        ];
+        let statuses: Vec<_> = Agent::watch_request_status(Arc::new(DefaultSleeper), || {
+            future::ready(Ok(polled.pop().unwrap()))
+        })
+        .collect()
+        .await;
+        assert_eq!(
+            statuses.into_iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            [
+                RequestStatusResponse::Received,
+                RequestStatusResponse::Processing,
+                RequestStatusResponse::Replied(ReplyResponse { arg: vec![1] }),
+            ]
+        );
+
+        // errors end the stream
+        let statuses: Vec<_> = Agent::watch_request_status(Arc::new(DefaultSleeper), || {
+            future::ready(Err(AgentError::CircuitOpen))
+        })
+        .collect()
+        .await;
+        assert!(matches!(
+            statuses[..],
+            [
+                Ok(RequestStatusResponse::Received),
+                Err(AgentError::CircuitOpen)
+            ]
+        ));
+    }
+
+    #[test]
+    fn polls_with_configured_sleeper() {
+        use futures_util::FutureExt;
+
+        let sleeper = Arc::new(RecordingSleeper::default());
+        let mut polled = vec![
+            RequestStatusResponse::Replied(ReplyResponse { arg: vec![1] }),
+            RequestStatusResponse::Processing,
+            RequestStatusResponse::Unknown,
+        ];
+        // no runtime is needed, as nothing actually sleeps
+        let statuses: Vec<_> = Agent::watch_request_status(sleeper.clone(), || {
+            future::ready(Ok(polled.pop().unwrap()))
+        })
+        .collect()
+        .now_or_never()
+        .unwrap();
+        assert_eq!(statuses.len(), 3);
+
+        let delays = sleeper.delays();
+        assert_eq!(delays.len(), 3);
+        // at most the maximum interval of 1s, randomized by up to 50%
+        assert!(delays
+            .iter()
+            .all(|delay| *delay <= Duration::from_millis(1500)));
+    }
+
+    #[tokio::test]
+    async fn custom_wire_format_gets_cbor_limits() {
+        /// CBOR, recording the limits query and read_state responses are decoded within.
+        #[derive(Debug, Default)]
+        struct Recording(Mutex<Vec<CborLimits>>);
+
+        impl WireFormat for Recording {
+            fn content_type(&self) -> &str {
+                Cbor.content_type()
+            }
+            fn encode_envelope(&self, envelope: &Envelope) -> Result<Vec<u8>, AgentError> {
+                Cbor.encode_envelope(envelope)
+            }
+            fn decode_envelope(&self, bytes: &[u8]) -> Result<Envelope<'static>, AgentError> {
+                Cbor.decode_envelope(bytes)
+            }
+            fn decode_query_response(
+                &self,
+                bytes: &[u8],
+                limits: &CborLimits,
+            ) -> Result<QueryResponse, AgentError> {
+                self.0.lock().unwrap().push(*limits);
+                Cbor.decode_query_response(bytes, limits)
+            }
+            fn decode_read_state_response(
+                &self,
+                bytes: &[u8],
+                limits: &CborLimits,
+            ) -> Result<ReadStateResponse, AgentError> {
+                self.0.lock().unwrap().push(*limits);
+                Cbor.decode_read_state_response(bytes, limits)
+            }
+            fn decode_call_response(
+                &self,
+                bytes: &[u8],
+                limits: &CborLimits,
+            ) -> Result<TransportCallResponse, AgentError> {
+                Cbor.decode_call_response(bytes, limits)
+            }
+            fn decode_reject_response(&self, bytes: &[u8]) -> Result<RejectResponse, AgentError> {
+                Cbor.decode_reject_response(bytes)
+            }
+        }
+
+        let limits = CborLimits {
+            max_depth: 4,
+            max_collection_length: 16,
+        };
+        let format = Arc::new(Recording::default());
+        // a map whose single value is an array nested 8 levels deep
+        let mut body = vec![0xa1, 0x61, b'x'];
+        body.extend([0x81; 8]);
+        body.push(0x00);
+        let query_body = body.clone();
+        let agent = Agent::builder()
+            .with_transport(
+                MockTransport::new()
+                    .on_read_state(move |_| future::ready(Ok(body.clone())))
+                    .on_query(move |_| future::ready(Ok(query_body.clone()))),
+            )
+            .with_wire_format(format.clone())
+            .with_cbor_limits(limits)
+            .build()
+            .unwrap();
+
+        let result = agent
+            .read_state_raw(vec![vec!["time".into()]], Principal::management_canister())
+            .await;
+        assert!(matches!(result, Err(AgentError::CborLimitExceeded(_))));
+        let result = agent
+            .query(&Principal::management_canister(), "greet")
+            .call()
+            .await;
+        assert!(matches!(result, Err(AgentError::CborLimitExceeded(_))));
+        assert_eq!(*format.0.lock().unwrap(), [limits, limits]);
+    }
+
+    #[tokio::test]
+    async fn update_builder_call_and_watch() {
+        let canister_id: Principal = "ryjl3-tyaaa-aaaaa-aaaba-cai".parse().unwrap();
+        let transport = MockTransport::new()
+            .on_call(move |request| {
+                assert_eq!(request.effective_canister_id, Principal::anonymous());
+                assert_eq!(request.retry_policy, RetryPolicy::Never);
+                future::ready(Ok(TransportCallResponse::Accepted))
+            })
+            .on_read_state(move |request| {
+                assert_eq!(request.effective_canister_id, Principal::anonymous());
+                future::ready(Err(AgentError::CircuitOpen))
+            });
+        let agent = Agent::builder().with_transport(transport).build().unwrap();
+
+        let statuses: Vec<_> = agent
+            .update(&canister_id, "greet")
+            .with_effective_canister_id(Principal::anonymous())
+            .with_retry_policy(RetryPolicy::Never)
+            .with_correlation_id("ticket-4711")
+            .call_and_watch()
+            .collect()
+            .await;
+        let [Ok(RequestStatusResponse::Received), Err(AgentError::Correlated {
+            correlation_id,
+            error,
+        })] = &statuses[..]
+        else {
+            panic!("{statuses:?}");
+        };
+        assert_eq!(correlation_id, "ticket-4711");
+        assert!(matches!(**error, AgentError::CircuitOpen));
+    }
+
+    #[test]
+    fn waits_for_healthy_replica() {
+        use futures_util::FutureExt;
+
+        /// Reports the health statuses in turn, failing once they run out.
+        fn booting(statuses: Vec<&'static str>) -> MockTransport {
+            let statuses = Mutex::new(statuses);
+            MockTransport::new().on_status(move || {
+                let result = match statuses.lock().unwrap().pop() {
+                    Some(health) => Ok(serde_cbor::to_vec(&std::collections::BTreeMap::from([(
+                        "replica_health_status",
+                        health,
+                    )]))
+                    .unwrap()),
+                    None => Err(AgentError::TransportError("connection refused".into())),
+                };
+                future::ready(result)
+            })
+        }
+
+        let sleeper = Arc::new(RecordingSleeper::default());
+        let agent = Agent::builder()
+            .with_transport(booting(vec!["healthy", "starting", "starting"]))
+            .with_sleeper(sleeper.clone())
+            .build()
+            .unwrap();
+        let status = agent
+            .wait_for_healthy(Duration::from_secs(60))
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.replica_health_status.as_deref(), Some("healthy"));
+        // the deadline of each of the three requests, and the two backoffs between them
+        assert_eq!(sleeper.delays().len(), 5);
+
+        let agent = Agent::builder()
+            .with_transport(booting(vec!["starting"]))
+            .with_sleeper(sleeper)
+            .build()
+            .unwrap();
+        let result = agent
+            .wait_for_healthy(Duration::ZERO)
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(AgentError::ReplicaUnhealthy { last_status }) if last_status.as_deref() == Some("starting")
+        ));
+    }
+
+    #[test]
+    fn wait_for_healthy_keeps_to_its_deadline() {
+        use futures_util::FutureExt;
+
+        /// A clock that only moves when slept on.
+        #[derive(Debug)]
+        struct VirtualTime(Mutex<OffsetDateTime>);
+
+        impl Clock for VirtualTime {
+            fn now(&self) -> OffsetDateTime {
+                *self.0.lock().unwrap()
+            }
+        }
+
+        impl Sleeper for VirtualTime {
+            fn sleep(&self, duration: Duration) -> SleepFuture {
+                *self.0.lock().unwrap() += duration;
+                Box::pin(future::ready(()))
+            }
+        }
+
+        let time = Arc::new(VirtualTime(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let agent = Agent::builder()
+            .with_transport(
+                MockTransport::new()
+                    .on_status(|| future::ready(Err(AgentError::InvalidReplicaStatus))),
+            )
+            .with_clock(time.clone())
+            .with_sleeper(time.clone())
+            .build()
+            .unwrap();
+        let result = agent
+            .wait_for_healthy(Duration::from_secs(60))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(result, Err(AgentError::InvalidReplicaStatus)));
+        assert_eq!(
+            time.now(),
+            OffsetDateTime::UNIX_EPOCH + Duration::from_secs(60)
+        );
+
+        // a status request that never completes is abandoned at the deadline
+        let time = Arc::new(VirtualTime(Mutex::new(OffsetDateTime::UNIX_EPOCH)));
+        let agent = Agent::builder()
+            .with_transport(MockTransport::new().on_status(future::pending))
+            .with_clock(time.clone())
+            .with_sleeper(time.clone())
+            .build()
+            .unwrap();
+        let result = agent
+            .wait_for_healthy(Duration::from_secs(60))
+            .now_or_never()
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(AgentError::ReplicaUnhealthy { last_status: None })
+        ));
+        assert_eq!(
+            time.now(),
+            OffsetDateTime::UNIX_EPOCH + Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn resolves_canister_aliases() {
+        let ledger = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_canister_aliases([("ledger".to_string(), ledger)].into())
+            .build()
+            .unwrap();
+
+        let query = agent.query_by_name("ledger", "icrc1_balance_of").unwrap();
+        assert_eq!(query.canister_id, ledger);
+        assert_eq!(query.effective_canister_id, ledger);
+        let update = agent.update_by_name("ledger", "icrc1_transfer").unwrap();
+        assert_eq!(update.sign().unwrap().canister_id, ledger);
+
+        assert!(matches!(
+            agent.query_by_name("governance", "get_neuron_info"),
+            Err(AgentError::UnknownCanisterAlias(name)) if name == "governance"
+        ));
+    }
+
+    #[test]
+    fn envelope_size_of_call() {
+        use crate::identity::Secp256k1Identity;
+
+        let canister_id = Principal::management_canister();
+        let identity =
+            Secp256k1Identity::from_private_key(k256::SecretKey::from_slice(&[1; 32]).unwrap());
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_identity(identity)
+            .build()
+            .unwrap();
+
+        let size = agent
+            .envelope_size(&canister_id, "greet", b"DIDL\0\0")
+            .unwrap();
+        let signed = agent
+            .update(&canister_id, "greet")
+            .with_arg(b"DIDL\0\0".to_vec())
+            .sign()
+            .unwrap();
+        assert_eq!(size, signed.signed_update.len());
+        // the content, a 64-byte signature and an 88-byte DER public key, with their CBOR framing
+        assert!((300..450).contains(&size), "{size}");
+
+        let larger = agent
+            .envelope_size(&canister_id, "greet", &[0; 1000])
+            .unwrap();
+        assert!((size + 990..size + 1010).contains(&larger), "{larger}");
+    }
+
+    #[test]
+    fn requires_identity_for_updates() {
+        use crate::identity::Secp256k1Identity;
+
+        let canister_id = Principal::management_canister();
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .build()
+            .unwrap();
+        let update = agent.update(&canister_id, "greet").sign().unwrap();
+        assert_eq!(update.sender, Principal::anonymous());
+
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_default_to_anonymous(false)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            agent.update(&canister_id, "greet").sign(),
+            Err(AgentError::IdentityRequired)
+        ));
+        let query = agent.query(&canister_id, "greet").sign().unwrap();
+        assert_eq!(query.sender, Principal::anonymous());
+
+        let identity =
+            Secp256k1Identity::from_private_key(k256::SecretKey::from_slice(&[1; 32]).unwrap());
+        let sender = identity.sender().unwrap();
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_identity(identity)
+            .with_default_to_anonymous(false)
+            .build()
+            .unwrap();
+        let update = agent.update(&canister_id, "greet").sign().unwrap();
+        assert_eq!(update.sender, sender);
+    }
+
+    #[test]
+    fn principal_of_identity() {
+        use crate::identity::BasicIdentity;
+        use ring::signature::Ed25519KeyPair;
+
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .build()
+            .unwrap();
+        assert_eq!(agent.get_principal().unwrap(), Principal::anonymous());
+
+        // the self-authenticating principal of the DER-encoded public key 3b6a27bc...8b59da29
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_identity(BasicIdentity::from_key_pair(key_pair))
+            .build()
+            .unwrap();
+        assert_eq!(
+            agent.get_principal().unwrap(),
+            Principal::from_text("535yc-uxytb-gfk7h-tny7p-vjkoe-i4krp-3qmcl-uqfgr-cpgej-yqtjq-rqe")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn estimate_call_cost() {
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .build()
+            .unwrap();
+        let canister_id = Principal::management_canister();
+        assert_eq!(agent.estimate_call_cost(&canister_id, "", &[]), 6_200_000);
+        // 5 bytes of method name and 1000 bytes of argument
+        assert_eq!(
+            agent.estimate_call_cost(&canister_id, "greet", &[0; 1000]),
+            6_200_000 + 2_000 * 1005
+        );
+    }
+
     #[tokio::test]
     async fn client_ratelimit() {
         struct SlowTransport(Arc<Mutex<usize>>);
@@ -1879,4 +4395,44 @@ mod offline_tests {
         crate::util::sleep(Duration::from_millis(250)).await;
         assert_eq!(*count.lock().unwrap(), 2);
     }
+
+    #[tokio::test]
+    async fn update_with_correlation_id() {
+        let transport = MockTransport::new()
+            .on_call(|request| {
+                assert_eq!(request.headers["x-correlation-id"], "ticket-4711");
+                future::ready(Ok(TransportCallResponse::Accepted))
+            })
+            .on_read_state(|request| {
+                assert!(request.headers.is_empty());
+                future::ready(Err(AgentError::MessageError("unreachable".to_string())))
+            });
+        let agent = Agent::builder().with_transport(transport).build().unwrap();
+        let canister_id = "ryjl3-tyaaa-aaaaa-aaaba-cai".parse().unwrap();
+
+        // the error comes from polling for the call's status, after it was submitted
+        let err = agent
+            .update(&canister_id, "greet")
+            .with_correlation_id("ticket-4711")
+            .call_and_wait()
+            .await
+            .unwrap_err();
+        let AgentError::Correlated {
+            correlation_id,
+            error,
+        } = &err
+        else {
+            panic!("{err:?}");
+        };
+        assert_eq!(correlation_id, "ticket-4711");
+        assert!(matches!(**error, AgentError::MessageError(_)));
+
+        let err = agent
+            .update(&canister_id, "greet")
+            .with_correlation_id("ticket\n4711")
+            .call()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::Correlated { .. }));
+    }
 }
@@ -0,0 +1,36 @@
+//! Hooks for observing the outcome of requests made by an [`Agent`](super::Agent).
+use time::Duration;
+
+use crate::{agent::RejectCode, RequestId};
+
+/// Receives notifications about requests made by an [`Agent`](super::Agent).
+///
+/// Register one with [`AgentBuilder::with_observer`](super::AgentBuilder::with_observer).
+/// Every method has an empty default implementation, so implementors only override what they need.
+/// Methods are called synchronously on the task making the request, and should return quickly.
+pub trait AgentObserver: Send + Sync {
+    /// Called when an update call is rejected, whether by the canister or by the system, as opposed
+    /// to failing at the HTTP or transport level. The rejection is also returned to the caller as
+    /// [`AgentError::CertifiedReject`](super::AgentError::CertifiedReject) or
+    /// [`AgentError::UncertifiedReject`](super::AgentError::UncertifiedReject).
+    fn on_rejection(&self, request_id: &RequestId, reject_code: RejectCode, reject_message: &str) {
+        let _ = (request_id, reject_code, reject_message);
+    }
+
+    /// Called when the `Date` header of a response differs from the local clock by more than the
+    /// threshold set with
+    /// [`ReqwestTransport::with_clock_skew_observer`](super::http_transport::ReqwestTransport::with_clock_skew_observer).
+    /// `skew` is how far the server's clock is ahead of the local one, and is negative if it is
+    /// behind. A skewed clock eventually makes requests fail because their ingress expiry is out of
+    /// range.
+    fn on_clock_skew(&self, skew: Duration) {
+        let _ = skew;
+    }
+
+    /// Called when the identity failed to sign a request and the
+    /// [fallback identity](super::AgentBuilder::with_fallback_identity) signed it instead. `error`
+    /// is the identity's error.
+    fn on_identity_fallback(&self, error: &str) {
+        let _ = error;
+    }
+}
@@ -0,0 +1,135 @@
+//! Tracking of update calls whose submission may have reached the replica without the agent
+//! learning of it, so that making the same call again does not submit it twice.
+use std::{collections::HashMap, sync::Mutex};
+
+use ic_transport_types::EnvelopeContent;
+use sha2::{Digest, Sha256};
+
+use crate::{export::Principal, AgentError, RequestId};
+
+/// What makes two update calls the same call: their sender, effective canister id, canister id,
+/// method name, and a hash of their argument. The nonce and expiry are left out, as they differ
+/// between otherwise identical calls.
+type CallKey = (Principal, Principal, Principal, String, [u8; 32]);
+
+/// A submitted call: its request id, and the signed envelope to submit again if needed.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingCall {
+    pub request_id: RequestId,
+    pub envelope: Vec<u8>,
+    pub ingress_expiry: u64,
+    /// Whether the call was abandoned before the outcome of its submission was known, by its
+    /// future being dropped or by a transport error.
+    abandoned: bool,
+}
+
+/// The calls being submitted or abandoned mid-submission, until their ingress expiry.
+#[derive(Debug, Default)]
+pub(crate) struct PendingCalls(Mutex<HashMap<CallKey, PendingCall>>);
+
+impl PendingCalls {
+    /// Takes over the abandoned call that `content` repeats, if it has not expired by `now`
+    /// (in nanoseconds since the Unix epoch).
+    pub fn claim(
+        &self,
+        effective_canister_id: Principal,
+        content: &EnvelopeContent,
+        now: u64,
+    ) -> Option<(PendingCall, Submission<'_>)> {
+        let key = call_key(effective_canister_id, content)?;
+        let mut calls = self.0.lock().unwrap();
+        calls.retain(|_, call| call.ingress_expiry > now);
+        let call = calls.get_mut(&key).filter(|call| call.abandoned)?;
+        call.abandoned = false;
+        let call = call.clone();
+        Some((call, Submission::new(self, key)))
+    }
+
+    /// Records the submission of `content`, signed as `envelope`. Returns `None` without recording
+    /// it if the same call is already being submitted.
+    pub fn start(
+        &self,
+        effective_canister_id: Principal,
+        content: &EnvelopeContent,
+        request_id: RequestId,
+        envelope: &[u8],
+    ) -> Option<Submission<'_>> {
+        let key = call_key(effective_canister_id, content)?;
+        let mut calls = self.0.lock().unwrap();
+        if calls.contains_key(&key) {
+            return None;
+        }
+        calls.insert(
+            key.clone(),
+            PendingCall {
+                request_id,
+                envelope: envelope.to_vec(),
+                ingress_expiry: content.ingress_expiry(),
+                abandoned: false,
+            },
+        );
+        Some(Submission::new(self, key))
+    }
+}
+
+fn call_key(effective_canister_id: Principal, content: &EnvelopeContent) -> Option<CallKey> {
+    match content {
+        EnvelopeContent::Call {
+            sender,
+            canister_id,
+            method_name,
+            arg,
+            ..
+        } => Some((
+            *sender,
+            effective_canister_id,
+            *canister_id,
+            method_name.clone(),
+            Sha256::digest(arg).into(),
+        )),
+        _ => None,
+    }
+}
+
+/// A call being submitted. If it is dropped before [`finish`](Self::finish), the call is marked as
+/// abandoned.
+#[derive(Debug)]
+pub(crate) struct Submission<'a> {
+    calls: &'a PendingCalls,
+    key: CallKey,
+    finished: bool,
+}
+
+impl<'a> Submission<'a> {
+    fn new(calls: &'a PendingCalls, key: CallKey) -> Self {
+        Self {
+            calls,
+            key,
+            finished: false,
+        }
+    }
+
+    /// Records the outcome of the submission. The call is forgotten unless `result` leaves it
+    /// unknown whether the replica received it: a transport error, or a timeout after connecting,
+    /// which may come after the envelope was sent. Only a connect timeout proves it was not.
+    pub fn finish<T>(mut self, result: &Result<T, AgentError>) {
+        if !matches!(
+            result,
+            Err(AgentError::TransportError(_) | AgentError::TimeoutError(_))
+        ) {
+            self.calls.0.lock().unwrap().remove(&self.key);
+            self.finished = true;
+        }
+    }
+}
+
+impl Drop for Submission<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Some(call) = self.calls.0.lock().unwrap().get_mut(&self.key) {
+            call.abandoned = true;
+        }
+    }
+}
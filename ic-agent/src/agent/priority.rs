@@ -0,0 +1,272 @@
+//! Per-request priorities, and the limit on concurrent requests that orders them.
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+/// The priority of a request while it waits for one of the agent's
+/// [concurrent request slots](super::AgentBuilder::with_max_concurrent_requests).
+///
+/// When a slot frees up, it goes to the request that has waited longest among those of the highest
+/// priority. Priorities are strict: while higher-priority requests keep arriving faster than slots
+/// free up, lower-priority ones are starved and wait indefinitely. A request that is already being
+/// sent is never preempted.
+///
+/// Set it with [`QueryBuilder::with_priority`](super::QueryBuilder::with_priority) or
+/// [`UpdateBuilder::with_priority`](super::UpdateBuilder::with_priority). Requests the agent makes
+/// on its own, such as polling for the status of a call, have [`Priority::Normal`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Waits for all higher-priority requests, e.g. for background jobs.
+    Low,
+    /// The priority of requests that don't set one.
+    #[default]
+    Normal,
+    /// Goes ahead of all lower-priority requests, e.g. for requests a user is waiting on.
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Limits the number of requests in flight, handing out free slots by [`Priority`], and in order of
+/// arrival within a priority.
+#[derive(Debug)]
+pub(crate) struct PriorityLimiter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    available: usize,
+    next_id: u64,
+    /// The waiting requests of each priority, oldest first.
+    queues: [VecDeque<(u64, Waker)>; Priority::COUNT],
+    /// The waiting requests that have been handed a slot, but not yet taken it.
+    granted: HashSet<u64>,
+}
+
+impl State {
+    /// Hands the slot of a finished request to the next waiting one, returning its waker.
+    fn release(&mut self) -> Option<Waker> {
+        let next = self
+            .queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front());
+        match next {
+            Some((id, waker)) => {
+                self.granted.insert(id);
+                Some(waker)
+            }
+            None => {
+                self.available += 1;
+                None
+            }
+        }
+    }
+}
+
+impl PriorityLimiter {
+    pub(crate) fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                available: permits,
+                next_id: 0,
+                queues: Default::default(),
+                granted: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Waits for a free slot. The slot is held until the returned permit is dropped.
+    pub(crate) fn acquire(&self, priority: Priority) -> Acquire<'_> {
+        Acquire {
+            limiter: self,
+            priority,
+            id: None,
+        }
+    }
+
+    fn release(&self) {
+        let waker = self.state.lock().unwrap().release();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by [`PriorityLimiter::acquire`].
+#[derive(Debug)]
+pub(crate) struct Acquire<'a> {
+    limiter: &'a PriorityLimiter,
+    priority: Priority,
+    /// The id of this request in the queue, once it has had to wait.
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let limiter = self.limiter;
+        let mut state = limiter.state.lock().unwrap();
+        match self.id {
+            None => {
+                // a free slot is only taken right away if no one is waiting for it
+                if state.available > 0 && state.queues.iter().all(VecDeque::is_empty) {
+                    state.available -= 1;
+                    return Poll::Ready(Permit { limiter });
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                state.queues[self.priority.index()].push_back((id, cx.waker().clone()));
+                self.id = Some(id);
+                Poll::Pending
+            }
+            Some(id) => {
+                if state.granted.remove(&id) {
+                    self.id = None;
+                    return Poll::Ready(Permit { limiter });
+                }
+                let queue = &mut state.queues[self.priority.index()];
+                if let Some((_, waker)) = queue.iter_mut().find(|(queued, _)| *queued == id) {
+                    waker.clone_from(cx.waker());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut state = self.limiter.state.lock().unwrap();
+        if state.granted.remove(&id) {
+            // the slot was handed over after all, so it goes to the next in line
+            let waker = state.release();
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        } else {
+            state.queues[self.priority.index()].retain(|(queued, _)| *queued != id);
+        }
+    }
+}
+
+/// A slot for one request in flight, freed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    limiter: &'a PriorityLimiter,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::EnvelopeContent,
+        export::Principal,
+        test_utils::{query_reply, MockTransport},
+        Agent,
+    };
+    use futures_util::{future::join_all, FutureExt};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn high_priority_goes_first() {
+        // answers queries with an empty reply, recording the methods called, and holds the reply
+        // to `hold` until notified
+        let called = Arc::new(Mutex::new(vec![]));
+        let release = Arc::new(Notify::new());
+        let transport = {
+            let called = called.clone();
+            let release = release.clone();
+            MockTransport::new().on_query(move |request| {
+                let EnvelopeContent::Query { method_name, .. } = request.content() else {
+                    unreachable!()
+                };
+                called.lock().unwrap().push(method_name.clone());
+                let release = release.clone();
+                async move {
+                    if method_name == "hold" {
+                        release.notified().await;
+                    }
+                    Ok(query_reply(vec![]))
+                }
+            })
+        };
+        let agent = Agent::builder()
+            .with_transport(transport)
+            .with_verify_query_signatures(false)
+            .with_max_concurrent_requests(1)
+            .build()
+            .unwrap();
+        let canister_id = Principal::management_canister();
+        let query = |method: &str, priority| {
+            agent
+                .query(&canister_id, method)
+                .with_priority(priority)
+                .call()
+        };
+
+        // the only slot is taken, so all of these queue up behind it
+        let mut hold = Box::pin(query("hold", Priority::Normal));
+        assert!((&mut hold).now_or_never().is_none());
+        let mut queued = vec![];
+        for (method, priority) in [
+            ("low1", Priority::Low),
+            ("low2", Priority::Low),
+            ("low3", Priority::Low),
+            ("high", Priority::High),
+        ] {
+            let mut future = Box::pin(query(method, priority));
+            assert!((&mut future).now_or_never().is_none());
+            queued.push(future);
+        }
+        assert_eq!(*called.lock().unwrap(), ["hold"]);
+
+        release.notify_one();
+        hold.await.unwrap();
+        for result in join_all(queued).await {
+            result.unwrap();
+        }
+        assert_eq!(
+            *called.lock().unwrap(),
+            ["hold", "high", "low1", "low2", "low3"]
+        );
+    }
+
+    #[test]
+    fn abandoned_waiter_passes_on_its_slot() {
+        let limiter = PriorityLimiter::new(1);
+        let permit = limiter.acquire(Priority::Normal).now_or_never().unwrap();
+        let mut first = Box::pin(limiter.acquire(Priority::High));
+        assert!((&mut first).now_or_never().is_none());
+        let mut second = Box::pin(limiter.acquire(Priority::Low));
+        assert!((&mut second).now_or_never().is_none());
+
+        // the slot is handed to `first`, which gives it up without taking it
+        drop(permit);
+        drop(first);
+        assert!(second.now_or_never().is_some());
+    }
+}
@@ -1,4 +1,6 @@
-use crate::agent::{ApiBoundaryNode, RejectCode, RejectResponse, RequestStatusResponse};
+use crate::agent::{
+    ApiBoundaryNode, RejectCode, RejectResponse, RequestStatusResponse, SubnetInfo,
+};
 use crate::{export::Principal, AgentError, RequestId};
 use ic_certification::hash_tree::{HashTree, SubtreeLookupResult};
 use ic_certification::{certificate::Certificate, hash_tree::Label, LookupResult};
@@ -53,9 +55,47 @@ pub(crate) fn lookup_canister_info<Storage: AsRef<[u8]>>(
     lookup_value(&certificate.tree, path_canister).map(<[u8]>::to_vec)
 }
 
-pub(crate) fn lookup_canister_metadata<Storage: AsRef<[u8]>>(
+pub(crate) fn lookup_canister_controllers<Storage: AsRef<[u8]>>(
     certificate: Certificate<Storage>,
     canister_id: Principal,
+) -> Result<Vec<Principal>, AgentError> {
+    let controllers = lookup_canister_info(certificate, canister_id, "controllers")?;
+    decode_controllers(&controllers)
+}
+
+/// Looks up `/canister/<canister_id>/module_hash`, which is absent if the canister has no module installed.
+pub(crate) fn lookup_canister_module_hash<Storage: AsRef<[u8]>>(
+    certificate: &Certificate<Storage>,
+    canister_id: Principal,
+) -> Result<Option<[u8; 32]>, AgentError> {
+    let path = [
+        "canister".as_bytes(),
+        canister_id.as_slice(),
+        "module_hash".as_bytes(),
+    ];
+    match lookup_value(&certificate.tree, path) {
+        Ok(hash) => hash
+            .try_into()
+            .map(Some)
+            .map_err(|_| AgentError::LookupPathError(path.into_vec())),
+        Err(AgentError::LookupPathAbsent(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Decodes the CBOR array of principals stored at `/canister/<canister_id>/controllers`.
+fn decode_controllers(controllers: &[u8]) -> Result<Vec<Principal>, AgentError> {
+    let controllers: Vec<serde_bytes::ByteBuf> =
+        serde_cbor::from_slice(controllers).map_err(AgentError::InvalidCborData)?;
+    controllers
+        .iter()
+        .map(|controller| Ok(Principal::try_from_slice(controller)?))
+        .collect()
+}
+
+pub(crate) fn lookup_canister_metadata<Storage: AsRef<[u8]>>(
+    certificate: &Certificate<Storage>,
+    canister_id: Principal,
     path: &str,
 ) -> Result<Vec<u8>, AgentError> {
     let path_canister = [
@@ -180,7 +220,41 @@ pub(crate) fn lookup_subnet<Storage: AsRef<[u8]> + Clone>(
         } else {
             serde_cbor::from_slice(lookup_value(&subnet_tree, [b"canister_ranges".as_ref()])?)?
         };
-    let node_keys_subtree = lookup_tree(&subnet_tree, [b"node".as_ref()])?;
+    let node_keys = lookup_node_keys(&subnet_tree)?;
+    let mut range_set = RangeInclusiveSet::new_with_step_fns();
+    for (low, high) in canister_ranges {
+        range_set.insert(low..=high);
+    }
+    let subnet = Subnet {
+        canister_ranges: range_set,
+        _key: key,
+        node_keys,
+    };
+    Ok((subnet_id, subnet))
+}
+
+pub(crate) fn lookup_subnet_info<Storage: AsRef<[u8]> + Clone>(
+    certificate: &Certificate<Storage>,
+    subnet_id: Principal,
+) -> Result<SubnetInfo, AgentError> {
+    let subnet_tree = lookup_tree(&certificate.tree, [b"subnet", subnet_id.as_slice()])?;
+    let public_key = lookup_value(&subnet_tree, [b"public_key".as_ref()])?.to_vec();
+    let canister_ranges =
+        serde_cbor::from_slice(lookup_value(&subnet_tree, [b"canister_ranges".as_ref()])?)?;
+    let node_keys = lookup_node_keys(&subnet_tree)?;
+    Ok(SubnetInfo {
+        subnet_id,
+        public_key,
+        canister_ranges,
+        node_keys,
+    })
+}
+
+/// Looks up the public keys of the nodes under `node` in the tree of a subnet, by node ID.
+fn lookup_node_keys<Storage: AsRef<[u8]> + Clone>(
+    subnet_tree: &HashTree<Storage>,
+) -> Result<HashMap<Principal, Vec<u8>>, AgentError> {
+    let node_keys_subtree = lookup_tree(subnet_tree, [b"node".as_ref()])?;
     let mut node_keys = HashMap::new();
     for path in node_keys_subtree.list_paths() {
         if path.len() < 2 {
@@ -201,16 +275,7 @@ pub(crate) fn lookup_subnet<Storage: AsRef<[u8]> + Clone>(
         let node_key = lookup_value(&node_keys_subtree, [node_id.as_slice(), b"public_key"])?;
         node_keys.insert(node_id, node_key.to_vec());
     }
-    let mut range_set = RangeInclusiveSet::new_with_step_fns();
-    for (low, high) in canister_ranges {
-        range_set.insert(low..=high);
-    }
-    let subnet = Subnet {
-        canister_ranges: range_set,
-        _key: key,
-        node_keys,
-    };
-    Ok((subnet_id, subnet))
+    Ok(node_keys)
 }
 
 pub(crate) fn lookup_api_boundary_nodes<Storage: AsRef<[u8]> + Clone>(
@@ -394,3 +459,157 @@ pub fn lookup_tree<P: LookupPath, Storage: AsRef<[u8]> + Clone>(
         SubtreeLookupResult::Found(value) => Ok(value),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_controllers, lookup_canister_module_hash, lookup_subnet, lookup_time};
+    use crate::{export::Principal, AgentError};
+    use ic_certification::{
+        hash_tree::{fork, label, leaf, pruned},
+        Certificate, Delegation,
+    };
+
+    #[test]
+    fn decodes_controllers() {
+        // 55799(["\x04", "\x00\x00\x00\x00\x00\x00\x00\x02\x01\x01"])
+        let controllers = hex::decode("d9d9f78241044a00000000000000020101").unwrap();
+        assert_eq!(
+            decode_controllers(&controllers).unwrap(),
+            vec![
+                Principal::anonymous(),
+                Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_empty_controllers() {
+        let controllers = hex::decode("d9d9f780").unwrap();
+        assert_eq!(decode_controllers(&controllers).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_malformed_controllers() {
+        assert!(decode_controllers(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn decodes_time() {
+        let certificate = Certificate {
+            tree: label("time", leaf(hex::decode("c4fbbbbadf93fac717").unwrap())),
+            signature: vec![],
+            delegation: None,
+        };
+        assert_eq!(lookup_time(&certificate).unwrap(), 1697831349698624964);
+    }
+
+    #[test]
+    fn looks_up_module_hashes() {
+        let [with_module, without_module, pruned_canister, short_hash] =
+            [0u8, 1, 2, 3].map(|n| Principal::from_slice(&[n]));
+        let certificate = Certificate {
+            tree: label(
+                "canister",
+                fork(
+                    fork(
+                        label(
+                            with_module.as_slice(),
+                            label("module_hash", leaf(vec![1; 32])),
+                        ),
+                        label(
+                            without_module.as_slice(),
+                            label("controllers", leaf(vec![0xd9, 0xd9, 0xf7, 0x80])),
+                        ),
+                    ),
+                    fork(
+                        label(pruned_canister.as_slice(), pruned([0; 32])),
+                        label(
+                            short_hash.as_slice(),
+                            label("module_hash", leaf(vec![1; 31])),
+                        ),
+                    ),
+                ),
+            ),
+            signature: vec![],
+            delegation: None,
+        };
+        assert_eq!(
+            lookup_canister_module_hash(&certificate, with_module).unwrap(),
+            Some([1; 32])
+        );
+        assert_eq!(
+            lookup_canister_module_hash(&certificate, without_module).unwrap(),
+            None
+        );
+        assert!(matches!(
+            lookup_canister_module_hash(&certificate, pruned_canister),
+            Err(AgentError::LookupPathUnknown(_))
+        ));
+        assert!(matches!(
+            lookup_canister_module_hash(&certificate, short_hash),
+            Err(AgentError::LookupPathError(_))
+        ));
+    }
+
+    #[test]
+    fn looks_up_subnet_node_keys() {
+        let root_key = vec![7; 8];
+        let [node_a, node_b] = [0u8, 1].map(|n| Principal::from_slice(&[n]));
+        let ranges = serde_cbor::to_vec(&[(
+            Principal::from_slice(&[0; 10]),
+            Principal::from_slice(&[0xff; 10]),
+        )])
+        .unwrap();
+        let subnet_tree = |subnet_id: Principal, ranges: Vec<u8>| {
+            label(
+                "subnet",
+                label(
+                    subnet_id.as_slice(),
+                    fork(
+                        fork(
+                            label("canister_ranges", leaf(ranges)),
+                            label(
+                                "node",
+                                fork(
+                                    label(node_a.as_slice(), label("public_key", leaf(vec![1]))),
+                                    label(node_b.as_slice(), label("public_key", leaf(vec![2]))),
+                                ),
+                            ),
+                        ),
+                        label("public_key", leaf(vec![3])),
+                    ),
+                ),
+            )
+        };
+        let expected = [(node_a, vec![1]), (node_b, vec![2])].into();
+
+        let root_subnet = Principal::self_authenticating(&root_key);
+        let certificate = Certificate {
+            tree: subnet_tree(root_subnet, ranges.clone()),
+            signature: vec![],
+            delegation: None,
+        };
+        let (subnet_id, subnet) = lookup_subnet(&certificate, &root_key).unwrap();
+        assert_eq!(subnet_id, root_subnet);
+        assert_eq!(subnet.node_keys, expected);
+
+        // with a delegation, the canister ranges come from the delegation certificate
+        let delegated_subnet = Principal::from_slice(&[9; 29]);
+        let delegation_certificate = Certificate {
+            tree: subnet_tree(delegated_subnet, ranges),
+            signature: vec![],
+            delegation: None,
+        };
+        let certificate = Certificate {
+            tree: subnet_tree(delegated_subnet, vec![0xff]),
+            signature: vec![],
+            delegation: Some(Delegation {
+                subnet_id: delegated_subnet.as_slice().to_vec(),
+                certificate: serde_cbor::to_vec(&delegation_certificate).unwrap(),
+            }),
+        };
+        let (subnet_id, subnet) = lookup_subnet(&certificate, &root_key).unwrap();
+        assert_eq!(subnet_id, delegated_subnet);
+        assert_eq!(subnet.node_keys, expected);
+    }
+}
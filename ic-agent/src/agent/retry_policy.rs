@@ -0,0 +1,40 @@
+//! Per-request overrides of how a transport retries requests.
+
+/// How a transport retries a single request that was rate-limited, or whose connection failed,
+/// overriding the retry settings of the transport for that request alone.
+///
+/// Set it with [`QueryBuilder::with_retry_policy`](super::QueryBuilder::with_retry_policy) or
+/// [`UpdateBuilder::with_retry_policy`](super::UpdateBuilder::with_retry_policy).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Retry as the transport is configured to.
+    #[default]
+    Transport,
+    /// Never retry: the request fails on its first error.
+    Never,
+    /// Make at most this many attempts, including the first, whether the request is rate-limited
+    /// or its connection fails.
+    MaxAttempts(usize),
+}
+
+impl RetryPolicy {
+    /// The max number of attempts for a rate-limited request, given the transport's own setting.
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn max_rate_limit_attempts(self, configured: Option<usize>) -> Option<usize> {
+        match self {
+            Self::Transport => configured,
+            Self::Never => Some(1),
+            Self::MaxAttempts(attempts) => Some(attempts),
+        }
+    }
+
+    /// The max number of retries of a failed connection, given the transport's own setting.
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn max_tcp_error_retries(self, configured: usize) -> usize {
+        match self {
+            Self::Transport => configured,
+            Self::Never => 0,
+            Self::MaxAttempts(attempts) => attempts.saturating_sub(1),
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! The source of the delays the agent and its transports wait out between attempts.
+use std::{future::Future, pin::Pin, time::Duration};
+
+/// The future returned by [`Sleeper::sleep`].
+#[cfg(not(target_family = "wasm"))]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// The future returned by [`Sleeper::sleep`].
+#[cfg(target_family = "wasm")]
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Waits out the delays between polls of a request's status and between retries of rate-limited
+/// requests.
+///
+/// The default is [`DefaultSleeper`]. Implement this to run the agent on an async runtime other
+/// than tokio, or to have tests record the delays instead of waiting them out.
+pub trait Sleeper: std::fmt::Debug + Send + Sync {
+    /// Returns a future that completes after `duration`.
+    fn sleep(&self, duration: Duration) -> SleepFuture;
+}
+
+/// Sleeps with tokio's timer, or with `setTimeout` on `wasm32` with the `wasm-bindgen` feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultSleeper;
+
+impl Sleeper for DefaultSleeper {
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        Box::pin(crate::util::sleep(duration))
+    }
+}
@@ -0,0 +1,109 @@
+//! The encoding of request envelopes and responses on the wire.
+use ic_transport_types::{
+    Envelope, QueryResponse, ReadStateResponse, RejectResponse, TransportCallResponse,
+};
+
+use crate::agent::{AgentError, CborLimits};
+
+/// An encoding of the messages exchanged with the replica, and the content type it is sent as.
+///
+/// The HTTP interface of the Internet Computer only speaks [`Cbor`], which is the default. Both the
+/// [`Agent`](super::Agent), which encodes envelopes and decodes responses, and the transport, which
+/// sets the `Content-Type` header and decodes call responses, must be configured with the same format.
+///
+/// Certificates and their hash trees are CBOR by definition, whatever the format of the response
+/// that carries them.
+pub trait WireFormat: std::fmt::Debug + Send + Sync {
+    /// The value of the `Content-Type` header requests are sent with.
+    fn content_type(&self) -> &str;
+
+    /// Encodes a signed request envelope.
+    fn encode_envelope(&self, envelope: &Envelope) -> Result<Vec<u8>, AgentError>;
+
+    /// Decodes a signed request envelope, e.g. one passed to [`Agent::query_signed`](super::Agent::query_signed).
+    fn decode_envelope(&self, bytes: &[u8]) -> Result<Envelope<'static>, AgentError>;
+
+    /// Decodes the response to a query, within the agent's
+    /// [CBOR limits](super::AgentBuilder::with_cbor_limits), or whatever this format's equivalent
+    /// of them is.
+    fn decode_query_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<QueryResponse, AgentError>;
+
+    /// Decodes the response to a read_state request, within the agent's
+    /// [CBOR limits](super::AgentBuilder::with_cbor_limits), or whatever this format's equivalent
+    /// of them is.
+    fn decode_read_state_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<ReadStateResponse, AgentError>;
+
+    /// Decodes the response to a synchronous call, within the transport's CBOR limits, e.g.
+    /// [`ReqwestTransport::with_cbor_limits`](super::http_transport::ReqwestTransport::with_cbor_limits).
+    fn decode_call_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<TransportCallResponse, AgentError>;
+
+    /// Decodes the rejection of a call by the replica before it was accepted.
+    fn decode_reject_response(&self, bytes: &[u8]) -> Result<RejectResponse, AgentError>;
+}
+
+/// The CBOR encoding used by the HTTP interface of the Internet Computer, with content type
+/// `application/cbor`.
+///
+/// Query, read_state, and call responses are checked against the [`CborLimits`] they are decoded
+/// within before they are decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cbor;
+
+impl Cbor {
+    /// The content type of CBOR messages.
+    pub const CONTENT_TYPE: &'static str = "application/cbor";
+}
+
+impl WireFormat for Cbor {
+    fn content_type(&self) -> &str {
+        Self::CONTENT_TYPE
+    }
+
+    fn encode_envelope(&self, envelope: &Envelope) -> Result<Vec<u8>, AgentError> {
+        Ok(envelope.encode_bytes())
+    }
+
+    fn decode_envelope(&self, bytes: &[u8]) -> Result<Envelope<'static>, AgentError> {
+        serde_cbor::from_slice(bytes).map_err(AgentError::InvalidCborData)
+    }
+
+    fn decode_query_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<QueryResponse, AgentError> {
+        limits.decode(bytes)
+    }
+
+    fn decode_read_state_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<ReadStateResponse, AgentError> {
+        limits.decode(bytes)
+    }
+
+    fn decode_call_response(
+        &self,
+        bytes: &[u8],
+        limits: &CborLimits,
+    ) -> Result<TransportCallResponse, AgentError> {
+        limits.decode(bytes)
+    }
+
+    fn decode_reject_response(&self, bytes: &[u8]) -> Result<RejectResponse, AgentError> {
+        serde_cbor::from_slice(bytes).map_err(AgentError::InvalidCborData)
+    }
+}
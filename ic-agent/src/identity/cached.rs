@@ -0,0 +1,149 @@
+use std::sync::Mutex;
+
+use crate::{agent::EnvelopeContent, export::Principal, identity::Identity, Signature};
+
+use super::{Delegation, SignedDelegation};
+
+/// Wraps an identity whose principal or public key is expensive to derive, e.g. one that asks a
+/// hardware key for it, and derives each only once.
+///
+/// The principal is cached the first time it is derived successfully, and the public key the first
+/// time it is asked for; an identity that fails to tell its principal is asked again on the next
+/// call. Everything else is passed through. To use a different identity, wrap that one instead,
+/// e.g. when passing it to [`Agent::set_identity`](crate::Agent::set_identity).
+///
+/// Do not wrap identities whose key changes from one request to the next, such as an
+/// [`IdentityRotator`](super::IdentityRotator).
+pub struct CachedIdentity<I> {
+    inner: I,
+    sender: Mutex<Option<Principal>>,
+    public_key: Mutex<Option<Option<Vec<u8>>>>,
+}
+
+impl<I: Identity> CachedIdentity<I> {
+    /// Wraps `inner`, with nothing cached yet.
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            sender: Mutex::new(None),
+            public_key: Mutex::new(None),
+        }
+    }
+
+    /// Returns the wrapped identity.
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+}
+
+impl<I> std::fmt::Debug for CachedIdentity<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedIdentity")
+            .field("sender", &self.sender.lock().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I: Identity> Identity for CachedIdentity<I> {
+    fn sender(&self) -> Result<Principal, String> {
+        // held while deriving, so that concurrent first calls derive it once
+        let mut sender = self.sender.lock().unwrap();
+        if let Some(sender) = *sender {
+            return Ok(sender);
+        }
+        let derived = self.inner.sender()?;
+        *sender = Some(derived);
+        Ok(derived)
+    }
+
+    fn public_key(&self) -> Option<Vec<u8>> {
+        self.public_key
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| self.inner.public_key())
+            .clone()
+    }
+
+    fn sign(&self, content: &EnvelopeContent) -> Result<Signature, String> {
+        self.inner.sign(content)
+    }
+
+    fn sign_delegation(&self, content: &Delegation) -> Result<Signature, String> {
+        self.inner.sign_delegation(content)
+    }
+
+    fn sign_arbitrary(&self, content: &[u8]) -> Result<Signature, String> {
+        self.inner.sign_arbitrary(content)
+    }
+
+    fn delegation_chain(&self) -> Vec<SignedDelegation> {
+        self.inner.delegation_chain()
+    }
+
+    fn is_declined(&self, error: &str) -> bool {
+        self.inner.is_declined(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{identity::Secp256k1Identity, Agent};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Counts how often its principal and public key are derived.
+    struct Counting {
+        inner: Secp256k1Identity,
+        derivations: Arc<AtomicUsize>,
+    }
+
+    impl Identity for Counting {
+        fn sender(&self) -> Result<Principal, String> {
+            self.derivations.fetch_add(1, Ordering::SeqCst);
+            self.inner.sender()
+        }
+        fn public_key(&self) -> Option<Vec<u8>> {
+            self.derivations.fetch_add(1, Ordering::SeqCst);
+            self.inner.public_key()
+        }
+        fn sign(&self, content: &EnvelopeContent) -> Result<Signature, String> {
+            self.inner.sign(content)
+        }
+    }
+
+    #[test]
+    fn derives_once() {
+        let inner =
+            Secp256k1Identity::from_private_key(k256::SecretKey::from_slice(&[1; 32]).unwrap());
+        let derivations = Arc::new(AtomicUsize::new(0));
+        let identity = Arc::new(CachedIdentity::new(Counting {
+            inner: inner.clone(),
+            derivations: derivations.clone(),
+        }));
+        let agent = Agent::builder()
+            .with_url("http://not-a-real-url")
+            .with_arc_identity(identity.clone())
+            .build()
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        let signed = agent
+                            .update(&Principal::management_canister(), "greet")
+                            .sign()
+                            .unwrap();
+                        assert_eq!(signed.sender, inner.sender().unwrap());
+                        assert_eq!(identity.public_key(), inner.public_key());
+                    }
+                });
+            }
+        });
+        // once for the principal, once for the public key
+        assert_eq!(derivations.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,6 +1,8 @@
 use candid::Principal;
+use simple_asn1::{from_der, oid, ASN1Block};
+use time::OffsetDateTime;
 
-use crate::{agent::EnvelopeContent, Signature};
+use crate::{agent::EnvelopeContent, AgentError, Signature};
 
 use super::{Delegation, Identity, SignedDelegation};
 
@@ -52,9 +54,162 @@ impl Identity for DelegatedIdentity {
             .sign_arbitrary(content)
             .map(|sig| self.chain_signature(sig))
     }
+    fn is_declined(&self, error: &str) -> bool {
+        self.to.is_declined(error)
+    }
     fn delegation_chain(&self) -> Vec<SignedDelegation> {
         let mut chain = self.to.delegation_chain();
         chain.extend(self.chain.iter().cloned());
         chain
     }
 }
+
+/// Checks that `chain` is a valid delegation chain from `root_pubkey`, e.g. one received from
+/// Internet Identity, before it is used to create a [`DelegatedIdentity`].
+///
+/// Each delegation must not have expired, and must be signed by the key preceding it: `root_pubkey`
+/// for the first delegation, and the key delegated to by the previous one for the rest. Keys are
+/// DER-encoded, and may be Ed25519, secp256k1 or prime256v1 keys. Canister signatures, as used by
+/// Internet Identity for its root key, cannot be verified without a certificate, so they fail with
+/// [`AgentError::DelegationSignatureInvalid`].
+///
+/// The targets of the delegations are not checked.
+pub fn verify_delegation_chain(
+    chain: &[SignedDelegation],
+    root_pubkey: &[u8],
+) -> Result<(), AgentError> {
+    let now = OffsetDateTime::now_utc().unix_timestamp_nanos() as u64;
+    let mut signer = root_pubkey;
+    for (index, signed) in chain.iter().enumerate() {
+        let expiration = signed.delegation.expiration;
+        if expiration < now {
+            return Err(AgentError::DelegationExpired { index, expiration });
+        }
+        verify_signature(signer, &signed.delegation.signable(), &signed.signature)
+            .map_err(|reason| AgentError::DelegationSignatureInvalid { index, reason })?;
+        signer = &signed.delegation.pubkey;
+    }
+    Ok(())
+}
+
+/// Verifies `signature` of `message` by the DER-encoded public key `der_key`.
+fn verify_signature(der_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    let Ok(blocks) = from_der(der_key) else {
+        return Err("the public key is not valid DER".to_string());
+    };
+    let (algorithm, key) = match &blocks[..] {
+        [ASN1Block::Sequence(_, info)] => match &info[..] {
+            [ASN1Block::Sequence(_, algorithm), ASN1Block::BitString(_, _, key)] => {
+                (algorithm, key)
+            }
+            _ => return Err("the public key is not a SubjectPublicKeyInfo".to_string()),
+        },
+        _ => return Err("the public key is not a SubjectPublicKeyInfo".to_string()),
+    };
+    match &algorithm[..] {
+        [ASN1Block::ObjectIdentifier(_, id)] if *id == oid!(1, 3, 101, 112) => {
+            use ed25519_consensus::{Signature, VerificationKey};
+            let key = VerificationKey::try_from(&key[..]).map_err(|e| e.to_string())?;
+            let signature = Signature::try_from(signature).map_err(|e| e.to_string())?;
+            key.verify(&signature, message).map_err(|e| e.to_string())
+        }
+        [ASN1Block::ObjectIdentifier(_, id), ASN1Block::ObjectIdentifier(_, curve)]
+            if *id == oid!(1, 2, 840, 10045, 2, 1) && *curve == oid!(1, 3, 132, 0, 10) =>
+        {
+            use k256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+            let key = VerifyingKey::from_sec1_bytes(key).map_err(|e| e.to_string())?;
+            let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+            key.verify(message, &signature).map_err(|e| e.to_string())
+        }
+        [ASN1Block::ObjectIdentifier(_, id), ASN1Block::ObjectIdentifier(_, curve)]
+            if *id == oid!(1, 2, 840, 10045, 2, 1) && *curve == oid!(1, 2, 840, 10045, 3, 1, 7) =>
+        {
+            use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+            let key = VerifyingKey::from_sec1_bytes(key).map_err(|e| e.to_string())?;
+            let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+            key.verify(message, &signature).map_err(|e| e.to_string())
+        }
+        _ => Err("the public key's algorithm is not supported".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::identity::{BasicIdentity, Prime256v1Identity, Secp256k1Identity};
+    use ring::signature::Ed25519KeyPair;
+
+    /// Signs a delegation to `to`'s key by `from`.
+    fn delegate(from: &dyn Identity, to: &dyn Identity, expiration: u64) -> SignedDelegation {
+        let delegation = Delegation {
+            pubkey: to.public_key().unwrap(),
+            expiration,
+            targets: None,
+        };
+        let signature = from
+            .sign_delegation(&delegation)
+            .unwrap()
+            .signature
+            .unwrap();
+        SignedDelegation {
+            delegation,
+            signature,
+        }
+    }
+
+    fn identities() -> (BasicIdentity, Secp256k1Identity, Prime256v1Identity) {
+        (
+            BasicIdentity::from_key_pair(Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap()),
+            Secp256k1Identity::from_private_key(k256::SecretKey::from_slice(&[2; 32]).unwrap()),
+            Prime256v1Identity::from_private_key(p256::SecretKey::from_slice(&[3; 32]).unwrap()),
+        )
+    }
+
+    #[test]
+    fn verifies_valid_chain() {
+        let (root, middle, session) = identities();
+        let chain = [
+            delegate(&root, &middle, u64::MAX),
+            delegate(&middle, &session, u64::MAX),
+        ];
+        verify_delegation_chain(&chain, &root.public_key().unwrap()).unwrap();
+        verify_delegation_chain(&[], &root.public_key().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_expired_delegation() {
+        let (root, middle, session) = identities();
+        let chain = [
+            delegate(&root, &middle, u64::MAX),
+            delegate(&middle, &session, 1),
+        ];
+        assert!(matches!(
+            verify_delegation_chain(&chain, &root.public_key().unwrap()),
+            Err(AgentError::DelegationExpired {
+                index: 1,
+                expiration: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let (root, middle, session) = identities();
+        let mut chain = [
+            delegate(&root, &middle, u64::MAX),
+            delegate(&middle, &session, u64::MAX),
+        ];
+        chain[0].signature[0] ^= 1;
+        assert!(matches!(
+            verify_delegation_chain(&chain, &root.public_key().unwrap()),
+            Err(AgentError::DelegationSignatureInvalid { index: 0, .. })
+        ));
+
+        // a delegation signed by a key other than the preceding one
+        let chain = [delegate(&middle, &session, u64::MAX)];
+        assert!(matches!(
+            verify_delegation_chain(&chain, &root.public_key().unwrap()),
+            Err(AgentError::DelegationSignatureInvalid { index: 0, .. })
+        ));
+    }
+}
@@ -5,8 +5,10 @@ use crate::{agent::EnvelopeContent, export::Principal};
 
 pub(crate) mod anonymous;
 pub(crate) mod basic;
+pub(crate) mod cached;
 pub(crate) mod delegated;
 pub(crate) mod prime256v1;
+pub(crate) mod rotator;
 pub(crate) mod secp256k1;
 
 #[cfg(feature = "pem")]
@@ -17,12 +19,16 @@ pub use anonymous::AnonymousIdentity;
 #[doc(inline)]
 pub use basic::BasicIdentity;
 #[doc(inline)]
-pub use delegated::DelegatedIdentity;
+pub use cached::CachedIdentity;
+#[doc(inline)]
+pub use delegated::{verify_delegation_chain, DelegatedIdentity};
 #[doc(inline)]
 pub use ic_transport_types::{Delegation, SignedDelegation};
 #[doc(inline)]
 pub use prime256v1::Prime256v1Identity;
 #[doc(inline)]
+pub use rotator::{IdentityRotator, Rotation};
+#[doc(inline)]
 pub use secp256k1::Secp256k1Identity;
 
 #[cfg(feature = "pem")]
@@ -83,6 +89,20 @@ pub trait Identity: Send + Sync {
     fn delegation_chain(&self) -> Vec<SignedDelegation> {
         vec![]
     }
+
+    /// Whether `error`, as returned by [`sign`](Identity::sign), means the signature was declined,
+    /// e.g. by the user of a wallet or hardware key that asks for confirmation of each signature.
+    ///
+    /// The agent neither retries nor falls back to another identity on a declined signature, and
+    /// fails with [`AgentError::SigningDeclined`](crate::AgentError::SigningDeclined) instead.
+    ///
+    /// As the request's ingress expiry is set before it is signed, the time spent waiting for
+    /// confirmation counts against it; identities that wait on a person may need a longer
+    /// [`ingress_expiry`](crate::agent::AgentBuilder::with_ingress_expiry).
+    fn is_declined(&self, error: &str) -> bool {
+        let _ = error; // silence unused warning
+        false
+    }
 }
 
 macro_rules! delegating_impl {
@@ -111,6 +131,10 @@ macro_rules! delegating_impl {
             fn delegation_chain(&$name) -> Vec<SignedDelegation> {
                 $self_expr.delegation_chain()
             }
+
+            fn is_declined(&$name, error: &str) -> bool {
+                $self_expr.is_declined(error)
+            }
         }
     };
 }
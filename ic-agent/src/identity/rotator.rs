@@ -0,0 +1,148 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use rand::Rng;
+
+use crate::{agent::EnvelopeContent, export::Principal, identity::Identity, Signature};
+
+/// How an [`IdentityRotator`] picks the identity for each request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    /// Cycle through the identities in order.
+    #[default]
+    RoundRobin,
+    /// Pick an identity uniformly at random.
+    Random,
+}
+
+/// An identity that signs each request as a different one of several identities, e.g. to spread
+/// calls across principals when load testing.
+///
+/// **The sender principal changes from one request to the next**, so canisters see each call as
+/// coming from a different caller. The agent still reads the status of an update call as the
+/// principal that made it, as the replica requires.
+///
+/// Requests are signed by whichever identity the sender of their content belongs to. Delegations
+/// and arbitrary bytes are not tied to a request, so they cannot be signed.
+pub struct IdentityRotator {
+    identities: Vec<Arc<dyn Identity>>,
+    rotation: Rotation,
+    next: AtomicUsize,
+}
+
+impl IdentityRotator {
+    /// Rotates through `identities` round-robin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `identities` is empty.
+    pub fn new(identities: Vec<Arc<dyn Identity>>) -> Self {
+        assert!(
+            !identities.is_empty(),
+            "IdentityRotator needs at least one identity"
+        );
+        Self {
+            identities,
+            rotation: Rotation::RoundRobin,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets how the identity for each request is picked.
+    pub fn with_rotation(self, rotation: Rotation) -> Self {
+        Self { rotation, ..self }
+    }
+}
+
+impl std::fmt::Debug for IdentityRotator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdentityRotator")
+            .field("identities", &self.identities.len())
+            .field("rotation", &self.rotation)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Identity for IdentityRotator {
+    /// Picks the identity for a new request, and returns its principal.
+    fn sender(&self) -> Result<Principal, String> {
+        let index = match self.rotation {
+            Rotation::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Rotation::Random => rand::thread_rng().gen(),
+        };
+        self.identities[index % self.identities.len()].sender()
+    }
+
+    /// Returns `None`, as the public key differs from one request to the next.
+    fn public_key(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn sign(&self, content: &EnvelopeContent) -> Result<Signature, String> {
+        let sender = content.sender();
+        for identity in &self.identities {
+            if identity.sender()? == *sender {
+                return identity.sign(content);
+            }
+        }
+        Err(format!(
+            "No identity in the rotation has the principal {sender}"
+        ))
+    }
+
+    fn is_declined(&self, error: &str) -> bool {
+        self.identities
+            .iter()
+            .any(|identity| identity.is_declined(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{AnonymousIdentity, Secp256k1Identity};
+    use k256::SecretKey;
+
+    fn identity(n: u8) -> Arc<dyn Identity> {
+        Arc::new(Secp256k1Identity::from_private_key(
+            SecretKey::from_slice(&[n; 32]).unwrap(),
+        ))
+    }
+
+    fn call_from(sender: Principal) -> EnvelopeContent {
+        EnvelopeContent::Call {
+            nonce: None,
+            ingress_expiry: 0,
+            sender,
+            canister_id: Principal::management_canister(),
+            method_name: "greet".into(),
+            arg: vec![],
+        }
+    }
+
+    #[test]
+    fn signs_as_sender() {
+        let identities = vec![identity(1), identity(2)];
+        let rotator = IdentityRotator::new(identities.clone());
+        for identity in &identities {
+            let content = call_from(identity.sender().unwrap());
+            assert_eq!(
+                rotator.sign(&content).unwrap().public_key,
+                identity.public_key()
+            );
+        }
+        assert!(rotator.sign(&call_from(Principal::anonymous())).is_err());
+    }
+
+    #[test]
+    fn picks_random_identities() {
+        let rotator = IdentityRotator::new(vec![Arc::new(AnonymousIdentity), identity(1)])
+            .with_rotation(Rotation::Random);
+        for _ in 0..10 {
+            let sender = rotator.sender().unwrap();
+            assert!(rotator.sign(&call_from(sender)).is_ok());
+        }
+    }
+}
@@ -188,6 +188,21 @@ N3d26cRxD99TPtm8uo2OuzKhSiq6EQ==
         assert!(DER_ENCODED_PUBLIC_KEY == hex::encode(identity.der_encoded_public_key));
     }
 
+    #[test]
+    fn test_secp256k1_sender() {
+        let identity = Secp256k1Identity::from_pem(IDENTITY_FILE.as_bytes())
+            .expect("Cannot create secp256k1 identity from PEM file.");
+
+        // The sender is the self-authenticating principal of the DER-encoded public key: its
+        // SHA-224 hash followed by the tag 0x02.
+        let expected =
+            Principal::from_text("t2kpu-6xt6l-tyb3d-rll2p-irv5c-no5nd-h6spj-jsetq-bmqdz-iap77-pqe")
+                .unwrap();
+        let public_key = hex::decode(DER_ENCODED_PUBLIC_KEY).unwrap();
+        assert_eq!(Principal::self_authenticating(public_key), expected);
+        assert_eq!(identity.sender().unwrap(), expected);
+    }
+
     #[test]
     fn test_secp256k1_signature() {
         // Create a secp256k1 identity from a PEM file.
@@ -115,6 +115,8 @@ compile_error!("Feature `hyper` cannot be used from WASM.");
 pub mod agent;
 pub mod export;
 pub mod identity;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 mod util;
 
 use agent::response_authentication::LookupPath;
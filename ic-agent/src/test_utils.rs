@@ -0,0 +1,340 @@
+//! Helpers for testing code that uses the agent without a replica. Only available with the
+//! `test-utils` feature, and not covered by semver.
+use std::{future::Future, pin::Pin, sync::Mutex, time::Duration};
+
+use http::HeaderMap;
+use ic_certification::{
+    hash_tree::{fork, label, leaf},
+    HashTree,
+};
+use ic_transport_types::{EnvelopeContent, QueryResponse, ReplyResponse, TransportCallResponse};
+
+use crate::{
+    agent::{
+        Envelope, RetryPolicy, SleepFuture, Sleeper, Transport, IC_ROOT_KEY,
+        IC_STATE_ROOT_DOMAIN_SEPARATOR,
+    },
+    export::Principal,
+    AgentError, Certificate,
+};
+
+#[cfg(not(target_family = "wasm"))]
+type MockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, AgentError>> + Send + 'a>>;
+
+#[cfg(target_family = "wasm")]
+type MockFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, AgentError>> + 'a>>;
+
+type Handler<T> = Box<dyn Fn(MockRequest) -> MockFuture<'static, T> + Send + Sync>;
+
+/// A request made of a [`MockTransport`].
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    /// The effective canister id of the request, or the subnet id of a subnet read_state request.
+    pub effective_canister_id: Principal,
+    /// The encoded envelope.
+    pub envelope: Vec<u8>,
+    /// The headers attached to this request only.
+    pub headers: HeaderMap,
+    /// How the request is to be retried.
+    pub retry_policy: RetryPolicy,
+}
+
+impl MockRequest {
+    /// Decodes the content of the envelope.
+    pub fn content(&self) -> EnvelopeContent {
+        let envelope: Envelope =
+            serde_cbor::from_slice(&self.envelope).expect("malformed envelope");
+        envelope.content.into_owned()
+    }
+}
+
+/// A [`Transport`] that answers each kind of request with a handler, and panics on requests it has
+/// no handler for.
+#[derive(Default)]
+pub struct MockTransport {
+    call: Option<Handler<TransportCallResponse>>,
+    query: Option<Handler<Vec<u8>>>,
+    read_state: Option<Handler<Vec<u8>>>,
+    read_subnet_state: Option<Handler<Vec<u8>>>,
+    status: Option<Box<dyn Fn() -> MockFuture<'static, Vec<u8>> + Send + Sync>>,
+}
+
+impl std::fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MockTransport")
+    }
+}
+
+fn handler<T, F, Fut>(f: F) -> Handler<T>
+where
+    F: Fn(MockRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, AgentError>> + Send + 'static,
+{
+    Box::new(move |request| Box::pin(f(request)))
+}
+
+impl MockTransport {
+    /// Creates a transport without handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers call requests with `f`.
+    pub fn on_call<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(MockRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<TransportCallResponse, AgentError>> + Send + 'static,
+    {
+        Self {
+            call: Some(handler(f)),
+            ..self
+        }
+    }
+
+    /// Answers query requests with `f`.
+    pub fn on_query<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(MockRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, AgentError>> + Send + 'static,
+    {
+        Self {
+            query: Some(handler(f)),
+            ..self
+        }
+    }
+
+    /// Answers canister read_state requests with `f`.
+    pub fn on_read_state<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(MockRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, AgentError>> + Send + 'static,
+    {
+        Self {
+            read_state: Some(handler(f)),
+            ..self
+        }
+    }
+
+    /// Answers subnet read_state requests with `f`.
+    pub fn on_read_subnet_state<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn(MockRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, AgentError>> + Send + 'static,
+    {
+        Self {
+            read_subnet_state: Some(handler(f)),
+            ..self
+        }
+    }
+
+    /// Answers status requests with `f`.
+    pub fn on_status<F, Fut>(self, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Vec<u8>, AgentError>> + Send + 'static,
+    {
+        Self {
+            status: Some(Box::new(move || Box::pin(f()))),
+            ..self
+        }
+    }
+
+    fn handle<T>(
+        handler: &Option<Handler<T>>,
+        endpoint: &str,
+        request: MockRequest,
+    ) -> MockFuture<'static, T> {
+        match handler {
+            Some(handler) => handler(request),
+            None => panic!("unexpected {endpoint} request"),
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> MockFuture<'_, TransportCallResponse> {
+        self.call_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn call_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> MockFuture<'_, TransportCallResponse> {
+        self.call_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn call_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> MockFuture<'_, TransportCallResponse> {
+        let request = MockRequest {
+            effective_canister_id,
+            envelope,
+            headers,
+            retry_policy,
+        };
+        Self::handle(&self.call, "call", request)
+    }
+
+    fn query(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> MockFuture<'_, Vec<u8>> {
+        self.query_with_headers(effective_canister_id, envelope, HeaderMap::new())
+    }
+
+    fn query_with_headers(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+    ) -> MockFuture<'_, Vec<u8>> {
+        self.query_with_retry_policy(
+            effective_canister_id,
+            envelope,
+            headers,
+            RetryPolicy::Transport,
+        )
+    }
+
+    fn query_with_retry_policy(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        headers: HeaderMap,
+        retry_policy: RetryPolicy,
+    ) -> MockFuture<'_, Vec<u8>> {
+        let request = MockRequest {
+            effective_canister_id,
+            envelope,
+            headers,
+            retry_policy,
+        };
+        Self::handle(&self.query, "query", request)
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> MockFuture<'_, Vec<u8>> {
+        let request = MockRequest {
+            effective_canister_id,
+            envelope,
+            headers: HeaderMap::new(),
+            retry_policy: RetryPolicy::Transport,
+        };
+        Self::handle(&self.read_state, "read_state", request)
+    }
+
+    fn read_subnet_state(
+        &self,
+        subnet_id: Principal,
+        envelope: Vec<u8>,
+    ) -> MockFuture<'_, Vec<u8>> {
+        let request = MockRequest {
+            effective_canister_id: subnet_id,
+            envelope,
+            headers: HeaderMap::new(),
+            retry_policy: RetryPolicy::Transport,
+        };
+        Self::handle(&self.read_subnet_state, "subnet read_state", request)
+    }
+
+    fn status(&self) -> MockFuture<'_, Vec<u8>> {
+        match &self.status {
+            Some(handler) => handler(),
+            None => panic!("unexpected status request"),
+        }
+    }
+}
+
+/// The encoded response to a query that replied with `arg`, without node signatures.
+pub fn query_reply(arg: impl Into<Vec<u8>>) -> Vec<u8> {
+    serde_cbor::to_vec(&QueryResponse::Replied {
+        reply: ReplyResponse { arg: arg.into() },
+        signatures: vec![],
+    })
+    .unwrap()
+}
+
+/// A [`Sleeper`] that records the delays it is asked to wait out, and completes immediately.
+#[derive(Debug, Default)]
+pub struct RecordingSleeper {
+    delays: Mutex<Vec<Duration>>,
+}
+
+impl RecordingSleeper {
+    /// The delays waited out so far, in order.
+    pub fn delays(&self) -> Vec<Duration> {
+        self.delays.lock().unwrap().clone()
+    }
+}
+
+impl Sleeper for RecordingSleeper {
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        self.delays.lock().unwrap().push(duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// The DER encoding of the public key of the fixed test root key, to pass to
+/// [`Agent::set_root_key`](crate::Agent::set_root_key) so that the agent trusts the certificates
+/// signed by [`sign_certificate`].
+pub fn test_root_key() -> Vec<u8> {
+    let mut der = IC_ROOT_KEY[..37].to_vec();
+    der.extend_from_slice(&test_root_private_key().public_key().serialize());
+    der
+}
+
+fn test_root_private_key() -> ic_verify_bls_signature::PrivateKey {
+    ic_verify_bls_signature::PrivateKey::deserialize(&[0x11; 32]).unwrap()
+}
+
+/// Signs a certificate of `tree`, with the current time added, by the fixed test root key. Returns
+/// the certificate and the DER-encoded root key.
+pub fn sign_certificate(tree: HashTree) -> (Certificate, Vec<u8>) {
+    sign_certificate_at(tree, time::OffsetDateTime::now_utc())
+}
+
+/// Like [`sign_certificate`], with `time` as the certificate's time.
+pub fn sign_certificate_at(tree: HashTree, time: time::OffsetDateTime) -> (Certificate, Vec<u8>) {
+    let cert = sign_certificate_by(tree, time, &test_root_private_key());
+    (cert, test_root_key())
+}
+
+/// Signs a certificate of `tree`, with `time` as the certificate's time, by `key`.
+pub(crate) fn sign_certificate_by(
+    tree: HashTree,
+    time: time::OffsetDateTime,
+    key: &ic_verify_bls_signature::PrivateKey,
+) -> Certificate {
+    let time = time.unix_timestamp_nanos() as u64;
+    let mut time_leb = vec![];
+    leb128::write::unsigned(&mut time_leb, time).unwrap();
+    // `tree` must only have labels that sort before "time"
+    let tree = fork(tree, label("time", leaf(time_leb)));
+
+    let mut message = IC_STATE_ROOT_DOMAIN_SEPARATOR.to_vec();
+    message.extend_from_slice(&tree.digest());
+    Certificate {
+        tree,
+        signature: key.sign(&message).serialize().to_vec(),
+        delegation: None,
+    }
+}
@@ -8,7 +8,9 @@ use std::borrow::Cow;
 
 use candid::Principal;
 use ic_certification::Label;
-pub use request_id::{to_request_id, RequestId, RequestIdError};
+pub use request_id::{
+    to_request_id, to_request_id_with_hasher, RequestId, RequestIdError, RequestIdHasher,
+};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use thiserror::Error;
@@ -34,6 +36,25 @@ pub struct Envelope<'a> {
     pub sender_delegation: Option<Vec<SignedDelegation>>,
 }
 
+impl Envelope<'_> {
+    /// Encodes the envelope as self-describing CBOR, the format expected by the HTTP interface.
+    ///
+    /// The encoding is deterministic as defined by RFC 8949: map keys are sorted and integers take
+    /// the shortest form, so the same envelope always encodes to the same bytes.
+    ///
+    /// This involves no transport or signing logic, so it can be used wherever envelopes are built.
+    pub fn encode_bytes(&self) -> Vec<u8> {
+        // maps in a `Value` are ordered canonically, rather than in field order
+        let value = serde_cbor::value::to_value(self).expect("infallible Envelope::serialize");
+        let mut serializer = serde_cbor::Serializer::new(Vec::new());
+        serializer.self_describe().unwrap();
+        value
+            .serialize(&mut serializer)
+            .expect("infallible Value::serialize");
+        serializer.into_inner()
+    }
+}
+
 /// The content of an IC ingress message, not including any signature information.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "request_type", rename_all = "snake_case")]
@@ -450,3 +471,93 @@ mod map_u128 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call_content() -> EnvelopeContent {
+        EnvelopeContent::Call {
+            nonce: None,
+            ingress_expiry: 1685570400000000000,
+            sender: Principal::anonymous(),
+            canister_id: Principal::from_slice(b"\x00\x00\x00\x00\x00\x00\x04\xD2"),
+            method_name: "hello".to_string(),
+            arg: b"DIDL\x00\xFD*".to_vec(),
+        }
+    }
+
+    #[test]
+    fn encode_bytes_is_self_describing() {
+        let envelope = Envelope {
+            content: Cow::Owned(call_content()),
+            sender_pubkey: None,
+            sender_sig: None,
+            sender_delegation: None,
+        };
+        let bytes = envelope.encode_bytes();
+        assert_eq!(&bytes[..3], &[0xd9, 0xd9, 0xf7]);
+    }
+
+    #[test]
+    fn encode_bytes_round_trip_preserves_request_id() {
+        let content = call_content();
+        let envelope = Envelope {
+            content: Cow::Borrowed(&content),
+            sender_pubkey: Some(vec![1, 2, 3]),
+            sender_sig: Some(vec![4, 5, 6]),
+            sender_delegation: None,
+        };
+        let decoded: Envelope<'_> = serde_cbor::from_slice(&envelope.encode_bytes()).unwrap();
+        assert_eq!(decoded.sender_pubkey, Some(vec![1, 2, 3]));
+        assert_eq!(decoded.sender_sig, Some(vec![4, 5, 6]));
+        // Hash taken from the example on the public spec.
+        assert_eq!(
+            hex::encode(decoded.content.to_request_id().as_slice()),
+            "1d1091364d6bb8a6c16b203ee75467d59ead468f523eb058880ae8ec80e2b101"
+        );
+        assert_eq!(decoded.content.to_request_id(), content.to_request_id());
+    }
+
+    #[test]
+    fn encode_bytes_is_canonical() {
+        let mut content = call_content();
+        if let EnvelopeContent::Call { nonce, .. } = &mut content {
+            *nonce = Some(vec![7; 8]);
+        }
+        let envelope = Envelope {
+            content: Cow::Borrowed(&content),
+            sender_pubkey: Some(vec![1, 2, 3]),
+            sender_sig: Some(vec![4, 5, 6]),
+            sender_delegation: None,
+        };
+        let bytes = envelope.encode_bytes();
+        assert_eq!(bytes, envelope.clone().encode_bytes());
+
+        // RFC 8949 orders keys by their encoding, so shorter keys come first, whatever the order
+        // of the fields
+        let position = |key: &str| {
+            let mut encoded = vec![0x60 + key.len() as u8];
+            encoded.extend_from_slice(key.as_bytes());
+            bytes
+                .windows(encoded.len())
+                .position(|window| window == encoded)
+                .unwrap()
+        };
+        for keys in [
+            &["content", "sender_sig", "sender_pubkey"][..],
+            &[
+                "arg",
+                "nonce",
+                "sender",
+                "canister_id",
+                "method_name",
+                "request_type",
+                "ingress_expiry",
+            ],
+        ] {
+            let positions: Vec<_> = keys.iter().map(|key| position(key)).collect();
+            assert!(positions.windows(2).all(|w| w[0] < w[1]), "{keys:?}");
+        }
+    }
+}
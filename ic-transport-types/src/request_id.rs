@@ -14,7 +14,7 @@ use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
 };
 use sha2::{Digest, Sha256};
-use std::{io::Write, ops::Deref, str::FromStr};
+use std::{fmt::Write, marker::PhantomData, ops::Deref, str::FromStr};
 
 mod error;
 #[doc(inline)]
@@ -28,7 +28,16 @@ type Sha256Hash = [u8; 32];
 /// Derive the request ID from a serializable data structure. This does not include the `ic-request` domain prefix.
 ///
 /// See [Representation-independent Hashing of Structured Data](https://internetcomputer.org/docs/current/references/ic-interface-spec#hash-of-map)
-/// from the IC spec for the method of calculation.
+/// from the IC spec for the method of calculation. In short:
+///
+/// * Strings are hashed as their UTF-8 bytes, and blobs as their raw bytes.
+/// * Natural numbers are hashed as their LEB128 encoding.
+/// * Arrays are hashed as the concatenation of the hashes of their elements.
+/// * Maps (structs and tagged enums) are hashed by concatenating the hash of each key with the hash of its value,
+///   sorting these pairs, and hashing their concatenation. Field order therefore does not matter.
+///
+/// For the content of a request, [`EnvelopeContent::to_request_id`](crate::EnvelopeContent::to_request_id) is
+/// equivalent and infallible.
 ///
 /// # Serialization
 ///
@@ -42,12 +51,56 @@ type Sha256Hash = [u8; 32];
 pub fn to_request_id<'a, V>(value: &V) -> Result<RequestId, RequestIdError>
 where
     V: 'a + Serialize,
+{
+    to_request_id_with_hasher::<Sha256, V>(value).map(RequestId)
+}
+
+/// Like [`to_request_id`], but hashes every value with `H` instead of SHA-256.
+///
+/// The protocol only accepts SHA-256 request IDs; this exists so that the structure of the hashing
+/// (which values are hashed, and in what order they are concatenated) can be inspected with a
+/// hasher whose output is readable, and to allow for a future change of hash function.
+pub fn to_request_id_with_hasher<'a, H, V>(value: &V) -> Result<H::Output, RequestIdError>
+where
+    H: RequestIdHasher,
+    V: 'a + Serialize,
 {
     value
-        .serialize(RequestIdSerializer)
+        .serialize(RequestIdSerializer::<H>::new())
         .transpose()
         .unwrap_or(Err(RequestIdError::EmptySerializer))
-        .map(RequestId)
+}
+
+/// A hash function for [`to_request_id_with_hasher`]. It is implemented for [`Sha256`], the
+/// hash function used by the protocol.
+pub trait RequestIdHasher: Default {
+    /// The hash of some data. Hashes of map entries are sorted by this type's ordering.
+    type Output: AsRef<[u8]> + Ord + Default;
+
+    /// Appends `data` to the data being hashed.
+    fn append(&mut self, data: &[u8]);
+
+    /// Returns the hash of all the data appended so far.
+    fn finish(self) -> Self::Output;
+
+    /// Returns the hash of `data`.
+    fn hash(data: &[u8]) -> Self::Output {
+        let mut hasher = Self::default();
+        hasher.append(data);
+        hasher.finish()
+    }
+}
+
+impl RequestIdHasher for Sha256 {
+    type Output = Sha256Hash;
+
+    fn append(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+
+    fn finish(self) -> Self::Output {
+        self.finalize().into()
+    }
 }
 
 /// A Request ID.
@@ -100,12 +153,18 @@ impl From<RequestId> for String {
 }
 
 // Request ID hashing in all contexts is implemented as a serde Serializer to eliminate any special-casing.
-struct RequestIdSerializer;
+struct RequestIdSerializer<H>(PhantomData<H>);
+
+impl<H> RequestIdSerializer<H> {
+    fn new() -> Self {
+        Self(PhantomData)
+    }
+}
 
-impl Serializer for RequestIdSerializer {
+impl<H: RequestIdHasher> Serializer for RequestIdSerializer<H> {
     // Serde conveniently allows us to have each serialization operation return a value.
     // Since this serializer is a hash function, this eliminates any need for a state-machine.
-    type Ok = Option<Sha256Hash>;
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
 
     // We support neither floats nor bools nor nulls.
@@ -131,7 +190,7 @@ impl Serializer for RequestIdSerializer {
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
         let mut arr = [0u8; 10];
         let n = leb128::write::signed(&mut &mut arr[..], v).unwrap();
-        Ok(Some(Sha256::digest(&arr[..n]).into()))
+        Ok(Some(H::hash(&arr[..n])))
     }
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
         self.serialize_i64(v as i64)
@@ -148,7 +207,7 @@ impl Serializer for RequestIdSerializer {
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
         let mut arr = [0u8; 10];
         let n = leb128::write::unsigned(&mut &mut arr[..], v).unwrap();
-        Ok(Some(Sha256::digest(&arr[..n]).into()))
+        Ok(Some(H::hash(&arr[..n])))
     }
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
         self.serialize_u64(v as u64)
@@ -163,7 +222,7 @@ impl Serializer for RequestIdSerializer {
     // Bytes are serialized as-is.
 
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        Ok(Some(Sha256::digest(v).into()))
+        Ok(Some(H::hash(v)))
     }
 
     // Strings are serialized as UTF-8 bytes.
@@ -212,17 +271,17 @@ impl Serializer for RequestIdSerializer {
 
     // Arrays, tuples, and tuple structs are treated identically.
 
-    type SerializeSeq = SeqSerializer;
+    type SerializeSeq = SeqSerializer<H>;
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
         self.serialize_tuple(len.unwrap_or(8))
     }
-    type SerializeTuple = SeqSerializer;
+    type SerializeTuple = SeqSerializer<H>;
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
         Ok(SeqSerializer {
             elems: Vec::with_capacity(len),
         })
     }
-    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer<H>;
     fn serialize_tuple_struct(
         self,
         _name: &'static str,
@@ -233,11 +292,11 @@ impl Serializer for RequestIdSerializer {
 
     // Maps and structs are treated identically.
 
-    type SerializeMap = StructSerializer;
+    type SerializeMap = StructSerializer<H>;
     fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
         self.serialize_struct("", len.unwrap_or(8))
     }
-    type SerializeStruct = StructSerializer;
+    type SerializeStruct = StructSerializer<H>;
     fn serialize_struct(
         self,
         _name: &'static str,
@@ -266,7 +325,7 @@ impl Serializer for RequestIdSerializer {
         SerializeStruct::serialize_field(&mut s, variant, value)?;
         SerializeStruct::end(s)
     }
-    type SerializeTupleVariant = TupleVariantSerializer;
+    type SerializeTupleVariant = TupleVariantSerializer<H>;
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
@@ -281,7 +340,7 @@ impl Serializer for RequestIdSerializer {
             },
         })
     }
-    type SerializeStructVariant = StructVariantSerializer;
+    type SerializeStructVariant = StructVariantSerializer<H>;
     fn serialize_struct_variant(
         self,
         _name: &'static str,
@@ -307,21 +366,22 @@ impl Serializer for RequestIdSerializer {
     where
         T: std::fmt::Display,
     {
-        let mut hasher = Sha256::new();
-        write!(hasher, "{value}").map_err(|e| RequestIdError::CustomSerdeError(format!("{e}")))?;
-        Ok(Some(hasher.finalize().into()))
+        let mut hasher = H::default();
+        write!(FmtHasher(&mut hasher), "{value}")
+            .map_err(|e| RequestIdError::CustomSerdeError(format!("{e}")))?;
+        Ok(Some(hasher.finish()))
     }
 }
 
-struct StructSerializer {
-    fields: Vec<(Sha256Hash, Sha256Hash)>,
-    field_name: Sha256Hash,
+struct StructSerializer<H: RequestIdHasher> {
+    fields: Vec<(H::Output, H::Output)>,
+    field_name: H::Output,
 }
 
 // Structs are hashed by hashing each key-value pair, sorting them, concatenating them, and hashing the result.
 
-impl SerializeStruct for StructSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeStruct for StructSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_field<T: ?Sized>(
         &mut self,
@@ -331,25 +391,24 @@ impl SerializeStruct for StructSerializer {
     where
         T: Serialize,
     {
-        if let Some(hash) = value.serialize(RequestIdSerializer)? {
-            self.fields
-                .push((Sha256::digest(key.as_bytes()).into(), hash));
+        if let Some(hash) = value.serialize(RequestIdSerializer::<H>::new())? {
+            self.fields.push((H::hash(key.as_bytes()), hash));
         }
         Ok(())
     }
     fn end(mut self) -> Result<Self::Ok, Self::Error> {
         self.fields.sort();
-        let mut hasher = Sha256::new();
+        let mut hasher = H::default();
         for (key, value) in self.fields {
-            hasher.update(key);
-            hasher.update(value);
+            hasher.append(key.as_ref());
+            hasher.append(value.as_ref());
         }
-        Ok(Some(hasher.finalize().into()))
+        Ok(Some(hasher.finish()))
     }
 }
 
-impl SerializeMap for StructSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeMap for StructSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     // This implementation naïvely assumes serialize_key is called before serialize_value, with no checks.
     // SerializeMap's documentation states that such a case is 'allowed to panic or produce bogus results.'
@@ -357,7 +416,7 @@ impl SerializeMap for StructSerializer {
     where
         T: Serialize,
     {
-        match key.serialize(RequestIdSerializer)? {
+        match key.serialize(RequestIdSerializer::<H>::new())? {
             Some(hash) => {
                 self.field_name = hash;
                 Ok(())
@@ -369,8 +428,9 @@ impl SerializeMap for StructSerializer {
     where
         T: Serialize,
     {
-        if let Some(hash) = value.serialize(RequestIdSerializer)? {
-            self.fields.push((self.field_name, hash));
+        if let Some(hash) = value.serialize(RequestIdSerializer::<H>::new())? {
+            self.fields
+                .push((std::mem::take(&mut self.field_name), hash));
         }
         Ok(())
     }
@@ -379,35 +439,35 @@ impl SerializeMap for StructSerializer {
     }
 }
 
-struct SeqSerializer {
-    elems: Vec<Sha256Hash>,
+struct SeqSerializer<H: RequestIdHasher> {
+    elems: Vec<H::Output>,
 }
 
 // Sequences are hashed by hashing each element, concatenating the hashes, and hashing the result.
 
-impl SerializeSeq for SeqSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeSeq for SeqSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: Serialize,
     {
-        if let Some(hash) = value.serialize(RequestIdSerializer)? {
+        if let Some(hash) = value.serialize(RequestIdSerializer::<H>::new())? {
             self.elems.push(hash);
         }
         Ok(())
     }
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        let mut hasher = Sha256::new();
+        let mut hasher = H::default();
         for elem in self.elems {
-            hasher.update(elem);
+            hasher.append(elem.as_ref());
         }
-        Ok(Some(hasher.finalize().into()))
+        Ok(Some(hasher.finish()))
     }
 }
 
-impl SerializeTuple for SeqSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeTuple for SeqSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -420,8 +480,8 @@ impl SerializeTuple for SeqSerializer {
     }
 }
 
-impl SerializeTupleStruct for SeqSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeTupleStruct for SeqSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -434,16 +494,16 @@ impl SerializeTupleStruct for SeqSerializer {
     }
 }
 
-struct StructVariantSerializer {
+struct StructVariantSerializer<H: RequestIdHasher> {
     name: &'static str,
-    struct_ser: StructSerializer,
+    struct_ser: StructSerializer<H>,
 }
 
 // Struct variants are serialized like structs, but then placed within another struct
 // under a key corresponding to the variant name.
 
-impl SerializeStructVariant for StructVariantSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeStructVariant for StructVariantSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_field<T: ?Sized>(
         &mut self,
@@ -459,24 +519,24 @@ impl SerializeStructVariant for StructVariantSerializer {
         let Some(inner_struct_hash) = SerializeStruct::end(self.struct_ser)? else {
             return Ok(None);
         };
-        let outer_struct = StructSerializer {
+        let outer_struct = StructSerializer::<H> {
             field_name: <_>::default(),
-            fields: vec![(Sha256::digest(self.name).into(), inner_struct_hash)],
+            fields: vec![(H::hash(self.name.as_bytes()), inner_struct_hash)],
         };
         SerializeStruct::end(outer_struct)
     }
 }
 
-struct TupleVariantSerializer {
+struct TupleVariantSerializer<H: RequestIdHasher> {
     name: &'static str,
-    seq_ser: SeqSerializer,
+    seq_ser: SeqSerializer<H>,
 }
 
 // Tuple variants are serialized like tuples, but then placed within another struct
 // under a key corresponding to the variant name.
 
-impl SerializeTupleVariant for TupleVariantSerializer {
-    type Ok = Option<Sha256Hash>;
+impl<H: RequestIdHasher> SerializeTupleVariant for TupleVariantSerializer<H> {
+    type Ok = Option<H::Output>;
     type Error = RequestIdError;
     fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
@@ -488,14 +548,24 @@ impl SerializeTupleVariant for TupleVariantSerializer {
         let Some(inner_seq_hash) = SerializeSeq::end(self.seq_ser)? else {
             return Ok(None);
         };
-        let outer_struct = StructSerializer {
+        let outer_struct = StructSerializer::<H> {
             field_name: <_>::default(),
-            fields: vec![(Sha256::digest(self.name).into(), inner_seq_hash)],
+            fields: vec![(H::hash(self.name.as_bytes()), inner_seq_hash)],
         };
         SerializeStruct::end(outer_struct)
     }
 }
 
+/// Feeds formatted output into a hasher, for [`Serializer::collect_str`].
+struct FmtHasher<'a, H>(&'a mut H);
+
+impl<H: RequestIdHasher> std::fmt::Write for FmtHasher<'_, H> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0.append(s.as_bytes());
+        Ok(())
+    }
+}
+
 // can't use serde_bytes on by-value arrays
 // these impls are effectively #[serde(with = "serde_bytes")]
 impl Serialize for RequestId {
@@ -674,6 +744,76 @@ mod tests {
         );
     }
 
+    /// Checks which values are hashed, and the order of concatenation, with a hasher that only
+    /// brackets its input.
+    #[test]
+    fn hashing_structure() {
+        #[derive(Default)]
+        struct Brackets(Vec<u8>);
+
+        impl RequestIdHasher for Brackets {
+            type Output = Vec<u8>;
+            fn append(&mut self, data: &[u8]) {
+                self.0.extend_from_slice(data);
+            }
+            fn finish(self) -> Vec<u8> {
+                [b"(", &self.0[..], b")"].concat()
+            }
+        }
+
+        #[derive(Serialize)]
+        struct Example {
+            z: &'static str,
+            a: u64,
+            m: Vec<&'static str>,
+            skipped: Option<u64>,
+        }
+        let example = Example {
+            z: "x",
+            a: 300,
+            m: vec!["q", "p"],
+            skipped: None,
+        };
+        // Fields are sorted by key hash, arrays keep their order, and numbers are LEB128-encoded.
+        assert_eq!(
+            to_request_id_with_hasher::<Brackets, _>(&example).unwrap(),
+            b"((a)(\xac\x02)(m)((q)(p))(z)(x))"
+        );
+    }
+
+    /// A request content map, checked against a hash computed by hand from the spec's algorithm.
+    #[test]
+    fn read_state_content_map() {
+        use crate::EnvelopeContent;
+        use ic_certification::Label;
+
+        fn h(bytes: &[u8]) -> Sha256Hash {
+            Sha256::digest(bytes).into()
+        }
+
+        let ingress_expiry = 1685570400000000000;
+        let content = EnvelopeContent::ReadState {
+            ingress_expiry,
+            sender: Principal::anonymous(),
+            paths: vec![vec![Label::from("time")]],
+        };
+
+        let mut expiry_leb = vec![];
+        leb128::write::unsigned(&mut expiry_leb, ingress_expiry).unwrap();
+        let path_hash = h(&h(b"time"));
+        let mut pairs = [
+            [h(b"request_type"), h(b"read_state")].concat(),
+            [h(b"ingress_expiry"), h(&expiry_leb)].concat(),
+            [h(b"sender"), h(Principal::anonymous().as_slice())].concat(),
+            [h(b"paths"), h(&path_hash)].concat(),
+        ];
+        pairs.sort();
+        let expected = h(&pairs.concat());
+
+        assert_eq!(content.to_request_id(), RequestId::new(&expected));
+        assert_eq!(to_request_id(&content).unwrap(), RequestId::new(&expected));
+    }
+
     /// A simple example with nested arrays and blobs
     #[test]
     #[allow(clippy::string_lit_as_bytes)]
@@ -1,7 +1,11 @@
 use crate::call::{AsyncCaller, SyncCaller};
 use candid::utils::ArgumentEncoder;
-use candid::{ser::IDLBuilder, types::value::IDLValue, utils::ArgumentDecoder, CandidType, Encode};
+use candid::{
+    decode_one, idl_hash, ser::IDLBuilder, types::value::IDLValue, utils::ArgumentDecoder,
+    CandidType, Encode, IDLArgs,
+};
 use ic_agent::{export::Principal, Agent, AgentError, RequestId};
+use serde::de::DeserializeOwned;
 use std::convert::TryInto;
 use thiserror::Error;
 
@@ -21,6 +25,19 @@ pub enum CanisterBuilderError {
     MustSpecifyCanisterId(),
 }
 
+/// An error from [`Canister::call_typed_with_error`].
+#[derive(Debug, Error)]
+pub enum TypedCallError<E> {
+    /// The canister rejected the call with a Candid-encoded error.
+    #[error("The canister rejected the call: {0:?}")]
+    Rejected(E),
+
+    /// The call failed otherwise, including when it was rejected with a message that is not a
+    /// Candid-encoded error.
+    #[error(transparent)]
+    Agent(#[from] AgentError),
+}
+
 /// A canister builder, which can be used to create a canister abstraction.
 #[derive(Debug, Default)]
 pub struct CanisterBuilder<'agent> {
@@ -131,6 +148,40 @@ impl<'agent> Canister<'agent> {
         SyncCallBuilder::new(self, method_name)
     }
 
+    /// Makes an update call with a single argument and result, waiting for the result. If the
+    /// canister rejects the call with a reject message that is the hex encoding of a Candid `Err`
+    /// value, the call fails with that value as [`TypedCallError::Rejected`]; any other rejection
+    /// keeps its message, as [`TypedCallError::Agent`].
+    ///
+    /// Reject messages are text, so this is how a canister can reject with a structured error.
+    pub async fn call_typed_with_error<In, Out, Err>(
+        &self,
+        method_name: &str,
+        arg: In,
+    ) -> Result<Out, TypedCallError<Err>>
+    where
+        In: CandidType + Sync + Send,
+        Out: CandidType + DeserializeOwned + Send + Sync,
+        Err: CandidType + DeserializeOwned,
+    {
+        match self
+            .update(method_name)
+            .with_arg(arg)
+            .build::<(Out,)>()
+            .call_and_wait()
+            .await
+        {
+            Ok((out,)) => Ok(out),
+            Err(err) => Err(match &err {
+                AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) => {
+                    decode_reject_message(&reject.reject_message)
+                        .map_or(TypedCallError::Agent(err), TypedCallError::Rejected)
+                }
+                _ => TypedCallError::Agent(err),
+            }),
+        }
+    }
+
     /// Call request_status on the RequestId in a loop and return the response as a byte vector.
     pub async fn wait<'canister>(
         &'canister self,
@@ -235,7 +286,7 @@ impl Argument {
 pub struct SyncCallBuilder<'agent, 'canister> {
     canister: &'canister Canister<'agent>,
     method_name: String,
-    effective_canister_id: Principal,
+    effective_canister_id: Option<Principal>,
     arg: Argument,
 }
 
@@ -248,7 +299,7 @@ impl<'agent: 'canister, 'canister> SyncCallBuilder<'agent, 'canister> {
         Self {
             canister,
             method_name: method_name.into(),
-            effective_canister_id: canister.canister_id().to_owned(),
+            effective_canister_id: None,
             arg: Default::default(),
         }
     }
@@ -287,8 +338,11 @@ impl<'agent: 'canister, 'canister> SyncCallBuilder<'agent, 'canister> {
     }
 
     /// Sets the [effective canister ID](https://internetcomputer.org/docs/references/current/ic-interface-spec#http-effective-canister-id) of the destination.
+    ///
+    /// Defaults to the canister being called, except for calls to the management canister, which
+    /// default to the canister they operate on; see [`ManagementCanister`](crate::interfaces::ManagementCanister).
     pub fn with_effective_canister_id(mut self, canister_id: Principal) -> Self {
-        self.effective_canister_id = canister_id;
+        self.effective_canister_id = Some(canister_id);
         self
     }
 
@@ -298,12 +352,15 @@ impl<'agent: 'canister, 'canister> SyncCallBuilder<'agent, 'canister> {
         Output: for<'de> ArgumentDecoder<'de> + Send + Sync,
     {
         let c = self.canister;
+        let arg = self.arg.serialize();
         SyncCaller {
             agent: c.agent,
-            effective_canister_id: self.effective_canister_id,
+            effective_canister_id: self
+                .effective_canister_id
+                .unwrap_or_else(|| default_effective_canister_id(c.canister_id, &arg)),
             canister_id: c.canister_id,
             method_name: self.method_name.clone(),
-            arg: self.arg.serialize(),
+            arg,
             expiry: Default::default(),
             phantom_out: std::marker::PhantomData,
         }
@@ -317,7 +374,7 @@ impl<'agent: 'canister, 'canister> SyncCallBuilder<'agent, 'canister> {
 pub struct AsyncCallBuilder<'agent, 'canister> {
     canister: &'canister Canister<'agent>,
     method_name: String,
-    effective_canister_id: Principal,
+    effective_canister_id: Option<Principal>,
     arg: Argument,
 }
 
@@ -330,7 +387,7 @@ impl<'agent: 'canister, 'canister> AsyncCallBuilder<'agent, 'canister> {
         Self {
             canister,
             method_name: method_name.to_string(),
-            effective_canister_id: canister.canister_id().to_owned(),
+            effective_canister_id: None,
             arg: Default::default(),
         }
     }
@@ -361,8 +418,11 @@ impl<'agent: 'canister, 'canister> AsyncCallBuilder<'agent, 'canister> {
     }
 
     /// Sets the [effective canister ID](https://internetcomputer.org/docs/current/references/ic-interface-spec#http-effective-canister-id) of the destination.
+    ///
+    /// Defaults to the canister being called, except for calls to the management canister, which
+    /// default to the canister they operate on; see [`ManagementCanister`](crate::interfaces::ManagementCanister).
     pub fn with_effective_canister_id(mut self, canister_id: Principal) -> Self {
-        self.effective_canister_id = canister_id;
+        self.effective_canister_id = Some(canister_id);
         self
     }
 
@@ -372,18 +432,60 @@ impl<'agent: 'canister, 'canister> AsyncCallBuilder<'agent, 'canister> {
         Output: for<'de> ArgumentDecoder<'de> + Send + Sync,
     {
         let c = self.canister;
+        let arg = self.arg.serialize();
         AsyncCaller {
             agent: c.agent,
-            effective_canister_id: self.effective_canister_id,
+            effective_canister_id: self
+                .effective_canister_id
+                .unwrap_or_else(|| default_effective_canister_id(c.canister_id, &arg)),
             canister_id: c.canister_id,
             method_name: self.method_name.clone(),
-            arg: self.arg.serialize(),
+            arg,
             expiry: Default::default(),
             phantom_out: std::marker::PhantomData,
         }
     }
 }
 
+/// Returns the effective canister id of a call to `canister_id` with `arg`.
+///
+/// Calls to the management canister are routed to the subnet of the canister they operate on,
+/// named by the `canister_id` field of their argument. Calls that do not name one are addressed to
+/// the management canister itself.
+fn default_effective_canister_id(
+    canister_id: Principal,
+    arg: &Result<Vec<u8>, AgentError>,
+) -> Principal {
+    if canister_id != Principal::management_canister() {
+        return canister_id;
+    }
+    arg.as_ref()
+        .ok()
+        .and_then(|arg| target_canister_id(arg))
+        .unwrap_or(canister_id)
+}
+
+/// Returns the `canister_id` field of a Candid-encoded record argument, if it has one.
+fn target_canister_id(arg: &[u8]) -> Option<Principal> {
+    let args = IDLArgs::from_bytes(arg).ok()?;
+    let IDLValue::Record(fields) = args.args.first()? else {
+        return None;
+    };
+    let canister_id = idl_hash("canister_id");
+    fields
+        .iter()
+        .find(|field| field.id.get_id() == canister_id)
+        .and_then(|field| match field.val {
+            IDLValue::Principal(principal) => Some(principal),
+            _ => None,
+        })
+}
+
+/// Decodes a reject message that is the hex encoding of a Candid value.
+fn decode_reject_message<E: CandidType + DeserializeOwned>(message: &str) -> Option<E> {
+    decode_one(&hex::decode(message.trim()).ok()?).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::interfaces::ManagementCanister;
@@ -463,4 +565,64 @@ mod tests {
             .await
             .is_err());
     }
+
+    mod typed_errors {
+        use super::super::{Canister, TypedCallError};
+        use candid::{CandidType, Deserialize, Encode};
+        use ic_agent::{
+            agent::{RejectCode, RejectResponse},
+            test_utils::MockTransport,
+            Agent, AgentError, TransportCallResponse,
+        };
+
+        /// Rejects every call with the same message.
+        fn rejecting_transport(reject_message: String) -> MockTransport {
+            MockTransport::new().on_call(move |_| {
+                let reject = RejectResponse {
+                    reject_code: RejectCode::CanisterReject,
+                    reject_message: reject_message.clone(),
+                    error_code: None,
+                };
+                std::future::ready(Ok(TransportCallResponse::NonReplicatedRejection(reject)))
+            })
+        }
+
+        #[derive(CandidType, Deserialize, Debug, PartialEq)]
+        enum TransferError {
+            InsufficientFunds { balance: u64 },
+        }
+
+        async fn transfer(reject_message: String) -> Result<(), TypedCallError<TransferError>> {
+            let agent = Agent::builder()
+                .with_transport(rejecting_transport(reject_message))
+                .build()
+                .unwrap();
+            let canister = Canister::builder()
+                .with_agent(&agent)
+                .with_canister_id("ryjl3-tyaaa-aaaaa-aaaba-cai")
+                .build()
+                .unwrap();
+            canister.call_typed_with_error("transfer", 100_u64).await
+        }
+
+        #[tokio::test]
+        async fn decodes_structured_rejection() {
+            let error = TransferError::InsufficientFunds { balance: 42 };
+            let message = hex::encode(Encode!(&error).unwrap());
+            assert!(matches!(
+                transfer(message).await,
+                Err(TypedCallError::Rejected(e)) if e == error
+            ));
+        }
+
+        #[tokio::test]
+        async fn keeps_plain_rejection() {
+            let result = transfer("insufficient funds".to_string()).await;
+            assert!(matches!(
+                result,
+                Err(TypedCallError::Agent(AgentError::UncertifiedReject(reject)))
+                    if reject.reject_message == "insufficient funds"
+            ));
+        }
+    }
 }
@@ -7,10 +7,13 @@ use crate::{
     Canister,
 };
 use candid::{CandidType, Deserialize, Nat};
-use ic_agent::{export::Principal, Agent};
+use futures_util::future::join_all;
+use ic_agent::{export::Principal, Agent, AgentError};
 use std::{convert::AsRef, ops::Deref};
 use strum_macros::{AsRefStr, Display, EnumString};
 
+use self::builders::CanisterSettings;
+
 pub mod attributes;
 pub mod builders;
 
@@ -21,6 +24,14 @@ pub use builders::{
 };
 
 /// The IC management canister.
+///
+/// Calls to the management canister are handled by the subnet of the canister they operate on, so
+/// their [effective canister id](https://internetcomputer.org/docs/current/references/ic-interface-spec#http-effective-canister-id)
+/// must be that canister rather than `aaaaa-aa`. Calls built from this type set it automatically
+/// to the `canister_id` field of their argument. Methods that do not operate on an existing canister,
+/// such as [`create_canister`](Self::create_canister) and [`raw_rand`](Self::raw_rand), use the
+/// management canister itself, or the requested id of a canister created with
+/// [`as_provisional_create_with_specified_id`](CreateCanisterBuilder::as_provisional_create_with_specified_id).
 #[derive(Debug, Clone)]
 pub struct ManagementCanister<'agent>(Canister<'agent>);
 
@@ -91,6 +102,9 @@ pub enum MgmtMethod {
     BitcoinGetCurrentFeePercentiles,
     /// There is no corresponding agent function as only canisters can call it.
     NodeMetricsHistory,
+    /// There is no corresponding agent function as only canisters can call it. Use
+    /// [`Agent::read_state_subnet_info`](ic_agent::Agent::read_state_subnet_info) instead.
+    SubnetInfo,
 }
 
 impl<'agent> ManagementCanister<'agent> {
@@ -266,11 +280,26 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(In {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
             .map(|result: (StatusCallResult,)| (result.0,))
     }
 
+    /// Get the statuses of several canisters, in the order of `canisters`.
+    ///
+    /// The calls are made concurrently, as many at a time as the agent's
+    /// [`max_concurrent_requests`](ic_agent::agent::AgentBuilder::with_max_concurrent_requests)
+    /// allows. A call that fails does not affect the others.
+    pub async fn canister_status_batch(
+        &self,
+        canisters: &[Principal],
+    ) -> Vec<Result<StatusCallResult, AgentError>> {
+        join_all(canisters.iter().map(|canister_id| async move {
+            let (status,) = self.canister_status(canister_id).call_and_wait().await?;
+            Ok(status)
+        }))
+        .await
+    }
+
     /// Create a canister.
     pub fn create_canister<'canister>(&'canister self) -> CreateCanisterBuilder<'agent, 'canister> {
         CreateCanisterBuilder::builder(self)
@@ -288,7 +317,6 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(Argument {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
@@ -303,10 +331,54 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(Argument {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
+    /// Until developers can convert real ICP tokens to provision a new canister with cycles,
+    /// the system provides the provisional_create_canister_with_cycles method.
+    /// It creates a canister with `settings` and `amount` fresh cycles, or MAX_CANISTER_BALANCE if
+    /// `amount` is `None`, and returns its id.
+    ///
+    /// The method only exists on local and test replicas, so this refuses to call it with
+    /// [`AgentError::UnavailableOnMainnet`] if the agent [points at mainnet](Agent::is_mainnet). To
+    /// create a canister with a specified id, use
+    /// [`CreateCanisterBuilder::as_provisional_create_with_specified_id`].
+    pub fn provisional_create_canister_with_cycles(
+        &self,
+        amount: Option<u128>,
+        settings: CanisterSettings,
+    ) -> Result<impl 'agent + AsyncCall<Value = (Principal,)>, AgentError> {
+        if self.agent.is_mainnet() {
+            return Err(AgentError::UnavailableOnMainnet(
+                MgmtMethod::ProvisionalCreateCanisterWithCycles
+                    .as_ref()
+                    .to_string(),
+            ));
+        }
+
+        #[derive(CandidType)]
+        struct Argument {
+            amount: Option<Nat>,
+            settings: CanisterSettings,
+            specified_id: Option<Principal>,
+        }
+        #[derive(Deserialize, CandidType)]
+        struct Out {
+            canister_id: Principal,
+        }
+
+        Ok(self
+            .update(MgmtMethod::ProvisionalCreateCanisterWithCycles.as_ref())
+            .with_arg(Argument {
+                amount: amount.map(Nat::from),
+                settings,
+                specified_id: None,
+            })
+            .with_effective_canister_id(Principal::management_canister())
+            .build()
+            .map(|(out,): (Out,)| (out.canister_id,)))
+    }
+
     /// Until developers can convert real ICP tokens to a top up an existing canister,
     /// the system provides the provisional_top_up_canister method.
     /// It adds amount cycles to the balance of canister identified by amount
@@ -327,7 +399,6 @@ impl<'agent> ManagementCanister<'agent> {
                 canister_id: *canister_id,
                 amount,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
@@ -351,7 +422,6 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(Argument {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
@@ -366,7 +436,6 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(Argument {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
@@ -387,11 +456,15 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(Argument {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(canister_id.to_owned())
             .build()
     }
 
     /// Install a canister, with all the arguments necessary for creating the canister.
+    ///
+    /// The module is sent in a single message, so a module too large for the transport fails with
+    /// [`AgentError::RequestTooLarge`]. Use
+    /// [`install`](Self::install), which switches to chunked installation for large modules, or
+    /// [`install_chunked`](Self::install_chunked) instead.
     pub fn install_code<'canister>(
         &'canister self,
         canister_id: &Principal,
@@ -426,7 +499,6 @@ impl<'agent> ManagementCanister<'agent> {
                 canister_id: *canister_id,
                 chunk,
             })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -441,7 +513,6 @@ impl<'agent> ManagementCanister<'agent> {
         }
         self.update(MgmtMethod::ClearChunkStore.as_ref())
             .with_arg(Argument { canister_id })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -456,7 +527,6 @@ impl<'agent> ManagementCanister<'agent> {
         }
         self.update(MgmtMethod::StoredChunks.as_ref())
             .with_arg(Argument { canister_id })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -482,6 +552,21 @@ impl<'agent> ManagementCanister<'agent> {
         InstallBuilder::builder(self, canister_id, wasm)
     }
 
+    /// Install a canister module using chunked installation regardless of module size: the module is split into chunks,
+    /// each chunk not already stored is uploaded via [`upload_chunk`](Self::upload_chunk), and the module is installed
+    /// from the collected hashes via [`install_chunked_code`](Self::install_chunked_code).
+    ///
+    /// # Warnings
+    ///
+    /// This will clear chunked code storage. Do not use with canisters that you are manually uploading chunked code to.
+    pub fn install_chunked<'canister: 'builder, 'builder>(
+        &'canister self,
+        canister_id: &Principal,
+        wasm: &'builder [u8],
+    ) -> InstallBuilder<'agent, 'canister, 'builder> {
+        InstallBuilder::builder(self, canister_id, wasm).with_chunked_install()
+    }
+
     /// Fetch the logs of a canister.
     pub fn fetch_canister_logs(
         &self,
@@ -497,7 +582,6 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(In {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -519,7 +603,6 @@ impl<'agent> ManagementCanister<'agent> {
                 canister_id: *canister_id,
                 replace_snapshot,
             })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -543,11 +626,13 @@ impl<'agent> ManagementCanister<'agent> {
                 snapshot_id,
                 sender_canister_version: None,
             })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
     /// List a canister's recorded snapshots.
+    ///
+    /// Like taking, loading, and deleting snapshots, this is only allowed to the canister's
+    /// controllers; other callers' calls are rejected.
     pub fn list_canister_snapshots(
         &self,
         canister_id: &Principal,
@@ -560,7 +645,6 @@ impl<'agent> ManagementCanister<'agent> {
             .with_arg(In {
                 canister_id: *canister_id,
             })
-            .with_effective_canister_id(*canister_id)
             .build()
     }
 
@@ -583,3 +667,301 @@ impl<'agent> ManagementCanister<'agent> {
             .build()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+    use ic_agent::{
+        agent::EnvelopeContent,
+        hash_tree::{fork, label, leaf},
+        test_utils::{sign_certificate, test_root_key, MockTransport, RecordingSleeper},
+        TransportCallResponse,
+    };
+    use sha2::{Digest, Sha256};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn status_of(canister_id: Principal) -> StatusCallResult {
+        let status = match canister_id.as_slice().last() {
+            Some(0) => CanisterStatus::Running,
+            Some(1) => CanisterStatus::Stopping,
+            _ => CanisterStatus::Stopped,
+        };
+        StatusCallResult {
+            status,
+            settings: DefiniteCanisterSettings {
+                controllers: vec![canister_id],
+                compute_allocation: Nat::from(0u8),
+                memory_allocation: Nat::from(0u8),
+                freezing_threshold: Nat::from(0u8),
+                reserved_cycles_limit: None,
+                wasm_memory_limit: None,
+                log_visibility: LogVisibility::Controllers,
+            },
+            module_hash: None,
+            memory_size: Nat::from(0u8),
+            cycles: Nat::from(0u8),
+            reserved_cycles: Nat::from(0u8),
+            idle_cycles_burned_per_day: Nat::from(0u8),
+            query_stats: QueryStats {
+                num_calls_total: Nat::from(0u8),
+                num_instructions_total: Nat::from(0u8),
+                request_payload_bytes_total: Nat::from(0u8),
+                response_payload_bytes_total: Nat::from(0u8),
+            },
+        }
+    }
+
+    /// Replies to calls with a certificate, signed by a fixed test root key, of the reply that the
+    /// function makes from the method name and argument of the call.
+    fn mgmt_transport<F>(f: F) -> MockTransport
+    where
+        F: Fn(&str, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        try_mgmt_transport(move |method_name, arg| Ok(f(method_name, arg)))
+    }
+
+    /// Like [`mgmt_transport`], failing the calls that the function fails.
+    fn try_mgmt_transport<F>(f: F) -> MockTransport
+    where
+        F: Fn(&str, &[u8]) -> Result<Vec<u8>, AgentError> + Send + Sync + 'static,
+    {
+        MockTransport::new().on_call(move |request| {
+            let content = request.content();
+            let request_id = content.to_request_id();
+            let EnvelopeContent::Call {
+                method_name, arg, ..
+            } = content
+            else {
+                panic!("expected a call envelope");
+            };
+            let reply = match f(&method_name, &arg) {
+                Ok(reply) => reply,
+                Err(err) => return std::future::ready(Err(err)),
+            };
+            let (certificate, _) = sign_certificate(label(
+                "request_status",
+                label(
+                    request_id.as_slice(),
+                    fork(
+                        label("reply", leaf(reply)),
+                        label("status", leaf("replied")),
+                    ),
+                ),
+            ));
+            let certificate = serde_cbor::to_vec(&certificate).unwrap();
+            std::future::ready(Ok(TransportCallResponse::Replied { certificate }))
+        })
+    }
+
+    fn make_agent(transport: MockTransport) -> Agent {
+        let agent = Agent::builder().with_transport(transport).build().unwrap();
+        agent.set_root_key(test_root_key());
+        agent
+    }
+
+    #[tokio::test]
+    async fn canister_status_batch_keeps_order() {
+        #[derive(CandidType, Deserialize)]
+        struct In {
+            canister_id: Principal,
+        }
+
+        // replies to `canister_status` with the status of the canister asked about
+        let agent = make_agent(mgmt_transport(|_: &str, arg: &[u8]| {
+            let canister_id = Decode!(arg, In).unwrap().canister_id;
+            Encode!(&status_of(canister_id)).unwrap()
+        }));
+
+        let canisters = [2, 0, 1].map(|i| Principal::from_slice(&[i]));
+        let statuses = ManagementCanister::create(&agent)
+            .canister_status_batch(&canisters)
+            .await;
+        let statuses = statuses
+            .into_iter()
+            .map(|status| status.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses.iter().map(|s| &s.status).collect::<Vec<_>>(),
+            [
+                &CanisterStatus::Stopped,
+                &CanisterStatus::Running,
+                &CanisterStatus::Stopping
+            ]
+        );
+        for (status, canister_id) in statuses.iter().zip(canisters) {
+            assert_eq!(status.settings.controllers, [canister_id]);
+        }
+    }
+
+    #[tokio::test]
+    async fn provisional_create_canister_with_cycles() {
+        #[derive(CandidType, Deserialize)]
+        struct In {
+            amount: Option<Nat>,
+            settings: CanisterSettings,
+            specified_id: Option<Principal>,
+        }
+        #[derive(CandidType)]
+        struct Out {
+            canister_id: Principal,
+        }
+
+        let controller = Principal::from_slice(&[9]);
+        let agent = make_agent(mgmt_transport(move |method: &str, arg: &[u8]| {
+            assert_eq!(method, "provisional_create_canister_with_cycles");
+            let arg = Decode!(arg, In).unwrap();
+            assert_eq!(arg.amount, Some(Nat::from(1_000_000_000_000_u128)));
+            assert_eq!(arg.settings.controllers, Some(vec![controller]));
+            assert_eq!(arg.specified_id, None);
+            Encode!(&Out {
+                canister_id: Principal::from_slice(&[7]),
+            })
+            .unwrap()
+        }));
+        let settings = CanisterSettings {
+            controllers: Some(vec![controller]),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+            reserved_cycles_limit: None,
+            wasm_memory_limit: None,
+            log_visibility: None,
+        };
+        let (canister_id,) = ManagementCanister::create(&agent)
+            .provisional_create_canister_with_cycles(Some(1_000_000_000_000), settings.clone())
+            .unwrap()
+            .call_and_wait()
+            .await
+            .unwrap();
+        assert_eq!(canister_id, Principal::from_slice(&[7]));
+
+        let mainnet = Agent::builder()
+            .with_url("https://icp-api.io")
+            .build()
+            .unwrap();
+        let result = ManagementCanister::create(&mainnet)
+            .provisional_create_canister_with_cycles(None, settings);
+        assert!(matches!(
+            result,
+            Err(AgentError::UnavailableOnMainnet(method))
+                if method == "provisional_create_canister_with_cycles"
+        ));
+    }
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        #[derive(CandidType, Deserialize)]
+        struct In {
+            canister_id: Principal,
+            replace_snapshot: Option<Vec<u8>>,
+        }
+
+        // takes snapshots of canisters, and lists them
+        let snapshots = Mutex::new(Vec::<(Principal, Snapshot)>::new());
+        let agent = make_agent(mgmt_transport(move |method: &str, arg: &[u8]| {
+            let mut snapshots = snapshots.lock().unwrap();
+            match method {
+                "take_canister_snapshot" => {
+                    let In {
+                        canister_id,
+                        replace_snapshot,
+                    } = Decode!(arg, In).unwrap();
+                    snapshots.retain(|(_, s)| Some(&s.id) != replace_snapshot.as_ref());
+                    let snapshot = Snapshot {
+                        id: vec![snapshots.len() as u8 + 1; 4],
+                        taken_at_timestamp: 1_700_000_000_000_000_000,
+                        total_size: 1024,
+                    };
+                    snapshots.push((canister_id, snapshot.clone()));
+                    Encode!(&snapshot).unwrap()
+                }
+                "list_canister_snapshots" => {
+                    let canister_id = Decode!(arg, In).unwrap().canister_id;
+                    let listed = snapshots
+                        .iter()
+                        .filter(|(c, _)| *c == canister_id)
+                        .map(|(_, s)| s.clone())
+                        .collect::<Vec<_>>();
+                    Encode!(&listed).unwrap()
+                }
+                method => panic!("unexpected call to {method}"),
+            }
+        }));
+        let mgmt = ManagementCanister::create(&agent);
+        let canister_id = Principal::from_slice(&[7]);
+
+        let (first,) = mgmt
+            .take_canister_snapshot(&canister_id, None)
+            .call_and_wait()
+            .await
+            .unwrap();
+        assert_eq!(first.id, [1; 4]);
+        assert_eq!(first.taken_at_timestamp, 1_700_000_000_000_000_000);
+        assert_eq!(first.total_size, 1024);
+        let (second,) = mgmt
+            .take_canister_snapshot(&canister_id, Some(&first.id))
+            .call_and_wait()
+            .await
+            .unwrap();
+
+        let (listed,) = mgmt
+            .list_canister_snapshots(&canister_id)
+            .call_and_wait()
+            .await
+            .unwrap();
+        assert_eq!(
+            listed.iter().map(|s| &s.id).collect::<Vec<_>>(),
+            [&second.id]
+        );
+    }
+
+    #[tokio::test]
+    async fn install_chunked_retries_failed_upload() {
+        #[derive(CandidType, Deserialize)]
+        struct UploadArg {
+            #[serde(with = "serde_bytes")]
+            chunk: Vec<u8>,
+        }
+
+        // the first upload of the chunk fails, as if the connection dropped
+        let uploads = Arc::new(Mutex::new(0));
+        let agent = {
+            let uploads = uploads.clone();
+            let transport = try_mgmt_transport(move |method: &str, arg: &[u8]| match method {
+                "stored_chunks" => Ok(Encode!(&StoreChunksResult::new()).unwrap()),
+                "upload_chunk" => {
+                    let mut uploads = uploads.lock().unwrap();
+                    *uploads += 1;
+                    if *uploads == 1 {
+                        return Err(AgentError::TransportError("connection reset".into()));
+                    }
+                    let chunk = Decode!(arg, UploadArg).unwrap().chunk;
+                    let hash = Sha256::digest(chunk).to_vec();
+                    Ok(Encode!(&ChunkHash { hash }).unwrap())
+                }
+                "install_chunked_code" | "clear_chunk_store" => Ok(Encode!().unwrap()),
+                method => panic!("unexpected call to {method}"),
+            })
+            // the agent checks whether the failed upload arrived before submitting it again
+            .on_read_state(|_| {
+                std::future::ready(Err(AgentError::TransportError("connection reset".into())))
+            });
+            Agent::builder().with_transport(transport)
+        };
+        let sleeper = Arc::new(RecordingSleeper::default());
+        let agent = agent.with_sleeper(sleeper.clone()).build().unwrap();
+        agent.set_root_key(test_root_key());
+
+        ManagementCanister::create(&agent)
+            .install_chunked(&Principal::from_slice(&[7]), b"\0asm\x01\0\0\0")
+            .call_and_wait()
+            .await
+            .unwrap();
+        assert_eq!(*uploads.lock().unwrap(), 2);
+        assert_eq!(sleeper.delays(), [Duration::from_millis(500)]);
+    }
+}
@@ -24,6 +24,7 @@ use std::{
     future::IntoFuture,
     pin::Pin,
     str::FromStr,
+    time::Duration,
 };
 
 /// The set of possible canister settings. Similar to [`DefiniteCanisterSettings`](super::DefiniteCanisterSettings),
@@ -645,6 +646,17 @@ impl<'agent, 'canister: 'agent> IntoFuture for InstallCodeBuilder<'agent, 'canis
     }
 }
 
+#[derive(CandidType, Deserialize)]
+struct InstallChunkedCodeArgs {
+    mode: InstallMode,
+    target_canister: Principal,
+    store_canister: Option<Principal>,
+    chunk_hashes_list: Vec<ChunkHash>,
+    wasm_module_hash: Vec<u8>,
+    arg: Vec<u8>,
+    sender_canister_version: Option<u64>,
+}
+
 /// A builder for an `install_chunked_code` call.
 #[derive(Debug)]
 pub struct InstallChunkedCodeBuilder<'agent, 'canister> {
@@ -716,16 +728,6 @@ impl<'agent: 'canister, 'canister> InstallChunkedCodeBuilder<'agent, 'canister>
 
     /// Create an [`AsyncCall`] implementation that, when called, will install the canister.
     pub fn build(self) -> Result<impl 'agent + AsyncCall<Value = ()>, AgentError> {
-        #[derive(CandidType)]
-        struct In {
-            mode: InstallMode,
-            target_canister: Principal,
-            store_canister: Option<Principal>,
-            chunk_hashes_list: Vec<ChunkHash>,
-            wasm_module_hash: Vec<u8>,
-            arg: Vec<u8>,
-            sender_canister_version: Option<u64>,
-        }
         let Self {
             mode,
             target_canister,
@@ -738,7 +740,7 @@ impl<'agent: 'canister, 'canister> InstallChunkedCodeBuilder<'agent, 'canister>
         Ok(self
             .canister
             .update(MgmtMethod::InstallChunkedCode.as_ref())
-            .with_arg(In {
+            .with_arg(InstallChunkedCodeArgs {
                 mode,
                 target_canister,
                 store_canister,
@@ -799,6 +801,7 @@ pub struct InstallBuilder<'agent, 'canister, 'builder> {
     wasm: &'builder [u8],
     arg: Argument,
     mode: InstallMode,
+    chunked: bool,
 }
 
 impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'canister, 'builder> {
@@ -818,6 +821,7 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
             wasm,
             arg: Default::default(),
             mode: InstallMode::Install,
+            chunked: false,
         }
     }
 
@@ -845,6 +849,14 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
         Self { mode, ..self }
     }
 
+    /// Always use chunked installation, even if the module and argument would fit in a single message.
+    pub fn with_chunked_install(self) -> Self {
+        Self {
+            chunked: true,
+            ..self
+        }
+    }
+
     /// Invoke the installation process. This may result in many calls which may take several seconds;
     /// use [`call_and_wait_with_progress`](Self::call_and_wait_with_progress) if you want progress reporting.
     pub async fn call_and_wait(self) -> Result<(), AgentError> {
@@ -863,7 +875,7 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
         let stream_res = /* try { */ async move {
             let arg = self.arg.serialize()?;
             let stream: BoxStream<'_, _> =
-                if self.wasm.len() + arg.len() < Self::CHUNK_CUTOFF {
+                if !self.chunked && self.wasm.len() + arg.len() < Self::CHUNK_CUTOFF {
                     Box::pin(
                         async move {
                             self.canister
@@ -878,7 +890,7 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
                 } else {
                     let (existing_chunks,) = self.canister.stored_chunks(&self.canister_id).call_and_wait().await?;
                     let existing_chunks = existing_chunks.into_iter().map(|c| c.hash).collect::<BTreeSet<_>>();
-                    let all_chunks = self.wasm.chunks(1024 * 1024).map(|x| (Sha256::digest(x).to_vec(), x)).collect::<Vec<_>>();
+                    let all_chunks = wasm_chunks(self.wasm);
                     let mut to_upload_chunks = vec![];
                     for (hash, chunk) in &all_chunks {
                         if !existing_chunks.contains(hash) {
@@ -888,14 +900,7 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
 
                     let upload_chunks_stream = FuturesUnordered::new();
                     for chunk in to_upload_chunks {
-                        upload_chunks_stream.push(async move {
-                            let (_res,) = self
-                                .canister
-                                .upload_chunk(&self.canister_id, chunk)
-                                .call_and_wait()
-                                .await?;
-                            Ok(())
-                        })
+                        upload_chunks_stream.push(upload_chunk_with_retries(self.canister, self.canister_id, chunk))
                     }
                     let install_chunked_code_stream = async move {
                         let results = all_chunks.iter().map(|(hash,_)| ChunkHash{ hash: hash.clone() }).collect();
@@ -934,6 +939,52 @@ impl<'agent: 'canister, 'canister: 'builder, 'builder> InstallBuilder<'agent, 'c
     }
 }
 
+/// The size of the pieces a module is split into for chunked installation.
+const WASM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How many times a single chunk upload is attempted before giving up.
+const MAX_CHUNK_UPLOAD_ATTEMPTS: u32 = 3;
+
+/// The delay before the second attempt at uploading a chunk, doubled before each attempt after.
+const CHUNK_UPLOAD_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Splits a module into the chunks to upload, each paired with its SHA-256 hash.
+fn wasm_chunks(wasm: &[u8]) -> Vec<(Vec<u8>, &[u8])> {
+    wasm.chunks(WASM_CHUNK_SIZE)
+        .map(|x| (Sha256::digest(x).to_vec(), x))
+        .collect()
+}
+
+/// Uploads a single chunk, retrying on failure after a backoff waited out with the agent's
+/// [sleeper](ic_agent::Agent::sleeper). The chunk store is addressed by hash, so re-uploading a
+/// chunk that was already stored by a failed-looking attempt is harmless.
+async fn upload_chunk_with_retries(
+    canister: &ManagementCanister<'_>,
+    canister_id: Principal,
+    chunk: &[u8],
+) -> Result<(), AgentError> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match canister
+            .upload_chunk(&canister_id, chunk)
+            .call_and_wait()
+            .await
+        {
+            Ok(_) => return Ok(()),
+            // Rejections are deterministic; retrying them will not help.
+            Err(err @ (AgentError::CertifiedReject(_) | AgentError::UncertifiedReject(_))) => {
+                return Err(err)
+            }
+            Err(err) if attempts >= MAX_CHUNK_UPLOAD_ATTEMPTS => return Err(err),
+            Err(_) => {
+                let backoff = CHUNK_UPLOAD_BACKOFF * 2u32.pow(attempts - 1);
+                canister.agent.sleeper().sleep(backoff).await;
+            }
+        }
+    }
+}
+
 impl<'agent: 'canister, 'canister: 'builder, 'builder> IntoFuture
     for InstallBuilder<'agent, 'canister, 'builder>
 {
@@ -1266,3 +1317,147 @@ impl<'agent, 'canister: 'agent> IntoFuture for UpdateCanisterBuilder<'agent, 'ca
 type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
 #[cfg(target_family = "wasm")]
 type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + 'a>>;
+
+#[cfg(test)]
+mod tests {
+    use super::{wasm_chunks, InstallChunkedCodeArgs, WASM_CHUNK_SIZE};
+    use crate::{
+        call::AsyncCall,
+        interfaces::management_canister::{ChunkHash, ManagementCanister},
+    };
+    use candid::{CandidType, Decode, Principal};
+    use ic_agent::{
+        agent::{CallResponse, EnvelopeContent},
+        test_utils::{MockRequest, MockTransport},
+        Agent, TransportCallResponse,
+    };
+    use sha2::{Digest, Sha256};
+    use std::sync::{Arc, Mutex};
+
+    /// Accepts call requests without a reply, recording them.
+    fn recording_transport(calls: Arc<Mutex<Vec<MockRequest>>>) -> MockTransport {
+        MockTransport::new().on_call(move |request| {
+            calls.lock().unwrap().push(request);
+            std::future::ready(Ok(TransportCallResponse::Accepted))
+        })
+    }
+
+    fn effective_canister_ids(calls: &Mutex<Vec<MockRequest>>) -> Vec<Principal> {
+        let calls = calls.lock().unwrap();
+        calls
+            .iter()
+            .map(|call| call.effective_canister_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn install_chunked_code_references_all_chunks() {
+        let wasm = (0..WASM_CHUNK_SIZE + 1)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let chunks = wasm_chunks(&wasm);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].1, &wasm[..WASM_CHUNK_SIZE]);
+        assert_eq!(chunks[1].1, &wasm[WASM_CHUNK_SIZE..]);
+        let hashes = chunks
+            .into_iter()
+            .map(|(hash, _)| ChunkHash { hash })
+            .collect::<Vec<_>>();
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let agent = Agent::builder()
+            .with_transport(recording_transport(calls.clone()))
+            .build()
+            .unwrap();
+        let canister_id = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+        let response = ManagementCanister::create(&agent)
+            .install_chunked_code(&canister_id, &Sha256::digest(&wasm))
+            .with_chunk_hashes(hashes.clone())
+            .call()
+            .await
+            .unwrap();
+        assert!(matches!(response, CallResponse::Poll(_)));
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        let EnvelopeContent::Call {
+            method_name, arg, ..
+        } = calls[0].content()
+        else {
+            panic!("expected a call envelope");
+        };
+        assert_eq!(method_name, "install_chunked_code");
+        let args = Decode!(&arg, InstallChunkedCodeArgs).unwrap();
+        assert_eq!(args.target_canister, canister_id);
+        assert_eq!(args.chunk_hashes_list, hashes);
+        assert_eq!(args.wasm_module_hash, Sha256::digest(&wasm).to_vec());
+    }
+
+    #[tokio::test]
+    async fn effective_canister_id_is_target_canister() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let agent = Agent::builder()
+            .with_transport(recording_transport(calls.clone()))
+            .build()
+            .unwrap();
+        let management_canister = ManagementCanister::create(&agent);
+        let canister_id = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+
+        management_canister
+            .canister_status(&canister_id)
+            .call()
+            .await
+            .unwrap();
+        management_canister
+            .install_code(&canister_id, b"\0asm\x01\0\0\0")
+            .call()
+            .await
+            .unwrap();
+        management_canister
+            .delete_canister_snapshot(&canister_id, &[1])
+            .call()
+            .await
+            .unwrap();
+        // calls built by hand get it too
+        #[derive(CandidType)]
+        struct In {
+            canister_id: Principal,
+        }
+        management_canister
+            .update("start_canister")
+            .with_arg(In { canister_id })
+            .build::<()>()
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(effective_canister_ids(&calls), [canister_id; 4]);
+    }
+
+    #[tokio::test]
+    async fn create_canister_effective_canister_id() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let agent = Agent::builder()
+            .with_transport(recording_transport(calls.clone()))
+            .build()
+            .unwrap();
+        let management_canister = ManagementCanister::create(&agent);
+        let specified_id = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+
+        management_canister
+            .create_canister()
+            .as_provisional_create_with_amount(None)
+            .call()
+            .await
+            .unwrap();
+        management_canister
+            .create_canister()
+            .as_provisional_create_with_specified_id(specified_id)
+            .call()
+            .await
+            .unwrap();
+        assert_eq!(
+            effective_canister_ids(&calls),
+            [Principal::management_canister(), specified_id]
+        );
+    }
+}
@@ -255,10 +255,11 @@ fn print_idl_blob(
 async fn fetch_root_key_from_non_ic(agent: &Agent, replica: &str) -> Result<()> {
     let normalized_replica = replica.strip_suffix('/').unwrap_or(replica);
     if normalized_replica != DEFAULT_IC_GATEWAY {
-        agent
-            .fetch_root_key()
-            .await
-            .context("Failed to fetch root key from replica")?;
+        match agent.fetch_root_key().await {
+            // other mainnet gateways use the built-in root key too
+            Ok(()) | Err(AgentError::FetchRootKeyOnMainnet) => {}
+            Err(err) => return Err(err).context("Failed to fetch root key from replica"),
+        }
     }
     Ok(())
 }
@@ -334,7 +335,8 @@ pub fn get_effective_canister_id(
             | MgmtMethod::BitcoinGetCurrentFeePercentiles
             | MgmtMethod::EcdsaPublicKey
             | MgmtMethod::SignWithEcdsa
-            | MgmtMethod::NodeMetricsHistory => {
+            | MgmtMethod::NodeMetricsHistory
+            | MgmtMethod::SubnetInfo => {
                 bail!("Management canister method {method_name} can only be run from canisters");
             }
         }
@@ -446,11 +448,16 @@ async fn main() -> Result<()> {
                         print_idl_blob(&blob, &t.output, &method_type)
                             .context("Failed to print result blob")?;
                     }
-                    Err(AgentError::TransportError(_)) => return Ok(()),
+                    Err(
+                        AgentError::TransportError(_)
+                        | AgentError::TimeoutError(_)
+                        | AgentError::ConnectTimeout(_),
+                    ) => return Ok(()),
                     Err(AgentError::HttpError(HttpErrorPayload {
                         status,
                         content_type,
                         content,
+                        ..
                     })) => {
                         let mut error_message =
                             format!("Server returned an HTTP Error:\n  Code: {}\n", status);
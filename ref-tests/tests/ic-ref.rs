@@ -893,7 +893,8 @@ mod management_canister {
         with_universal_canister(|agent, _| async move {
             let metrics = agent
                 .read_state_subnet_metrics(Principal::self_authenticating(&agent.read_root_key()))
-                .await?;
+                .await?
+                .value;
             assert!(
                 metrics.num_canisters >= 1,
                 "expected universal canister in num_canisters"
@@ -22,7 +22,6 @@ use ref_tests::{
     get_wallet_wasm_from_env, universal_canister::payload, with_universal_canister,
     with_wallet_canister,
 };
-use serde::Serialize;
 use std::{
     borrow::Cow,
     sync::Arc,
@@ -75,14 +74,6 @@ fn basic_expiry() {
 #[test]
 fn wait_signed() {
     with_universal_canister(|mut agent, canister_id| async move {
-        fn serialized_bytes(envelope: Envelope) -> Vec<u8> {
-            let mut serialized_bytes = Vec::new();
-            let mut serializer = serde_cbor::Serializer::new(&mut serialized_bytes);
-            serializer.self_describe().unwrap();
-            envelope.serialize(&mut serializer).unwrap();
-            serialized_bytes
-        }
-
         let arg = payload().reply_data(b"hello").build();
         let ingress_expiry = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
             + Duration::from_secs(120))
@@ -110,7 +101,7 @@ fn wait_signed() {
             sender_delegation: call_signature.delegations,
         };
 
-        let call_envelope_serialized = serialized_bytes(call_envelope);
+        let call_envelope_serialized = call_envelope.encode_bytes();
 
         agent
             .update_signed(canister_id, call_envelope_serialized)
@@ -136,7 +127,7 @@ fn wait_signed() {
             sender_delegation: read_signature.delegations,
         };
 
-        let read_envelope_serialized = serialized_bytes(read_state_envelope);
+        let read_envelope_serialized = read_state_envelope.encode_bytes();
 
         let result = agent
             .wait_signed(&call_request_id, canister_id, read_envelope_serialized)